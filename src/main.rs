@@ -16,10 +16,34 @@ fn default_cart() -> String {
 #[derive(FromArgs)]
 /// Rusty Sega Emulator.
 struct RustSegaArgs {
-    /// print PC State Debug Info
+    /// print an instruction trace to stdout (see '--trace')
     #[argh(switch, short = 'd')]
     debug: bool,
 
+    /// write an instruction trace to a file
+    #[argh(option)]
+    trace: Option<String>,
+
+    /// only trace PCs in this (hex) range, eg '0x0038-0x0100' (repeatable)
+    #[argh(option, from_str_fn(parse_pc_range))]
+    trace_pc: Vec<(u16, u16)>,
+
+    /// only trace when executing from this ROM bank (repeatable)
+    #[argh(option)]
+    trace_bank: Vec<u8>,
+
+    /// clock cycle to start tracing
+    #[argh(option)]
+    trace_start: Option<u64>,
+
+    /// clock cycle to stop tracing
+    #[argh(option)]
+    trace_stop: Option<u64>,
+
+    /// only keep the last N traced instructions (written when stopping)
+    #[argh(option)]
+    trace_ring: Option<usize>,
+
     /// run the emulator with no delay (rather than real-time)
     #[argh(switch, short = 'n')]
     no_delay: bool,
@@ -32,6 +56,42 @@ struct RustSegaArgs {
     #[argh(switch, short = 'f')]
     fullscreen: bool,
 
+    /// treat unimplemented opcodes as no-ops (rather than stopping)
+    #[argh(switch, short = 'i')]
+    ignore_faults: bool,
+
+    /// start paused in the interactive debugger (commands are read from stdin)
+    #[argh(switch)]
+    debugger: bool,
+
+    /// wait for a GDB (remote serial protocol) connection on this localhost port
+    #[argh(option)]
+    gdb: Option<u16>,
+
+    /// frames between rewind snapshots, and so stepped back at a time (default 1)
+    #[argh(option)]
+    rewind_interval: Option<u32>,
+
+    /// memory for rewind snapshots, in MB (default 32, 0 to disable rewind)
+    #[argh(option)]
+    rewind_budget: Option<usize>,
+
+    /// ROM database file, its entries override the built in ones
+    #[argh(option)]
+    romdb: Option<String>,
+
+    /// console region, 'japan' or 'export' (default from the ROM database or header)
+    #[argh(option, from_str_fn(parse_nationality))]
+    region: Option<sega::memory::romdb::Nationality>,
+
+    /// video standard, 'ntsc' or 'pal' (default from the ROM database)
+    #[argh(option, from_str_fn(parse_video))]
+    tv: Option<sega::memory::romdb::VideoStandard>,
+
+    /// BIOS image to boot (which then starts the cartridge)
+    #[argh(option)]
+    bios: Option<String>,
+
     /// list SDL drivers
     #[argh(switch, short = 'l')]
     list_drivers: bool,
@@ -41,6 +101,43 @@ struct RustSegaArgs {
     cartridge_name: String,
 }
 
+fn parse_pc_range(value: &str) -> Result<(u16, u16), String> {
+    sega::cpu::trace::parse_pc_range(value)
+}
+
+fn parse_nationality(value: &str) -> Result<sega::memory::romdb::Nationality, String> {
+    sega::memory::romdb::Nationality::parse(value)
+}
+
+fn parse_video(value: &str) -> Result<sega::memory::romdb::VideoStandard, String> {
+    sega::memory::romdb::VideoStandard::parse(value)
+}
+
+fn build_tracer(args: &RustSegaArgs) -> Option<sega::cpu::trace::Tracer> {
+    if !args.debug && args.trace.is_none() {
+        return None;
+    }
+
+    let mut filter = sega::cpu::trace::TraceFilter::new();
+    filter.pc_ranges = args.trace_pc.clone();
+    filter.banks = args.trace_bank.clone();
+    filter.start_cycle = args.trace_start.unwrap_or(0);
+    filter.stop_cycle = args.trace_stop;
+
+    let mut tracer = match &args.trace {
+        Some(filename) => sega::cpu::trace::Tracer::to_file(filename, filter).unwrap_or_else(|e| {
+            println!("Unable to create trace file {}: {}", filename, e);
+            std::process::exit(1);
+        }),
+        None => sega::cpu::trace::Tracer::new(Box::new(std::io::stdout()), filter),
+    };
+    if let Some(ring_size) = args.trace_ring {
+        tracer.set_ring_size(ring_size);
+    }
+    tracer.flush_on_panic();
+    Some(tracer)
+}
+
 fn full_description_string() -> String {
     let mut description =
         "Possible audio drivers, to use prefix command with: SDL_AUDIODRIVER=<driver>\n".to_owned();
@@ -65,14 +162,65 @@ fn main() {
     if args.list_drivers {
         println!("{}", full_description_string());
     }
+    let romdb = match &args.romdb {
+        Some(filename) => {
+            sega::memory::romdb::RomDb::with_overrides(filename).unwrap_or_else(|e| {
+                println!("Unable to read ROM database {}: {}", filename, e);
+                std::process::exit(1);
+            })
+        }
+        None => sega::memory::romdb::RomDb::embedded(),
+    };
+    let bios = args.bios.as_ref().map(|filename| {
+        std::fs::read(filename).unwrap_or_else(|e| {
+            println!("Unable to read BIOS {}: {}", filename, e);
+            std::process::exit(1);
+        })
+    });
+
     let mut sega_machine = sega::sega::Sega::new(
-        args.debug,
+        build_tracer(&args),
         !args.no_delay,
         args.stop_clock.unwrap_or(0),
         &args.cartridge_name,
         args.fullscreen,
+        args.ignore_faults,
+        sega::sega::MachineOptions {
+            romdb,
+            bios,
+            nationality: args.region,
+            video: args.tv,
+        },
     );
 
+    let rewind_budget = args
+        .rewind_budget
+        .map_or(sega::rewind::Rewind::DEFAULT_BUDGET, |budget| {
+            budget * 1024 * 1024
+        });
+    if rewind_budget > 0 {
+        sega_machine.set_rewind(sega::rewind::Rewind::new(
+            args.rewind_interval
+                .unwrap_or(sega::rewind::Rewind::DEFAULT_INTERVAL),
+            rewind_budget,
+        ));
+    }
+
+    if let Some(port) = args.gdb {
+        match sega::debugger::gdb::GdbStub::listen(port) {
+            Ok(gdb_stub) => sega_machine.set_debugger(Box::new(gdb_stub)),
+            Err(e) => {
+                println!("Unable to listen for GDB on port {}: {}", port, e);
+                return;
+            }
+        }
+    } else if args.debugger {
+        sega_machine.set_debugger(Box::new(sega::debugger::repl::Debugger::new(
+            Box::new(std::io::BufReader::new(std::io::stdin())),
+            Box::new(std::io::stdout()),
+        )));
+    }
+
     #[cfg(target_os = "emscripten")]
     {
         let mut main_loop = move || {
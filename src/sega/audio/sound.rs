@@ -1,3 +1,4 @@
+use super::super::savestate;
 use super::soundchannel;
 use sdl2::audio;
 
@@ -186,3 +187,25 @@ impl Sound {
         }
     }
 }
+
+impl savestate::SaveState for Sound {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_u8(self.latched_reg.data);
+        writer.write_u32(self.channels.len() as u32);
+        for channel in &self.channels {
+            channel.save_state(writer);
+        }
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.latched_reg.data = reader.read_u8()?;
+        reader.read_length(self.channels.len())?;
+        for channel in self.channels.iter_mut() {
+            channel.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
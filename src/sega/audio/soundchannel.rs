@@ -1,4 +1,7 @@
-pub trait SoundGenerator {
+use super::super::savestate;
+
+// Save states are part of the generator, as each channel type has its own registers.
+pub trait SoundGenerator: savestate::SaveState {
     // Data may be from latched or data
     fn set_volume(&mut self, data: u8);
     // Data is from both 'latched' and 'data', may represent noise or tone (depending on channel).
@@ -194,3 +197,45 @@ impl SoundGenerator for NoiseSoundChannel {
         channel_wave
     }
 }
+
+impl savestate::SaveState for ToneSoundChannel {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_u16(self.freq_reg);
+        writer.write_u8(self.volume_reg);
+        writer.write_bool(self.current_level);
+        writer.write_u32(self.frequency_counter);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.freq_reg = reader.read_u16()?;
+        self.volume_reg = reader.read_u8()?;
+        self.current_level = reader.read_bool()?;
+        self.frequency_counter = reader.read_u32()?;
+        Ok(())
+    }
+}
+
+impl savestate::SaveState for NoiseSoundChannel {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_bool(self.noise_period_select);
+        writer.write_u16(self.noise_shift_register);
+        writer.write_u16(self.freq_reg);
+        writer.write_u8(self.volume_reg);
+        writer.write_u32(self.frequency_counter);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.noise_period_select = reader.read_bool()?;
+        self.noise_shift_register = reader.read_u16()?;
+        self.freq_reg = reader.read_u16()?;
+        self.volume_reg = reader.read_u8()?;
+        self.frequency_counter = reader.read_u32()?;
+        Ok(())
+    }
+}
@@ -1,3 +1,6 @@
+use super::memory::romdb;
+use super::savestate;
+
 pub type ClockType = u64;
 
 //#[derive(Copy)]
@@ -14,3 +17,193 @@ impl Clock {
         self.cycles = self.cycles.wrapping_add(inc as u64);
     }
 }
+
+impl savestate::SaveState for Clock {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_u64(self.cycles);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.cycles = reader.read_u64()?;
+        Ok(())
+    }
+}
+
+// V-counter values through a frame, as ranges (the counter only has 8 bits,
+// so it jumps back part way through the frame).
+pub type VCounterRanges = &'static [(u8, u8)];
+
+// Timing of the console's video standard, the CPU clock and the number of
+// lines in a frame (NTSC at 60Hz, or PAL at 50Hz).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimingProfile {
+    pub clock_hz: u32,
+    pub lines_per_frame: u16,
+    pub v_counters: [VCounterRanges; 3], // For 192, 224 and 240 active lines.
+}
+
+impl TimingProfile {
+    pub const CYCLES_PER_LINE: u16 = 228;
+    pub const ACTIVE_LINES: [u16; 3] = [192, 224, 240];
+
+    // The H-counter counts every other pixel (of 342), jumping from 0x93 to 0xE9.
+    const H_COUNTER_JUMP: (u8, u8) = (0x93, 0xE9);
+
+    pub const NTSC: TimingProfile = TimingProfile {
+        clock_hz: 3579545,
+        lines_per_frame: 262,
+        v_counters: [
+            &[(0x00, 0xDA), (0xD5, 0xFF)],
+            &[(0x00, 0xEA), (0xE5, 0xFF)],
+            &[(0x00, 0xFF), (0x00, 0x05)],
+        ],
+    };
+    pub const PAL: TimingProfile = TimingProfile {
+        clock_hz: 3546893,
+        lines_per_frame: 313,
+        v_counters: [
+            &[(0x00, 0xF2), (0xBA, 0xFF)],
+            &[(0x00, 0xFF), (0x00, 0x02), (0xCA, 0xFF)],
+            &[(0x00, 0xFF), (0x00, 0x0A), (0xD2, 0xFF)],
+        ],
+    };
+
+    pub fn for_video(video: romdb::VideoStandard) -> Self {
+        match video {
+            romdb::VideoStandard::Ntsc => TimingProfile::NTSC,
+            romdb::VideoStandard::Pal => TimingProfile::PAL,
+        }
+    }
+
+    pub fn frame_cycles(&self) -> u32 {
+        self.lines_per_frame as u32 * TimingProfile::CYCLES_PER_LINE as u32
+    }
+
+    // V-counter for a line of the frame, with 'active_lines' (192, 224 or 240).
+    pub fn v_counter(&self, active_lines: u16, line: u16) -> u8 {
+        let mode = TimingProfile::ACTIVE_LINES
+            .iter()
+            .position(|lines| *lines == active_lines)
+            .unwrap_or(0);
+        let mut line = line % self.lines_per_frame;
+        for (first, last) in self.v_counters[mode] {
+            let length = (*last - *first) as u16 + 1;
+            if line < length {
+                return *first + line as u8;
+            }
+            line -= length;
+        }
+        unreachable!("V-counter ranges should cover the frame");
+    }
+
+    // H-counter for a number of CPU cycles into the line.
+    pub fn h_counter(line_cycles: u32) -> u8 {
+        let (jump_from, jump_to) = TimingProfile::H_COUNTER_JUMP;
+        // Three pixels every two cycles, counting every other pixel.
+        let count = (line_cycles % TimingProfile::CYCLES_PER_LINE as u32) * 3 / 4;
+        if count <= jump_from as u32 {
+            count as u8
+        } else {
+            (count - jump_from as u32 - 1 + jump_to as u32) as u8
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::clocks;
+
+    #[test]
+    fn test_timing_profiles() {
+        let ntsc = clocks::TimingProfile::NTSC;
+        assert_eq!(ntsc.frame_cycles(), 59736);
+        assert_eq!(ntsc.clock_hz / ntsc.frame_cycles(), 59); // 59.92Hz
+
+        let pal = clocks::TimingProfile::PAL;
+        assert_eq!(pal.frame_cycles(), 71364);
+        assert_eq!(pal.clock_hz / pal.frame_cycles(), 49); // 49.70Hz
+    }
+
+    #[test]
+    fn test_v_counter() {
+        // (profile, active lines, [(line, V-counter)]), around the jumps and the frame's end.
+        let test_values = [
+            (
+                clocks::TimingProfile::NTSC,
+                192,
+                vec![
+                    (0, 0x00),
+                    (0xDA, 0xDA),
+                    (0xDB, 0xD5),
+                    (261, 0xFF),
+                    (262, 0x00),
+                ],
+            ),
+            (
+                clocks::TimingProfile::NTSC,
+                224,
+                vec![(0, 0x00), (0xEA, 0xEA), (0xEB, 0xE5), (261, 0xFF)],
+            ),
+            (
+                clocks::TimingProfile::NTSC,
+                240,
+                vec![(0, 0x00), (0xFF, 0xFF), (0x100, 0x00), (261, 0x05)],
+            ),
+            (
+                clocks::TimingProfile::PAL,
+                192,
+                vec![(0, 0x00), (0xF2, 0xF2), (0xF3, 0xBA), (312, 0xFF)],
+            ),
+            (
+                clocks::TimingProfile::PAL,
+                224,
+                vec![(0xFF, 0xFF), (0x102, 0x02), (0x103, 0xCA), (312, 0xFF)],
+            ),
+            (
+                clocks::TimingProfile::PAL,
+                240,
+                vec![(0xFF, 0xFF), (0x10A, 0x0A), (0x10B, 0xD2), (312, 0xFF)],
+            ),
+        ];
+        for (profile, active_lines, values) in test_values {
+            for (line, v_counter) in values {
+                assert_eq!(
+                    profile.v_counter(active_lines, line),
+                    v_counter,
+                    "{} lines, line {}",
+                    active_lines,
+                    line
+                );
+            }
+        }
+
+        // Every mode's ranges cover the whole frame.
+        for profile in [clocks::TimingProfile::NTSC, clocks::TimingProfile::PAL] {
+            for ranges in profile.v_counters {
+                let lines: u16 = ranges
+                    .iter()
+                    .map(|(first, last)| (*last - *first) as u16 + 1)
+                    .sum();
+                assert_eq!(lines, profile.lines_per_frame);
+            }
+        }
+    }
+
+    #[test]
+    fn test_h_counter() {
+        let test_values = [
+            (0, 0x00),
+            (4, 0x03),
+            (197, 0x93),
+            (198, 0xE9),
+            (227, 0xFF),
+            (228, 0x00),
+        ];
+        for (line_cycles, h_counter) in test_values {
+            assert_eq!(clocks::TimingProfile::h_counter(line_cycles), h_counter);
+        }
+    }
+}
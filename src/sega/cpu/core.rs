@@ -3,8 +3,12 @@ use super::super::graphics;
 use super::super::interruptor;
 use super::super::memory::memory;
 use super::super::ports;
+use super::super::savestate;
+use super::fault;
+use super::instruction_set;
 use super::instructions;
 use super::pc_state;
+use super::trace;
 use std::thread;
 use std::time;
 
@@ -16,16 +20,16 @@ pub struct Core<M> {
     interruptor: interruptor::Interruptor,
     raw_display: Vec<u8>,
     start_time: time::SystemTime,
-}
-
-struct Constants {}
-
-impl Constants {
-    pub const CLOCK_HZ: u32 = 3590000; // set to Z80 clock speed for SMS
+    fault_policy: fault::FaultPolicy,
+    tracer: Option<trace::Tracer>,
+    timing: clocks::TimingProfile,
 }
 
 impl<M: memory::MemoryRW> Core<M> {
     pub const IRQIM1ADDR: u16 = 0x38;
+    // Nothing drives the data bus during the interrupt acknowledge, so it's
+    // read as 0xFF (RST 38h in IM 0, low byte of the vector in IM 2).
+    pub const IRQ_DATA_BUS: u8 = 0xFF;
 
     pub fn new(
         clock: clocks::Clock,
@@ -50,39 +54,113 @@ impl<M: memory::MemoryRW> Core<M> {
                     * (graphics::display::SDLUtility::bytes_per_pixel() as usize)
             ],
             start_time: time::SystemTime::now(),
+            fault_policy: fault::FaultPolicy::Stop,
+            tracer: None,
+            timing: clocks::TimingProfile::NTSC,
         }
     }
 
+    // CPU clock speed, for the real-time throttle.
+    pub fn set_timing(&mut self, timing: clocks::TimingProfile) {
+        self.timing = timing;
+    }
+
+    pub fn get_timing(&self) -> clocks::TimingProfile {
+        self.timing
+    }
+
+    pub fn set_fault_policy(&mut self, fault_policy: fault::FaultPolicy) {
+        self.fault_policy = fault_policy;
+    }
+
+    pub fn set_tracer(&mut self, tracer: trace::Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Write out any traced instructions still held (eg in a ring).
+    pub fn flush_trace(&mut self) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.flush();
+        }
+    }
+
+    pub fn get_pc_state(&self) -> &pc_state::PcState {
+        &self.pc_state
+    }
+
+    pub fn get_pc_state_mut(&mut self) -> &mut pc_state::PcState {
+        &mut self.pc_state
+    }
+
     fn interupt(&mut self) {
-        if self.pc_state.get_iff1() {
-            if self.pc_state.get_im() == 1 {
-                self.pc_state.increment_sp(-1);
-                self.memory
-                    .write(self.pc_state.get_sp(), self.pc_state.get_pc_high());
-                self.pc_state.increment_sp(-1);
-                self.memory
-                    .write(self.pc_state.get_sp(), self.pc_state.get_pc_low());
-                self.pc_state.set_pc(Core::<M>::IRQIM1ADDR);
-
-                // Disable mask-able interrupts
-                self.pc_state.set_iff1(false);
-            } else {
-                // TODO: Fix error messages/handling.
-                println!("interupt mode not supported");
-            }
+        // Not accepted in the instruction straight after EI.
+        if !self.pc_state.get_iff1() || self.pc_state.get_ei_delay() {
+            return;
+        }
+
+        if instruction_set::interrupt(
+            &mut self.clock,
+            &mut self.memory,
+            &mut self.pc_state,
+            Core::<M>::IRQ_DATA_BUS,
+        ) {
+            self.pc_state.set_halted(false);
+        } else {
+            // TODO: Fix error messages/handling.
+            println!(
+                "interupt mode not supported: {} (data bus {:x})",
+                self.pc_state.get_im(),
+                Core::<M>::IRQ_DATA_BUS
+            );
         }
     }
 
+    // Non-maskable interrupt (the SMS 'pause' button), always serviced.
+    pub fn nmi(&mut self) {
+        self.pc_state.set_halted(false);
+        instruction_set::nmi(&mut self.clock, &mut self.memory, &mut self.pc_state);
+    }
+
+    // While halted, the CPU executes NOPs until an interrupt is serviced.
+    // Rather than stepping through them, skip straight to the next interrupt
+    // (R is derived from the clock, see 'ld_a_r', so it still increments).
+    fn halted_step(&mut self) {
+        let nop_cycles = match self.ports.next_interrupt() {
+            Some(next_interrupt) if next_interrupt > self.clock.cycles => {
+                // Round up to a whole number of NOPs.
+                (next_interrupt - self.clock.cycles + 3) & !0x3
+            }
+            _ => 4,
+        };
+        self.clock.increment(nop_cycles as u32);
+    }
+
     pub fn export(&mut self) -> bool {
         self.ports.export(&mut self.raw_display)
     }
 
+    pub fn redraw(&mut self) {
+        self.ports.redraw(&mut self.raw_display)
+    }
+
+    // Restart the real-time clock from the current cycle count (eg after
+    // being paused), rather than catching up on the lost time.
+    pub fn resync_realtime(&mut self) {
+        let elapsed = time::Duration::from_millis(self.elapsed_ms());
+        self.start_time = time::SystemTime::now() - elapsed;
+    }
+
+    // Milliseconds of emulated time, at the CPU's clock speed.
+    fn elapsed_ms(&self) -> u64 {
+        1000 * self.clock.cycles / self.timing.clock_hz as u64
+    }
+
     pub fn reset(&mut self) {
         self.pc_state = pc_state::PcState::new();
         self.start_time = time::SystemTime::now();
     }
 
-    pub fn step(&mut self, debug: bool, realtime: bool) {
+    pub fn step(&mut self, realtime: bool) -> Result<(), fault::CpuFault> {
         // Start with 'expanded' version of step
 
         if realtime {
@@ -91,37 +169,65 @@ impl<M: memory::MemoryRW> Core<M> {
                 .elapsed()
                 .expect("Error getting eplapsed")
                 .as_millis() as u64;
-            if 1000 * self.clock.cycles / Constants::CLOCK_HZ as u64 > in_ms {
-                let required_sleep =
-                    (1000 * self.clock.cycles / Constants::CLOCK_HZ as u64) - in_ms;
-                thread::sleep(time::Duration::from_millis(required_sleep));
+            let elapsed_ms = self.elapsed_ms();
+            if elapsed_ms > in_ms {
+                thread::sleep(time::Duration::from_millis(elapsed_ms - in_ms));
             }
         }
 
         self.interruptor.set_cycle(self.clock.cycles);
 
+        // Any EI delay only applies to the previous instruction.
+        self.pc_state.set_ei_delay(false);
+
+        if self.pc_state.get_halted() {
+            self.halted_step();
+            self.poll_interrupts();
+            return Ok(());
+        }
+
         let op_code = self.memory.read(self.pc_state.get_pc());
 
-        if debug {
-            print!(
-                "{} {:x} {:x} ({:x} {:x}) ",
-                self.clock.cycles,
-                op_code,
-                self.pc_state.get_pc(),
-                op_code,
-                self.memory.read(self.pc_state.get_pc() + 1)
-            );
-            println!("{}", self.pc_state);
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.trace(self.clock.cycles, &self.memory, &self.pc_state);
         }
         self.pc_state.increment_pc(1);
-        instructions::Instruction::execute(
+        if let Err(cpu_fault) = instructions::Instruction::execute(
             op_code,
             &mut self.clock,
             &mut self.memory,
             &mut self.pc_state,
             &mut self.ports,
             &mut self.interruptor,
-        );
+        ) {
+            match self.fault_policy {
+                fault::FaultPolicy::Stop => {
+                    // Write out the instructions leading up to the fault.
+                    self.flush_trace();
+                    return Err(cpu_fault);
+                }
+                fault::FaultPolicy::TreatAsNop => {
+                    // The PC has already moved past the opcode, so just
+                    // account for the opcode fetches.
+                    println!("{}, treating as NOP", cpu_fault);
+                    self.clock.increment(4 * cpu_fault.op_codes.len() as u32);
+                }
+            }
+        }
+        // The ports don't have access to the memory, so pass on any memory control write.
+        if let Some(value) = self.ports.take_memory_control() {
+            self.memory.memory_control(value);
+        }
+        self.poll_interrupts();
+        Ok(())
+    }
+
+    fn poll_interrupts(&mut self) {
+        // The NMI takes priority, the maskable interrupt will be ignored
+        // (iff1 is cleared) until the NMI handler returns via RETN.
+        if self.ports.joysticks.poll_nmi() {
+            self.nmi();
+        }
         if self
             .ports
             .poll_interrupts(&mut self.raw_display, &self.clock)
@@ -136,6 +242,29 @@ impl<M: memory::MemoryRW> Core<M> {
     }
 }
 
+// The machine state, the real-time clock, timing, fault policy and tracer are
+// settings of this run (so aren't included).
+impl<M: savestate::SaveState> savestate::SaveState for Core<M> {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        self.clock.save_state(writer);
+        self.pc_state.save_state(writer);
+        self.memory.save_state(writer);
+        self.ports.save_state(writer);
+        self.interruptor.save_state(writer);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.clock.load_state(reader)?;
+        self.pc_state.load_state(reader)?;
+        self.memory.load_state(reader)?;
+        self.ports.load_state(reader)?;
+        self.interruptor.load_state(reader)
+    }
+}
+
 #[test]
 fn test_core_creation() {
     use super::super::graphics::vdp;
@@ -149,7 +278,152 @@ fn test_core_creation() {
     ports.add_device(Box::new(vdp));
     let mut core = Core::new(clock, memory, pc_state, ports, interruptor);
 
-    core.step(true, false);
+    core.step(false).unwrap();
     println!("{}", core.pc_state);
-    core.step(true, false);
+    core.step(false).unwrap();
+}
+
+#[test]
+fn test_core_fault_policy() {
+    // INC A; NOP, with INC A faulting.
+    instructions::set_fault_op_code(Some(0x3C));
+
+    let mut core = test_core_with_program(&[0x3C, 0x00]);
+    let cpu_fault = core.step(false).unwrap_err();
+    assert_eq!(cpu_fault.prefix, fault::Prefix::None);
+    assert_eq!(cpu_fault.op_codes, vec![0x3C]);
+    assert_eq!(cpu_fault.pc, 0x100);
+    assert_eq!(cpu_fault.cycles, 0);
+
+    let mut core = test_core_with_program(&[0x3C, 0x00]);
+    core.set_fault_policy(fault::FaultPolicy::TreatAsNop);
+    core.step(false).unwrap();
+    assert_eq!(core.pc_state.get_pc(), 0x101);
+    assert_eq!(core.pc_state.get_a(), 0);
+    assert_eq!(core.clock.cycles, 4);
+    core.step(false).unwrap();
+    assert_eq!(core.pc_state.get_pc(), 0x102);
+
+    instructions::set_fault_op_code(None);
+}
+
+// Core with flat memory, the program at 0x100 and a VDP (which has a line
+// interrupt pending after the first step).
+#[cfg(test)]
+fn test_core_with_program(program: &[u8]) -> Core<super::cpm::CpmMemory> {
+    use super::super::graphics::vdp;
+    use super::super::memory::memory::MemoryRW;
+    use super::cpm;
+
+    let mut memory = cpm::CpmMemory::new();
+    for (offset, op_code) in program.iter().enumerate() {
+        MemoryRW::write(&mut memory, 0x100 + offset as u16, *op_code);
+    }
+    let mut ports = ports::Ports::new();
+    ports.add_device(Box::new(vdp::Vdp::new()));
+    let mut core = Core::new(
+        clocks::Clock::new(),
+        memory,
+        pc_state::PcState::new(),
+        ports,
+        interruptor::Interruptor::new(),
+    );
+    core.pc_state.set_pc(0x100);
+    core.pc_state.sp_reg.set(0x8000);
+    core.pc_state.set_im(1);
+    core
+}
+
+#[test]
+fn test_core_halt() {
+    use super::super::memory::memory::MemoryRW;
+
+    let mut core = test_core_with_program(&[0x76]); // HALT
+
+    core.step(false).unwrap();
+    assert!(core.pc_state.get_halted());
+    assert_eq!(core.pc_state.get_pc(), 0x101);
+    assert_eq!(core.clock.cycles, 4);
+
+    // Interrupts disabled, skips to the next (line) interrupt, but stays halted.
+    core.step(false).unwrap();
+    assert!(core.pc_state.get_halted());
+    assert_eq!(core.pc_state.get_pc(), 0x101);
+    assert_eq!(core.clock.cycles, 228);
+
+    // The (still pending) interrupt ends the HALT, returning to the next instruction.
+    core.pc_state.set_iff1(true);
+    core.step(false).unwrap();
+    assert!(!core.pc_state.get_halted());
+    assert_eq!(core.pc_state.get_pc(), 0x38);
+    assert_eq!(core.memory.read16(core.pc_state.get_sp()), 0x101);
+
+    // NMI also ends the HALT.
+    core.pc_state.set_pc(0x100);
+    core.step(false).unwrap();
+    assert!(core.pc_state.get_halted());
+    core.nmi();
+    assert!(!core.pc_state.get_halted());
+    assert_eq!(core.pc_state.get_pc(), 0x66);
+}
+
+#[test]
+fn test_core_ei_delay() {
+    use super::super::memory::memory::MemoryRW;
+
+    // EI; NOP, the pending interrupt is taken after the NOP.
+    let mut core = test_core_with_program(&[0xFB, 0x00]);
+    core.step(false).unwrap();
+    assert!(core.pc_state.get_iff1());
+    assert_eq!(core.pc_state.get_pc(), 0x101);
+    core.step(false).unwrap();
+    assert_eq!(core.pc_state.get_pc(), 0x38);
+    assert_eq!(core.memory.read16(core.pc_state.get_sp()), 0x102);
+
+    // EI; EI; NOP, each EI delays the interrupt again.
+    let mut core = test_core_with_program(&[0xFB, 0xFB, 0x00]);
+    core.step(false).unwrap();
+    core.step(false).unwrap();
+    assert_eq!(core.pc_state.get_pc(), 0x102);
+    core.step(false).unwrap();
+    assert_eq!(core.pc_state.get_pc(), 0x38);
+    assert_eq!(core.memory.read16(core.pc_state.get_sp()), 0x103);
+
+    // EI; HALT, the interrupt ends the HALT straight away, returning after it.
+    let mut core = test_core_with_program(&[0xFB, 0x76]);
+    core.step(false).unwrap();
+    core.step(false).unwrap();
+    assert!(!core.pc_state.get_halted());
+    assert_eq!(core.pc_state.get_pc(), 0x38);
+    assert_eq!(core.memory.read16(core.pc_state.get_sp()), 0x102);
+
+    // DI; EI; RETI, the handler returns before the next interrupt is taken.
+    let mut core = test_core_with_program(&[0xF3, 0xFB, 0xED, 0x4D]);
+    core.pc_state.sp_reg.set(0x7FFE);
+    MemoryRW::write(&mut core.memory, 0x7FFE, 0x00);
+    MemoryRW::write(&mut core.memory, 0x7FFF, 0x02); // Return to 0x200
+    core.step(false).unwrap();
+    core.step(false).unwrap();
+    core.step(false).unwrap();
+    assert_eq!(core.pc_state.get_pc(), 0x38);
+    assert_eq!(core.memory.read16(core.pc_state.get_sp()), 0x200);
+}
+
+#[test]
+fn test_core_light_phaser_latch() {
+    let mut core = test_core_with_program(&[0x00; 4]);
+    core.step(false).unwrap();
+
+    // Latched at the end of the step the phaser sees the beam, without a port write.
+    core.ports.joysticks.lg1(false);
+    core.step(false).unwrap();
+    assert_eq!(core.clock.cycles, 8);
+    let expected = clocks::TimingProfile::h_counter(8);
+    let clock = clocks::Clock::new();
+    assert_eq!(core.ports.port_read(&clock, 0x7F), expected);
+
+    // And not again as it's released.
+    core.ports.joysticks.lg1(true);
+    core.step(false).unwrap();
+    assert_eq!(core.ports.port_read(&clock, 0x7F), expected);
 }
@@ -0,0 +1,297 @@
+// Minimal CP/M environment, used to run Z80 test programs (eg ZEXDOC/ZEXALL)
+// against the CPU core.
+// The '.COM' image is loaded at 0x100 into a flat 64K memory, BDOS calls
+// (CALL 5) are trapped to capture console output (functions 2 and 9 only),
+// and a jump to 0x0000 (warm boot) ends the program.
+use super::super::clocks;
+use super::super::interruptor;
+use super::super::memory::memory;
+use super::super::ports;
+use super::fault;
+use super::instructions;
+use super::pc_state;
+use crate::impl_common_memoryrw;
+use std::fmt;
+
+pub struct Constants {}
+
+impl Constants {
+    pub const MEMORY_SIZE: usize = 0x10000;
+    pub const TPA_START: u16 = 0x0100; // Where '.COM' programs are loaded.
+    pub const WARM_BOOT: u16 = 0x0000;
+    pub const BDOS_ENTRY: u16 = 0x0005;
+    // 'JP BDOS_RETURN' at 0x0005, so programs reading the top of memory from
+    // 0x0006 (as the ZEX tests do) get a sensible stack.
+    pub const BDOS_RETURN: u16 = 0xFE00;
+
+    pub const C_WRITE: u8 = 2; // Print the character in E.
+    pub const C_WRITESTR: u8 = 9; // Print the '$' terminated string at DE.
+}
+
+// Flat 64K of RAM, no paging.
+pub struct CpmMemory {
+    memory: Vec<u8>,
+}
+
+impl CpmMemory {
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; Constants::MEMORY_SIZE],
+        }
+    }
+
+    fn read(&self, address: memory::AddressType) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address: memory::AddressType, data: u8) {
+        self.memory[address as usize] = data;
+    }
+
+    fn rom_bank(&self, address: memory::AddressType) -> Option<u8> {
+        None
+    }
+}
+
+impl_common_memoryrw!(CpmMemory);
+
+#[derive(Debug, PartialEq)]
+pub enum CpmError {
+    Fault(fault::CpuFault),
+    CycleLimit(clocks::ClockType), // Didn't finish within the allowed cycles.
+    UnsupportedBdos(u8),
+}
+
+impl fmt::Display for CpmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpmError::Fault(cpu_fault) => write!(f, "{}", cpu_fault),
+            CpmError::CycleLimit(cycles) => {
+                write!(f, "Program didn't finish after {} cycles", cycles)
+            }
+            CpmError::UnsupportedBdos(function) => {
+                write!(f, "BDOS function not supported: {}", function)
+            }
+        }
+    }
+}
+
+pub struct CpmMachine {
+    pub clock: clocks::Clock,
+    pub memory: CpmMemory,
+    pub pc_state: pc_state::PcState,
+    pub ports: ports::Ports,
+    pub interruptor: interruptor::Interruptor,
+    pub output: String,
+}
+
+impl CpmMachine {
+    pub fn new(program: &[u8]) -> Self {
+        let mut machine = Self {
+            clock: clocks::Clock::new(),
+            memory: CpmMemory::new(),
+            pc_state: pc_state::PcState::new(),
+            ports: ports::Ports::new(),
+            interruptor: interruptor::Interruptor::new(),
+            output: String::new(),
+        };
+
+        let start = Constants::TPA_START as usize;
+        machine.memory.memory[start..start + program.len()].copy_from_slice(program);
+
+        // JP BDOS_RETURN, where there's a RET back to the caller.
+        machine.memory.write(Constants::BDOS_ENTRY, 0xC3);
+        machine
+            .memory
+            .write(Constants::BDOS_ENTRY + 1, Constants::BDOS_RETURN as u8);
+        machine.memory.write(
+            Constants::BDOS_ENTRY + 2,
+            (Constants::BDOS_RETURN >> 8) as u8,
+        );
+        machine.memory.write(Constants::BDOS_RETURN, 0xC9);
+
+        // Returning from the program goes to the warm boot address.
+        machine.pc_state.sp_reg.set(Constants::BDOS_RETURN - 2);
+        machine
+            .memory
+            .write(Constants::BDOS_RETURN - 2, Constants::WARM_BOOT as u8);
+        machine.memory.write(
+            Constants::BDOS_RETURN - 1,
+            (Constants::WARM_BOOT >> 8) as u8,
+        );
+        machine.pc_state.set_pc(Constants::TPA_START);
+
+        machine
+    }
+
+    // Capture the console output, the 'JP' at the BDOS entry point then
+    // returns to the caller.
+    fn bdos(&mut self) -> Result<(), CpmError> {
+        match self.pc_state.get_c() {
+            Constants::C_WRITE => {
+                self.output.push(self.pc_state.get_e() as char);
+            }
+            Constants::C_WRITESTR => {
+                let mut address = self.pc_state.get_de();
+                while self.memory.read(address) != b'$' {
+                    self.output.push(self.memory.read(address) as char);
+                    address = address.wrapping_add(1);
+                }
+            }
+            function => {
+                return Err(CpmError::UnsupportedBdos(function));
+            }
+        }
+        Ok(())
+    }
+
+    // Run until the program jumps to the warm boot address.
+    pub fn run(&mut self, max_cycles: clocks::ClockType) -> Result<(), CpmError> {
+        loop {
+            match self.pc_state.get_pc() {
+                Constants::WARM_BOOT => {
+                    return Ok(());
+                }
+                Constants::BDOS_ENTRY => {
+                    self.bdos()?;
+                }
+                _ => {}
+            }
+
+            if self.clock.cycles > max_cycles {
+                return Err(CpmError::CycleLimit(self.clock.cycles));
+            }
+
+            let op_code = self.memory.read(self.pc_state.get_pc());
+            self.pc_state.increment_pc(1);
+            instructions::Instruction::execute(
+                op_code,
+                &mut self.clock,
+                &mut self.memory,
+                &mut self.pc_state,
+                &mut self.ports,
+                &mut self.interruptor,
+            )
+            .map_err(CpmError::Fault)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::cpu::cpm;
+    use std::fs;
+
+    // Hand-assembled programs that check the harness itself (the BDOS calls
+    // and the warm boot), rather than the CPU core.  The CPU is only checked
+    // by the ZEX runs below.
+    #[rustfmt::skip]
+    const HARNESS_PROGRAMS: [(&str, &[u8], &str); 4] = [
+        (
+            "BDOS 9, print string",
+            &[
+                0x11, 0x09, 0x01,       // LD DE, 0x0109
+                0x0E, 0x09,             // LD C, 9
+                0xCD, 0x05, 0x00,       // CALL 5
+                0xC9,                   // RET
+                b'H', b'e', b'l', b'l', b'o', b'$',
+            ],
+            "Hello",
+        ),
+        (
+            "BDOS 2, print characters",
+            &[
+                0x1E, 0x30,             // LD E, '0'
+                0x06, 0x0A,             // LD B, 10
+                0xC5,                   // loop: PUSH BC
+                0xD5,                   // PUSH DE
+                0x0E, 0x02,             // LD C, 2
+                0xCD, 0x05, 0x00,       // CALL 5
+                0xD1,                   // POP DE
+                0xC1,                   // POP BC
+                0x1C,                   // INC E
+                0x10, 0xF4,             // DJNZ loop
+                0xC3, 0x00, 0x00,       // JP 0
+            ],
+            "0123456789",
+        ),
+        (
+            "LDIR",
+            &[
+                0x21, 0x16, 0x01,       // LD HL, 0x0116
+                0x11, 0x00, 0x02,       // LD DE, 0x0200
+                0x01, 0x08, 0x00,       // LD BC, 8
+                0xED, 0xB0,             // LDIR
+                0x11, 0x00, 0x02,       // LD DE, 0x0200
+                0x0E, 0x09,             // LD C, 9
+                0xCD, 0x05, 0x00,       // CALL 5
+                0xC3, 0x00, 0x00,       // JP 0
+                b'L', b'D', b'I', b'R', b' ', b'o', b'k', b'$',
+            ],
+            "LDIR ok",
+        ),
+        (
+            "DAA and flags",
+            &[
+                0x3E, 0x15,             // LD A, 0x15
+                0xC6, 0x27,             // ADD A, 0x27
+                0x27,                   // DAA
+                0xFE, 0x42,             // CP 0x42
+                0x11, 0x17, 0x01,       // LD DE, 0x0117 ('ok')
+                0xCA, 0x10, 0x01,       // JP Z, print
+                0x11, 0x1A, 0x01,       // LD DE, 0x011A ('ERROR')
+                0x0E, 0x09,             // print: LD C, 9
+                0xCD, 0x05, 0x00,       // CALL 5
+                0xC9,                   // RET
+                0x00,
+                b'o', b'k', b'$',
+                b'E', b'R', b'R', b'O', b'R', b'$',
+            ],
+            "ok",
+        ),
+    ];
+
+    #[test]
+    fn test_cpm_harness_programs() {
+        for (name, program, expected) in HARNESS_PROGRAMS {
+            let mut machine = cpm::CpmMachine::new(program);
+            machine
+                .run(100_000)
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(machine.output, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_cpm_unsupported_bdos() {
+        // LD C, 1 (console input); CALL 5
+        let mut machine = cpm::CpmMachine::new(&[0x0E, 0x01, 0xCD, 0x05, 0x00]);
+        assert_eq!(machine.run(1000), Err(cpm::CpmError::UnsupportedBdos(1)));
+    }
+
+    // The ZEX images aren't part of the repository, copy them to the project
+    // root ('zexdoc.com', 'zexall.com') and run with '--ignored'.  These take
+    // a long time (several minutes in a release build).
+    fn run_zex(filename: &str) {
+        let program =
+            fs::read(filename).unwrap_or_else(|e| panic!("Unable to read {}: {}", filename, e));
+        let mut machine = cpm::CpmMachine::new(&program);
+        let result = machine.run(u64::MAX);
+        println!("{}", machine.output);
+        result.unwrap();
+        assert!(!machine.output.contains("ERROR"));
+        assert!(machine.output.contains("Tests complete"));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_zexdoc() {
+        run_zex("zexdoc.com");
+    }
+
+    #[test]
+    #[ignore]
+    fn test_zexall() {
+        run_zex("zexall.com");
+    }
+}
@@ -0,0 +1,503 @@
+// Z80 disassembler, decodes a single instruction (including any prefixes)
+// into its mnemonic, length and base T-states.
+// Op codes are split into fields as 'xxyyyzzz' (and 'y' into 'ppq'), which
+// is how the instruction set is grouped.
+// Base T-states are for conditions that aren't met (no jump/call/return) and
+// block instructions that don't repeat.
+use super::super::memory::memory;
+use std::fmt;
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP ",
+];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const ACCUMULATOR_OPS: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+// The undefined modes (0x4E, 0x6E) behave as IM 0.
+const IM_MODES: [u8; 8] = [0, 0, 1, 2, 0, 0, 1, 2];
+const BLOCK_OPS: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+pub struct Disassembly {
+    pub text: String,
+    pub length: u16, // Number of bytes, including prefixes.
+    pub t_states: u8,
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+// Disassemble the instruction at 'address'.
+pub fn disassemble<M>(memory: &M, address: u16) -> Disassembly
+where
+    M: memory::MemoryRW,
+{
+    let mut decoder = Decoder {
+        memory,
+        address,
+        length: 0,
+        index: None,
+    };
+    let (text, t_states) = decoder.decode();
+    Disassembly {
+        text,
+        length: decoder.length,
+        t_states,
+    }
+}
+
+struct Decoder<'a, M> {
+    memory: &'a M,
+    address: u16,
+    length: u16,
+    index: Option<&'static str>, // 'IX' or 'IY' replaces HL after a DD/FD prefix.
+}
+
+impl<'a, M: memory::MemoryRW> Decoder<'a, M> {
+    fn peek_byte(&self) -> u8 {
+        self.memory.peek(self.address.wrapping_add(self.length))
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let value = self.peek_byte();
+        self.length += 1;
+        value
+    }
+
+    fn n(&mut self) -> String {
+        format!("0x{:02X}", self.next_byte())
+    }
+
+    fn nn(&mut self) -> String {
+        let low = self.next_byte() as u16;
+        let high = self.next_byte() as u16;
+        format!("0x{:04X}", high << 8 | low)
+    }
+
+    // Relative jumps are shown as the destination address.
+    fn relative(&mut self) -> String {
+        let displacement = self.next_byte() as i8;
+        let destination = self
+            .address
+            .wrapping_add(self.length)
+            .wrapping_add(displacement as u16);
+        format!("0x{:04X}", destination)
+    }
+
+    // '(IX+d)' / '(IY+d)'
+    fn indexed_address(&mut self, index: &str) -> String {
+        let displacement = self.next_byte() as i8;
+        if displacement < 0 {
+            format!("({}-0x{:02X})", index, displacement.unsigned_abs())
+        } else {
+            format!("({}+0x{:02X})", index, displacement)
+        }
+    }
+
+    // 8-bit register, with H, L and (HL) replaced after a DD/FD prefix.
+    fn r(&mut self, reg_select: u8) -> String {
+        match (self.index, reg_select) {
+            (Some(index), 4) => format!("{}H", index),
+            (Some(index), 5) => format!("{}L", index),
+            (Some(index), 6) => self.indexed_address(index),
+            _ => R[reg_select as usize].to_string(),
+        }
+    }
+
+    fn hl(&self) -> &'static str {
+        self.index.unwrap_or("HL")
+    }
+
+    fn rp(&self, p: u8) -> &'static str {
+        if p == 2 {
+            self.hl()
+        } else {
+            RP[p as usize]
+        }
+    }
+
+    fn rp2(&self, p: u8) -> &'static str {
+        if p == 2 {
+            self.hl()
+        } else {
+            RP2[p as usize]
+        }
+    }
+
+    fn decode(&mut self) -> (String, u8) {
+        let op_code = self.next_byte();
+        match op_code {
+            0xCB => self.decode_cb(),
+            0xED => self.decode_ed(),
+            0xDD => self.decode_index("IX"),
+            0xFD => self.decode_index("IY"),
+            _ => self.decode_unprefixed(op_code),
+        }
+    }
+
+    fn decode_unprefixed(&mut self, op_code: u8) -> (String, u8) {
+        let x = op_code >> 6;
+        let y = (op_code >> 3) & 0x7;
+        let z = op_code & 0x7;
+        let p = y >> 1;
+        let q = y & 0x1;
+
+        match (x, z) {
+            (0, 0) => match y {
+                0 => ("NOP".to_string(), 4),
+                1 => ("EX AF,AF'".to_string(), 4),
+                2 => (format!("DJNZ {}", self.relative()), 8),
+                3 => (format!("JR {}", self.relative()), 12),
+                _ => (
+                    format!("JR {},{}", CC[(y - 4) as usize], self.relative()),
+                    7,
+                ),
+            },
+            (0, 1) if q == 0 => (format!("LD {},{}", self.rp(p), self.nn()), 10),
+            (0, 1) => (format!("ADD {},{}", self.hl(), self.rp(p)), 11),
+            (0, 2) => match y {
+                0 => ("LD (BC),A".to_string(), 7),
+                1 => ("LD A,(BC)".to_string(), 7),
+                2 => ("LD (DE),A".to_string(), 7),
+                3 => ("LD A,(DE)".to_string(), 7),
+                4 => (format!("LD ({}),{}", self.nn(), self.hl()), 16),
+                5 => (format!("LD {},({})", self.hl(), self.nn()), 16),
+                6 => (format!("LD ({}),A", self.nn()), 13),
+                _ => (format!("LD A,({})", self.nn()), 13),
+            },
+            (0, 3) if q == 0 => (format!("INC {}", self.rp(p)), 6),
+            (0, 3) => (format!("DEC {}", self.rp(p)), 6),
+            (0, 4) => (format!("INC {}", self.r(y)), if y == 6 { 11 } else { 4 }),
+            (0, 5) => (format!("DEC {}", self.r(y)), if y == 6 { 11 } else { 4 }),
+            (0, 6) => {
+                let dst = self.r(y);
+                (
+                    format!("LD {},{}", dst, self.n()),
+                    if y == 6 { 10 } else { 7 },
+                )
+            }
+            (0, _) => (ACCUMULATOR_OPS[y as usize].to_string(), 4),
+
+            (1, _) if y == 6 && z == 6 => ("HALT".to_string(), 4),
+            (1, _) => {
+                // With (IX+d), the other operand is still H/L.
+                let (dst, src) = if y == 6 {
+                    (self.r(6), R[z as usize].to_string())
+                } else if z == 6 {
+                    (R[y as usize].to_string(), self.r(6))
+                } else {
+                    (self.r(y), self.r(z))
+                };
+                let t_states = if y == 6 || z == 6 { 7 } else { 4 };
+                (format!("LD {},{}", dst, src), t_states)
+            }
+
+            (2, _) => (
+                format!("{}{}", ALU[y as usize], self.r(z)),
+                if z == 6 { 7 } else { 4 },
+            ),
+
+            (_, 0) => (format!("RET {}", CC[y as usize]), 5),
+            (_, 1) if q == 0 => (format!("POP {}", self.rp2(p)), 10),
+            (_, 1) => match p {
+                0 => ("RET".to_string(), 10),
+                1 => ("EXX".to_string(), 4),
+                2 => (format!("JP ({})", self.hl()), 4),
+                _ => (format!("LD SP,{}", self.hl()), 6),
+            },
+            (_, 2) => (format!("JP {},{}", CC[y as usize], self.nn()), 10),
+            (_, 3) => match y {
+                0 => (format!("JP {}", self.nn()), 10),
+                2 => (format!("OUT ({}),A", self.n()), 11),
+                3 => (format!("IN A,({})", self.n()), 11),
+                4 => (format!("EX (SP),{}", self.hl()), 19),
+                5 => ("EX DE,HL".to_string(), 4),
+                6 => ("DI".to_string(), 4),
+                _ => ("EI".to_string(), 4),
+            },
+            (_, 4) => (format!("CALL {},{}", CC[y as usize], self.nn()), 10),
+            (_, 5) if q == 0 => (format!("PUSH {}", self.rp2(p)), 11),
+            (_, 5) => (format!("CALL {}", self.nn()), 17),
+            (_, 6) => (format!("{}{}", ALU[y as usize], self.n()), 7),
+            (_, _) => (format!("RST 0x{:02X}", y * 8), 11),
+        }
+    }
+
+    fn decode_cb(&mut self) -> (String, u8) {
+        let op_code = self.next_byte();
+        let x = op_code >> 6;
+        let y = (op_code >> 3) & 0x7;
+        let z = op_code & 0x7;
+        let operand = R[z as usize];
+
+        let text = match x {
+            0 => format!("{} {}", ROT[y as usize], operand),
+            1 => format!("BIT {},{}", y, operand),
+            2 => format!("RES {},{}", y, operand),
+            _ => format!("SET {},{}", y, operand),
+        };
+        let t_states = match (x, z) {
+            (_, z) if z != 6 => 8,
+            (1, _) => 12,
+            _ => 15,
+        };
+        (text, t_states)
+    }
+
+    fn decode_ed(&mut self) -> (String, u8) {
+        let op_code = self.next_byte();
+        let x = op_code >> 6;
+        let y = (op_code >> 3) & 0x7;
+        let z = op_code & 0x7;
+        let p = y >> 1;
+        let q = y & 0x1;
+
+        match (x, z) {
+            (1, 0) if y == 6 => ("IN (C)".to_string(), 12),
+            (1, 0) => (format!("IN {},(C)", R[y as usize]), 12),
+            (1, 1) if y == 6 => ("OUT (C),0".to_string(), 12),
+            (1, 1) => (format!("OUT (C),{}", R[y as usize]), 12),
+            (1, 2) if q == 0 => (format!("SBC HL,{}", RP[p as usize]), 15),
+            (1, 2) => (format!("ADC HL,{}", RP[p as usize]), 15),
+            (1, 3) if q == 0 => (format!("LD ({}),{}", self.nn(), RP[p as usize]), 20),
+            (1, 3) => (format!("LD {},({})", RP[p as usize], self.nn()), 20),
+            (1, 4) => ("NEG".to_string(), 8),
+            (1, 5) if y == 1 => ("RETI".to_string(), 14),
+            (1, 5) => ("RETN".to_string(), 14),
+            (1, 6) => (format!("IM {}", IM_MODES[y as usize]), 8),
+            (1, 7) => match y {
+                0 => ("LD I,A".to_string(), 9),
+                1 => ("LD R,A".to_string(), 9),
+                2 => ("LD A,I".to_string(), 9),
+                3 => ("LD A,R".to_string(), 9),
+                4 => ("RRD".to_string(), 18),
+                5 => ("RLD".to_string(), 18),
+                _ => ("NOP".to_string(), 8),
+            },
+            (2, z) if z <= 3 && y >= 4 => (BLOCK_OPS[(y - 4) as usize][z as usize].to_string(), 16),
+            _ => ("NOP".to_string(), 8),
+        }
+    }
+
+    fn decode_index(&mut self, index: &'static str) -> (String, u8) {
+        let op_code = self.peek_byte();
+        if op_code == 0xCB {
+            self.length += 1;
+            return self.decode_index_cb(index);
+        }
+        if !uses_hl(op_code) {
+            // The prefix has no effect, other than the extra 4 T-states.
+            let (text, t_states) = self.decode();
+            return (text, t_states + 4);
+        }
+
+        self.length += 1;
+        self.index = Some(index);
+        let (text, t_states) = self.decode_unprefixed(op_code);
+        let extra_t_states = match op_code {
+            0x36 => 9, // LD (IX+d), n
+            n if uses_displacement(n) => 12,
+            _ => 4,
+        };
+        (text, t_states + extra_t_states)
+    }
+
+    // DD CB d op, FD CB d op
+    fn decode_index_cb(&mut self, index: &'static str) -> (String, u8) {
+        let address = self.indexed_address(index);
+        let op_code = self.next_byte();
+        let x = op_code >> 6;
+        let y = (op_code >> 3) & 0x7;
+        let z = op_code & 0x7;
+
+        // Undocumented: the result is also copied to register 'z' (except for BIT).
+        let copy = if z != 6 {
+            format!(",{}", R[z as usize])
+        } else {
+            String::new()
+        };
+        match x {
+            0 => (format!("{} {}{}", ROT[y as usize], address, copy), 23),
+            1 => (format!("BIT {},{}", y, address), 20),
+            2 => (format!("RES {},{}{}", y, address, copy), 23),
+            _ => (format!("SET {},{}{}", y, address, copy), 23),
+        }
+    }
+}
+
+// Op codes where a DD/FD prefix replaces HL (or H, L, (HL)).
+fn uses_hl(op_code: u8) -> bool {
+    let x = op_code >> 6;
+    let y = (op_code >> 3) & 0x7;
+    let z = op_code & 0x7;
+    let p = y >> 1;
+    let hl_operand = |r: u8| (4..=6).contains(&r);
+
+    match x {
+        0 => match z {
+            1 => (y & 0x1 == 1) || p == 2, // ADD HL, rp; LD HL, nn
+            2 | 3 => p == 2,               // LD (nn), HL; LD HL, (nn); INC/DEC HL
+            4..=6 => hl_operand(y),
+            _ => false,
+        },
+        1 => op_code != 0x76 && (hl_operand(y) || hl_operand(z)),
+        2 => hl_operand(z),
+        _ => matches!(op_code, 0xE1 | 0xE3 | 0xE5 | 0xE9 | 0xF9),
+    }
+}
+
+// Op codes where a DD/FD prefix replaces (HL) with (IX+d).
+fn uses_displacement(op_code: u8) -> bool {
+    let x = op_code >> 6;
+    let y = (op_code >> 3) & 0x7;
+    let z = op_code & 0x7;
+
+    match x {
+        0 => (4..=6).contains(&z) && y == 6,
+        1 => op_code != 0x76 && (y == 6 || z == 6),
+        2 => z == 6,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::clocks;
+    use crate::sega::cpu::cpm;
+    use crate::sega::cpu::disassembler;
+    use crate::sega::cpu::instructions;
+    use crate::sega::cpu::pc_state;
+    use crate::sega::interruptor;
+    use crate::sega::memory::memory::MemoryRW;
+    use crate::sega::ports;
+
+    fn disassemble_bytes(op_codes: &[u8]) -> disassembler::Disassembly {
+        let mut memory = cpm::CpmMemory::new();
+        for (offset, op_code) in op_codes.iter().enumerate() {
+            MemoryRW::write(&mut memory, 0x100 + offset as u16, *op_code);
+        }
+        disassembler::disassemble(&memory, 0x100)
+    }
+
+    #[test]
+    fn test_disassemble_text() {
+        let test_values: [(&[u8], &str, u16); 28] = [
+            (&[0x00], "NOP", 1),
+            (&[0x01, 0x34, 0x12], "LD BC,0x1234", 3),
+            (&[0x20, 0xFE], "JR NZ,0x0100", 2),
+            (&[0x10, 0x10], "DJNZ 0x0112", 2),
+            (&[0x3A, 0x00, 0xC0], "LD A,(0xC000)", 3),
+            (&[0x36, 0x42], "LD (HL),0x42", 2),
+            (&[0x76], "HALT", 1),
+            (&[0x7E], "LD A,(HL)", 1),
+            (&[0x9E], "SBC A,(HL)", 1),
+            (&[0xD3, 0xBF], "OUT (0xBF),A", 2),
+            (&[0xE2, 0x00, 0x80], "JP PO,0x8000", 3),
+            (&[0xFF], "RST 0x38", 1),
+            (&[0xCB, 0x36], "SLL (HL)", 2),
+            (&[0xCB, 0x7F], "BIT 7,A", 2),
+            (&[0xED, 0xB0], "LDIR", 2),
+            (&[0xED, 0x73, 0x00, 0xDF], "LD (0xDF00),SP", 4),
+            (&[0xED, 0x5E], "IM 2", 2),
+            (&[0xED, 0x70], "IN (C)", 2),
+            (&[0xED, 0x00], "NOP", 2),
+            (&[0xDD, 0x21, 0x00, 0x40], "LD IX,0x4000", 4),
+            (&[0xDD, 0x7E, 0xFE], "LD A,(IX-0x02)", 3),
+            (&[0xFD, 0x74, 0x05], "LD (IY+0x05),H", 3),
+            (&[0xFD, 0x36, 0x01, 0x99], "LD (IY+0x01),0x99", 4),
+            (&[0xDD, 0x65], "LD IXH,IXL", 2),
+            (&[0xFD, 0xE9], "JP (IY)", 2),
+            (&[0xDD, 0xEB], "EX DE,HL", 2),
+            (&[0xDD, 0xCB, 0x03, 0x46], "BIT 0,(IX+0x03)", 4),
+            (&[0xFD, 0xCB, 0xFF, 0xC1], "SET 0,(IY-0x01),C", 4),
+        ];
+
+        for (op_codes, text, length) in test_values {
+            let disassembly = disassemble_bytes(op_codes);
+            assert_eq!(disassembly.text, text, "{:x?}", op_codes);
+            assert_eq!(disassembly.length, length, "{:x?}", op_codes);
+        }
+    }
+
+    // Execute every op code in each prefix group and check the disassembler
+    // length and T-states match.  Each op code is run with the flags all
+    // clear/all set and with B (and BC) both ending at zero/not zero, the base
+    // T-states are the quickest of these (condition not met, no repeat).
+    #[test]
+    fn test_disassemble_matches_execution() {
+        fn execute(op_codes: &[u8], flags: u8, bc: u16) -> (u16, clocks::ClockType) {
+            const START_ADDRESS: u16 = 0x100;
+
+            let mut memory = cpm::CpmMemory::new();
+            for (offset, op_code) in op_codes.iter().enumerate() {
+                MemoryRW::write(&mut memory, START_ADDRESS + offset as u16, *op_code);
+            }
+            let mut clock = clocks::Clock::new();
+            let mut pc_state = pc_state::PcState::new();
+            pc_state.set_af(flags as u16);
+            pc_state.set_bc(bc);
+            pc_state.sp_reg.set(0x8000);
+            pc_state.set_pc(START_ADDRESS + 1);
+            instructions::Instruction::execute(
+                op_codes[0],
+                &mut clock,
+                &mut memory,
+                &mut pc_state,
+                &mut ports::Ports::new(),
+                &mut interruptor::Interruptor::new(),
+            )
+            .unwrap();
+            (pc_state.get_pc().wrapping_sub(START_ADDRESS), clock.cycles)
+        }
+
+        let prefixes: [&[u8]; 7] = [
+            &[],
+            &[0xCB],
+            &[0xED],
+            &[0xDD],
+            &[0xFD],
+            &[0xDD, 0xCB, 0x00],
+            &[0xFD, 0xCB, 0x00],
+        ];
+        for prefix in prefixes {
+            for op_code in 0..=0xFF_u8 {
+                let mut op_codes = prefix.to_vec();
+                op_codes.push(op_code);
+                // Operands that don't jump anywhere surprising (and aren't op codes).
+                op_codes.extend_from_slice(&[0x00, 0x00]);
+
+                let disassembly = disassemble_bytes(&op_codes);
+                let results: Vec<(u16, clocks::ClockType)> = [0x00, 0xFF]
+                    .iter()
+                    .flat_map(|flags| [0x0001, 0x0101].map(|bc| execute(&op_codes, *flags, bc)))
+                    .collect();
+                let base_t_states = results.iter().map(|(_, cycles)| *cycles).min().unwrap();
+                assert_eq!(
+                    disassembly.t_states as clocks::ClockType, base_t_states,
+                    "{:x?} {}",
+                    op_codes, disassembly
+                );
+
+                // Jumps, calls, etc change the PC, check the rest.
+                let (length, _) = results[0];
+                if results.iter().all(|(other, _)| *other == length) && length < 5 {
+                    assert_eq!(
+                        disassembly.length, length,
+                        "{:x?} {}",
+                        op_codes, disassembly
+                    );
+                }
+            }
+        }
+    }
+}
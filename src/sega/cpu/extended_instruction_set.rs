@@ -131,7 +131,9 @@ pub fn ld_dd_mem_nn<M, F: FnMut(&mut pc_state::PcState, u16)>(
 ) where
     M: memory::MemoryRW,
 {
-    reg16(pc_state, memory.read16(memory.read16(pc_state.get_pc())));
+    let address = memory.read16(pc_state.get_pc());
+    reg16(pc_state, memory.read16(address));
+    pc_state.set_memptr(address.wrapping_add(1));
 
     pc_state.increment_pc(2);
     clock.increment(20);
@@ -260,23 +262,8 @@ pub fn lddr<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_stat
 where
     M: memory::MemoryRW,
 {
-    memory.write(pc_state.de_reg.get(), memory.read(pc_state.hl_reg.get()));
-    pc_state::PcState::increment_reg(&mut pc_state.de_reg, -1);
-    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, -1);
-    pc_state::PcState::increment_reg(&mut pc_state.bc_reg, -1);
-    if pc_state.bc_reg.get() == 0 {
-        let mut f_status = pc_state.get_f();
-        f_status.set_h(0);
-        f_status.set_n(0);
-        f_status.set_pv(0);
-        pc_state.set_f(f_status);
-
-        clock.increment(16);
-    } else {
-        pc_state::PcState::increment_reg(&mut pc_state.pc_reg, -2);
-        // This branch is longer because the PC is actually 'decremented' by two
-        clock.increment(21);
-    }
+    ldd(clock, memory, pc_state);
+    block_repeat(clock, pc_state, pc_state.bc_reg.get() != 0);
 }
 
 // LDIR
@@ -284,24 +271,18 @@ pub fn ldir<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_stat
 where
     M: memory::MemoryRW,
 {
-    pc_state::PcState::increment_reg(&mut pc_state.bc_reg, -1);
-    memory.write(pc_state.de_reg.get(), memory.read(pc_state.hl_reg.get()));
-    pc_state::PcState::increment_reg(&mut pc_state.de_reg, 1);
-    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, 1);
+    ldi(clock, memory, pc_state);
+    block_repeat(clock, pc_state, pc_state.bc_reg.get() != 0);
+}
 
-    let mut f_status = pc_state.get_f();
-    f_status.set_h(0);
-    f_status.set_pv(0);
-    if pc_state.bc_reg.get() == 0 {
-        f_status.set_n(0);
-        clock.increment(16);
-    } else {
+// Repeat the current block instruction (LDIR, CPIR, etc) if 'condition'.
+fn block_repeat(clock: &mut clocks::Clock, pc_state: &mut pc_state::PcState, condition: bool) {
+    if condition {
         // This branch is longer because the PC is actually 'decremented' by two
-        f_status.set_n(1); // Not sure.
-        pc_state::PcState::increment_reg(&mut pc_state.pc_reg, -2);
-        clock.increment(21);
+        pc_state.increment_pc(-2);
+        pc_state.set_memptr(pc_state.get_pc().wrapping_add(1));
+        clock.increment(5);
     }
-    pc_state.set_f(f_status);
 }
 
 // OTIR
@@ -316,6 +297,7 @@ pub fn otir<M>(
 {
     pc_state.set_b(pc_state.get_b().wrapping_sub(1));
     ports.port_write(clock, pc_state.get_c(), memory.read(pc_state.hl_reg.get()));
+    pc_state.set_memptr(pc_state.get_bc().wrapping_add(1));
     pc_state::PcState::increment_reg(&mut pc_state.hl_reg, 1);
 
     let mut f_status = pc_state.get_f();
@@ -380,21 +362,19 @@ pub fn bit_b_r<R16, F16>(
 }
 
 // BIT b, (HL)
-pub fn bit_b_mem<M, R16, F16>(
+// The undocumented X/Y flags are taken from the high byte of MEMPTR.
+pub fn bit_b_mem<M>(
     clock: &mut clocks::Clock,
     memory: &mut M,
     bit_pos: u8,
-    pc_reg: &mut R16,
-    af_reg: &mut F16,
-    addr_reg: &R16,
+    pc_state: &mut pc_state::PcState,
 ) where
     M: memory::MemoryRW,
-    R16: pc_state::Reg16RW,
-    F16: pc_state::FlagReg,
 {
-    let mut f_status = af_reg.get_flags();
-    status_flags::set_bit_test_flags(memory.read(addr_reg.get()), bit_pos, &mut f_status);
-    af_reg.set_flags(&f_status);
+    let mut f_status = pc_state.get_f();
+    status_flags::set_bit_test_flags(memory.read(pc_state.get_hl()), bit_pos, &mut f_status);
+    status_flags::xy_flags(&mut f_status, (pc_state.get_memptr() >> 8) as u8);
+    pc_state.set_f(f_status);
     clock.increment(12);
 }
 
@@ -425,7 +405,7 @@ pub fn set_b_mem<M, R16>(
         memory.read(addr_reg.get()) | (0x1 << bit_pos),
     );
 
-    clock.increment(12);
+    clock.increment(15);
 }
 
 // RES b, r
@@ -456,53 +436,99 @@ pub fn res_b_mem<M, R16>(
         memory.read(addr_reg.get()) & !(0x1 << bit_pos),
     );
 
-    clock.increment(12);
+    clock.increment(15);
 }
 
-// BIT b, (IY+d),  BIT b, (IX+d) (if mem at pc + 3 -> 0b01XXXXXX)
-// RES b, (IY+d),  RES b, (IX+d) (if mem at pc + 3 -> 0b10XXXXXX)
-// SET b, (IY+d),  SET b, (IX+d) (if mem at pc + 3 -> 0b11XXXXXX)
-// (if mem at pc + 3 -> 0b11XXXXXX) -> ERROR
-pub fn bit_res_set_b_i_d<M, R16, F16>(
+// BIT b, (IY+d),  BIT b, (IX+d) (if mem at pc + 3 -> 0b01bbbrrr)
+// RES b, (IY+d),  RES b, (IX+d) (if mem at pc + 3 -> 0b10bbbrrr)
+// SET b, (IY+d),  SET b, (IX+d) (if mem at pc + 3 -> 0b11bbbrrr)
+// Undocumented: for RES/SET, if rrr != 0b110 the result is also copied to register r.
+// BIT ignores rrr.
+pub fn bit_res_set_b_i_d<M, F: FnMut(&mut pc_state::PcState, u8)>(
     clock: &mut clocks::Clock,
     memory: &mut M,
-    pc_reg: &mut R16,
-    af_reg: &mut F16,
-    i16_reg: &R16,
+    pc_state: &mut pc_state::PcState,
+    i16_value: u16,
+    mut dst_fn: F,
 ) where
     M: memory::MemoryRW,
-    R16: pc_state::Reg16RW,
-    F16: pc_state::FlagReg,
 {
-    let tmp16 = get_i_d_address(memory, pc_reg, i16_reg);
+    let tmp16 = i16_value.wrapping_add(get_i8_displacement_as_u8(memory, &pc_state.pc_reg));
     let test_value = memory.read(tmp16);
-    let op_details = memory.read(pc_reg.get() + 1);
+    let op_details = memory.read(pc_state.get_pc().wrapping_add(1));
     let bit_pos = (op_details >> 3) & 0x7;
 
-    match op_details >> 6 {
+    let new_value = match op_details >> 6 {
         0b01 => {
-            /* BIT b */
-            let mut f_status = af_reg.get_flags();
+            /* BIT b, X/Y are from the high byte of the address */
+            let mut f_status = pc_state.get_f();
             status_flags::set_bit_test_flags(test_value, bit_pos, &mut f_status);
-            af_reg.set_flags(&f_status);
+            status_flags::xy_flags(&mut f_status, (tmp16 >> 8) as u8);
+            pc_state.set_f(f_status);
             clock.increment(20);
+            None
         }
         0b10 => {
             /* RES b */
-            memory.write(tmp16, test_value & !(0x1 << bit_pos));
-            clock.increment(23);
+            Some(test_value & !(0x1 << bit_pos))
         }
         0b11 => {
             /* SET b */
-            memory.write(tmp16, test_value | (0x1 << bit_pos));
-            clock.increment(23);
+            Some(test_value | (0x1 << bit_pos))
         }
         _ => {
             panic!("Unsupported byte value! {}", op_details);
         }
+    };
+
+    if let Some(new_value) = new_value {
+        memory.write(tmp16, new_value);
+        if op_details & 0x7 != 0b110 {
+            dst_fn(pc_state, new_value);
+        }
+        clock.increment(23);
     }
 
-    pc_state::PcState::increment_reg(pc_reg, 2);
+    pc_state.increment_pc(2);
+}
+
+// RLC/RRC/RL/RR/SLA/SRA/SLL/SRL (IY+d), (IX+d) (if mem at pc + 3 -> 0b00ooorrr)
+// Undocumented: if rrr != 0b110 the result is also copied to register r.
+pub fn rotate_shift_i_d<M, F: FnMut(&mut pc_state::PcState, u8)>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_state: &mut pc_state::PcState,
+    i16_value: u16,
+    mut dst_fn: F,
+) where
+    M: memory::MemoryRW,
+{
+    let address = i16_value.wrapping_add(get_i8_displacement_as_u8(memory, &pc_state.pc_reg));
+    let src = memory.read(address);
+    let op_details = memory.read(pc_state.get_pc().wrapping_add(1));
+    let mut f_value = pc_state.get_f();
+    let carry_in = f_value.get_c() == 1;
+
+    let (new_value, carry) = match (op_details >> 3) & 0x7 {
+        0b000 => instruction_set::rotate_left_carry(src),
+        0b001 => instruction_set::rotate_right_carry(src),
+        0b010 => instruction_set::rotate_left(src, carry_in),
+        0b011 => instruction_set::rotate_right(src, carry_in),
+        0b100 => instruction_set::shift_left_arithmetic(src),
+        0b101 => instruction_set::shift_right_arithmetic(src),
+        0b110 => instruction_set::shift_left_logical(src),
+        _ => instruction_set::shift_right_logical(src),
+    };
+    status_flags::set_shift_register_flags(new_value, carry, &mut f_value);
+    pc_state.set_f(f_value);
+
+    memory.write(address, new_value);
+    if op_details & 0x7 != 0b110 {
+        dst_fn(pc_state, new_value);
+    }
+
+    pc_state.increment_pc(2);
+    clock.increment(23);
 }
 
 ///////////////////////////////////////////////////////////////////////
@@ -541,54 +567,82 @@ pub fn cp_i_d<M, R16, AF>(
     clock.increment(19);
 }
 
-// CPI
-// Compare accumulator with contents of memory address HL, increment HL
-pub fn cpi<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_state::PcState)
-where
+// CPI, CPD
+// Compare accumulator with contents of memory address HL, increment/decrement HL
+// (hl_increment 1/-1), decrement BC.  Carry isn't affected.
+fn block_compare<M>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_state: &mut pc_state::PcState,
+    hl_increment: i8,
+) where
     M: memory::MemoryRW,
 {
+    let original_carry = pc_state.get_f().get_c();
+    let value = memory.read(pc_state.hl_reg.get());
     // This function sets the 'pc_state.f'
-    instruction_set::cp_flags(
-        pc_state.get_a(),
-        memory.read(pc_state.hl_reg.get()),
-        &mut pc_state.af_reg,
-    );
+    instruction_set::cp_flags(pc_state.get_a(), value, &mut pc_state.af_reg);
 
-    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, 1);
+    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, hl_increment);
     pc_state::PcState::increment_reg(&mut pc_state.bc_reg, -1);
-    let mut f_status = pc_state.get_f();
-    if pc_state.bc_reg.get() == 0 {
-        f_status.set_pv(1);
-    } else {
-        f_status.set_pv(0);
-    }
+    pc_state.set_memptr(
+        pc_state
+            .get_memptr()
+            .wrapping_add(hl_increment as i16 as u16),
+    );
 
+    let mut f_status = pc_state.get_f();
+    f_status.set_c(original_carry);
+    f_status.set_pv((pc_state.bc_reg.get() != 0) as u8);
+    let n = pc_state
+        .get_a()
+        .wrapping_sub(value)
+        .wrapping_sub(f_status.get_h());
+    status_flags::block_xy_flags(&mut f_status, n);
     pc_state.set_f(f_status);
 
     clock.increment(16);
 }
 
+// CPI
+// Compare accumulator with contents of memory address HL, increment HL
+pub fn cpi<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_state::PcState)
+where
+    M: memory::MemoryRW,
+{
+    block_compare(clock, memory, pc_state, 1);
+}
+
 // CPD
 // Compare accumulator with contents of memory address HL, decrement HL
 pub fn cpd<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_state::PcState)
 where
     M: memory::MemoryRW,
 {
-    // This function sets the 'pc_state.f'
-    instruction_set::cp_flags(
-        pc_state.get_a(),
-        memory.read(pc_state.hl_reg.get()),
-        &mut pc_state.af_reg,
-    );
+    block_compare(clock, memory, pc_state, -1);
+}
 
-    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, -1);
+// LDI, LDD
+// Load, increment/decrement HL, DE (hl_increment 1/-1), decrement BC.
+fn block_load<M>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_state: &mut pc_state::PcState,
+    hl_increment: i8,
+) where
+    M: memory::MemoryRW,
+{
+    let value = memory.read(pc_state.hl_reg.get());
+    memory.write(pc_state.de_reg.get(), value);
+
+    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, hl_increment);
+    pc_state::PcState::increment_reg(&mut pc_state.de_reg, hl_increment);
     pc_state::PcState::increment_reg(&mut pc_state.bc_reg, -1);
     let mut f_status = pc_state.get_f();
-    if pc_state.bc_reg.get() == 0 {
-        f_status.set_pv(1);
-    } else {
-        f_status.set_pv(0);
-    }
+    f_status.set_pv((pc_state.bc_reg.get() != 0) as u8);
+    f_status.set_h(0);
+    f_status.set_n(0);
+    status_flags::block_xy_flags(&mut f_status, pc_state.get_a().wrapping_add(value));
 
     pc_state.set_f(f_status);
 
@@ -601,23 +655,7 @@ pub fn ldi<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_state
 where
     M: memory::MemoryRW,
 {
-    memory.write(pc_state.de_reg.get(), memory.read(pc_state.hl_reg.get()));
-
-    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, 1);
-    pc_state::PcState::increment_reg(&mut pc_state.de_reg, 1);
-    pc_state::PcState::increment_reg(&mut pc_state.bc_reg, -1);
-    let mut f_status = pc_state.get_f();
-    if pc_state.bc_reg.get() == 0 {
-        f_status.set_pv(1);
-    } else {
-        f_status.set_pv(0);
-    }
-    f_status.set_h(0);
-    f_status.set_n(0);
-
-    pc_state.set_f(f_status);
-
-    clock.increment(16);
+    block_load(clock, memory, pc_state, 1);
 }
 
 // LDD
@@ -626,23 +664,7 @@ pub fn ldd<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_state
 where
     M: memory::MemoryRW,
 {
-    memory.write(pc_state.de_reg.get(), memory.read(pc_state.hl_reg.get()));
-
-    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, -1);
-    pc_state::PcState::increment_reg(&mut pc_state.de_reg, -1);
-    pc_state::PcState::increment_reg(&mut pc_state.bc_reg, -1);
-    let mut f_status = pc_state.get_f();
-    if pc_state.bc_reg.get() == 0 {
-        f_status.set_pv(1);
-    } else {
-        f_status.set_pv(0);
-    }
-    f_status.set_h(0);
-    f_status.set_n(0);
-
-    pc_state.set_f(f_status);
-
-    clock.increment(16);
+    block_load(clock, memory, pc_state, -1);
 }
 
 // CPIR
@@ -651,27 +673,12 @@ pub fn cpir<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_stat
 where
     M: memory::MemoryRW,
 {
-    // This function sets the 'pc_state.f'
-    let original_carry = pc_state.get_f().get_c();
-    pc_state::PcState::increment_reg(&mut pc_state.bc_reg, -1);
-    instruction_set::cp_flags(
-        pc_state.get_a(),
-        memory.read(pc_state.hl_reg.get()),
-        &mut pc_state.af_reg,
+    cpi(clock, memory, pc_state);
+    block_repeat(
+        clock,
+        pc_state,
+        pc_state.bc_reg.get() != 0 && pc_state.get_f().get_z() == 0,
     );
-    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, 1);
-    let mut f_status = pc_state.get_f();
-    f_status.set_c(original_carry);
-    if (pc_state.bc_reg.get() == 0) || f_status.get_z() == 1 {
-        f_status.set_pv(0);
-        clock.increment(16);
-    } else {
-        f_status.set_pv(1);
-        pc_state.increment_pc(-2);
-        clock.increment(21);
-    }
-
-    pc_state.set_f(f_status);
 }
 
 // CPDR
@@ -680,27 +687,12 @@ pub fn cpdr<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_stat
 where
     M: memory::MemoryRW,
 {
-    // This function sets the 'pc_state.f'
-    let original_carry = pc_state.get_f().get_c();
-    pc_state::PcState::increment_reg(&mut pc_state.bc_reg, -1);
-    instruction_set::cp_flags(
-        pc_state.get_a(),
-        memory.read(pc_state.hl_reg.get()),
-        &mut pc_state.af_reg,
+    cpd(clock, memory, pc_state);
+    block_repeat(
+        clock,
+        pc_state,
+        pc_state.bc_reg.get() != 0 && pc_state.get_f().get_z() == 0,
     );
-    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, -1);
-    let mut f_status = pc_state.get_f();
-    f_status.set_c(original_carry);
-    if (pc_state.bc_reg.get() == 0) || f_status.get_z() == 1 {
-        f_status.set_pv(0);
-        clock.increment(16);
-    } else {
-        f_status.set_pv(1);
-        pc_state.increment_pc(-2);
-        clock.increment(21);
-    }
-
-    pc_state.set_f(f_status);
 }
 
 // RTI
@@ -715,10 +707,21 @@ where
     pc_state.increment_sp(1);
     pc_state.set_pc_high(memory.read(pc_state.sp_reg.get()));
     pc_state.increment_sp(1);
+    pc_state.set_memptr(pc_state.get_pc());
 
     clock.increment(14);
 }
 
+// RETN
+// Return from a non-maskable interrupt, restoring 'iff1' from 'iff2'.
+pub fn retn<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_state::PcState)
+where
+    M: memory::MemoryRW,
+{
+    reti(clock, memory, pc_state);
+    pc_state.set_iff1(pc_state.get_iff2());
+}
+
 ////////////////////////////////////////////////////
 // 16-bit arithmetic Group
 ////////////////////////////////////////////////////
@@ -865,6 +868,33 @@ pub fn adc_i_d<M, R16, F16>(
     clock.increment(19);
 }
 
+// SBC A, (IX+d),
+// SBC A, (IY+d),
+pub fn sbc_i_d<M, R16, F16>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_reg: &mut R16,
+    i16_reg: &R16,
+    af_reg: &mut F16,
+) where
+    M: memory::MemoryRW,
+    R16: pc_state::Reg16RW,
+    F16: pc_state::FlagReg + pc_state::AfRegister,
+{
+    let address = get_i_d_address(memory, pc_reg, i16_reg);
+
+    let new_value = instruction_set::sub8c(
+        af_reg.get_a(),
+        memory.read(address),
+        af_reg.get_flags().get_c() == 1,
+        af_reg,
+    );
+    af_reg.set_a(new_value);
+
+    pc_state::PcState::increment_reg(pc_reg, 1);
+    clock.increment(19);
+}
+
 // SUB (IX+d),
 // SUB (IY+d),
 pub fn sub_i_d<M, R16, F16>(
@@ -1179,6 +1209,52 @@ pub fn rrc_hl<M, R16, F16>(
     clock.increment(15);
 }
 
+// RL (HL)
+pub fn rl_hl<M, R16, F16>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_reg: &mut R16,
+    af_reg: &mut F16,
+    addr_reg: &R16,
+) where
+    M: memory::MemoryRW,
+    R16: pc_state::Reg16RW,
+    F16: pc_state::FlagReg,
+{
+    let src = memory.read(addr_reg.get());
+    let mut f_value = af_reg.get_flags();
+
+    let (new_value, carry) = instruction_set::rotate_left(src, f_value.get_c() == 1);
+    status_flags::set_shift_register_flags(new_value, carry, &mut f_value);
+    af_reg.set_flags(&f_value);
+    memory.write(addr_reg.get(), new_value);
+
+    clock.increment(15);
+}
+
+// RR (HL)
+pub fn rr_hl<M, R16, F16>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_reg: &mut R16,
+    af_reg: &mut F16,
+    addr_reg: &R16,
+) where
+    M: memory::MemoryRW,
+    R16: pc_state::Reg16RW,
+    F16: pc_state::FlagReg,
+{
+    let src = memory.read(addr_reg.get());
+    let mut f_value = af_reg.get_flags();
+
+    let (new_value, carry) = instruction_set::rotate_right(src, f_value.get_c() == 1);
+    status_flags::set_shift_register_flags(new_value, carry, &mut f_value);
+    af_reg.set_flags(&f_value);
+    memory.write(addr_reg.get(), new_value);
+
+    clock.increment(15);
+}
+
 // SLA (HL)
 pub fn sla_hl<M, R16, F16>(
     clock: &mut clocks::Clock,
@@ -1202,6 +1278,29 @@ pub fn sla_hl<M, R16, F16>(
     clock.increment(15);
 }
 
+// SLL (HL), undocumented
+pub fn sll_hl<M, R16, F16>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_reg: &mut R16,
+    af_reg: &mut F16,
+    addr_reg: &R16,
+) where
+    M: memory::MemoryRW,
+    R16: pc_state::Reg16RW,
+    F16: pc_state::FlagReg,
+{
+    let src = memory.read(addr_reg.get());
+    let mut f_value = af_reg.get_flags();
+
+    let (new_value, carry) = instruction_set::shift_left_logical(src);
+    status_flags::set_shift_register_flags(new_value, carry, &mut f_value);
+    af_reg.set_flags(&f_value);
+    memory.write(addr_reg.get(), new_value);
+
+    clock.increment(15);
+}
+
 // SRA (HL)
 pub fn sra_hl<M, R16, F16>(
     clock: &mut clocks::Clock,
@@ -1270,6 +1369,7 @@ where
     status_flags::rotate_decimal_flags(&mut f_value, new_value);
 
     pc_state.set_f(f_value);
+    pc_state.set_memptr(pc_state.get_hl().wrapping_add(1));
 
     clock.increment(18);
 }
@@ -1295,6 +1395,7 @@ where
     let mut f_value = pc_state.get_f();
     status_flags::rotate_decimal_flags(&mut f_value, new_value);
     pc_state.set_f(f_value);
+    pc_state.set_memptr(pc_state.get_hl().wrapping_add(1));
 
     clock.increment(18);
 }
@@ -1307,10 +1408,29 @@ pub fn in_r<F: FnMut(&mut pc_state::PcState, u8)>(
     mut dst_fn: F,
     ports: &mut ports::Ports,
 ) {
-    dst_fn(pc_state, ports.port_read(clock, src_val));
+    let value = ports.port_read(clock, src_val);
+    pc_state.set_memptr(pc_state.get_bc().wrapping_add(1));
+    dst_fn(pc_state, value);
+
+    // Same flag behaviour as RRD/RLD (carry not affected).
+    let mut f_value = pc_state.get_f();
+    status_flags::rotate_decimal_flags(&mut f_value, value);
+    pc_state.set_f(f_value);
+
     clock.increment(12);
 }
 
+// IN (C), undocumented (also written IN F, (C))
+// Only updates the flags, the value read is discarded.
+pub fn in_f(
+    clock: &mut clocks::Clock,
+    src_val: u8,
+    pc_state: &mut pc_state::PcState,
+    ports: &mut ports::Ports,
+) {
+    in_r(clock, src_val, pc_state, |_state, _x| {}, ports);
+}
+
 // OUT r, (C)
 pub fn out_r(
     clock: &mut clocks::Clock,
@@ -1320,6 +1440,7 @@ pub fn out_r(
     ports: &mut ports::Ports,
 ) {
     ports.port_write(clock, src_val, out);
+    pc_state.set_memptr(pc_state.get_bc().wrapping_add(1));
     clock.increment(12);
 }
 
@@ -1334,6 +1455,7 @@ pub fn outi<M>(
 {
     pc_state.set_b(pc_state.get_b().wrapping_sub(1));
     ports.port_write(clock, pc_state.get_c(), memory.read(pc_state.hl_reg.get()));
+    pc_state.set_memptr(pc_state.get_bc().wrapping_add(1));
     pc_state::PcState::increment_reg(&mut pc_state.hl_reg, 1);
 
     let mut f_status = pc_state.get_f();
@@ -1357,6 +1479,7 @@ pub fn ini<M>(
 ) where
     M: memory::MemoryRW,
 {
+    pc_state.set_memptr(pc_state.get_bc().wrapping_add(1));
     pc_state.set_b(pc_state.get_b().wrapping_sub(1));
     memory.write(
         pc_state.hl_reg.get(),
@@ -1387,6 +1510,7 @@ pub fn outd<M>(
 {
     pc_state.set_b(pc_state.get_b().wrapping_sub(1));
     ports.port_write(clock, pc_state.get_c(), memory.read(pc_state.hl_reg.get()));
+    pc_state.set_memptr(pc_state.get_bc().wrapping_sub(1));
 
     let mut f_status = pc_state.get_f();
     if pc_state.get_b() == 0 {
@@ -1401,6 +1525,124 @@ pub fn outd<M>(
     clock.increment(16);
 }
 
+// IND
+pub fn ind<M>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_state: &mut pc_state::PcState,
+    ports: &mut ports::Ports,
+) where
+    M: memory::MemoryRW,
+{
+    pc_state.set_memptr(pc_state.get_bc().wrapping_sub(1));
+    pc_state.set_b(pc_state.get_b().wrapping_sub(1));
+    memory.write(
+        pc_state.hl_reg.get(),
+        ports.port_read(clock, pc_state.get_c()),
+    );
+    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, -1);
+
+    let mut f_status = pc_state.get_f();
+    if pc_state.get_b() == 0 {
+        f_status.set_z(1);
+    } else {
+        f_status.set_z(0);
+    }
+    f_status.set_n(1);
+    pc_state.set_f(f_status);
+
+    clock.increment(16);
+}
+
+// INIR, INDR
+// Repeats INI/IND (hl_increment 1/-1) until B is zero.
+fn in_repeat<M>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_state: &mut pc_state::PcState,
+    ports: &mut ports::Ports,
+    hl_increment: i8,
+) where
+    M: memory::MemoryRW,
+{
+    pc_state.set_memptr(pc_state.get_bc().wrapping_add(hl_increment as i16 as u16));
+    pc_state.set_b(pc_state.get_b().wrapping_sub(1));
+    memory.write(
+        pc_state.hl_reg.get(),
+        ports.port_read(clock, pc_state.get_c()),
+    );
+    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, hl_increment);
+
+    let mut f_status = pc_state.get_f();
+    f_status.set_n(1);
+    if pc_state.get_b() == 0 {
+        f_status.set_z(1);
+        clock.increment(16);
+    } else {
+        f_status.set_z(0);
+        pc_state.increment_pc(-2);
+        clock.increment(21);
+    }
+
+    pc_state.set_f(f_status);
+}
+
+// INIR
+pub fn inir<M>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_state: &mut pc_state::PcState,
+    ports: &mut ports::Ports,
+) where
+    M: memory::MemoryRW,
+{
+    in_repeat(clock, memory, pc_state, ports, 1);
+}
+
+// INDR
+pub fn indr<M>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_state: &mut pc_state::PcState,
+    ports: &mut ports::Ports,
+) where
+    M: memory::MemoryRW,
+{
+    in_repeat(clock, memory, pc_state, ports, -1);
+}
+
+// OTDR
+// Flags match OTIR
+pub fn otdr<M>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_state: &mut pc_state::PcState,
+    ports: &mut ports::Ports,
+) where
+    M: memory::MemoryRW,
+{
+    pc_state.set_b(pc_state.get_b().wrapping_sub(1));
+    ports.port_write(clock, pc_state.get_c(), memory.read(pc_state.hl_reg.get()));
+    pc_state.set_memptr(pc_state.get_bc().wrapping_sub(1));
+    pc_state::PcState::increment_reg(&mut pc_state.hl_reg, -1);
+
+    let mut f_status = pc_state.get_f();
+    f_status.set_s(0); // Unknown
+    f_status.set_h(0); // Unknown
+    f_status.set_pv(0); // Unknown
+    f_status.set_n(1);
+    if pc_state.get_b() == 0 {
+        f_status.set_z(1);
+        clock.increment(16);
+    } else {
+        f_status.set_z(0);
+        pc_state.increment_pc(-2);
+        clock.increment(21);
+    }
+
+    pc_state.set_f(f_status);
+}
+
 /*************************************************************************************/
 /* General purpose arithmetic and CPU control                                        */
 /*************************************************************************************/
@@ -1412,11 +1654,29 @@ pub fn neg(clock: &mut clocks::Clock, pc_state: &mut pc_state::PcState) {
     clock.increment(8);
 }
 
+// Undefined 0xED op codes, behave as two NOPs.
+pub fn ed_nop(clock: &mut clocks::Clock) {
+    clock.increment(8);
+}
+
+// IM 0
+pub fn im_0(clock: &mut clocks::Clock, pc_state: &mut pc_state::PcState) {
+    pc_state.set_im(0);
+    clock.increment(8);
+}
+
+// IM 1
 pub fn im_1(clock: &mut clocks::Clock, pc_state: &mut pc_state::PcState) {
     pc_state.set_im(1);
     clock.increment(8);
 }
 
+// IM 2
+pub fn im_2(clock: &mut clocks::Clock, pc_state: &mut pc_state::PcState) {
+    pc_state.set_im(2);
+    clock.increment(8);
+}
+
 pub fn sbc_hl_r16<R16, F16>(
     clock: &mut clocks::Clock,
     src_value: u16,
@@ -1455,10 +1715,6 @@ pub fn adc_hl_r16<R16, F16>(
     clock.increment(15);
 }
 
-pub fn sbc_i() {
-    panic!("SBC A, (IX+d), SBC A, (IY+d) not implemented");
-}
-
 #[cfg(test)]
 mod tests {
     use crate::sega::cpu::extended_instruction_set;
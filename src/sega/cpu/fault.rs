@@ -0,0 +1,73 @@
+use super::super::clocks;
+use std::fmt;
+
+// Opcode prefix of the instruction that caused a fault.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prefix {
+    None,
+    Cb,
+    Dd,
+    Ed,
+    Fd,
+    DdCb,
+    FdCb,
+}
+
+// What 'Core::step' should do when an instruction faults.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultPolicy {
+    Stop,       // Return the fault to the caller.
+    TreatAsNop, // Report the fault, skip the opcode and carry on.
+}
+
+// An instruction that couldn't be executed (currently, just opcodes that
+// aren't implemented).
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuFault {
+    pub prefix: Prefix,
+    pub op_codes: Vec<u8>, // All of the opcode bytes read, including prefixes.
+    pub pc: u16,           // Address of the first opcode byte.
+    pub cycles: clocks::ClockType,
+}
+
+impl CpuFault {
+    pub fn new(prefix: Prefix, op_codes: Vec<u8>, pc: u16, cycles: clocks::ClockType) -> Self {
+        Self {
+            prefix,
+            op_codes,
+            pc,
+            cycles,
+        }
+    }
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op_codes: Vec<String> = self.op_codes.iter().map(|x| format!("{:x}", x)).collect();
+        write!(
+            f,
+            "Opcode not implemented: {} (prefix: {:?}) PC:{:x} cycles:{}",
+            op_codes.join(" "),
+            self.prefix,
+            self.pc,
+            self.cycles
+        )
+    }
+}
+
+impl std::error::Error for CpuFault {}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::cpu::fault;
+
+    #[test]
+    fn test_fault_display() {
+        let cpu_fault =
+            fault::CpuFault::new(fault::Prefix::DdCb, vec![0xDD, 0xCB, 0x2, 0x6], 0x1234, 42);
+        assert_eq!(
+            format!("{}", cpu_fault),
+            "Opcode not implemented: dd cb 2 6 (prefix: DdCb) PC:1234 cycles:42"
+        );
+    }
+}
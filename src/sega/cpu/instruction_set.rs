@@ -19,7 +19,9 @@ pub fn in_a_n<M>(
 ) where
     M: memory::MemoryRW,
 {
-    pc_state.set_a(ports.port_read(clock, memory.read(pc_state.get_pc())));
+    let port_address = memory.read(pc_state.get_pc());
+    pc_state.set_memptr(((pc_state.get_a() as u16) << 8 | port_address as u16).wrapping_add(1));
+    pc_state.set_a(ports.port_read(clock, port_address));
     pc_state.increment_pc(1);
     clock.increment(11);
 }
@@ -34,18 +36,20 @@ pub fn out_n_a<M>(
 ) where
     M: memory::MemoryRW,
 {
-    ports.port_write(clock, memory.read(pc_state.pc_reg.get()), pc_state.get_a());
+    let port_address = memory.read(pc_state.pc_reg.get());
+    ports.port_write(clock, port_address, pc_state.get_a());
+    pc_state.set_memptr((pc_state.get_a() as u16) << 8 | port_address.wrapping_add(1) as u16);
     pc_state.increment_pc(1);
     clock.increment(11);
 }
 
-//0xF3, disable interrupts
+//0xFB, enable interrupts
+// Maskable interrupts aren't accepted until after the next instruction,
+// 'Core::step' checks 'ei_delay'.
 pub fn ei(clock: &mut clocks::Clock, pc_state: &mut pc_state::PcState) {
-    // The 'step' and program counter increment is currently implemented
-    // 'outside' of this function.
-
     pc_state.set_iff1(true);
     pc_state.set_iff2(true);
+    pc_state.set_ei_delay(true);
     clock.increment(4);
 }
 
@@ -158,7 +162,11 @@ where
     F16: pc_state::FlagReg,
 {
     // CP flags calculated set the same as for subtaction, but the result is ignored.
+    // The undocumented X/Y flags are copied from the operand, not the result.
     sub8(a, b, af_reg);
+    let mut f_status = af_reg.get_flags();
+    status_flags::xy_flags(&mut f_status, b);
+    af_reg.set_flags(&f_status);
 }
 
 // Subtract two 8 bit ints and the carry bit, set flags accordingly
@@ -221,6 +229,7 @@ fn calculate_daa_add(pc_state: &mut pc_state::PcState) {
     } else {
         f_status.set_z(0);
     }
+    status_flags::xy_flags(&mut f_status, pc_state.get_a());
 
     pc_state.set_f(f_status)
 }
@@ -262,6 +271,7 @@ fn calculate_daa_sub(pc_state: &mut pc_state::PcState) {
     } else {
         f_status.set_z(0);
     }
+    status_flags::xy_flags(&mut f_status, pc_state.get_a());
 
     pc_state.set_f(f_status);
 }
@@ -389,7 +399,9 @@ pub fn ld_r8_mem<M, F: FnMut(&mut pc_state::PcState, u8)>(
 ) where
     M: memory::MemoryRW,
 {
-    dst_fn(pc_state, memory.read(memory.read16(pc_state.get_pc())));
+    let address = memory.read16(pc_state.get_pc());
+    dst_fn(pc_state, memory.read(address));
+    pc_state.set_memptr(address.wrapping_add(1));
     pc_state.increment_pc(2);
     clock.increment(13);
 }
@@ -468,7 +480,9 @@ pub fn jp_nn<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_sta
 where
     M: memory::MemoryRW,
 {
-    pc_state.set_pc(memory.read16(pc_state.get_pc()));
+    let address = memory.read16(pc_state.get_pc());
+    pc_state.set_pc(address);
+    pc_state.set_memptr(address);
     clock.increment(10);
 }
 
@@ -496,6 +510,7 @@ pub fn jr_cc_e<M>(
 
     if condition {
         pc_state.increment_pc(memory.read(pc_state.get_pc()) as i8);
+        pc_state.set_memptr(pc_state.get_pc().wrapping_add(1));
         clock.increment(5);
     }
     pc_state.increment_pc(1);
@@ -553,8 +568,11 @@ pub fn jump_cc_nn<M>(
 ) where
     M: memory::MemoryRW,
 {
+    // MEMPTR is loaded with the address, whether or not the jump is taken.
+    let address = memory.read16(pc_state.get_pc());
+    pc_state.set_memptr(address);
     if condition {
-        pc_state.set_pc(memory.read16(pc_state.get_pc()));
+        pc_state.set_pc(address);
     } else {
         pc_state.increment_pc(2);
     }
@@ -569,6 +587,7 @@ where
     pc_state.set_b(pc_state.get_b().wrapping_sub(1));
     if pc_state.get_b() != 0 {
         pc_state.increment_pc(memory.read(pc_state.pc_reg.get()) as i8);
+        pc_state.set_memptr(pc_state.get_pc().wrapping_add(1));
         clock.increment(13);
     } else {
         clock.increment(8);
@@ -623,6 +642,7 @@ where
     tmp8 = memory.read(pc_state.sp_reg.get() + 1);
     memory.write(pc_state.sp_reg.get() + 1, pc_state.get_h());
     pc_state.set_h(tmp8);
+    pc_state.set_memptr(pc_state.get_hl());
 
     clock.increment(19);
 }
@@ -637,15 +657,16 @@ pub fn call_cc_nn<M>(
 ) where
     M: memory::MemoryRW,
 {
+    // MEMPTR is loaded with the address, whether or not the call is made.
+    let address = memory.read16(pc_state.get_pc());
+    pc_state.set_memptr(address);
     pc_state.increment_pc(2);
     if condition {
         pc_state.increment_sp(-1);
         memory.write(pc_state.sp_reg.get(), pc_state.pc_reg.high);
         pc_state.increment_sp(-1);
         memory.write(pc_state.sp_reg.get(), pc_state.pc_reg.low);
-        pc_state
-            .pc_reg
-            .set(memory.read16(pc_state.pc_reg.get() - 2));
+        pc_state.pc_reg.set(address);
         clock.increment(17);
     } else {
         clock.increment(10);
@@ -677,6 +698,80 @@ pub fn rst<M>(
     memory.write(pc_state.sp_reg.get(), pc_state.get_pc_low());
 
     pc_state.set_pc(rst_addr as u16);
+    pc_state.set_memptr(rst_addr as u16);
+
+    clock.increment(11);
+}
+
+/**********************************************************/
+/* Interrupt response                                     */
+/**********************************************************/
+
+// Maskable interrupt acknowledge, called once 'iff1' has been checked.
+// IM 0: Execute the instruction on the data bus, only 'RST p' is supported
+//       (the SMS leaves the bus floating at 0xFF, so it's RST 38h).
+// IM 1: RST 38h.
+// IM 2: Call the address from the vector table at (I << 8 | data bus).
+// Returns 'false' if the interrupt couldn't be serviced.
+pub fn interrupt<M>(
+    clock: &mut clocks::Clock,
+    memory: &mut M,
+    pc_state: &mut pc_state::PcState,
+    data_bus: u8,
+) -> bool
+where
+    M: memory::MemoryRW,
+{
+    match pc_state.get_im() {
+        0 if (data_bus & 0b11000111 == 0b11000111) => {
+            // RST p, with 2 additional wait states for the acknowledge cycle.
+            rst(clock, memory, pc_state, data_bus & 0b00111000);
+            clock.increment(2);
+        }
+        1 => {
+            rst(clock, memory, pc_state, 0x38);
+            clock.increment(2);
+        }
+        2 => {
+            pc_state.increment_sp(-1);
+            memory.write(pc_state.sp_reg.get(), pc_state.get_pc_high());
+            pc_state.increment_sp(-1);
+            memory.write(pc_state.sp_reg.get(), pc_state.get_pc_low());
+
+            let vector_address = ((pc_state.get_i() as u16) << 8) | data_bus as u16;
+            pc_state.set_pc(memory.read16(vector_address));
+            pc_state.set_memptr(pc_state.get_pc());
+
+            clock.increment(19);
+        }
+        _ => {
+            return false;
+        }
+    }
+
+    // Disable mask-able interrupts
+    pc_state.set_iff1(false);
+    pc_state.set_iff2(false);
+
+    true
+}
+
+// Non-maskable interrupt acknowledge.
+// Calls 0x66, 'iff1' is saved in 'iff2' (to be restored by RETN).
+pub fn nmi<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut pc_state::PcState)
+where
+    M: memory::MemoryRW,
+{
+    pc_state.increment_sp(-1);
+    memory.write(pc_state.sp_reg.get(), pc_state.get_pc_high());
+    pc_state.increment_sp(-1);
+    memory.write(pc_state.sp_reg.get(), pc_state.get_pc_low());
+
+    pc_state.set_pc(0x66);
+    pc_state.set_memptr(0x66);
+
+    pc_state.set_iff2(pc_state.get_iff1());
+    pc_state.set_iff1(false);
 
     clock.increment(11);
 }
@@ -806,8 +901,10 @@ pub fn ld_mem_nn_hl<M>(clock: &mut clocks::Clock, memory: &mut M, pc_state: &mut
 where
     M: memory::MemoryRW,
 {
-    memory.write(memory.read16(pc_state.get_pc()), pc_state.get_l());
-    memory.write(memory.read16(pc_state.get_pc()) + 1, pc_state.get_h());
+    let address = memory.read16(pc_state.get_pc());
+    memory.write(address, pc_state.get_l());
+    memory.write(address.wrapping_add(1), pc_state.get_h());
+    pc_state.set_memptr(address.wrapping_add(1));
 
     pc_state.increment_pc(2);
     clock.increment(16);
@@ -1110,7 +1207,7 @@ pub fn rrca<F: FnMut(&mut pc_state::PcState, u8)>(
 
     dst_fn(pc_state, new_value);
     let mut f_value = pc_state.get_f();
-    status_flags::set_rotate_accumulator_flags(carry, &mut f_value);
+    status_flags::set_rotate_accumulator_flags(new_value, carry, &mut f_value);
     pc_state.set_f(f_value);
     clock.increment(4);
 }
@@ -1127,7 +1224,7 @@ pub fn rra<F: FnMut(&mut pc_state::PcState, u8)>(
     let (new_value, carry) = rotate_right(src, f_value.get_c() == 1);
 
     dst_fn(pc_state, new_value);
-    status_flags::set_rotate_accumulator_flags(carry, &mut f_value);
+    status_flags::set_rotate_accumulator_flags(new_value, carry, &mut f_value);
     pc_state.set_f(f_value);
     clock.increment(4);
 }
@@ -1144,7 +1241,7 @@ pub fn rlca<F: FnMut(&mut pc_state::PcState, u8)>(
 
     dst_fn(pc_state, new_value);
     let mut f_value = pc_state.get_f();
-    status_flags::set_rotate_accumulator_flags(carry, &mut f_value);
+    status_flags::set_rotate_accumulator_flags(new_value, carry, &mut f_value);
     pc_state.set_f(f_value);
     clock.increment(4);
 }
@@ -1161,7 +1258,7 @@ pub fn rla<F: FnMut(&mut pc_state::PcState, u8)>(
     let (new_value, carry) = rotate_left(src, f_value.get_c() == 1);
 
     dst_fn(pc_state, new_value);
-    status_flags::set_rotate_accumulator_flags(carry, &mut f_value);
+    status_flags::set_rotate_accumulator_flags(new_value, carry, &mut f_value);
     pc_state.set_f(f_value);
     clock.increment(4);
 }
@@ -1175,6 +1272,7 @@ where
     pc_state.increment_sp(1);
     pc_state.set_pc_high(memory.read(pc_state.sp_reg.get()));
     pc_state.increment_sp(1);
+    pc_state.set_memptr(pc_state.get_pc());
 
     clock.increment(10);
 }
@@ -1193,6 +1291,7 @@ pub fn ret_cc<M>(
         pc_state.increment_sp(1);
         pc_state.set_pc_high(memory.read(pc_state.sp_reg.get()));
         pc_state.increment_sp(1);
+        pc_state.set_memptr(pc_state.get_pc());
         clock.increment(11);
     } else {
         clock.increment(5);
@@ -1261,8 +1360,9 @@ pub fn cpl(clock: &mut clocks::Clock, pc_state: &mut pc_state::PcState) {
     let mut f_status = pc_state.get_f();
     f_status.set_h(1);
     f_status.set_n(1);
-    pc_state.set_f(f_status);
     pc_state.set_a(pc_state.get_a() ^ 0xFF);
+    status_flags::xy_flags(&mut f_status, pc_state.get_a());
+    pc_state.set_f(f_status);
 
     clock.increment(4);
 }
@@ -1272,12 +1372,13 @@ pub fn cpl(clock: &mut clocks::Clock, pc_state: &mut pc_state::PcState) {
 pub fn scf<R16, F16>(clock: &mut clocks::Clock, pc_reg: &mut R16, af_reg: &mut F16)
 where
     R16: pc_state::Reg16RW,
-    F16: pc_state::FlagReg,
+    F16: pc_state::FlagReg + pc_state::AfRegister,
 {
     let mut f_status = af_reg.get_flags();
     f_status.set_h(0);
     f_status.set_n(0);
     f_status.set_c(1);
+    status_flags::xy_flags(&mut f_status, af_reg.get_a());
     af_reg.set_flags(&f_status);
 
     clock.increment(4);
@@ -1288,24 +1389,25 @@ where
 pub fn ccf<R16, F16>(clock: &mut clocks::Clock, pc_reg: &mut R16, af_reg: &mut F16)
 where
     R16: pc_state::Reg16RW,
-    F16: pc_state::FlagReg,
+    F16: pc_state::FlagReg + pc_state::AfRegister,
 {
     let mut f_status = af_reg.get_flags();
     f_status.set_h(f_status.get_c());
     f_status.set_n(0);
     f_status.set_c(f_status.get_c() ^ 1);
+    status_flags::xy_flags(&mut f_status, af_reg.get_a());
     af_reg.set_flags(&f_status);
 
     clock.increment(4);
 }
 
-pub fn halt<R16>(clock: &mut clocks::Clock, pc_reg: &mut R16)
-where
-    R16: pc_state::Reg16RW,
-{
-    // Should 'loop/no-op' until an inerrupt occurs (or just immediately trigger one).
+// HALT
+// The CPU executes NOPs until an interrupt (or NMI) is serviced, which then
+// returns to the instruction after the HALT.  The NOPs are handled by
+// 'Core::step' while 'halted' is set.
+pub fn halt(clock: &mut clocks::Clock, pc_state: &mut pc_state::PcState) {
+    pc_state.set_halted(true);
     clock.increment(4);
-    pc_state::PcState::increment_reg(pc_reg, -1);
 }
 
 #[cfg(test)]
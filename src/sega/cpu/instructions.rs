@@ -3,11 +3,24 @@ use super::super::interruptor;
 use super::super::memory::memory;
 use super::super::ports;
 use super::extended_instruction_set;
+use super::fault;
 use super::instruction_set;
 use super::pc_state;
 
 pub struct Instruction {}
 
+// Every opcode is implemented, so tests choose one to fault (as if it wasn't)
+// to check the fault handling.
+#[cfg(test)]
+thread_local! {
+    static FAULT_OP_CODE: std::cell::Cell<Option<u8>> = const { std::cell::Cell::new(None) };
+}
+
+#[cfg(test)]
+pub fn set_fault_op_code(op_code: Option<u8>) {
+    FAULT_OP_CODE.with(|fault_op_code| fault_op_code.set(op_code));
+}
+
 // Gets the value from the particular 8-bit register.
 fn select_8_bit_read_register(pc_state: &pc_state::PcState, reg_select: u8) -> u8 {
     match reg_select & 0x7 {
@@ -68,13 +81,13 @@ fn get_8_bit_register_set_function(reg_select: u8) -> impl FnMut(&mut pc_state::
     }
 }
 
-// Gets the value from the particular 8-bit register.
+// Gets the value from the particular 16-bit register (ss/dd encoding).
 fn select_16_bit_read_register(pc_state: &pc_state::PcState, reg_select: u8) -> u16 {
     match reg_select & 0x3 {
         0b00 => pc_state.get_bc(),
         0b01 => pc_state.get_de(),
         0b10 => pc_state.get_hl(),
-        0b11 => pc_state.get_af(),
+        0b11 => pc_state.get_sp(),
         _ => {
             panic!(
                 "Code path that was thought to be unreachable was reached! {}",
@@ -90,7 +103,7 @@ fn get_16_bit_ss_set_function(reg_select: u8) -> impl FnMut(&mut pc_state::PcSta
         0b00 => state.set_bc(x),
         0b01 => state.set_de(x),
         0b10 => state.set_hl(x),
-        0b11 => state.set_af(x),
+        0b11 => state.sp_reg.set(x),
         _ => {
             panic!(
                 "Code path that was thought to be unreachable was reached! {}",
@@ -100,6 +113,49 @@ fn get_16_bit_ss_set_function(reg_select: u8) -> impl FnMut(&mut pc_state::PcSta
     }
 }
 
+// As 'select_8_bit_read_register', but with H/L replaced by the high/low
+// bytes of the index register (undocumented IXH/IXL/IYH/IYL).
+fn select_8_bit_index_read_register(
+    pc_state: &pc_state::PcState,
+    index_value: u16,
+    reg_select: u8,
+) -> u8 {
+    match reg_select & 0x7 {
+        4 => (index_value >> 8) as u8,
+        5 => index_value as u8,
+        _ => select_8_bit_read_register(pc_state, reg_select),
+    }
+}
+
+// As 'get_8_bit_register_set_function', but with H/L replaced by the high/low
+// bytes of the index register.
+fn get_8_bit_index_register_set_function<FM>(
+    index_reg_fn_mut: FM,
+    reg_select: u8,
+) -> impl FnMut(&mut pc_state::PcState, u8)
+where
+    FM: Fn(&mut pc_state::IndexRegisters) -> &mut pc_state::Reg16 + Copy,
+{
+    move |state: &mut pc_state::PcState, x| match reg_select & 0x7 {
+        4 => index_reg_fn_mut(&mut state.index_registers).high = x,
+        5 => index_reg_fn_mut(&mut state.index_registers).low = x,
+        _ => get_8_bit_register_set_function(reg_select)(state, x),
+    }
+}
+
+// Index (DD/FD) op codes that address memory with '(IX+d)', the displacement
+// is the byte following the op code.
+fn uses_index_displacement(op_code: u8) -> bool {
+    match op_code {
+        0x76 => false, // HALT
+        0x34 | 0x35 | 0x36 | 0xCB => true,
+        n if (n & 0b11000111 == 0b01000110) => true, // LD r, (IX+d)
+        n if (n & 0b11111000 == 0b01110000) => true, // LD (IX+d), r
+        n if (n & 0b11000111 == 0b10000110) => true, // ALU (IX+d)
+        _ => false,
+    }
+}
+
 impl Instruction {
     pub fn execute<M>(
         op_code: u8,
@@ -108,32 +164,37 @@ impl Instruction {
         pc_state: &mut pc_state::PcState,
         ports: &mut ports::Ports,
         _interruptor: &mut interruptor::Interruptor,
-    ) where
+    ) -> Result<(), fault::CpuFault>
+    where
         M: memory::MemoryRW,
     {
+        #[cfg(test)]
+        if FAULT_OP_CODE.with(|fault_op_code| fault_op_code.get()) == Some(op_code) {
+            return Err(fault::CpuFault::new(
+                fault::Prefix::None,
+                vec![op_code],
+                pc_state.get_pc().wrapping_sub(1),
+                clock.cycles,
+            ));
+        }
+
         match op_code {
             // Extended op codes, not executed directly
             0xcb => {
-                Self::execute_cb(clock, memory, pc_state);
+                Self::execute_cb(clock, memory, pc_state)?;
             }
             0xdd => {
-                Self::execute_dd(clock, memory, pc_state);
+                Self::execute_dd(clock, memory, pc_state, ports, _interruptor)?;
             }
             0xed => {
-                Self::execute_ed(clock, memory, pc_state, ports);
+                Self::execute_ed(clock, memory, pc_state, ports)?;
             }
             0xfd => {
-                Self::execute_fd(clock, memory, pc_state);
+                Self::execute_fd(clock, memory, pc_state, ports, _interruptor)?;
             }
 
             0xfb => {
-                // Perform a 'step' before enabling interrupts.
-                let next_op_code = memory.read(pc_state.get_pc());
-                pc_state.increment_pc(1);
-                Self::execute(next_op_code, clock, memory, pc_state, ports, _interruptor);
-
                 instruction_set::ei(clock, pc_state);
-                // TODO: Add polling as part of ei. Currently leaving it to outside of this call.
             }
 
             0x00 => {
@@ -155,6 +216,9 @@ impl Instruction {
                     &mut pc_state.pc_reg,
                     &pc_state.bc_reg,
                 );
+                pc_state.set_memptr(
+                    (pc_state.get_a() as u16) << 8 | (pc_state.get_bc().wrapping_add(1) & 0xFF),
+                );
             } // LD (BC), A
             0x12 => {
                 instruction_set::ld_mem_r(
@@ -164,10 +228,14 @@ impl Instruction {
                     &mut pc_state.pc_reg,
                     &pc_state.de_reg,
                 );
+                pc_state.set_memptr(
+                    (pc_state.get_a() as u16) << 8 | (pc_state.get_de().wrapping_add(1) & 0xFF),
+                );
             } // LD (DE), A
 
             n if (n & 0b11001111 == 0b00001001) => {
                 let ss = (n >> 4) & 0x3;
+                pc_state.set_memptr(pc_state.get_hl().wrapping_add(1));
                 instruction_set::add16(
                     clock,
                     select_16_bit_read_register(pc_state, ss),
@@ -230,6 +298,7 @@ impl Instruction {
                 );
             } // LD HL, nn
             0x2a => {
+                pc_state.set_memptr(memory.read16(pc_state.get_pc()).wrapping_add(1));
                 instruction_set::ld_r16_mem(
                     clock,
                     memory,
@@ -439,6 +508,7 @@ impl Instruction {
                     |state: &mut pc_state::PcState, x| state.set_a(x),
                     pc_state.bc_reg.get(),
                 );
+                pc_state.set_memptr(pc_state.get_bc().wrapping_add(1));
             } // LD A, (BC)
             0x1a => {
                 instruction_set::ld_r_mem(
@@ -448,6 +518,7 @@ impl Instruction {
                     |state: &mut pc_state::PcState, x| state.set_a(x),
                     pc_state.de_reg.get(),
                 );
+                pc_state.set_memptr(pc_state.get_de().wrapping_add(1));
             } // LD A, (DE)
 
             // LD r,n
@@ -502,7 +573,10 @@ impl Instruction {
                 instruction_set::jrnc_e(clock, memory, pc_state);
             }
             0x32 => {
+                let address = memory.read16(pc_state.get_pc());
                 instruction_set::ld_nn_r(clock, memory, pc_state.get_a(), &mut pc_state.pc_reg);
+                pc_state
+                    .set_memptr((pc_state.get_a() as u16) << 8 | (address.wrapping_add(1) & 0xFF));
             }
             0x37 => {
                 instruction_set::scf(clock, &mut pc_state.pc_reg, &mut pc_state.af_reg);
@@ -514,7 +588,7 @@ impl Instruction {
                 instruction_set::ccf(clock, &mut pc_state.pc_reg, &mut pc_state.af_reg);
             }
             0x76 => {
-                instruction_set::halt(clock, &mut pc_state.pc_reg);
+                instruction_set::halt(clock, pc_state);
             }
             0x86 => {
                 instruction_set::add_hl(clock, memory, pc_state);
@@ -711,9 +785,15 @@ impl Instruction {
             }
 
             _ => {
-                panic!("Opcode not implemented: {:x}", op_code);
+                return Err(fault::CpuFault::new(
+                    fault::Prefix::None,
+                    vec![op_code],
+                    pc_state.get_pc().wrapping_sub(1),
+                    clock.cycles,
+                ));
             }
         }
+        Ok(())
     }
 
     // Extended instructions
@@ -721,7 +801,8 @@ impl Instruction {
         clock: &mut clocks::Clock,
         memory: &mut M,
         pc_state: &mut pc_state::PcState,
-    ) where
+    ) -> Result<(), fault::CpuFault>
+    where
         M: memory::MemoryRW,
     {
         let op_code = memory.read(pc_state.get_pc());
@@ -744,14 +825,7 @@ impl Instruction {
             }
             n if (n & 0b11000110 == 0b01000110) => {
                 let bit_pos = (n >> 3) & 0x7;
-                extended_instruction_set::bit_b_mem(
-                    clock,
-                    memory,
-                    bit_pos,
-                    &mut pc_state.pc_reg,
-                    &mut pc_state.af_reg,
-                    &pc_state.hl_reg,
-                );
+                extended_instruction_set::bit_b_mem(clock, memory, bit_pos, pc_state);
             }
 
             // SET b, r
@@ -883,6 +957,24 @@ impl Instruction {
                     &pc_state.hl_reg,
                 );
             }
+            0x16 => {
+                extended_instruction_set::rl_hl(
+                    clock,
+                    memory,
+                    &mut pc_state.pc_reg,
+                    &mut pc_state.af_reg,
+                    &pc_state.hl_reg,
+                );
+            }
+            0x1e => {
+                extended_instruction_set::rr_hl(
+                    clock,
+                    memory,
+                    &mut pc_state.pc_reg,
+                    &mut pc_state.af_reg,
+                    &pc_state.hl_reg,
+                );
+            }
             0x26 => {
                 extended_instruction_set::sla_hl(
                     clock,
@@ -902,6 +994,15 @@ impl Instruction {
                 );
             }
             0x36 => {
+                extended_instruction_set::sll_hl(
+                    clock,
+                    memory,
+                    &mut pc_state.pc_reg,
+                    &mut pc_state.af_reg,
+                    &pc_state.hl_reg,
+                );
+            }
+            0x3e => {
                 extended_instruction_set::srl_hl(
                     clock,
                     memory,
@@ -912,38 +1013,63 @@ impl Instruction {
             }
 
             _ => {
-                panic!("Extended(0xCB) Opcode not implemented: {:x}", op_code);
+                return Err(fault::CpuFault::new(
+                    fault::Prefix::Cb,
+                    vec![0xCB, op_code],
+                    pc_state.get_pc().wrapping_sub(2),
+                    clock.cycles,
+                ));
             }
         }
+        Ok(())
     }
 
     // Extended instructions
     pub fn execute_index<
         M,
         F: Fn(&pc_state::IndexRegisters) -> &pc_state::Reg16,
-        FM: FnMut(&mut pc_state::IndexRegisters) -> &mut pc_state::Reg16,
+        FM: Fn(&mut pc_state::IndexRegisters) -> &mut pc_state::Reg16 + Copy,
     >(
         clock: &mut clocks::Clock,
         memory: &mut M,
         pc_state: &mut pc_state::PcState,
+        ports: &mut ports::Ports,
+        interruptor: &mut interruptor::Interruptor,
         index_reg_fn: F,
-        mut index_reg_fn_mut: FM,
-    ) where
+        index_reg_fn_mut: FM,
+    ) -> Result<(), fault::CpuFault>
+    where
         M: memory::MemoryRW,
     {
         let op_code = memory.read(pc_state.get_pc());
         pc_state.increment_pc(1);
+        if uses_index_displacement(op_code) {
+            // MEMPTR is set to the (IX+d) address.
+            let displacement = memory.read(pc_state.get_pc()) as i8;
+            pc_state.set_memptr(
+                index_reg_fn(&pc_state.index_registers)
+                    .get()
+                    .wrapping_add(displacement as u16),
+            );
+        }
         match op_code {
+            // 0xDD, 0xCB, 0bdddddddd, 0bxxxxxrrr
             0xcb => {
-                extended_instruction_set::bit_res_set_b_i_d(
-                    clock,
-                    memory,
-                    &mut pc_state.pc_reg,
-                    &mut pc_state.af_reg,
-                    index_reg_fn(&mut pc_state.index_registers),
-                );
+                let extended_op_code = memory.read(pc_state.get_pc().wrapping_add(1));
+                let i16_value = index_reg_fn(&pc_state.index_registers).get();
+                let dst_fn = get_8_bit_register_set_function(extended_op_code);
+                if extended_op_code >> 6 == 0b00 {
+                    extended_instruction_set::rotate_shift_i_d(
+                        clock, memory, pc_state, i16_value, dst_fn,
+                    );
+                } else {
+                    extended_instruction_set::bit_res_set_b_i_d(
+                        clock, memory, pc_state, i16_value, dst_fn,
+                    );
+                }
             }
             0x22 => {
+                pc_state.set_memptr(memory.read16(pc_state.get_pc()).wrapping_add(1));
                 extended_instruction_set::ld_mem_nn_reg16(
                     clock,
                     memory,
@@ -952,6 +1078,7 @@ impl Instruction {
                 );
             }
             0x2A => {
+                pc_state.set_memptr(memory.read16(pc_state.get_pc()).wrapping_add(1));
                 extended_instruction_set::ld_i_mem_nn(
                     clock,
                     memory,
@@ -968,10 +1095,6 @@ impl Instruction {
                 );
             }
 
-            0x9e => {
-                extended_instruction_set::sbc_i();
-            }
-
             n if (n & 0b11000111 == 0b01000110) && ((n >> 3) & 0b111 != 0b110) => {
                 let reg_index = (n >> 3) & 0x7;
                 let dst_fn = get_8_bit_register_set_function(reg_index);
@@ -1022,11 +1145,22 @@ impl Instruction {
                 );
             }
 
+            // ADD IX, pp (pp: BC 00, DE 01, IX 10, SP 11)
             n if (n & 0b11001111 == 0b00001001) => {
                 let ss = (n >> 4) & 0x3;
+                let src_value = if ss == 0b10 {
+                    index_reg_fn(&pc_state.index_registers).get()
+                } else {
+                    select_16_bit_read_register(pc_state, ss)
+                };
+                pc_state.set_memptr(
+                    index_reg_fn(&pc_state.index_registers)
+                        .get()
+                        .wrapping_add(1),
+                );
                 extended_instruction_set::add16(
                     clock,
-                    select_16_bit_read_register(pc_state, ss),
+                    src_value,
                     &mut pc_state.pc_reg,
                     index_reg_fn_mut(&mut pc_state.index_registers),
                     &mut pc_state.af_reg,
@@ -1074,6 +1208,15 @@ impl Instruction {
                     &mut pc_state.af_reg,
                 );
             }
+            0x9e => {
+                extended_instruction_set::sbc_i_d(
+                    clock,
+                    memory,
+                    &mut pc_state.pc_reg,
+                    index_reg_fn_mut(&mut pc_state.index_registers),
+                    &mut pc_state.af_reg,
+                );
+            }
             0x96 => {
                 extended_instruction_set::sub_i_d(
                     clock,
@@ -1145,6 +1288,7 @@ impl Instruction {
                     &mut pc_state.sp_reg,
                     index_reg_fn_mut(&mut pc_state.index_registers),
                 );
+                pc_state.set_memptr(index_reg_fn(&pc_state.index_registers).get());
             }
             0xF9 => {
                 extended_instruction_set::ld_sp_i(
@@ -1155,20 +1299,116 @@ impl Instruction {
                 );
             }
 
+            // Undocumented IXH/IXL (IYH/IYL) instructions, the 'H'/'L' forms of
+            // the unprefixed instruction with the high/low index register byte
+            // substituted, taking an extra 4 cycles for the prefix.
+
+            // INC IXH, INC IXL
+            // op_code: 0b00rrr100
+            0x24 | 0x2C => {
+                let reg_index = (op_code >> 3) & 0x7;
+                let dst_fn = get_8_bit_index_register_set_function(index_reg_fn_mut, reg_index);
+                let index_value = index_reg_fn(&pc_state.index_registers).get();
+                instruction_set::inc_r(
+                    clock,
+                    pc_state,
+                    dst_fn,
+                    select_8_bit_index_read_register(pc_state, index_value, reg_index),
+                );
+                clock.increment(4);
+            }
+
+            // DEC IXH, DEC IXL
+            // op_code: 0b00rrr101
+            0x25 | 0x2D => {
+                let reg_index = (op_code >> 3) & 0x7;
+                let dst_fn = get_8_bit_index_register_set_function(index_reg_fn_mut, reg_index);
+                let index_value = index_reg_fn(&pc_state.index_registers).get();
+                instruction_set::dec_r(
+                    clock,
+                    pc_state,
+                    dst_fn,
+                    select_8_bit_index_read_register(pc_state, index_value, reg_index),
+                );
+                clock.increment(4);
+            }
+
+            // LD IXH, n, LD IXL, n
+            // opcode: 0b00rrr110 nnnnnnnn
+            0x26 | 0x2E => {
+                let reg_index = (op_code >> 3) & 0x7;
+                let dst_fn = get_8_bit_index_register_set_function(index_reg_fn_mut, reg_index);
+                instruction_set::ld_r(clock, memory, pc_state, dst_fn);
+                clock.increment(4);
+            }
+
+            // LD r, r' where either r or r' is IXH/IXL
+            // opcode: 0b01dddsss
+            n if ((n & 0b11000000) == 0b01000000)
+                && ((n & 0x07) != 0x6)
+                && ((n & 0x38) != 0x30)
+                && ((n & 0x06 == 0x04) || (n & 0x30 == 0x20)) =>
+            {
+                let dst_reg_index = (n >> 3) & 0x7;
+                let src_reg_index = n & 0x7;
+                let dst_fn = get_8_bit_index_register_set_function(index_reg_fn_mut, dst_reg_index);
+                let index_value = index_reg_fn(&pc_state.index_registers).get();
+                instruction_set::ld_r_r(
+                    clock,
+                    select_8_bit_index_read_register(pc_state, index_value, src_reg_index),
+                    pc_state,
+                    dst_fn,
+                );
+                clock.increment(4);
+            }
+
+            // ADD/ADC/SUB/SBC/AND/XOR/OR/CP IXH, IXL
+            // opcode: 0b10ooo10r
+            n if (n & 0b11000110 == 0b10000100) => {
+                let index_value = index_reg_fn(&pc_state.index_registers).get();
+                let r = select_8_bit_index_read_register(pc_state, index_value, n & 0x7);
+                match (n >> 3) & 0x7 {
+                    0b000 => instruction_set::add_r(clock, r, pc_state),
+                    0b001 => instruction_set::adc_r(clock, r, pc_state),
+                    0b010 => instruction_set::sub_r(clock, r, pc_state),
+                    0b011 => instruction_set::sbc_r(clock, r, pc_state),
+                    0b100 => instruction_set::and_r(clock, r, pc_state),
+                    0b101 => instruction_set::xor_r(clock, r, pc_state),
+                    0b110 => instruction_set::or_r(clock, r, pc_state),
+                    _ => instruction_set::cp_r(clock, r, pc_state),
+                }
+                clock.increment(4);
+            }
+
+            // Any other op code doesn't use HL, so the prefix is ignored (costing
+            // 4 cycles) and the op code is executed as normal.
             _ => {
-                panic!("Extended(0xDD) Opcode not implemented: {:x}", op_code);
+                clock.increment(4);
+                Self::execute(op_code, clock, memory, pc_state, ports, interruptor)?;
             }
         }
+        Ok(())
     }
     // Extended instructions
     pub fn execute_dd<M>(
         clock: &mut clocks::Clock,
         memory: &mut M,
         pc_state: &mut pc_state::PcState,
-    ) where
+        ports: &mut ports::Ports,
+        interruptor: &mut interruptor::Interruptor,
+    ) -> Result<(), fault::CpuFault>
+    where
         M: memory::MemoryRW,
     {
-        Self::execute_index(clock, memory, pc_state, |x| &x.ix_reg, |x| &mut x.ix_reg);
+        Self::execute_index(
+            clock,
+            memory,
+            pc_state,
+            ports,
+            interruptor,
+            |x| &x.ix_reg,
+            |x| &mut x.ix_reg,
+        )
     }
 
     // Extended instructions
@@ -1176,10 +1416,21 @@ impl Instruction {
         clock: &mut clocks::Clock,
         memory: &mut M,
         pc_state: &mut pc_state::PcState,
-    ) where
+        ports: &mut ports::Ports,
+        interruptor: &mut interruptor::Interruptor,
+    ) -> Result<(), fault::CpuFault>
+    where
         M: memory::MemoryRW,
     {
-        Self::execute_index(clock, memory, pc_state, |x| &x.iy_reg, |x| &mut x.iy_reg);
+        Self::execute_index(
+            clock,
+            memory,
+            pc_state,
+            ports,
+            interruptor,
+            |x| &x.iy_reg,
+            |x| &mut x.iy_reg,
+        )
     }
 
     // Extended instructions
@@ -1188,17 +1439,14 @@ impl Instruction {
         memory: &mut M,
         pc_state: &mut pc_state::PcState,
         ports: &mut ports::Ports,
-    ) where
+    ) -> Result<(), fault::CpuFault>
+    where
         M: memory::MemoryRW,
     {
         let op_code = memory.read(pc_state.get_pc());
         pc_state.increment_pc(1);
 
         match op_code {
-            0x00 => {
-                instruction_set::noop(clock, pc_state);
-            }
-
             // 0b01dd1011 -> BC 00, DE 01, HL 10, SP 11
             n if (n & 0b11001111 == 0b01001011) => {
                 let dd = (n >> 4) & 0x3;
@@ -1212,6 +1460,7 @@ impl Instruction {
 
             // 0b00dd0001 -> dd -> BC 00, DE 01, HL 10, SP 11
             0x43 => {
+                pc_state.set_memptr(memory.read16(pc_state.get_pc()).wrapping_add(1));
                 extended_instruction_set::ld_mem_nn_reg16(
                     clock,
                     memory,
@@ -1220,6 +1469,7 @@ impl Instruction {
                 );
             }
             0x53 => {
+                pc_state.set_memptr(memory.read16(pc_state.get_pc()).wrapping_add(1));
                 extended_instruction_set::ld_mem_nn_reg16(
                     clock,
                     memory,
@@ -1228,6 +1478,7 @@ impl Instruction {
                 );
             }
             0x63 => {
+                pc_state.set_memptr(memory.read16(pc_state.get_pc()).wrapping_add(1));
                 extended_instruction_set::ld_mem_nn_reg16(
                     clock,
                     memory,
@@ -1236,6 +1487,7 @@ impl Instruction {
                 );
             }
             0x73 => {
+                pc_state.set_memptr(memory.read16(pc_state.get_pc()).wrapping_add(1));
                 extended_instruction_set::ld_mem_nn_reg16(
                     clock,
                     memory,
@@ -1283,22 +1535,48 @@ impl Instruction {
                 );
             }
 
+            // IN (C) (undocumented, only sets the flags) 0xED, 0x70
+            0x70 => {
+                extended_instruction_set::in_f(clock, pc_state.get_c(), pc_state, ports);
+            }
+
+            // OUT (C), 0 (undocumented) 0xED, 0x71
+            0x71 => {
+                extended_instruction_set::out_r(clock, pc_state.get_c(), pc_state, 0, ports);
+            }
+
             0xA3 => {
                 extended_instruction_set::outi(clock, memory, pc_state, ports);
             }
             0xAB => {
                 extended_instruction_set::outd(clock, memory, pc_state, ports);
             }
-            0x44 => {
+            // NEG, 0x4C/0x54/0x5C/0x64/0x6C/0x74/0x7C are undocumented mirrors.
+            0x44 | 0x4C | 0x54 | 0x5C | 0x64 | 0x6C | 0x74 | 0x7C => {
                 extended_instruction_set::neg(clock, pc_state);
             }
             0x4D => {
                 extended_instruction_set::reti(clock, memory, pc_state);
             }
+            0x45 | 0x55 | 0x5D | 0x65 | 0x6D | 0x75 | 0x7D => {
+                extended_instruction_set::retn(clock, memory, pc_state);
+            }
 
             0xB3 => {
                 extended_instruction_set::otir(clock, memory, pc_state, ports);
             }
+            0xBB => {
+                extended_instruction_set::otdr(clock, memory, pc_state, ports);
+            }
+            0xAA => {
+                extended_instruction_set::ind(clock, memory, pc_state, ports);
+            }
+            0xB2 => {
+                extended_instruction_set::inir(clock, memory, pc_state, ports);
+            }
+            0xBA => {
+                extended_instruction_set::indr(clock, memory, pc_state, ports);
+            }
             0xB8 => {
                 extended_instruction_set::lddr(clock, memory, pc_state);
             }
@@ -1326,14 +1604,22 @@ impl Instruction {
             0xA8 => {
                 extended_instruction_set::ldd(clock, memory, pc_state);
             }
-            0x56 => {
+            // IM 0/1/2, 0x4E/0x6E (undefined IM 0/1) behave as IM 0.
+            0x46 | 0x4E | 0x66 | 0x6E => {
+                extended_instruction_set::im_0(clock, pc_state);
+            }
+            0x56 | 0x76 => {
                 extended_instruction_set::im_1(clock, pc_state);
             }
+            0x5E | 0x7E => {
+                extended_instruction_set::im_2(clock, pc_state);
+            }
 
             // ADC HL, ss
             // 0b01ss1010
             n if (n & 0b11001111 == 0b01001010) => {
                 let ss = (n >> 4) & 0x3;
+                pc_state.set_memptr(pc_state.get_hl().wrapping_add(1));
                 extended_instruction_set::adc_hl_r16(
                     clock,
                     select_16_bit_read_register(pc_state, ss),
@@ -1347,6 +1633,7 @@ impl Instruction {
             // 0b01ss0010
             n if (n & 0b11001111 == 0b01000010) => {
                 let ss = (n >> 4) & 0x3;
+                pc_state.set_memptr(pc_state.get_hl().wrapping_add(1));
                 extended_instruction_set::sbc_hl_r16(
                     clock,
                     select_16_bit_read_register(pc_state, ss),
@@ -1356,10 +1643,12 @@ impl Instruction {
                 );
             }
 
+            // Remaining (undefined) op codes act as an 8 cycle NOP.
             _ => {
-                panic!("Extended(0xED) Opcode not implemented: {:x}", op_code);
+                extended_instruction_set::ed_nop(clock);
             }
         }
+        Ok(())
     }
 }
 
@@ -1367,10 +1656,13 @@ impl Instruction {
 mod tests {
     use crate::impl_common_memoryrw;
     use crate::sega::clocks;
+    use crate::sega::cpu::fault;
+    use crate::sega::cpu::instruction_set;
     use crate::sega::cpu::instructions;
     use crate::sega::cpu::pc_state;
     use crate::sega::interruptor;
     use crate::sega::memory::memory;
+    use crate::sega::memory::memory::MemoryRW;
     use crate::sega::ports;
 
     // Create a 'test memory' class, to allow simple/arbitrary population of memory.
@@ -1411,6 +1703,10 @@ mod tests {
         fn write(&mut self, address: memory::AddressType, data: u8) {
             self.dummy_memory[address as usize] = data;
         }
+
+        fn rom_bank(&self, address: memory::AddressType) -> Option<u8> {
+            None
+        }
     }
 
     // Allow the memory to be used as 'MemoryRW'
@@ -1430,7 +1726,23 @@ mod tests {
             &mut test_core.pc_state,
             &mut test_core.ports,
             &mut test_core.interruptor,
-        );
+        )
+        .unwrap();
+    }
+
+    // Execute the instruction at the current PC (leaving memory as is).
+    fn execute_next(test_core: &mut TestCore) {
+        let op_code = test_core.memory.dummy_memory[test_core.pc_state.get_pc() as usize];
+        test_core.pc_state.increment_pc(1);
+        instructions::Instruction::execute(
+            op_code,
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+            &mut test_core.ports,
+            &mut test_core.interruptor,
+        )
+        .unwrap();
     }
 
     #[test]
@@ -1495,7 +1807,8 @@ mod tests {
             &mut test_core.pc_state,
             &mut test_core.ports,
             &mut test_core.interruptor,
-        ); // LD r,'r  C -> B
+        )
+        .unwrap(); // LD r,'r  C -> B
         assert_eq!(test_core.pc_state.get_b(), 0x42);
         assert_eq!(test_core.clock.cycles, 4);
     }
@@ -1513,7 +1826,8 @@ mod tests {
             &mut test_core.pc_state,
             &mut test_core.ports,
             &mut test_core.interruptor,
-        ); // JP (HL)
+        )
+        .unwrap(); // JP (HL)
         assert_eq!(test_core.pc_state.get_pc(), 0x4233);
     }
 
@@ -1532,7 +1846,8 @@ mod tests {
             &mut test_core.pc_state,
             &mut test_core.ports,
             &mut test_core.interruptor,
-        ); // dec_r, for h
+        )
+        .unwrap(); // dec_r, for h
         assert_eq!(test_core.pc_state.get_h(), 0x7F);
         assert_eq!(test_core.pc_state.get_f().get_h(), 1);
         assert_eq!(test_core.pc_state.get_f().get_c(), 1);
@@ -1552,7 +1867,8 @@ mod tests {
             &mut test_core.pc_state,
             &mut test_core.ports,
             &mut test_core.interruptor,
-        ); // no-op
+        )
+        .unwrap(); // no-op
         assert_eq!(test_core.pc_state.get_pc(), 0x1);
         assert_eq!(test_core.clock.cycles, 4);
 
@@ -1570,7 +1886,8 @@ mod tests {
             &mut test_core.pc_state,
             &mut test_core.ports,
             &mut test_core.interruptor,
-        );
+        )
+        .unwrap();
         assert_eq!(test_core.pc_state.get_pc(), 0x3);
         assert_eq!(test_core.pc_state.get_bc(), 0x3310);
         assert_eq!(test_core.clock.cycles, 10);
@@ -1756,8 +2073,8 @@ mod tests {
         default_pc_op_code_check(vec![0x98], vec![4], "SBC A,r"); // SBC A,s
         default_pc_op_code_check(vec![0xDE, 0x00], vec![4, 3], "SBC A,n"); // SBC A,s
         default_pc_op_code_check(vec![0x9E], vec![4, 3], "SBC A,(HL)"); // SBC A,s
-                                                                        // Not ipmlemented        default_pc_op_code_check(vec![0xDD,0x9E,0x00], vec![4,4,3,5,3], "SBC A,(IY+d)"); // SBC A,s
-                                                                        // Not ipmlemented        default_pc_op_code_check(vec![0xFD,0x9E,0x00], vec![4,4,3,5,3], "SBC A,(IY+d)"); // SBC A,s
+        default_pc_op_code_check(vec![0xDD, 0x9E, 0x00], vec![4, 4, 3, 5, 3], "SBC A,(IX+d)"); // SBC A,s
+        default_pc_op_code_check(vec![0xFD, 0x9E, 0x00], vec![4, 4, 3, 5, 3], "SBC A,(IY+d)"); // SBC A,s
 
         default_pc_op_code_check(vec![0xA0], vec![4], "AND r"); // AND s
         default_pc_op_code_check(vec![0xE6, 0x00], vec![4, 3], "AND n"); // AND s
@@ -1922,4 +2239,526 @@ mod tests {
         test_op_code_cycle_count(&mut test_core, vec![0xFD, 0x35, 0xFD, 0x09], 3, 23); // DEC (IY+d) (d = -3)
         assert_eq!(test_core.memory.dummy_memory[3], 0x8);
     }
+
+    #[test]
+    fn test_interrupt_mode_opcodes() {
+        let mut test_core = TestCore::new();
+
+        // IM 0, IM 1, IM 2 (and the undocumented mirrors)
+        for (op_code, expected_mode) in [
+            (0x46, 0),
+            (0x4E, 0),
+            (0x66, 0),
+            (0x6E, 0),
+            (0x56, 1),
+            (0x76, 1),
+            (0x5E, 2),
+            (0x7E, 2),
+        ] {
+            test_core.pc_state.set_im(0xFF);
+            check_op_code_cycle_count(&mut test_core, vec![0xED, op_code], 2, 8);
+            assert_eq!(test_core.pc_state.get_im(), expected_mode);
+        }
+    }
+
+    #[test]
+    fn test_interrupt_modes() {
+        fn interrupt_core(mode: u8) -> TestCore {
+            let mut test_core = TestCore::new();
+            test_core.memory.dummy_memory = vec![0; 0x200];
+            test_core.pc_state.set_pc(0x1234);
+            test_core.pc_state.sp_reg.set(0x200);
+            test_core.pc_state.set_im(mode);
+            test_core.pc_state.set_iff1(true);
+            test_core.pc_state.set_iff2(true);
+            test_core
+        }
+
+        fn check_return_address(test_core: &TestCore) {
+            assert_eq!(test_core.pc_state.get_sp(), 0x1FE);
+            assert_eq!(test_core.memory.dummy_memory[0x1FE], 0x34);
+            assert_eq!(test_core.memory.dummy_memory[0x1FF], 0x12);
+            assert!(!test_core.pc_state.get_iff1());
+            assert!(!test_core.pc_state.get_iff2());
+        }
+
+        // IM 0, with 0xFF on the data bus (RST 38h)
+        let mut test_core = interrupt_core(0);
+        assert!(instruction_set::interrupt(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+            0xFF
+        ));
+        assert_eq!(test_core.pc_state.get_pc(), 0x38);
+        assert_eq!(test_core.clock.cycles, 13);
+        check_return_address(&test_core);
+
+        // IM 0, with RST 10h on the data bus
+        let mut test_core = interrupt_core(0);
+        assert!(instruction_set::interrupt(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+            0xD7
+        ));
+        assert_eq!(test_core.pc_state.get_pc(), 0x10);
+        check_return_address(&test_core);
+
+        // IM 0, only RST is supported from the data bus.
+        let mut test_core = interrupt_core(0);
+        assert!(!instruction_set::interrupt(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+            0x00
+        ));
+        assert_eq!(test_core.pc_state.get_pc(), 0x1234);
+        assert_eq!(test_core.clock.cycles, 0);
+        assert!(test_core.pc_state.get_iff1());
+
+        // IM 1
+        let mut test_core = interrupt_core(1);
+        assert!(instruction_set::interrupt(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+            0xFF
+        ));
+        assert_eq!(test_core.pc_state.get_pc(), 0x38);
+        assert_eq!(test_core.clock.cycles, 13);
+        check_return_address(&test_core);
+
+        // IM 2, vector table at (I << 8 | 0xFF)
+        let mut test_core = interrupt_core(2);
+        test_core.pc_state.set_i(0x01);
+        test_core.memory.dummy_memory.resize(0x201, 0);
+        test_core.memory.dummy_memory[0x1FF] = 0x78;
+        test_core.memory.dummy_memory[0x200] = 0x56;
+        test_core.pc_state.sp_reg.set(0x1F0);
+        assert!(instruction_set::interrupt(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+            0xFF
+        ));
+        assert_eq!(test_core.pc_state.get_pc(), 0x5678);
+        assert_eq!(test_core.clock.cycles, 19);
+        assert_eq!(test_core.pc_state.get_sp(), 0x1EE);
+        assert_eq!(test_core.memory.dummy_memory[0x1EE], 0x34);
+        assert_eq!(test_core.memory.dummy_memory[0x1EF], 0x12);
+        assert!(!test_core.pc_state.get_iff1());
+
+        // IM 2, set through the opcodes, with the vector selected by 'LD I,A'
+        let mut test_core = interrupt_core(0);
+        test_core.memory.dummy_memory = vec![0; 0x300];
+        test_core.memory.dummy_memory[0..4].copy_from_slice(&[0xED, 0x5E, 0xED, 0x47]);
+        test_core.memory.dummy_memory[0x1FF] = 0x81;
+        test_core.memory.dummy_memory[0x200] = 0x40;
+        test_core.pc_state.sp_reg.set(0x2F0);
+        test_core.pc_state.set_a(0x01);
+        test_core.pc_state.set_pc(0);
+        execute_next(&mut test_core); // IM 2
+        execute_next(&mut test_core); // LD I,A
+        assert_eq!(test_core.pc_state.get_im(), 2);
+        assert_eq!(test_core.pc_state.get_i(), 0x01);
+        assert!(instruction_set::interrupt(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+            0xFF
+        ));
+        assert_eq!(test_core.pc_state.get_pc(), 0x4081);
+    }
+
+    // T-states for every op code (indexed [high nibble][low nibble]), starting
+    // with the flags clear (so NZ/NC/PO/P conditions are met), BC = 0x0101 (so
+    // DJNZ falls through and LDIR/LDDR repeat) and all following bytes 0x00.
+    #[rustfmt::skip]
+    const UNPREFIXED_T_STATES: [[u8; 16]; 16] = [
+        [ 4, 10,  7,  6,  4,  4,  7,  4,  4, 11,  7,  6,  4,  4,  7,  4],
+        [ 8, 10,  7,  6,  4,  4,  7,  4, 12, 11,  7,  6,  4,  4,  7,  4],
+        [12, 10, 16,  6,  4,  4,  7,  4,  7, 11, 16,  6,  4,  4,  7,  4],
+        [12, 10, 13,  6, 11, 11, 10,  4,  7, 11, 13,  6,  4,  4,  7,  4],
+        [ 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4],
+        [ 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4],
+        [ 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4],
+        [ 7,  7,  7,  7,  7,  7,  4,  7,  4,  4,  4,  4,  4,  4,  7,  4],
+        [ 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4],
+        [ 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4],
+        [ 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4],
+        [ 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4],
+        [11, 10, 10, 10, 17, 11,  7, 11,  5, 10, 10,  8, 10, 17,  7, 11],
+        [11, 10, 10, 11, 17, 11,  7, 11,  5,  4, 10, 11, 10,  8,  7, 11],
+        [11, 10, 10, 19, 17, 11,  7, 11,  5,  4, 10,  4, 10,  8,  7, 11],
+        [11, 10, 10,  4, 17, 11,  7, 11,  5,  6, 10,  4, 10,  8,  7, 11],
+    ];
+
+    #[rustfmt::skip]
+    const CB_T_STATES: [[u8; 16]; 16] = [
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8],
+        [ 8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8],
+        [ 8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8],
+        [ 8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+        [ 8,  8,  8,  8,  8,  8, 15,  8,  8,  8,  8,  8,  8,  8, 15,  8],
+    ];
+
+    #[rustfmt::skip]
+    const ED_T_STATES: [[u8; 16]; 16] = [
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+        [12, 12, 15, 20,  8, 14,  8,  9, 12, 12, 15, 20,  8, 14,  8,  9],
+        [12, 12, 15, 20,  8, 14,  8,  9, 12, 12, 15, 20,  8, 14,  8,  9],
+        [12, 12, 15, 20,  8, 14,  8, 18, 12, 12, 15, 20,  8, 14,  8, 18],
+        [12, 12, 15, 20,  8, 14,  8,  8, 12, 12, 15, 20,  8, 14,  8,  8],
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+        [16, 16, 16, 16,  8,  8,  8,  8, 16, 16, 16, 16,  8,  8,  8,  8],
+        [21, 16, 16, 16,  8,  8,  8,  8, 21, 16, 16, 16,  8,  8,  8,  8],
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+        [ 8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8],
+    ];
+
+    // Used for both 0xDD and 0xFD.
+    #[rustfmt::skip]
+    const INDEX_T_STATES: [[u8; 16]; 16] = [
+        [ 8, 14, 11, 10,  8,  8, 11,  8,  8, 15, 11, 10,  8,  8, 11,  8],
+        [12, 14, 11, 10,  8,  8, 11,  8, 16, 15, 11, 10,  8,  8, 11,  8],
+        [16, 14, 20, 10,  8,  8, 11,  8, 11, 15, 20, 10,  8,  8, 11,  8],
+        [16, 14, 17, 10, 23, 23, 19,  8, 11, 15, 17, 10,  8,  8, 11,  8],
+        [ 8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8],
+        [ 8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8],
+        [ 8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8],
+        [19, 19, 19, 19, 19, 19,  8, 19,  8,  8,  8,  8,  8,  8, 19,  8],
+        [ 8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8],
+        [ 8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8],
+        [ 8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8],
+        [ 8,  8,  8,  8,  8,  8, 19,  8,  8,  8,  8,  8,  8,  8, 19,  8],
+        [15, 14, 14, 14, 21, 15, 11, 15,  9, 14, 14, 23, 14, 21, 11, 15],
+        [15, 14, 14, 15, 21, 15, 11, 15,  9,  8, 14, 15, 14, 12, 11, 15],
+        [15, 14, 14, 23, 21, 15, 11, 15,  9,  8, 14,  8, 14, 12, 11, 15],
+        [15, 14, 14,  8, 21, 15, 11, 15,  9, 10, 14,  8, 14, 12, 11, 15],
+    ];
+
+    // Used for both 0xDD, 0xCB and 0xFD, 0xCB.
+    #[rustfmt::skip]
+    const INDEX_CB_T_STATES: [[u8; 16]; 16] = [
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20],
+        [20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20],
+        [20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20],
+        [20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20, 20],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+        [23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23, 23],
+    ];
+
+    #[test]
+    fn test_opcode_faults() {
+        let mut test_core = TestCore::new();
+        test_core.memory.dummy_memory = vec![0x3C]; // INC A
+        test_core.pc_state.set_pc(1);
+        test_core.clock.cycles = 10;
+        instructions::set_fault_op_code(Some(0x3C));
+        let cpu_fault = instructions::Instruction::execute(
+            0x3C,
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+            &mut test_core.ports,
+            &mut test_core.interruptor,
+        )
+        .unwrap_err();
+        instructions::set_fault_op_code(None);
+        assert_eq!(cpu_fault.prefix, fault::Prefix::None);
+        assert_eq!(cpu_fault.op_codes, vec![0x3C]);
+        assert_eq!(cpu_fault.pc, 0);
+        assert_eq!(cpu_fault.cycles, 10);
+        assert_eq!(test_core.pc_state.get_pc(), 1);
+        assert_eq!(test_core.pc_state.get_a(), 0);
+    }
+
+    #[test]
+    fn test_all_opcode_cycle_times() {
+        // Runs every op code (after the given prefix bytes) from a clean state,
+        // checking it executes and takes the expected number of T-states.
+        fn check_op_codes(prefix: &[u8], t_states: &[[u8; 16]; 16]) {
+            const START_ADDRESS: usize = 0x100;
+
+            for op_code in 0..=0xFF_u8 {
+                let mut op_codes = prefix.to_vec();
+                op_codes.push(op_code);
+
+                let mut test_core = TestCore::new();
+                test_core.memory.dummy_memory = vec![0; 0x10000];
+                test_core.memory.dummy_memory[START_ADDRESS..START_ADDRESS + op_codes.len()]
+                    .copy_from_slice(&op_codes);
+                test_core.pc_state.set_af(0);
+                test_core.pc_state.set_bc(0x0101);
+                test_core.pc_state.sp_reg.set(0x8000);
+                test_core.pc_state.set_pc(START_ADDRESS as u16 + 1);
+
+                if let Err(cpu_fault) = instructions::Instruction::execute(
+                    op_codes[0],
+                    &mut test_core.clock,
+                    &mut test_core.memory,
+                    &mut test_core.pc_state,
+                    &mut test_core.ports,
+                    &mut test_core.interruptor,
+                ) {
+                    panic!("{}", cpu_fault);
+                }
+
+                assert_eq!(
+                    test_core.clock.cycles,
+                    t_states[(op_code >> 4) as usize][(op_code & 0xF) as usize]
+                        as clocks::ClockType,
+                    "op codes: {:x?}",
+                    op_codes
+                );
+            }
+        }
+
+        check_op_codes(&[], &UNPREFIXED_T_STATES);
+        check_op_codes(&[0xCB], &CB_T_STATES);
+        check_op_codes(&[0xED], &ED_T_STATES);
+        check_op_codes(&[0xDD], &INDEX_T_STATES);
+        check_op_codes(&[0xFD], &INDEX_T_STATES);
+        check_op_codes(&[0xDD, 0xCB, 0x00], &INDEX_CB_T_STATES);
+        check_op_codes(&[0xFD, 0xCB, 0x00], &INDEX_CB_T_STATES);
+    }
+
+    #[test]
+    fn test_undocumented_opcodes() {
+        let mut test_core = TestCore::new();
+
+        // LD IXH, n; LD IYL, n
+        simple_execute(&mut test_core, vec![0xDD, 0x26, 0x12]);
+        simple_execute(&mut test_core, vec![0xFD, 0x2E, 0x34]);
+        assert_eq!(test_core.pc_state.index_registers.ix_reg.get(), 0x1200);
+        assert_eq!(test_core.pc_state.index_registers.iy_reg.get(), 0x0034);
+
+        // INC IYL, LD B, IXH, ADD A, IYL (H/L unaffected)
+        test_core.pc_state.set_hl(0x5555);
+        test_core.pc_state.set_a(0x01);
+        simple_execute(&mut test_core, vec![0xFD, 0x2C]);
+        simple_execute(&mut test_core, vec![0xDD, 0x44]);
+        simple_execute(&mut test_core, vec![0xFD, 0x85]);
+        assert_eq!(test_core.pc_state.index_registers.iy_reg.get(), 0x0035);
+        assert_eq!(test_core.pc_state.get_b(), 0x12);
+        assert_eq!(test_core.pc_state.get_a(), 0x36);
+        assert_eq!(test_core.pc_state.get_hl(), 0x5555);
+
+        // ADD IX, IX; INC SP
+        simple_execute(&mut test_core, vec![0xDD, 0x29]);
+        assert_eq!(test_core.pc_state.index_registers.ix_reg.get(), 0x2400);
+        test_core.pc_state.sp_reg.set(0x1234);
+        simple_execute(&mut test_core, vec![0x33]);
+        assert_eq!(test_core.pc_state.get_sp(), 0x1235);
+
+        // SLL (HL)
+        test_core.pc_state.set_hl(0x02);
+        simple_execute(&mut test_core, vec![0xCB, 0x36, 0x81]);
+        assert_eq!(test_core.memory.dummy_memory[2], 0x03);
+        assert_eq!(test_core.pc_state.get_f().get_c(), 1);
+
+        // RLC (IX+d), C -> result written to both memory and C.
+        test_core.pc_state.index_registers.ix_reg.set(0x02);
+        simple_execute(&mut test_core, vec![0xDD, 0xCB, 0x03, 0x01, 0x00, 0x81]);
+        assert_eq!(test_core.memory.dummy_memory[5], 0x03);
+        assert_eq!(test_core.pc_state.get_c(), 0x03);
+        assert_eq!(test_core.pc_state.get_pc(), 4);
+
+        // SET 7, (IY+d), E; BIT 0, (IY+d), L (BIT doesn't write back)
+        test_core.pc_state.index_registers.iy_reg.set(0x06);
+        test_core.pc_state.set_l(0x42);
+        simple_execute(&mut test_core, vec![0xFD, 0xCB, 0xFF, 0xFB, 0x00, 0x01]);
+        assert_eq!(test_core.memory.dummy_memory[5], 0x81);
+        assert_eq!(test_core.pc_state.get_e(), 0x81);
+        simple_execute(&mut test_core, vec![0xFD, 0xCB, 0xFF, 0x45, 0x00, 0x00]);
+        assert_eq!(test_core.pc_state.get_f().get_z(), 1);
+        assert_eq!(test_core.pc_state.get_l(), 0x42);
+
+        // Prefix with no effect (0xDD, EX DE, HL), exchanges DE/HL not IX.
+        test_core.pc_state.set_de(0x1111);
+        test_core.pc_state.set_hl(0x2222);
+        simple_execute(&mut test_core, vec![0xDD, 0xEB]);
+        assert_eq!(test_core.pc_state.get_de(), 0x2222);
+        assert_eq!(test_core.pc_state.get_hl(), 0x1111);
+        assert_eq!(test_core.pc_state.index_registers.ix_reg.get(), 0x02);
+    }
+
+    #[test]
+    fn test_undocumented_flags_and_memptr() {
+        let mut test_core = TestCore::new();
+
+        // LD A, (nn) -> MEMPTR = nn + 1
+        simple_execute(&mut test_core, vec![0x3A, 0x03, 0x00, 0x28]);
+        assert_eq!(test_core.pc_state.get_a(), 0x28);
+        assert_eq!(test_core.pc_state.get_memptr(), 0x0004);
+
+        // JP nn, JP NZ, nn (not taken), MEMPTR = nn for both.
+        simple_execute(&mut test_core, vec![0xC3, 0x34, 0x12]);
+        assert_eq!(test_core.pc_state.get_memptr(), 0x1234);
+        let mut f_status = test_core.pc_state.get_f();
+        f_status.set_z(1);
+        test_core.pc_state.set_f(f_status);
+        simple_execute(&mut test_core, vec![0xC2, 0x78, 0x56]);
+        assert_eq!(test_core.pc_state.get_memptr(), 0x5678);
+        assert_eq!(test_core.pc_state.get_pc(), 3);
+
+        // LD A, (IX+d), MEMPTR = IX + d (negative displacement)
+        test_core.pc_state.index_registers.ix_reg.set(0x10);
+        simple_execute(&mut test_core, vec![0xDD, 0x7E, 0xF2]);
+        assert_eq!(test_core.pc_state.get_a(), 0xF2);
+        assert_eq!(test_core.pc_state.get_memptr(), 0x0002);
+
+        // BIT 0, (HL), X/Y come from the high byte of MEMPTR.
+        test_core.pc_state.set_hl(0);
+        test_core.pc_state.set_memptr(0x2800);
+        simple_execute(&mut test_core, vec![0xCB, 0x46]);
+        assert_eq!(test_core.pc_state.get_f().get_z(), 0);
+        assert_eq!(test_core.pc_state.get_f().get_x1(), 1);
+        assert_eq!(test_core.pc_state.get_f().get_x2(), 1);
+        test_core.pc_state.set_memptr(0x0000);
+        simple_execute(&mut test_core, vec![0xCB, 0x46]);
+        assert_eq!(test_core.pc_state.get_f().get_x1(), 0);
+        assert_eq!(test_core.pc_state.get_f().get_x2(), 0);
+
+        // CP n, X/Y come from the operand (0x28), not the result (0xD8).
+        test_core.pc_state.set_a(0x00);
+        simple_execute(&mut test_core, vec![0xFE, 0x28]);
+        assert_eq!(test_core.pc_state.get_f().get_x1(), 1);
+        assert_eq!(test_core.pc_state.get_f().get_x2(), 1);
+
+        // LDI, X is bit 3 and Y is bit 1 of (A + value), P/V clear once BC is 0.
+        test_core.pc_state.set_a(0x20);
+        test_core.pc_state.set_hl(4);
+        test_core.pc_state.set_de(5);
+        test_core.pc_state.set_bc(1);
+        simple_execute(&mut test_core, vec![0xED, 0xA0, 0x00, 0x00, 0x0A, 0x00]);
+        assert_eq!(test_core.memory.dummy_memory[5], 0x0A);
+        assert_eq!(test_core.pc_state.get_f().get_x1(), 1);
+        assert_eq!(test_core.pc_state.get_f().get_x2(), 1);
+        assert_eq!(test_core.pc_state.get_f().get_pv(), 0);
+    }
+
+    #[test]
+    fn test_nmi_interrupt_ordering() {
+        fn nmi_core() -> TestCore {
+            let mut test_core = TestCore::new();
+            test_core.memory.dummy_memory = vec![0; 0x200];
+            test_core.memory.dummy_memory[0x38..0x3A].copy_from_slice(&[0xED, 0x4D]); // RETI
+            test_core.memory.dummy_memory[0x66..0x68].copy_from_slice(&[0xED, 0x45]); // RETN
+            test_core.pc_state.set_pc(0x1234);
+            test_core.pc_state.sp_reg.set(0x200);
+            test_core.pc_state.set_im(1);
+            test_core.pc_state.set_iff1(true);
+            test_core.pc_state.set_iff2(true);
+            test_core
+        }
+
+        // NMI, maskable interrupts are blocked until RETN restores iff1.
+        let mut test_core = nmi_core();
+        instruction_set::nmi(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+        );
+        assert_eq!(test_core.pc_state.get_pc(), 0x66);
+        assert_eq!(test_core.pc_state.get_sp(), 0x1FE);
+        assert_eq!(test_core.memory.read16(0x1FE), 0x1234);
+        assert_eq!(test_core.clock.cycles, 11);
+        assert!(!test_core.pc_state.get_iff1());
+        assert!(test_core.pc_state.get_iff2());
+
+        test_core.clock.cycles = 0;
+        execute_next(&mut test_core); // RETN
+        assert_eq!(test_core.pc_state.get_pc(), 0x1234);
+        assert_eq!(test_core.pc_state.get_sp(), 0x200);
+        assert_eq!(test_core.clock.cycles, 14);
+        assert!(test_core.pc_state.get_iff1());
+        assert!(test_core.pc_state.get_iff2());
+
+        // Maskable interrupt, then an NMI within the interrupt handler.
+        assert!(instruction_set::interrupt(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+            0xFF
+        ));
+        assert_eq!(test_core.pc_state.get_pc(), 0x38);
+        instruction_set::nmi(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+        );
+        assert_eq!(test_core.pc_state.get_pc(), 0x66);
+        assert!(!test_core.pc_state.get_iff2());
+        execute_next(&mut test_core); // RETN
+        assert_eq!(test_core.pc_state.get_pc(), 0x38);
+        assert!(!test_core.pc_state.get_iff1());
+        execute_next(&mut test_core); // RETI
+        assert_eq!(test_core.pc_state.get_pc(), 0x1234);
+        assert_eq!(test_core.pc_state.get_sp(), 0x200);
+
+        // Nested NMI, the second NMI overwrites the saved iff1 state.
+        let mut test_core = nmi_core();
+        instruction_set::nmi(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+        );
+        instruction_set::nmi(
+            &mut test_core.clock,
+            &mut test_core.memory,
+            &mut test_core.pc_state,
+        );
+        assert_eq!(test_core.pc_state.get_pc(), 0x66);
+        assert_eq!(test_core.pc_state.get_sp(), 0x1FC);
+        assert_eq!(test_core.memory.read16(0x1FC), 0x66);
+        assert!(!test_core.pc_state.get_iff2());
+        execute_next(&mut test_core); // RETN
+        assert_eq!(test_core.pc_state.get_pc(), 0x66);
+        execute_next(&mut test_core); // RETN
+        assert_eq!(test_core.pc_state.get_pc(), 0x1234);
+        assert!(!test_core.pc_state.get_iff1());
+
+        // RETN mirrors
+        for op_code in [0x55, 0x5D, 0x65, 0x6D, 0x75, 0x7D] {
+            let mut test_core = nmi_core();
+            instruction_set::nmi(
+                &mut test_core.clock,
+                &mut test_core.memory,
+                &mut test_core.pc_state,
+            );
+            test_core.memory.dummy_memory[0x67] = op_code;
+            execute_next(&mut test_core);
+            assert_eq!(test_core.pc_state.get_pc(), 0x1234);
+            assert!(test_core.pc_state.get_iff1());
+        }
+    }
 }
@@ -1,6 +1,11 @@
 pub mod core;
+#[cfg(test)]
+pub mod cpm;
+pub mod disassembler;
 pub mod extended_instruction_set;
+pub mod fault;
 pub mod instruction_set;
 pub mod instructions;
 pub mod pc_state;
 pub mod status_flags;
+pub mod trace;
@@ -1,3 +1,4 @@
+use super::super::savestate;
 use bitfield::bitfield;
 use std::fmt;
 
@@ -88,6 +89,12 @@ pub struct PcState {
     iff1: bool,
     iff2: bool,
     im: u8,
+    halted: bool,   // Set by HALT, cleared when an interrupt is serviced.
+    ei_delay: bool, // Set by EI, interrupts are blocked until the next instruction has run.
+
+    // Internal 'MEMPTR' (or 'WZ') register.  Not visible to programs, but
+    // leaks into the undocumented X/Y flags after 'BIT n,(HL)'.
+    memptr: u16,
 }
 
 impl fmt::Display for PcState {
@@ -239,6 +246,9 @@ impl PcState {
             iff1: false,
             iff2: false,
             im: 0,
+            halted: false,
+            ei_delay: false,
+            memptr: 0,
         }
     }
 
@@ -327,6 +337,15 @@ impl PcState {
     pub fn get_im(&self) -> u8 {
         self.im
     }
+    pub fn get_halted(&self) -> bool {
+        self.halted
+    }
+    pub fn get_ei_delay(&self) -> bool {
+        self.ei_delay
+    }
+    pub fn get_memptr(&self) -> u16 {
+        self.memptr
+    }
 
     pub fn set_b(&mut self, input: u8) {
         self.bc_reg.high = input;
@@ -394,6 +413,15 @@ impl PcState {
     pub fn set_im(&mut self, input: u8) {
         self.im = input;
     }
+    pub fn set_halted(&mut self, input: bool) {
+        self.halted = input;
+    }
+    pub fn set_ei_delay(&mut self, input: bool) {
+        self.ei_delay = input;
+    }
+    pub fn set_memptr(&mut self, input: u16) {
+        self.memptr = input;
+    }
 
     // Additional utility functions, intended to simplify some of the calls.
     pub fn increment_reg(register: &mut dyn Reg16RW, increment: i8) {
@@ -467,3 +495,63 @@ fn test_pc_state_16_changes() {
     // Use the formatted state to check the output.
     assert_eq!(format!("{}", pc_state), "A:80 SP:dffd B:3 C:12 D:0 E:0 H:0 L:0 F:f6 PCHigh:0 PCLow:0 SPHigh:df SPLow:fd IXHigh:0 IXLow:0 IYHigh:0 IYLow:0 (C:0 N:1 PV:1 X1:0 H:1 X2:1 Z:1 S:1)")
 }
+
+impl savestate::SaveState for PcState {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        for reg in [
+            &self.bc_reg,
+            &self.de_reg,
+            &self.af_reg.reg16,
+            &self.hl_reg,
+            &self.pc_reg,
+            &self.sp_reg,
+            &self.index_registers.ix_reg,
+            &self.index_registers.iy_reg,
+            &self.shadow_bc_reg,
+            &self.shadow_de_reg,
+            &self.shadow_hl_reg,
+            &self.shadow_af_reg.reg16,
+        ] {
+            writer.write_u16(reg.get());
+        }
+        writer.write_u8(self.r);
+        writer.write_u8(self.i);
+        writer.write_bool(self.iff1);
+        writer.write_bool(self.iff2);
+        writer.write_u8(self.im);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.ei_delay);
+        writer.write_u16(self.memptr);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        for reg in [
+            &mut self.bc_reg,
+            &mut self.de_reg,
+            &mut self.af_reg.reg16,
+            &mut self.hl_reg,
+            &mut self.pc_reg,
+            &mut self.sp_reg,
+            &mut self.index_registers.ix_reg,
+            &mut self.index_registers.iy_reg,
+            &mut self.shadow_bc_reg,
+            &mut self.shadow_de_reg,
+            &mut self.shadow_hl_reg,
+            &mut self.shadow_af_reg.reg16,
+        ] {
+            reg.set(reader.read_u16()?);
+        }
+        self.r = reader.read_u8()?;
+        self.i = reader.read_u8()?;
+        self.iff1 = reader.read_bool()?;
+        self.iff2 = reader.read_bool()?;
+        self.im = reader.read_u8()?;
+        self.halted = reader.read_bool()?;
+        self.ei_delay = reader.read_bool()?;
+        self.memptr = reader.read_u16()?;
+        Ok(())
+    }
+}
@@ -94,8 +94,7 @@ pub fn i8_carry(a: u8, b: u8, c: bool, f_status: &mut pc_state::PcStatusFlagFiel
     f_status.set_pv(sub_overflow_flag!(a, b, r, u8));
     f_status.set_n(1);
     f_status.set_c(calculate_borrow_carry!(a, b, c, 0xFF) as u8);
-    f_status.set_s(sign_flag!(r, u8));
-    f_status.set_z(zero_flag!(r));
+    zero_and_sign_flags(f_status, r);
 
     r
 }
@@ -117,6 +116,7 @@ pub fn u16_carry(a: u16, b: u16, c: bool, f_status: &mut pc_state::PcStatusFlagF
     f_status.set_pv(add_overflow_flag!(a, b, r, u16));
     f_status.set_h(calculate_ucarry!(a, b, c, 0xFFF) as u8);
     f_status.set_c(calculate_ucarry!(a, b, c, 0xFFFF) as u8);
+    xy_flags(f_status, (r >> 8) as u8);
 
     r
 }
@@ -128,6 +128,7 @@ pub fn u16_no_carry(a: u16, b: u16, f_status: &mut pc_state::PcStatusFlagFields)
 
     f_status.set_h(calculate_ucarry!(a, b, false, 0xFFF) as u8);
     f_status.set_c(calculate_ucarry!(a, b, false, 0xFFFF) as u8);
+    xy_flags(f_status, (r >> 8) as u8);
 
     r
 }
@@ -142,6 +143,7 @@ pub fn i16_carry(a: u16, b: u16, c: bool, f_status: &mut pc_state::PcStatusFlagF
     f_status.set_c(calculate_borrow_carry!(a, b, c, 0xFFFF) as u8);
     f_status.set_s(sign_flag!(r, u16));
     f_status.set_z(zero_flag!(r));
+    xy_flags(f_status, (r >> 8) as u8);
 
     r
 }
@@ -213,10 +215,15 @@ pub fn xor_flags(status: &mut pc_state::PcStatusFlagFields, value: u8) {
 }
 
 // The 'new' value and carry
-pub fn set_rotate_accumulator_flags(carry: bool, status: &mut pc_state::PcStatusFlagFields) {
+pub fn set_rotate_accumulator_flags(
+    value: u8,
+    carry: bool,
+    status: &mut pc_state::PcStatusFlagFields,
+) {
     status.set_c(carry as u8);
     status.set_h(0);
     status.set_n(0);
+    xy_flags(status, value);
 }
 
 // The 'new' value and carry.  The flags set for rotating accumulator vs registers differ.
@@ -237,23 +244,41 @@ pub fn rotate_decimal_flags(status: &mut pc_state::PcStatusFlagFields, value: u8
 }
 
 pub fn zero_and_sign_flags(status: &mut pc_state::PcStatusFlagFields, value: u8) {
-    // Utility function, to set the zero and sign flags
+    // Utility function, to set the zero and sign flags (and the undocumented
+    // X/Y flags, which are copied from the same result).
     status.set_s(sign_flag!(value, u8));
     status.set_z(zero_flag!(value));
+    xy_flags(status, value);
+}
+
+// Undocumented flags, bits 3 (X) and 5 (Y) are copies of the same bits of
+// the value (usually the result).
+pub fn xy_flags(status: &mut pc_state::PcStatusFlagFields, value: u8) {
+    status.set_x1((value >> 3) & 0x1);
+    status.set_x2((value >> 5) & 0x1);
+}
+
+// The block instructions (LDI, CPI, etc) set X from bit 3 and Y from bit 1
+// of an intermediate value.
+pub fn block_xy_flags(status: &mut pc_state::PcStatusFlagFields, value: u8) {
+    status.set_x1((value >> 3) & 0x1);
+    status.set_x2((value >> 1) & 0x1);
 }
 
 pub fn or_flags(status: &mut pc_state::PcStatusFlagFields, value: u8) {
     xor_flags(status, value);
 }
 
-// Add two 8 bit ints plus the carry bit, and set flags accordingly
+// Flags for 'BIT b, r'.  P/V matches Z, S is only set when testing bit 7 and
+// it's set.  X/Y come from 'r', the '(HL)' and '(IX+d)' forms override them.
 pub fn set_bit_test_flags(r: u8, bit_pos: u8, f_status: &mut pc_state::PcStatusFlagFields) {
     let bit = (r >> (bit_pos & 7)) & 0x1;
     f_status.set_z(bit ^ 0x1);
-    f_status.set_pv(calculate_parity(bit) as u8); // Documented as 'unknown', not sure if/where this is needed.
+    f_status.set_pv(bit ^ 0x1);
     f_status.set_h(1);
     f_status.set_n(0);
-    f_status.set_s(0);
+    f_status.set_s(if (bit_pos & 7) == 7 { bit } else { 0 });
+    xy_flags(f_status, r);
 }
 
 #[cfg(test)]
@@ -398,4 +423,29 @@ mod tests {
         status_flags::set_bit_test_flags(0x30, 3, &mut f_status);
         assert_eq!(f_status.get_z(), 1);
     }
+
+    #[test]
+    fn test_xy_flags() {
+        let mut f_status = pc_state::PcStatusFlagFields(0);
+        status_flags::zero_and_sign_flags(&mut f_status, 0x28);
+        assert_eq!((f_status.get_x1(), f_status.get_x2()), (1, 1));
+        status_flags::zero_and_sign_flags(&mut f_status, 0xD7);
+        assert_eq!((f_status.get_x1(), f_status.get_x2()), (0, 0));
+
+        // 16-bit results use the high byte.
+        status_flags::u16_no_carry(0x0800, 0x2000, &mut f_status);
+        assert_eq!((f_status.get_x1(), f_status.get_x2()), (1, 1));
+
+        // BIT 7 sets S if the bit is set, P/V follows Z.
+        status_flags::set_bit_test_flags(0x80, 7, &mut f_status);
+        assert_eq!(
+            (f_status.get_s(), f_status.get_z(), f_status.get_pv()),
+            (1, 0, 0)
+        );
+        status_flags::set_bit_test_flags(0x80, 6, &mut f_status);
+        assert_eq!(
+            (f_status.get_s(), f_status.get_z(), f_status.get_pv()),
+            (0, 1, 1)
+        );
+    }
 }
@@ -0,0 +1,365 @@
+// Instruction trace, written before each instruction is executed.
+// One line per instruction, in a fixed format (so traces can be diffed
+// against other emulators):
+//   <cycles> <PC> <op code bytes> AF:xxxx BC:xxxx DE:xxxx HL:xxxx IX:xxxx IY:xxxx SP:xxxx <flags>
+// Tracing can be limited to PC ranges, ROM banks and a cycle window.  With a
+// ring size set, only the last N lines are kept (in memory), and are written
+// out when the tracer is flushed (eg after a CPU fault, or on a panic with
+// 'flush_on_panic') or dropped.
+use super::super::clocks;
+use super::super::memory::memory;
+use super::disassembler;
+use super::pc_state;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::panic;
+use std::sync;
+
+// Flag names, from bit 7 to bit 0 (a '-' is shown when clear).
+const FLAG_NAMES: [char; 8] = ['S', 'Z', 'Y', 'H', 'X', 'P', 'N', 'C'];
+
+pub struct TraceFilter {
+    pub pc_ranges: Vec<(u16, u16)>, // Inclusive, empty traces all addresses.
+    pub banks: Vec<u8>,             // ROM banks, empty traces all banks (and RAM).
+    pub start_cycle: clocks::ClockType,
+    pub stop_cycle: Option<clocks::ClockType>,
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        Self {
+            pc_ranges: Vec::new(),
+            banks: Vec::new(),
+            start_cycle: 0,
+            stop_cycle: None,
+        }
+    }
+
+    pub fn matches(&self, cycles: clocks::ClockType, pc: u16, bank: Option<u8>) -> bool {
+        if cycles < self.start_cycle || self.stop_cycle.is_some_and(|stop| cycles >= stop) {
+            return false;
+        }
+        if !self.pc_ranges.is_empty()
+            && !self
+                .pc_ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&pc))
+        {
+            return false;
+        }
+        self.banks.is_empty() || bank.is_some_and(|bank| self.banks.contains(&bank))
+    }
+}
+
+// Parse a (hex) address, with an optional '0x' prefix.
+pub fn parse_address(address: &str) -> Result<u16, String> {
+    let address = address.trim();
+    let digits = address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix("0X"))
+        .unwrap_or(address);
+    u16::from_str_radix(digits, 16).map_err(|e| format!("Invalid address '{}': {}", address, e))
+}
+
+// Parse a PC range, 'start-end' (inclusive), or a single address.
+pub fn parse_pc_range(value: &str) -> Result<(u16, u16), String> {
+    match value.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse_address(start)?, parse_address(end)?);
+            if start > end {
+                return Err(format!("Invalid range '{}', start is after end", value));
+            }
+            Ok((start, end))
+        }
+        None => {
+            let address = parse_address(value)?;
+            Ok((address, address))
+        }
+    }
+}
+
+pub fn format_line<M>(cycles: clocks::ClockType, memory: &M, pc_state: &pc_state::PcState) -> String
+where
+    M: memory::MemoryRW,
+{
+    let pc = pc_state.get_pc();
+    let length = disassembler::disassemble(memory, pc).length;
+    let op_codes: Vec<String> = (0..length)
+        .map(|offset| format!("{:02X}", memory.peek(pc.wrapping_add(offset))))
+        .collect();
+    let f = pc_state.get_f().0;
+    let flags: String = FLAG_NAMES
+        .iter()
+        .enumerate()
+        .map(|(i, name)| if f & (0x80 >> i) != 0 { *name } else { '-' })
+        .collect();
+
+    format!(
+        "{:>12} {:04X} {:<11} AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} IX:{:04X} IY:{:04X} SP:{:04X} {}",
+        cycles,
+        pc,
+        op_codes.join(" "),
+        pc_state.get_af(),
+        pc_state.get_bc(),
+        pc_state.get_de(),
+        pc_state.get_hl(),
+        pc_state.index_registers.ix_reg.get(),
+        pc_state.index_registers.iy_reg.get(),
+        pc_state.get_sp(),
+        flags
+    )
+}
+
+pub struct Tracer {
+    filter: TraceFilter,
+    output: sync::Arc<sync::Mutex<TraceOutput>>, // Shared with the panic hook.
+}
+
+struct TraceOutput {
+    writer: Box<dyn io::Write + Send>,
+    ring_size: Option<usize>,
+    ring: VecDeque<String>,
+}
+
+impl TraceOutput {
+    fn flush(&mut self) {
+        while let Some(line) = self.ring.pop_front() {
+            self.write_line(&line);
+        }
+        if let Err(e) = self.writer.flush() {
+            println!("Error writing trace: {}", e);
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if let Err(e) = writeln!(self.writer, "{}", line) {
+            println!("Error writing trace: {}", e);
+        }
+    }
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn io::Write + Send>, filter: TraceFilter) -> Self {
+        Self {
+            filter,
+            output: sync::Arc::new(sync::Mutex::new(TraceOutput {
+                writer,
+                ring_size: None,
+                ring: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn to_file(filename: &str, filter: TraceFilter) -> io::Result<Self> {
+        let file = fs::File::create(filename)?;
+        Ok(Self::new(Box::new(io::BufWriter::new(file)), filter))
+    }
+
+    // Only keep the last 'ring_size' lines, written out by 'flush'.
+    pub fn set_ring_size(&mut self, ring_size: usize) {
+        let mut output = self.output.lock().unwrap();
+        output.ring_size = Some(ring_size);
+        output.ring = VecDeque::with_capacity(ring_size);
+    }
+
+    // Also flush on a panic, as the release build aborts (so the tracer
+    // isn't dropped).
+    pub fn flush_on_panic(&self) {
+        let output = self.output.clone();
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            default_hook(info);
+            // Not if the panic was while tracing.
+            if let Ok(mut output) = output.try_lock() {
+                output.flush();
+            }
+        }));
+    }
+
+    pub fn trace<M>(&mut self, cycles: clocks::ClockType, memory: &M, pc_state: &pc_state::PcState)
+    where
+        M: memory::MemoryRW,
+    {
+        let pc = pc_state.get_pc();
+        if !self.filter.matches(cycles, pc, memory.rom_bank(pc)) {
+            return;
+        }
+
+        let line = format_line(cycles, memory, pc_state);
+        let mut output = self.output.lock().unwrap();
+        match output.ring_size {
+            Some(ring_size) => {
+                if output.ring.len() >= ring_size {
+                    output.ring.pop_front();
+                }
+                if ring_size > 0 {
+                    output.ring.push_back(line);
+                }
+            }
+            None => {
+                output.write_line(&line);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Ok(mut output) = self.output.lock() {
+            output.flush();
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::cpu::cpm;
+    use crate::sega::cpu::pc_state;
+    use crate::sega::cpu::trace;
+    use crate::sega::debugger::watch;
+    use crate::sega::memory::memory::MemoryRW;
+    use std::io;
+    use std::sync;
+
+    // Writer that can be inspected after the tracer has been dropped.
+    struct SharedWriter(sync::Arc<sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Trace 'steps' NOPs, with 4 cycles per step.
+    fn trace_nops(filter: trace::TraceFilter, ring_size: Option<usize>, steps: u16) -> Vec<String> {
+        let output = sync::Arc::new(sync::Mutex::new(Vec::new()));
+        let memory = cpm::CpmMemory::new();
+        let mut pc_state = pc_state::PcState::new();
+        {
+            let mut tracer = trace::Tracer::new(Box::new(SharedWriter(output.clone())), filter);
+            if let Some(ring_size) = ring_size {
+                tracer.set_ring_size(ring_size);
+            }
+            for step in 0..steps {
+                pc_state.set_pc(step);
+                tracer.trace(4 * step as u64, &memory, &pc_state);
+            }
+        }
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        output.lines().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_trace_format() {
+        let mut memory = cpm::CpmMemory::new();
+        MemoryRW::write(&mut memory, 0x200, 0xDD);
+        MemoryRW::write(&mut memory, 0x201, 0x36);
+        MemoryRW::write(&mut memory, 0x202, 0x05);
+        MemoryRW::write(&mut memory, 0x203, 0x42);
+        let mut pc_state = pc_state::PcState::new();
+        pc_state.set_pc(0x200);
+        pc_state.set_af(0x12C1);
+        pc_state.set_hl(0xBEEF);
+        pc_state.sp_reg.set(0xDFF0);
+
+        assert_eq!(
+            trace::format_line(1234, &memory, &pc_state),
+            "        1234 0200 DD 36 05 42 AF:12C1 BC:0000 DE:0000 HL:BEEF IX:0000 IY:0000 SP:DFF0 SZ-----C"
+        );
+    }
+
+    #[test]
+    fn test_trace_filters() {
+        let all = trace_nops(trace::TraceFilter::new(), None, 10);
+        assert_eq!(all.len(), 10);
+
+        let mut filter = trace::TraceFilter::new();
+        filter.pc_ranges = vec![(2, 3), (8, 8)];
+        let lines = trace_nops(filter, None, 10);
+        assert_eq!(lines, vec![all[2].clone(), all[3].clone(), all[8].clone()]);
+
+        // Cycles 8 to 20 (exclusive), steps 2 to 4.
+        let mut filter = trace::TraceFilter::new();
+        filter.start_cycle = 8;
+        filter.stop_cycle = Some(20);
+        let lines = trace_nops(filter, None, 10);
+        assert_eq!(lines, all[2..5].to_vec());
+
+        // Flat memory has no ROM banks.
+        let mut filter = trace::TraceFilter::new();
+        filter.banks = vec![0];
+        assert!(trace_nops(filter, None, 10).is_empty());
+    }
+
+    #[test]
+    fn test_trace_ring() {
+        let all = trace_nops(trace::TraceFilter::new(), None, 10);
+        let lines = trace_nops(trace::TraceFilter::new(), Some(3), 10);
+        assert_eq!(lines, all[7..].to_vec());
+    }
+
+    #[test]
+    fn test_trace_unwatched() {
+        // LD A, (0x1234), with a read watchpoint on the address operand.
+        let mut memory = watch::WatchedMemory::new(cpm::CpmMemory::new());
+        MemoryRW::write(&mut memory, 0x100, 0x3A);
+        MemoryRW::write(&mut memory, 0x101, 0x34);
+        MemoryRW::write(&mut memory, 0x102, 0x12);
+        memory
+            .watchpoints
+            .push(watch::Watchpoint::new(0x101, 0x102, true, false));
+        let mut pc_state = pc_state::PcState::new();
+        pc_state.set_pc(0x100);
+
+        let mut tracer = trace::Tracer::new(Box::new(io::sink()), trace::TraceFilter::new());
+        tracer.trace(0, &memory, &pc_state);
+        assert!(trace::format_line(0, &memory, &pc_state).contains(" 3A 34 12 "));
+        assert_eq!(memory.take_hit(), None);
+
+        // Only the CPU's reads are watched.
+        memory.read(0x101);
+        assert_eq!(memory.take_hit().map(|hit| hit.address), Some(0x101));
+    }
+
+    #[test]
+    fn test_trace_ring_flushed_on_panic() {
+        let output = sync::Arc::new(sync::Mutex::new(Vec::new()));
+        let memory = cpm::CpmMemory::new();
+        let pc_state = pc_state::PcState::new();
+        let mut tracer = trace::Tracer::new(
+            Box::new(SharedWriter(output.clone())),
+            trace::TraceFilter::new(),
+        );
+        tracer.set_ring_size(2);
+        for cycles in 0..3 {
+            tracer.trace(cycles, &memory, &pc_state);
+        }
+
+        tracer.flush_on_panic();
+        assert!(std::panic::catch_unwind(|| panic!("Crash")).is_err());
+        let _ = std::panic::take_hook(); // Back to the default hook.
+
+        // Written by the panic, while the tracer is still alive.
+        let lines = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_parse_pc_range() {
+        assert_eq!(trace::parse_pc_range("0x100-0x1FF"), Ok((0x100, 0x1FF)));
+        assert_eq!(trace::parse_pc_range("38"), Ok((0x38, 0x38)));
+        assert!(trace::parse_pc_range("0x200-0x100").is_err());
+        assert!(trace::parse_pc_range("xyz").is_err());
+    }
+}
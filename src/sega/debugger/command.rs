@@ -0,0 +1,191 @@
+// Debugger commands, parsed from a line of input.
+// Addresses and values are hex (with an optional '0x' prefix), counts are decimal.
+use super::super::cpu::trace;
+use super::watch;
+
+pub const HELP: &str = "\
+Commands (addresses and values are hex, counts are decimal):
+  c, continue               continue running
+  s, step [count]           step 'count' instructions (default 1)
+  n, next                   step, over any CALL/RST
+  f, finish                 run until the current function returns
+  b, break [address]        add a breakpoint (list breakpoints/watchpoints without an address)
+  d, delete <address>       remove a breakpoint
+  w, watch <range> [r|w|rw] break on memory access, eg 'w c000-c0ff w' (default rw)
+  wp, watchport <range> [r|w|rw]
+                            break on a port access
+  clear                     remove all breakpoints and watchpoints
+  r, regs [reg value]       show registers, or set one (A, F, BC, IX, SP, PC, etc.)
+  x, mem <address> [count]  show memory (default 64 bytes)
+  m, set <address> <value>...
+                            write memory
+  u, dis [address] [count]  disassemble (default from the PC, 10 instructions)
+  h, help                   show this help
+  q, quit                   stop the emulator
+An empty line repeats the previous command.";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Continue,
+    Step(u32),
+    StepOver,
+    Finish,
+    Break(Option<u16>),
+    Delete(u16),
+    Watch(watch::Watchpoint),
+    WatchPort(watch::Watchpoint),
+    Clear,
+    Registers,
+    SetRegister(String, u16),
+    Memory(u16, u16),
+    SetMemory(u16, Vec<u8>),
+    Disassemble(Option<u16>, u16),
+    Help,
+    Quit,
+}
+
+fn parse_count(value: Option<&str>, default: u32) -> Result<u32, String> {
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|e| format!("Invalid count '{}': {}", value, e)),
+        None => Ok(default),
+    }
+}
+
+fn parse_byte(value: &str) -> Result<u8, String> {
+    let byte = trace::parse_address(value)?;
+    u8::try_from(byte).map_err(|_| format!("Invalid byte '{}'", value))
+}
+
+fn parse_watchpoint(range: Option<&str>, kind: Option<&str>) -> Result<watch::Watchpoint, String> {
+    let (start, end) = trace::parse_pc_range(range.ok_or("Missing address range")?)?;
+    let (read, write) = match kind.unwrap_or("rw") {
+        "r" => (true, false),
+        "w" => (false, true),
+        "rw" => (true, true),
+        kind => {
+            return Err(format!(
+                "Invalid access kind '{}', expected r, w or rw",
+                kind
+            ))
+        }
+    };
+    Ok(watch::Watchpoint::new(start, end, read, write))
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().ok_or("No command")?;
+    let args: Vec<&str> = words.collect();
+    let arg = |index: usize| args.get(index).copied();
+    let address = |index: usize| -> Result<u16, String> {
+        trace::parse_address(arg(index).ok_or("Missing address")?)
+    };
+
+    match name {
+        "c" | "continue" => Ok(Command::Continue),
+        "s" | "step" => Ok(Command::Step(parse_count(arg(0), 1)?)),
+        "n" | "next" => Ok(Command::StepOver),
+        "f" | "finish" => Ok(Command::Finish),
+        "b" | "break" => match arg(0) {
+            Some(_) => Ok(Command::Break(Some(address(0)?))),
+            None => Ok(Command::Break(None)),
+        },
+        "d" | "delete" => Ok(Command::Delete(address(0)?)),
+        "w" | "watch" => Ok(Command::Watch(parse_watchpoint(arg(0), arg(1))?)),
+        "wp" | "watchport" => {
+            let watchpoint = parse_watchpoint(arg(0), arg(1))?;
+            if watchpoint.end > 0xFF {
+                return Err("Ports are 0 to FF".to_string());
+            }
+            Ok(Command::WatchPort(watchpoint))
+        }
+        "clear" => Ok(Command::Clear),
+        "r" | "regs" => match (arg(0), arg(1)) {
+            (None, _) => Ok(Command::Registers),
+            (Some(register), Some(_)) => {
+                Ok(Command::SetRegister(register.to_uppercase(), address(1)?))
+            }
+            (Some(_), None) => Err("Missing register value".to_string()),
+        },
+        "x" | "mem" => Ok(Command::Memory(
+            address(0)?,
+            parse_count(arg(1), 64)? as u16,
+        )),
+        "m" | "set" => {
+            let values = args
+                .iter()
+                .skip(1)
+                .map(|value| parse_byte(value))
+                .collect::<Result<Vec<u8>, String>>()?;
+            if values.is_empty() {
+                return Err("Missing values".to_string());
+            }
+            Ok(Command::SetMemory(address(0)?, values))
+        }
+        "u" | "dis" => {
+            let start = match arg(0) {
+                Some(_) => Some(address(0)?),
+                None => None,
+            };
+            Ok(Command::Disassemble(start, parse_count(arg(1), 10)? as u16))
+        }
+        "h" | "help" | "?" => Ok(Command::Help),
+        "q" | "quit" => Ok(Command::Quit),
+        name => Err(format!("Unknown command '{}', try 'help'", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::debugger::command;
+    use crate::sega::debugger::watch;
+
+    #[test]
+    fn test_parse_commands() {
+        let test_values = [
+            ("c", command::Command::Continue),
+            ("step", command::Command::Step(1)),
+            ("s 10", command::Command::Step(10)),
+            ("n", command::Command::StepOver),
+            ("b 0x38", command::Command::Break(Some(0x38))),
+            ("b", command::Command::Break(None)),
+            ("d 38", command::Command::Delete(0x38)),
+            (
+                "w c000-c0ff w",
+                command::Command::Watch(watch::Watchpoint::new(0xC000, 0xC0FF, false, true)),
+            ),
+            (
+                "wp bf",
+                command::Command::WatchPort(watch::Watchpoint::new(0xBF, 0xBF, true, true)),
+            ),
+            (
+                "r hl 1234",
+                command::Command::SetRegister("HL".to_string(), 0x1234),
+            ),
+            ("x dff0 16", command::Command::Memory(0xDFF0, 16)),
+            (
+                "m c000 1 ff",
+                command::Command::SetMemory(0xC000, vec![0x01, 0xFF]),
+            ),
+            ("u", command::Command::Disassemble(None, 10)),
+            ("u 100 3", command::Command::Disassemble(Some(0x100), 3)),
+        ];
+        for (line, expected) in test_values {
+            assert_eq!(command::parse(line), Ok(expected), "{}", line);
+        }
+
+        for line in [
+            "",
+            "jump",
+            "b xyz",
+            "w c000 x",
+            "wp 100",
+            "m c000 100",
+            "r a",
+        ] {
+            assert!(command::parse(line).is_err(), "{}", line);
+        }
+    }
+}
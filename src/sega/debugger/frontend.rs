@@ -0,0 +1,11 @@
+// Interface between the emulator loop and a debugger (the command line
+// debugger, or a remote GDB connection).
+use super::super::cpu::core;
+use super::watch;
+
+pub trait Frontend<M> {
+    // Called before each 'Core::step', false to stop the emulator.
+    fn before_step(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>) -> bool;
+    // Called after each 'Core::step', to check for watchpoint hits, etc.
+    fn after_step(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>);
+}
@@ -0,0 +1,618 @@
+// GDB remote serial protocol stub, so GDB (built with Z80 support) or an IDE
+// debugger frontend can be attached to the emulator:
+//   (gdb) target remote localhost:<port>
+// Supports reading/writing registers and memory, software breakpoints,
+// memory watchpoints (via 'WatchedMemory'), single step and continue.
+// Registers are sent in GDB's Z80 order, all 16-bit little endian:
+//   AF BC DE HL SP PC IX IY AF' BC' DE' HL' IR
+use super::super::cpu::core;
+use super::super::cpu::pc_state;
+use super::super::cpu::pc_state::Reg16RW;
+use super::super::memory::memory;
+use super::frontend;
+use super::watch;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net;
+
+pub struct Constants {}
+
+impl Constants {
+    pub const NUM_REGISTERS: usize = 13;
+    pub const INTERRUPT: u8 = 0x03; // Sent by GDB (Ctrl-C) to stop a running target.
+    pub const INTERRUPT_CHECK_STEPS: u32 = 1000; // Steps between checks for an interrupt.
+    pub const SIGINT: u8 = 2;
+    pub const SIGTRAP: u8 = 5;
+}
+
+enum Response {
+    Reply(String),
+    Resume,
+    Detach,
+    Quit,
+}
+
+pub struct GdbStub {
+    stream: net::TcpStream,
+    breakpoints: Vec<u16>,
+    paused: bool,
+    stepping: bool,
+    detached: bool,
+    resume_pc: Option<u16>, // Don't stop at a breakpoint on the PC being resumed from.
+    steps_since_check: u32,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(hex: &str) -> Option<u16> {
+    u16::from_str_radix(hex, 16).ok()
+}
+
+// 'address,length'
+fn parse_address_length(text: &str) -> Option<(u16, u16)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+fn registers(pc_state: &pc_state::PcState) -> [u16; Constants::NUM_REGISTERS] {
+    [
+        pc_state.get_af(),
+        pc_state.get_bc(),
+        pc_state.get_de(),
+        pc_state.get_hl(),
+        pc_state.get_sp(),
+        pc_state.get_pc(),
+        pc_state.index_registers.ix_reg.get(),
+        pc_state.index_registers.iy_reg.get(),
+        pc_state.shadow_af_reg.get(),
+        pc_state.shadow_bc_reg.get(),
+        pc_state.shadow_de_reg.get(),
+        pc_state.shadow_hl_reg.get(),
+        (pc_state.get_i() as u16) << 8 | pc_state.get_r() as u16,
+    ]
+}
+
+fn set_register(pc_state: &mut pc_state::PcState, register: usize, value: u16) -> bool {
+    match register {
+        0 => pc_state.set_af(value),
+        1 => pc_state.set_bc(value),
+        2 => pc_state.set_de(value),
+        3 => pc_state.set_hl(value),
+        4 => pc_state.sp_reg.set(value),
+        5 => pc_state.set_pc(value),
+        6 => pc_state.index_registers.ix_reg.set(value),
+        7 => pc_state.index_registers.iy_reg.set(value),
+        8 => pc_state.shadow_af_reg.set(value),
+        9 => pc_state.shadow_bc_reg.set(value),
+        10 => pc_state.shadow_de_reg.set(value),
+        11 => pc_state.shadow_hl_reg.set(value),
+        12 => {
+            pc_state.set_i((value >> 8) as u8);
+            pc_state.set_r(value as u8);
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn stop_reply(hit: Option<watch::WatchHit>) -> String {
+    match hit {
+        Some(hit) => {
+            let kind = match hit.kind {
+                watch::AccessKind::Read => "rwatch",
+                watch::AccessKind::Write => "watch",
+            };
+            format!("T{:02x}{}:{:04x};", Constants::SIGTRAP, kind, hit.address)
+        }
+        None => format!("S{:02x}", Constants::SIGTRAP),
+    }
+}
+
+impl GdbStub {
+    // Wait for GDB to connect (on localhost only), starts paused.
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = net::TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB connection on port {}", port);
+        let (stream, address) = listener.accept()?;
+        println!("GDB connected from {}", address);
+        Ok(Self::new(stream))
+    }
+
+    pub fn new(stream: net::TcpStream) -> Self {
+        // Packets are small, send them straight away.
+        let _ = stream.set_nodelay(true);
+        Self {
+            stream,
+            breakpoints: Vec::new(),
+            paused: true,
+            stepping: false,
+            detached: false,
+            resume_pc: None,
+            steps_since_check: 0,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Read the next '$data#checksum' packet, None if the connection has closed.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acknowledgements ('+'/'-') and interrupts (already stopped).
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut expected = [0; 2];
+            self.stream.read_exact(&mut expected)?;
+
+            let data = String::from_utf8_lossy(&data).to_string();
+            let expected = std::str::from_utf8(&expected)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if expected == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+
+    fn handle_packet<M>(
+        &mut self,
+        core: &mut core::Core<watch::WatchedMemory<M>>,
+        packet: &str,
+    ) -> Response
+    where
+        M: memory::MemoryRW,
+    {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = |text: &str| Response::Reply(text.to_string());
+        let error = || reply("E01");
+
+        match command {
+            "?" => Response::Reply(stop_reply(None)),
+            "g" => {
+                let bytes: Vec<u8> = registers(core.get_pc_state())
+                    .iter()
+                    .flat_map(|register| register.to_le_bytes())
+                    .collect();
+                Response::Reply(hex_bytes(&bytes))
+            }
+            "G" => match parse_hex_bytes(args) {
+                Some(bytes) if bytes.len() == 2 * Constants::NUM_REGISTERS => {
+                    for (register, value) in bytes.chunks(2).enumerate() {
+                        let value = u16::from_le_bytes([value[0], value[1]]);
+                        set_register(core.get_pc_state_mut(), register, value);
+                    }
+                    reply("OK")
+                }
+                _ => error(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(register) if register < Constants::NUM_REGISTERS => Response::Reply(hex_bytes(
+                    &registers(core.get_pc_state())[register].to_le_bytes(),
+                )),
+                _ => error(),
+            },
+            "P" => {
+                let register = args.split_once('=').and_then(|(register, value)| {
+                    Some((
+                        usize::from_str_radix(register, 16).ok()?,
+                        parse_hex_bytes(value)?,
+                    ))
+                });
+                match register {
+                    Some((register, value)) if value.len() == 2 => {
+                        let value = u16::from_le_bytes([value[0], value[1]]);
+                        if set_register(core.get_pc_state_mut(), register, value) {
+                            reply("OK")
+                        } else {
+                            error()
+                        }
+                    }
+                    _ => error(),
+                }
+            }
+            "m" => match parse_address_length(args) {
+                Some((address, length)) => {
+                    let bytes: Vec<u8> = (0..length)
+                        .map(|offset| core.memory.memory.read(address.wrapping_add(offset)))
+                        .collect();
+                    Response::Reply(hex_bytes(&bytes))
+                }
+                None => error(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(address_length, data)| {
+                    let (address, length) = parse_address_length(address_length)?;
+                    let data = parse_hex_bytes(data)?;
+                    (data.len() == length as usize).then_some((address, data))
+                });
+                match write {
+                    Some((address, data)) => {
+                        for (offset, value) in data.iter().enumerate() {
+                            core.memory
+                                .memory
+                                .write(address.wrapping_add(offset as u16), *value);
+                        }
+                        reply("OK")
+                    }
+                    None => error(),
+                }
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(address) => core.get_pc_state_mut().set_pc(address),
+                        None => return error(),
+                    }
+                }
+                self.stepping = command == "s";
+                Response::Resume
+            }
+            "Z" | "z" => self.update_breakpoint(core, command == "Z", args),
+            "q" => {
+                if args.starts_with("Supported") {
+                    reply("PacketSize=1000")
+                } else if args.starts_with("Attached") {
+                    reply("1")
+                } else {
+                    reply("")
+                }
+            }
+            "H" => reply("OK"),
+            "D" => Response::Detach,
+            "k" => Response::Quit,
+            _ => reply(""), // Not supported.
+        }
+    }
+
+    // 'Z'/'z' type,address,kind
+    fn update_breakpoint<M>(
+        &mut self,
+        core: &mut core::Core<watch::WatchedMemory<M>>,
+        insert: bool,
+        args: &str,
+    ) -> Response {
+        let mut fields = args.split(',');
+        let (breakpoint_type, address, length) = match (
+            fields.next(),
+            fields.next().and_then(parse_hex),
+            fields.next().and_then(parse_hex),
+        ) {
+            (Some(breakpoint_type), Some(address), Some(length)) => {
+                (breakpoint_type, address, length.max(1))
+            }
+            _ => return Response::Reply("E01".to_string()),
+        };
+
+        // Software and hardware breakpoints are the same thing here.
+        if breakpoint_type == "0" || breakpoint_type == "1" {
+            if insert {
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                }
+            } else {
+                self.breakpoints.retain(|breakpoint| *breakpoint != address);
+            }
+            return Response::Reply("OK".to_string());
+        }
+
+        let (read, write) = match breakpoint_type {
+            "2" => (false, true),
+            "3" => (true, false),
+            "4" => (true, true),
+            _ => return Response::Reply(String::new()),
+        };
+        let watchpoint =
+            watch::Watchpoint::new(address, address.wrapping_add(length - 1), read, write);
+        if insert {
+            core.memory.watchpoints.push(watchpoint);
+        } else {
+            core.memory.watchpoints.retain(|w| *w != watchpoint);
+        }
+        Response::Reply("OK".to_string())
+    }
+
+    // Stop, tell GDB why, and handle packets until told to carry on.
+    fn stop<M>(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>, reply: &str) -> bool
+    where
+        M: memory::MemoryRW,
+    {
+        self.paused = true;
+        if self.stream.set_nonblocking(false).is_err() || self.send_packet(reply).is_err() {
+            self.detach(core);
+            return true;
+        }
+        self.serve(core)
+    }
+
+    // Handle packets while paused, false to quit.
+    fn serve<M>(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>) -> bool
+    where
+        M: memory::MemoryRW,
+    {
+        loop {
+            let packet = match self.read_packet() {
+                Ok(Some(packet)) => packet,
+                _ => {
+                    // Connection lost, let the emulator carry on.
+                    println!("GDB disconnected");
+                    self.detach(core);
+                    return true;
+                }
+            };
+
+            let result = match self.handle_packet(core, &packet) {
+                Response::Reply(reply) => self.send_packet(&reply),
+                Response::Resume => {
+                    self.paused = false;
+                    self.resume_pc = Some(core.get_pc_state().get_pc());
+                    core.resync_realtime();
+                    // Non-blocking, so interrupts can be checked for while running.
+                    return self.stream.set_nonblocking(true).is_ok() || {
+                        self.detach(core);
+                        true
+                    };
+                }
+                Response::Detach => {
+                    let _ = self.send_packet("OK");
+                    self.detach(core);
+                    return true;
+                }
+                Response::Quit => {
+                    return false;
+                }
+            };
+            if result.is_err() {
+                self.detach(core);
+                return true;
+            }
+        }
+    }
+
+    // Remove all breakpoints/watchpoints and leave the emulator running.
+    fn detach<M>(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>) {
+        self.detached = true;
+        self.paused = false;
+        self.stepping = false;
+        self.breakpoints.clear();
+        core.memory.watchpoints.clear();
+    }
+
+    // Check for (and consume) an interrupt request from GDB while running.
+    fn interrupt_requested(&mut self) -> bool {
+        let mut byte = [0];
+        match self.stream.read(&mut byte) {
+            Ok(1) => byte[0] == Constants::INTERRUPT,
+            _ => false,
+        }
+    }
+}
+
+impl<M: memory::MemoryRW> frontend::Frontend<M> for GdbStub {
+    fn before_step(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>) -> bool {
+        if self.detached {
+            return true;
+        }
+        if self.paused {
+            return self.serve(core);
+        }
+
+        self.steps_since_check += 1;
+        if self.steps_since_check >= Constants::INTERRUPT_CHECK_STEPS {
+            self.steps_since_check = 0;
+            if self.interrupt_requested() {
+                return self.stop(core, &format!("S{:02x}", Constants::SIGINT));
+            }
+        }
+
+        let pc_state = core.get_pc_state();
+        let pc = pc_state.get_pc();
+        let at_breakpoint =
+            !pc_state.get_halted() && self.resume_pc != Some(pc) && self.breakpoints.contains(&pc);
+        self.resume_pc = None;
+        if at_breakpoint {
+            return self.stop(core, &stop_reply(None));
+        }
+        true
+    }
+
+    fn after_step(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>) {
+        if self.detached {
+            return;
+        }
+        let hit = core.memory.take_hit();
+        if hit.is_some() || self.stepping {
+            self.stepping = false;
+            // Packets are handled on the next 'before_step'.
+            self.paused = true;
+            if self.stream.set_nonblocking(false).is_err()
+                || self.send_packet(&stop_reply(hit)).is_err()
+            {
+                self.detach(core);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::clocks;
+    use crate::sega::cpu::core;
+    use crate::sega::cpu::cpm;
+    use crate::sega::cpu::pc_state;
+    use crate::sega::debugger::frontend::Frontend;
+    use crate::sega::debugger::gdb;
+    use crate::sega::debugger::watch;
+    use crate::sega::interruptor;
+    use crate::sega::memory::memory::MemoryRW;
+    use crate::sega::ports;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net;
+    use std::thread;
+
+    // Minimal GDB client, sends each packet and returns the replies.
+    fn client(port: u16, packets: &[&str]) -> Vec<String> {
+        let mut stream = net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let read_reply = |stream: &mut net::TcpStream| {
+            let mut reply = String::new();
+            let mut byte = [0];
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'+' if reply.is_empty() => {}
+                    b'#' => break,
+                    byte => reply.push(byte as char),
+                }
+            }
+            let mut checksum = [0; 2];
+            stream.read_exact(&mut checksum).unwrap();
+            stream.write_all(b"+").unwrap();
+            reply.trim_start_matches('$').to_string()
+        };
+
+        let mut replies = Vec::new();
+        for packet in packets {
+            let checksum = packet
+                .bytes()
+                .fold(0_u8, |sum, byte| sum.wrapping_add(byte));
+            write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+            if *packet != "k" {
+                replies.push(read_reply(&mut stream));
+            }
+        }
+        replies
+    }
+
+    // Run the program at 0x100 with a GDB client sending 'packets', returns the replies.
+    fn run_with_client(program: &[u8], packets: &'static [&'static str]) -> Vec<String> {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || client(port, packets));
+        let (stream, _) = listener.accept().unwrap();
+
+        let mut memory = cpm::CpmMemory::new();
+        for (offset, op_code) in program.iter().enumerate() {
+            MemoryRW::write(&mut memory, 0x100 + offset as u16, *op_code);
+        }
+        let mut core = core::Core::new(
+            clocks::Clock::new(),
+            watch::WatchedMemory::new(memory),
+            pc_state::PcState::new(),
+            ports::Ports::new(),
+            interruptor::Interruptor::new(),
+        );
+        core.get_pc_state_mut().set_pc(0x100);
+        core.get_pc_state_mut().sp_reg.set(0x8000);
+
+        let mut stub = gdb::GdbStub::new(stream);
+        for _ in 0..1000 {
+            if !stub.before_step(&mut core) {
+                break;
+            }
+            core.step(false).unwrap();
+            stub.after_step(&mut core);
+        }
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn test_gdb_stub() {
+        const PROGRAM: [u8; 9] = [
+            0x3E, 0x42, // 0x100: LD A, 0x42
+            0x32, 0x00, 0xC0, // 0x102: LD (0xC000), A
+            0x3C, // 0x105: INC A
+            0x18, 0xF8, // 0x106: JR 0x100
+            0x00,
+        ];
+        let replies = run_with_client(
+            &PROGRAM,
+            &[
+                "qSupported:swbreak+",
+                "?",
+                "s",
+                "p0",
+                "m100,3",
+                "Z0,105,1",
+                "c",
+                "p5",
+                "z0,105,1",
+                "Z2,c000,1",
+                "c",
+                "p5",
+                "P3=3412",
+                "M c,1:ff",
+                "M8000,2:aabb",
+                "m8000,2",
+                "g",
+                "k",
+            ],
+        );
+        assert_eq!(
+            replies,
+            vec![
+                "PacketSize=1000",
+                "S05",
+                "S05",
+                "0042",   // AF, after LD A, 0x42
+                "3e4232", // 'LD A, 0x42; LD ...'
+                "OK",
+                "S05",  // Breakpoint at 0x105
+                "0501", // PC
+                "OK",
+                "OK",
+                "T05watch:c000;", // Around the loop to the store
+                "0501",
+                "OK",
+                "E01",
+                "OK",
+                "aabb",
+                "0042000000003412008005010000000000000000000000000000",
+            ]
+        );
+    }
+}
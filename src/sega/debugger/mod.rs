@@ -0,0 +1,5 @@
+pub mod command;
+pub mod frontend;
+pub mod gdb;
+pub mod repl;
+pub mod watch;
@@ -0,0 +1,498 @@
+// Interactive debugger, stops the emulator at breakpoints/watchpoints (or
+// when stepping) and runs a command prompt until told to carry on.
+// 'before_step'/'after_step' are called around each 'Core::step'.
+use super::super::cpu::core;
+use super::super::cpu::disassembler;
+use super::super::cpu::pc_state;
+use super::super::cpu::trace;
+use super::super::memory::memory;
+use super::command;
+use super::frontend;
+use super::watch;
+use std::io;
+
+enum RunMode {
+    Continue,
+    Step(u32),          // Instructions left to step.
+    StepOver(u16, u16), // Return address, and the stack pointer at the call.
+    Finish(u16),        // Stop once the stack pointer is above this.
+}
+
+enum Action {
+    Prompt, // Keep reading commands.
+    Resume,
+    Quit,
+}
+
+pub struct Debugger {
+    input: Box<dyn io::BufRead>,
+    output: Box<dyn io::Write>,
+    breakpoints: Vec<u16>,
+    paused: bool,
+    run_mode: RunMode,
+    resume_pc: Option<u16>, // Don't stop at a breakpoint on the PC being resumed from.
+    last_command: Option<command::Command>,
+}
+
+impl Debugger {
+    // Starts paused, so breakpoints can be set before anything runs.
+    pub fn new(input: Box<dyn io::BufRead>, output: Box<dyn io::Write>) -> Self {
+        Self {
+            input,
+            output,
+            breakpoints: Vec::new(),
+            paused: true,
+            run_mode: RunMode::Continue,
+            resume_pc: None,
+            last_command: None,
+        }
+    }
+
+    fn pause(&mut self) {
+        self.paused = true;
+        self.run_mode = RunMode::Continue;
+    }
+
+    fn print(&mut self, text: &str) {
+        // Nothing sensible to do if the output has gone.
+        let _ = writeln!(self.output, "{}", text);
+    }
+
+    fn prompt<M>(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>) -> bool
+    where
+        M: memory::MemoryRW,
+    {
+        self.disassemble(core, None, 1);
+        loop {
+            let _ = write!(self.output, "(debug) ");
+            let _ = self.output.flush();
+
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    // End of input, nothing more can be done.
+                    return false;
+                }
+                Ok(_) => {}
+            }
+
+            let command = if line.trim().is_empty() {
+                match self.last_command.clone() {
+                    Some(command) => command,
+                    None => continue,
+                }
+            } else {
+                match command::parse(&line) {
+                    Ok(command) => command,
+                    Err(message) => {
+                        self.print(&message);
+                        continue;
+                    }
+                }
+            };
+            self.last_command = Some(command.clone());
+
+            match self.run_command(core, command) {
+                Action::Prompt => {}
+                Action::Resume => {
+                    self.paused = false;
+                    self.resume_pc = Some(core.get_pc_state().get_pc());
+                    // Don't try to catch up on the time spent paused.
+                    core.resync_realtime();
+                    return true;
+                }
+                Action::Quit => {
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn run_command<M>(
+        &mut self,
+        core: &mut core::Core<watch::WatchedMemory<M>>,
+        command: command::Command,
+    ) -> Action
+    where
+        M: memory::MemoryRW,
+    {
+        let pc = core.get_pc_state().get_pc();
+        let sp = core.get_pc_state().get_sp();
+        match command {
+            command::Command::Continue => {
+                self.run_mode = RunMode::Continue;
+                Action::Resume
+            }
+            command::Command::Step(count) => {
+                self.run_mode = RunMode::Step(count.max(1));
+                Action::Resume
+            }
+            command::Command::StepOver => {
+                let disassembly = disassembler::disassemble(&core.memory.memory, pc);
+                self.run_mode = if disassembly.text.starts_with("CALL")
+                    || disassembly.text.starts_with("RST")
+                {
+                    RunMode::StepOver(pc.wrapping_add(disassembly.length), sp)
+                } else {
+                    RunMode::Step(1)
+                };
+                Action::Resume
+            }
+            command::Command::Finish => {
+                self.run_mode = RunMode::Finish(sp);
+                Action::Resume
+            }
+            command::Command::Break(Some(address)) => {
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                }
+                self.print(&format!("Breakpoint at 0x{:04X}", address));
+                Action::Prompt
+            }
+            command::Command::Break(None) => {
+                let mut lines: Vec<String> = self
+                    .breakpoints
+                    .iter()
+                    .map(|address| format!("Breakpoint 0x{:04X}", address))
+                    .collect();
+                lines.extend(
+                    core.memory
+                        .watchpoints
+                        .iter()
+                        .map(|watchpoint| format!("Watchpoint (memory) {}", watchpoint)),
+                );
+                lines.extend(
+                    core.ports
+                        .watchpoints
+                        .iter()
+                        .map(|watchpoint| format!("Watchpoint (port) {}", watchpoint)),
+                );
+                if lines.is_empty() {
+                    lines.push("No breakpoints or watchpoints".to_string());
+                }
+                self.print(&lines.join("\n"));
+                Action::Prompt
+            }
+            command::Command::Delete(address) => {
+                if !self.breakpoints.contains(&address) {
+                    self.print(&format!("No breakpoint at 0x{:04X}", address));
+                }
+                self.breakpoints.retain(|breakpoint| *breakpoint != address);
+                Action::Prompt
+            }
+            command::Command::Watch(watchpoint) => {
+                core.memory.watchpoints.push(watchpoint);
+                Action::Prompt
+            }
+            command::Command::WatchPort(watchpoint) => {
+                core.ports.watchpoints.push(watchpoint);
+                Action::Prompt
+            }
+            command::Command::Clear => {
+                self.breakpoints.clear();
+                core.memory.watchpoints.clear();
+                core.ports.watchpoints.clear();
+                Action::Prompt
+            }
+            command::Command::Registers => {
+                let pc_state = core.get_pc_state();
+                let text = format!(
+                    "{}\nI:{:02X} R:{:02X} IM:{} IFF1:{} IFF2:{} HALT:{}",
+                    trace::format_line(core.clock.cycles, &core.memory.memory, pc_state),
+                    pc_state.get_i(),
+                    pc_state.get_r(),
+                    pc_state.get_im(),
+                    pc_state.get_iff1(),
+                    pc_state.get_iff2(),
+                    pc_state.get_halted()
+                );
+                self.print(&text);
+                Action::Prompt
+            }
+            command::Command::SetRegister(name, value) => {
+                if let Err(message) = set_register(core.get_pc_state_mut(), &name, value) {
+                    self.print(&message);
+                }
+                Action::Prompt
+            }
+            command::Command::Memory(address, count) => {
+                let text = hex_dump(&core.memory.memory, address, count);
+                self.print(&text);
+                Action::Prompt
+            }
+            command::Command::SetMemory(address, values) => {
+                for (offset, value) in values.iter().enumerate() {
+                    core.memory
+                        .memory
+                        .write(address.wrapping_add(offset as u16), *value);
+                }
+                Action::Prompt
+            }
+            command::Command::Disassemble(address, count) => {
+                self.disassemble(core, address, count);
+                Action::Prompt
+            }
+            command::Command::Help => {
+                self.print(command::HELP);
+                Action::Prompt
+            }
+            command::Command::Quit => Action::Quit,
+        }
+    }
+
+    fn disassemble<M>(
+        &mut self,
+        core: &core::Core<watch::WatchedMemory<M>>,
+        address: Option<u16>,
+        count: u16,
+    ) where
+        M: memory::MemoryRW,
+    {
+        let mut address = address.unwrap_or(core.get_pc_state().get_pc());
+        for _ in 0..count {
+            let disassembly = disassembler::disassemble(&core.memory.memory, address);
+            let op_codes: Vec<String> = (0..disassembly.length)
+                .map(|offset| {
+                    format!(
+                        "{:02X}",
+                        core.memory.memory.read(address.wrapping_add(offset))
+                    )
+                })
+                .collect();
+            let breakpoint = if self.breakpoints.contains(&address) {
+                "*"
+            } else {
+                " "
+            };
+            self.print(&format!(
+                "{}0x{:04X}: {:<11}  {}",
+                breakpoint,
+                address,
+                op_codes.join(" "),
+                disassembly
+            ));
+            address = address.wrapping_add(disassembly.length);
+        }
+    }
+}
+
+impl<M: memory::MemoryRW> frontend::Frontend<M> for Debugger {
+    // Run the command prompt if paused (or at a breakpoint), false to quit.
+    fn before_step(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>) -> bool {
+        let pc_state = core.get_pc_state();
+        let pc = pc_state.get_pc();
+        if !pc_state.get_halted() && self.resume_pc != Some(pc) && self.breakpoints.contains(&pc) {
+            self.print(&format!("Breakpoint at 0x{:04X}", pc));
+            self.pause();
+        }
+        self.resume_pc = None;
+
+        if !self.paused {
+            return true;
+        }
+        self.prompt(core)
+    }
+
+    // Check for watchpoint hits, and whether a step/next/finish is complete.
+    fn after_step(&mut self, core: &mut core::Core<watch::WatchedMemory<M>>) {
+        if let Some(hit) = core.memory.take_hit() {
+            self.print(&format!("Watchpoint, memory {}", hit));
+            self.pause();
+        }
+        if let Some(hit) = core.ports.take_watch_hit() {
+            self.print(&format!("Watchpoint, port {}", hit));
+            self.pause();
+        }
+
+        let pc_state = core.get_pc_state();
+        match self.run_mode {
+            RunMode::Continue => {}
+            RunMode::Step(count) => {
+                if count <= 1 {
+                    self.pause();
+                } else {
+                    self.run_mode = RunMode::Step(count - 1);
+                }
+            }
+            RunMode::StepOver(address, sp) => {
+                if pc_state.get_pc() == address && pc_state.get_sp() >= sp {
+                    self.pause();
+                }
+            }
+            RunMode::Finish(sp) => {
+                if pc_state.get_sp() > sp {
+                    self.pause();
+                }
+            }
+        }
+    }
+}
+
+fn set_register(pc_state: &mut pc_state::PcState, name: &str, value: u16) -> Result<(), String> {
+    let byte = || u8::try_from(value).map_err(|_| format!("{} is an 8-bit register", name));
+    match name {
+        "A" => pc_state.set_a(byte()?),
+        "F" => pc_state.set_af((pc_state.get_af() & 0xFF00) | byte()? as u16),
+        "B" => pc_state.set_b(byte()?),
+        "C" => pc_state.set_c(byte()?),
+        "D" => pc_state.set_d(byte()?),
+        "E" => pc_state.set_e(byte()?),
+        "H" => pc_state.set_h(byte()?),
+        "L" => pc_state.set_l(byte()?),
+        "I" => pc_state.set_i(byte()?),
+        "R" => pc_state.set_r(byte()?),
+        "AF" => pc_state.set_af(value),
+        "BC" => pc_state.set_bc(value),
+        "DE" => pc_state.set_de(value),
+        "HL" => pc_state.set_hl(value),
+        "IX" => pc_state.index_registers.ix_reg.set(value),
+        "IY" => pc_state.index_registers.iy_reg.set(value),
+        "SP" => pc_state.sp_reg.set(value),
+        "PC" => pc_state.set_pc(value),
+        _ => return Err(format!("Unknown register '{}'", name)),
+    }
+    Ok(())
+}
+
+// 16 bytes per line, 'C000: 00 01 02 ...'
+fn hex_dump<M>(memory: &M, address: u16, count: u16) -> String
+where
+    M: memory::MemoryRW,
+{
+    let mut lines = Vec::new();
+    for row in (0..count).step_by(16) {
+        let row_address = address.wrapping_add(row);
+        let values: Vec<String> = (0..(count - row).min(16))
+            .map(|offset| format!("{:02X}", memory.read(row_address.wrapping_add(offset))))
+            .collect();
+        lines.push(format!("{:04X}: {}", row_address, values.join(" ")));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::clocks;
+    use crate::sega::cpu::core;
+    use crate::sega::cpu::cpm;
+    use crate::sega::cpu::pc_state;
+    use crate::sega::debugger::frontend::Frontend;
+    use crate::sega::debugger::repl;
+    use crate::sega::debugger::watch;
+    use crate::sega::interruptor;
+    use crate::sega::memory::memory::MemoryRW;
+    use crate::sega::ports;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    // Writer that can be inspected while the debugger still owns it.
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    type TestCore = core::Core<watch::WatchedMemory<cpm::CpmMemory>>;
+
+    // Program at 0x100 (with no interrupt sources), and the debugger reading 'commands'.
+    fn test_debugger(
+        program: &[u8],
+        commands: &str,
+    ) -> (TestCore, repl::Debugger, Rc<RefCell<Vec<u8>>>) {
+        let mut memory = cpm::CpmMemory::new();
+        for (offset, op_code) in program.iter().enumerate() {
+            MemoryRW::write(&mut memory, 0x100 + offset as u16, *op_code);
+        }
+        let mut core = core::Core::new(
+            clocks::Clock::new(),
+            watch::WatchedMemory::new(memory),
+            pc_state::PcState::new(),
+            ports::Ports::new(),
+            interruptor::Interruptor::new(),
+        );
+        core.get_pc_state_mut().set_pc(0x100);
+        core.get_pc_state_mut().sp_reg.set(0x8000);
+
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let debugger = repl::Debugger::new(
+            Box::new(io::Cursor::new(commands.to_string())),
+            Box::new(SharedWriter(output.clone())),
+        );
+        (core, debugger, output)
+    }
+
+    // Run until the debugger quits (or runs out of commands).
+    fn run(core: &mut TestCore, debugger: &mut repl::Debugger) {
+        for _ in 0..1000 {
+            if !debugger.before_step(core) {
+                return;
+            }
+            core.step(false).unwrap();
+            debugger.after_step(core);
+        }
+        panic!("Debugger didn't stop");
+    }
+
+    const PROGRAM: [u8; 13] = [
+        0x3E, 0x01, // 0x100: LD A, 0x01
+        0xCD, 0x09, 0x01, // 0x102: CALL 0x109
+        0x32, 0x00, 0xC0, // 0x105: LD (0xC000), A
+        0x76, // 0x108: HALT
+        0x3C, // 0x109: INC A
+        0xD3, 0xBE, // 0x10A: OUT (0xBE), A
+        0xC9, // 0x10C: RET
+    ];
+
+    #[test]
+    fn test_debugger_breakpoints_and_stepping() {
+        let (mut core, mut debugger, _) = test_debugger(&PROGRAM, "b 10a\nc\nf\nr a 10\nn\nq\n");
+        run(&mut core, &mut debugger);
+        // Broke in the function, finished, changed A, then stepped over the store.
+        assert_eq!(core.get_pc_state().get_pc(), 0x108);
+        assert_eq!(core.memory.memory.read(0xC000), 0x10);
+
+        // Step over the call, then a single step.
+        let (mut core, mut debugger, _) = test_debugger(&PROGRAM, "s\nn\ns\nq\n");
+        run(&mut core, &mut debugger);
+        assert_eq!(core.get_pc_state().get_pc(), 0x108);
+        assert_eq!(core.get_pc_state().get_a(), 0x02);
+
+        // Step into the call, an empty line repeats the step.
+        let (mut core, mut debugger, _) = test_debugger(&PROGRAM, "s 2\n\nq\n");
+        run(&mut core, &mut debugger);
+        assert_eq!(core.get_pc_state().get_pc(), 0x10C);
+    }
+
+    #[test]
+    fn test_debugger_watchpoints() {
+        let (mut core, mut debugger, output) =
+            test_debugger(&PROGRAM, "wp be w\nc\nw c000 w\nc\nq\n");
+        run(&mut core, &mut debugger);
+        assert_eq!(core.get_pc_state().get_pc(), 0x108);
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        assert!(output.contains("Watchpoint, port write 0x02 to 0x00BE"));
+        assert!(output.contains("Watchpoint, memory write 0x02 to 0xC000"));
+    }
+
+    #[test]
+    fn test_debugger_inspection() {
+        let (mut core, mut debugger, output) = test_debugger(
+            &PROGRAM,
+            "m c000 12 34\nx c000 2\nu 102 1\nr hl 1234\nr\nr a 100\nq\n",
+        );
+        run(&mut core, &mut debugger);
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        assert!(output.contains("C000: 12 34\n"));
+        assert!(output.contains(" 0x0102: CD 09 01     CALL 0x0109\n"));
+        assert!(output.contains("HL:1234"));
+        assert!(output.contains("A is an 8-bit register"));
+    }
+}
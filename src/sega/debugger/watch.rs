@@ -0,0 +1,217 @@
+// Watchpoints, on memory (via a 'MemoryRW' wrapper) and on ports.
+// A hit is recorded (rather than stopping straight away), so the debugger
+// checks for hits once the current instruction has completed.
+use super::super::memory::memory;
+use super::super::savestate;
+use std::cell::Cell;
+use std::fmt;
+use std::ops;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16, // Inclusive
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    pub fn new(start: u16, end: u16, read: bool, write: bool) -> Self {
+        Self {
+            start,
+            end,
+            read,
+            write,
+        }
+    }
+
+    pub fn matches(&self, address: u16, kind: AccessKind) -> bool {
+        let kind_matches = match kind {
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+        };
+        kind_matches && (self.start..=self.end).contains(&address)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            _ => "w",
+        };
+        if self.start == self.end {
+            write!(f, "0x{:04X} ({})", self.start, kind)
+        } else {
+            write!(f, "0x{:04X}-0x{:04X} ({})", self.start, self.end, kind)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub kind: AccessKind,
+    pub address: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            AccessKind::Read => write!(f, "read 0x{:02X} from 0x{:04X}", self.value, self.address),
+            AccessKind::Write => write!(f, "write 0x{:02X} to 0x{:04X}", self.value, self.address),
+        }
+    }
+}
+
+// Hit for the first watchpoint matching the access (if any).
+pub fn find_hit(
+    watchpoints: &[Watchpoint],
+    address: u16,
+    kind: AccessKind,
+    value: u8,
+) -> Option<WatchHit> {
+    watchpoints
+        .iter()
+        .any(|w| w.matches(address, kind))
+        .then_some(WatchHit {
+            kind,
+            address,
+            value,
+        })
+}
+
+// Memory wrapper, checks the watchpoints on every access made through 'MemoryRW'.
+// Dereferences to the wrapped memory, for access that shouldn't be watched.
+pub struct WatchedMemory<M> {
+    pub memory: M,
+    pub watchpoints: Vec<Watchpoint>,
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl<M> WatchedMemory<M> {
+    pub fn new(memory: M) -> Self {
+        Self {
+            memory,
+            watchpoints: Vec::new(),
+            hit: Cell::new(None),
+        }
+    }
+
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    // Keep the first hit (until it's taken).
+    fn check(&self, address: u16, kind: AccessKind, value: u8) {
+        if !self.watchpoints.is_empty() && self.hit.get().is_none() {
+            self.hit
+                .set(find_hit(&self.watchpoints, address, kind, value));
+        }
+    }
+}
+
+impl<M: memory::MemoryRW> memory::MemoryRW for WatchedMemory<M> {
+    fn read(&self, address: memory::AddressType) -> u8 {
+        let value = self.memory.read(address);
+        self.check(address, AccessKind::Read, value);
+        value
+    }
+
+    fn read16(&self, address: memory::AddressType) -> u16 {
+        self.read(address) as u16 + ((self.read(address.wrapping_add(1)) as u16) << 8)
+    }
+
+    fn write(&mut self, address: memory::AddressType, data: u8) {
+        self.check(address, AccessKind::Write, data);
+        self.memory.write(address, data);
+    }
+
+    fn peek(&self, address: memory::AddressType) -> u8 {
+        self.memory.peek(address)
+    }
+
+    fn rom_bank(&self, address: memory::AddressType) -> Option<u8> {
+        self.memory.rom_bank(address)
+    }
+
+    fn memory_control(&mut self, value: u8) {
+        self.memory.memory_control(value);
+    }
+}
+
+impl<M: savestate::SaveState> savestate::SaveState for WatchedMemory<M> {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        self.memory.save_state(writer);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.memory.load_state(reader)
+    }
+}
+
+impl<M> ops::Deref for WatchedMemory<M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        &self.memory
+    }
+}
+
+impl<M> ops::DerefMut for WatchedMemory<M> {
+    fn deref_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::cpu::cpm;
+    use crate::sega::debugger::watch;
+    use crate::sega::memory::memory::MemoryRW;
+
+    #[test]
+    fn test_watched_memory() {
+        let mut memory = watch::WatchedMemory::new(cpm::CpmMemory::new());
+        memory
+            .watchpoints
+            .push(watch::Watchpoint::new(0xC000, 0xC0FF, false, true));
+        memory
+            .watchpoints
+            .push(watch::Watchpoint::new(0xD000, 0xD000, true, false));
+
+        memory.write(0xBFFF, 1);
+        memory.read(0xC000);
+        assert_eq!(memory.take_hit(), None);
+
+        // Only the first hit is kept.
+        memory.write(0xC010, 0x42);
+        memory.write(0xC011, 0x43);
+        assert_eq!(
+            memory.take_hit(),
+            Some(watch::WatchHit {
+                kind: watch::AccessKind::Write,
+                address: 0xC010,
+                value: 0x42
+            })
+        );
+        assert_eq!(memory.take_hit(), None);
+
+        memory.read16(0xCFFF);
+        assert_eq!(
+            memory.take_hit().map(|hit| (hit.kind, hit.address)),
+            Some((watch::AccessKind::Read, 0xD000))
+        );
+        assert_eq!(memory.read(0xC010), 0x42);
+    }
+}
@@ -1,10 +1,10 @@
 use super::super::clocks;
 use super::super::ports;
+use super::super::savestate;
 use super::display;
 
 #[derive(Clone, Copy, Default)]
 pub struct TileAttribute {
-    priority_cleared: bool,
     priority: u8,
     palette_select: bool,
     vertical_flip: bool,
@@ -174,12 +174,9 @@ pub struct Constants {}
 impl Constants {
     const RAMSIZE: u16 = 0x4000;
     const CRAMSIZE: u8 = 0x20;
-    // 3Mhz CPU, 50Hz refresh ~= 60000 ticks
-    const VSYNCCYCLETIME: u16 = 65232;
-    const BLANKTIME: u16 = ((Constants::VSYNCCYCLETIME as u32 * 72) / 262) as u16;
-    const VFRAMETIME: u16 =
-        ((Constants::VSYNCCYCLETIME as u32 * Constants::SMS_HEIGHT as u32) / 262) as u16;
-    const HSYNCCYCLETIME: u16 = 216;
+    // The frame and line times come from the 'TimingProfile' (NTSC or PAL).
+    const HSYNCCYCLETIME: u16 = clocks::TimingProfile::CYCLES_PER_LINE;
+    const VFRAMETIME: u32 = Constants::HSYNCCYCLETIME as u32 * Constants::SMS_HEIGHT as u32;
 
     const REGISTERMASK: u8 = 0x0F;
     const REGISTERUPDATEMASK: u8 = 0xF0;
@@ -209,6 +206,10 @@ impl Constants {
     const VDP1BIGSPRITES: u8 = 0x02;
     const VDP1DOUBLESPRITES: u8 = 0x01;
 
+    // Display modes (M4 | M3 | M2 | M1) with more than 192 lines.
+    const MODE_224_LINES: u8 = 0xB;
+    const MODE_240_LINES: u8 = 0xE;
+
     const NUMSPRITES: u8 = 64;
 
     pub const SMS_WIDTH: u16 = 256;
@@ -352,6 +353,7 @@ pub struct Vdp {
     vdp_register: [u8; Constants::NUMVDPREGISTERS as usize],
 
     screen_buffer_pending: bool,
+    next_line: u16, // The next line to draw (once the frame reaches the end of it).
 
     horizontal_scroll_info: Vec<HorizontalScroll>,
     vertical_scroll_info: Vec<u8>,
@@ -365,7 +367,6 @@ pub struct Vdp {
     display_buffers: DisplayBuffers,
 
     patterns4: Vec<u8>,
-    patterns16: Vec<Vec<display::Colour>>,
     tile_attributes: Vec<TileAttribute>,
     sprites: Vec<Sprite>,
 
@@ -386,6 +387,7 @@ pub struct Vdp {
 
     read_be_latch: u8,
     address_latch: bool,
+    h_counter: u8, // Latched by the TH lines (see 'Joystick::take_h_counter_latch').
 
     display_mode: u8,
 
@@ -401,8 +403,10 @@ pub struct Vdp {
 }
 
 pub struct VDPInterrupts {
+    timing: clocks::TimingProfile,
+    active_lines: u16, // 192, 224 or 240, for the V-counter.
     vdp_status_register: u8,
-    v_sync: u16,
+    v_sync: u32,
     y_end: u16,
     current_y_pos: u16,
     last_v_sync_clock: clocks::Clock,
@@ -428,6 +432,7 @@ impl Vdp {
             c_ram: vec![0; Constants::CRAMSIZE as usize],
             vdp_register: [0; Constants::NUMVDPREGISTERS as usize],
             screen_buffer_pending: false,
+            next_line: 0,
 
             // One entry per scan line for horizontal and vertical scroll info.
             horizontal_scroll_info: vec![
@@ -446,13 +451,6 @@ impl Vdp {
             screen_palette: vec![display::Colour::new(0, 0, 0); Constants::CRAMSIZE as usize],
             display_buffers: DisplayBuffers::new(),
             patterns4: vec![0; (Constants::MAXPATTERNS * (Constants::PATTERNSIZE as u16)) as usize],
-            patterns16: vec![
-                vec![
-                    display::Colour::new(0, 0, 0);
-                    (Constants::MAXPATTERNS * (Constants::PATTERNSIZE as u16)) as usize
-                ];
-                Constants::MAXPALETTES as usize
-            ],
             tile_attributes: vec![TileAttribute::default(); Constants::NUMTILEATTRIBUTES as usize],
             sprites: vec![Sprite::default(); Constants::MAXSPRITES as usize],
 
@@ -470,6 +468,7 @@ impl Vdp {
             write_bf_low_address: 0,
             border_colour: 0,
             address_latch: false,
+            h_counter: 0,
             display_mode: 0,
             interrupt_handler: VDPInterrupts::new(),
 
@@ -482,16 +481,21 @@ impl Vdp {
         }
     }
 
+    // Frame length and V-counter, for the console's video standard.
+    pub fn set_timing(&mut self, timing: clocks::TimingProfile) {
+        self.interrupt_handler.timing = timing;
+    }
+
     pub fn read_port_7e(&mut self, clock: &clocks::Clock) -> u8 {
         self.address_latch = false; // Address is unlatched during port read
 
-        let v_counter: u8 = ((clock.cycles - self.interrupt_handler.last_v_sync_clock.cycles)
-            as u32
-            / Constants::HSYNCCYCLETIME as u32) as u8;
-        self.interrupt_handler.current_y_pos =
-            (((clock.cycles - self.interrupt_handler.last_v_sync_clock.cycles) as u32
-                / Constants::HSYNCCYCLETIME as u32)
-                + 1) as u16;
+        let line = ((clock.cycles - self.interrupt_handler.last_v_sync_clock.cycles) as u32
+            / Constants::HSYNCCYCLETIME as u32) as u16;
+        let v_counter = self
+            .interrupt_handler
+            .timing
+            .v_counter(self.interrupt_handler.active_lines, line);
+        self.interrupt_handler.current_y_pos = line + 1;
 
         // I can't think of an ellegant solution, so this is as good as it gets
         // for now (fudge factor and all)
@@ -502,11 +506,15 @@ impl Vdp {
     pub fn read_port_7f(&mut self, _clock: &clocks::Clock) -> u8 {
         self.address_latch = false; // Address is unlatched during port read
 
-        // TODO: Add/fix joystick (light gun)
-        // I can't think of an ellegant solution, so this is as good as it gets
-        // for now (fudge factor and all)
-        // hCounter = ((self.inputs.joystick.getXpos() + 0x28)/2 & 0x7F)
-        0
+        // Only changes when latched (by the TH lines).
+        self.h_counter
+    }
+
+    // Position along the current line, as an H-counter value.
+    fn current_h_counter(&self, clock: &clocks::Clock) -> u8 {
+        let line_cycles = (clock.cycles - self.interrupt_handler.last_v_sync_clock.cycles)
+            % Constants::HSYNCCYCLETIME as clocks::ClockType;
+        clocks::TimingProfile::h_counter(line_cycles as u32)
     }
 
     pub fn read_port_be(&mut self, _clock: &clocks::Clock) -> u8 {
@@ -528,45 +536,53 @@ impl Vdp {
 
         if self.c_ram[addr as usize] != data {
             self.c_ram[addr as usize] = data;
+            self.screen_palette[addr as usize] = Vdp::palette_colour(data);
+        }
+    }
 
-            // Generate 8-bit RGB components, just to be generic
-            let r = ((data as u16 & 0x3) * 0xFF) / 0x3;
-            let g = (((data as u16 >> 2) & 0x3) * 0xFF) / 0x3;
-            let b = (((data as u16 >> 4) & 0x3) * 0xFF) / 0x3;
-
-            let colour = display::Colour::new(r as u8, g as u8, b as u8);
+    fn palette_colour(data: u8) -> display::Colour {
+        // Generate 8-bit RGB components, just to be generic
+        let r = ((data as u16 & 0x3) * 0xFF) / 0x3;
+        let g = (((data as u16 >> 2) & 0x3) * 0xFF) / 0x3;
+        let b = (((data as u16 >> 4) & 0x3) * 0xFF) / 0x3;
 
-            self.screen_palette[addr as usize] = colour;
-        }
+        display::Colour::new(r as u8, g as u8, b as u8)
     }
 
     pub fn update_tile_attributes(&mut self, address: u16, old_data: u8, data: u8) {
         // Only update if altered
         if old_data != data {
-            let tile = (address & Constants::TILEATTRIBUTESTILEMASK) >> Constants::TILESHIFT;
+            self.set_tile_attribute(address, data);
+        }
+    }
 
-            // Alteration of the high byte
-            if 0 != address & Constants::TILEATTRIBUTESHMASK {
-                if 0 != self.tile_attributes[tile as usize].priority
-                    && 0 == (data >> Constants::TILEPRIORITYSHIFT)
-                {
-                    self.tile_attributes[tile as usize].priority_cleared = true;
-                }
+    fn set_tile_attribute(&mut self, address: u16, data: u8) {
+        let tile = (address & Constants::TILEATTRIBUTESTILEMASK) >> Constants::TILESHIFT;
+
+        // Alteration of the high byte
+        if 0 != address & Constants::TILEATTRIBUTESHMASK {
+            self.tile_attributes[tile as usize].priority = data >> Constants::TILEPRIORITYSHIFT;
+            self.tile_attributes[tile as usize].palette_select =
+                0 != (data >> Constants::TILEPALETTESHIFT) & 0x1;
+            self.tile_attributes[tile as usize].vertical_flip =
+                0 != (data >> Constants::TILEVFLIPSHIFT) & 0x1;
+            self.tile_attributes[tile as usize].horizontal_flip =
+                0 != (data >> Constants::TILEHFLIPSHIFT) & 0x1;
+            self.tile_attributes[tile as usize].tile_number =
+                (self.tile_attributes[tile as usize].tile_number & 0xFF)
+                    | (((data as u16) & 0x1) << 8);
+        } else {
+            self.tile_attributes[tile as usize].tile_number =
+                (self.tile_attributes[tile as usize].tile_number & 0x100) | (data as u16);
+        }
+    }
 
-                self.tile_attributes[tile as usize].priority = data >> Constants::TILEPRIORITYSHIFT;
-                self.tile_attributes[tile as usize].palette_select =
-                    0 != (data >> Constants::TILEPALETTESHIFT) & 0x1;
-                self.tile_attributes[tile as usize].vertical_flip =
-                    0 != (data >> Constants::TILEVFLIPSHIFT) & 0x1;
-                self.tile_attributes[tile as usize].horizontal_flip =
-                    0 != (data >> Constants::TILEHFLIPSHIFT) & 0x1;
-                self.tile_attributes[tile as usize].tile_number =
-                    (self.tile_attributes[tile as usize].tile_number & 0xFF)
-                        | (((data as u16) & 0x1) << 8);
-            } else {
-                self.tile_attributes[tile as usize].tile_number =
-                    (self.tile_attributes[tile as usize].tile_number & 0x100) | (data as u16);
-            }
+    // The name table has moved, so rebuild the tile attributes from the ram
+    // at the new address.
+    fn reload_tile_attributes(&mut self) {
+        for offset in 0..Constants::NUMTILEATTRIBUTES {
+            let address = self.tile_attributes_address + offset;
+            self.set_tile_attribute(address, self.ram[address as usize]);
         }
     }
 
@@ -657,6 +673,38 @@ impl Vdp {
         }
     }
 
+    // The sprite attribute table has moved (or the sprite tiles have), so
+    // rebuild the sprites, and the scan lines they're on, from the ram.
+    fn reload_sprites(&mut self) {
+        for sprite_scan_line in self.display_buffers.sprite_scan_lines.iter_mut() {
+            sprite_scan_line.num_sprites = 0;
+        }
+
+        self.total_sprites = Constants::MAXSPRITES;
+        for sprite_num in 0..Constants::MAXSPRITES {
+            let y = self.ram[(self.sprite_attributes_address + sprite_num as u16) as usize];
+            let xn_address = self.sprite_attributes_address
+                + Constants::SPRITEXNMASK
+                + ((sprite_num as u16) << 1);
+
+            if y == Constants::LASTSPRITETOKEN && self.total_sprites == Constants::MAXSPRITES {
+                self.total_sprites = sprite_num;
+            }
+            self.sprites[sprite_num as usize].y = (y as u16) + 1;
+            self.sprites[sprite_num as usize].x = self.ram[xn_address as usize] as u16;
+            self.sprites[sprite_num as usize].tile_number =
+                self.ram[(xn_address + 1) as usize] as u16 | self.sprite_tile_shift;
+        }
+
+        for i in 0..self.total_sprites {
+            for y in self.sprites[i as usize].y
+                ..(self.sprites[i as usize].y + (self.mode_2_control.sprite_height as u16))
+            {
+                self.add_sprite_to_scan_lines(y, i);
+            }
+        }
+    }
+
     pub fn add_sprite_to_scan_lines(&mut self, scan_line_number: u16, sprite_number: u8) {
         let scan_line_number = scan_line_number & 0xFF;
 
@@ -833,19 +881,31 @@ impl Vdp {
                 self.update_mode_2_control();
             }
             2 => {
-                self.tile_attributes_address = ((data as u16) & 0xE) << 10;
+                let tile_attributes_address = ((data as u16) & 0xE) << 10;
+                if tile_attributes_address != self.tile_attributes_address {
+                    self.tile_attributes_address = tile_attributes_address;
+                    self.reload_tile_attributes();
+                }
                 self.debug_name_table_offset = self.tile_attributes_address;
             }
             5 => {
-                self.sprite_attributes_address = ((data as u16) & 0x7E) << 7;
+                let sprite_attributes_address = ((data as u16) & 0x7E) << 7;
+                if sprite_attributes_address != self.sprite_attributes_address {
+                    self.sprite_attributes_address = sprite_attributes_address;
+                    self.reload_sprites();
+                }
                 self.debug_sprite_information_table_offset = self.sprite_attributes_address;
             }
 
             6 => {
-                // self._tileDefinitions = &self._vdpRAM[(data & 0x4) << 11]
-                //  Probably should do more when this changes, as all the
-                //  sprite tile numbers should change... maybe later
-                self.sprite_tile_shift = ((data as u16) & 0x4) << 6;
+                // All of the sprite tile numbers move with the shift
+                let sprite_tile_shift = ((data as u16) & 0x4) << 6;
+                if sprite_tile_shift != self.sprite_tile_shift {
+                    self.sprite_tile_shift = sprite_tile_shift;
+                    for sprite in self.sprites.iter_mut() {
+                        sprite.tile_number = (sprite.tile_number & 0xFF) | sprite_tile_shift;
+                    }
+                }
             }
 
             7 => {
@@ -929,6 +989,13 @@ impl Vdp {
     fn update_display_mode(&mut self, display_mode_1: u8, display_mode_2: u8) {
         self.display_mode = display_mode_1 | display_mode_2;
 
+        // The taller modes aren't drawn yet, but the V-counter follows them.
+        self.interrupt_handler.active_lines = match self.display_mode {
+            Constants::MODE_224_LINES => 224,
+            Constants::MODE_240_LINES => 240,
+            _ => Constants::SMS_HEIGHT,
+        };
+
         // Need to see what the modes do/mean.
         if (self.display_mode == 0x8) || (self.display_mode == 0xA) {
             self.interrupt_handler.y_end = Constants::SMS_HEIGHT;
@@ -942,10 +1009,8 @@ impl Vdp {
         let mut fine_scroll = 0;
         let mut x_offset = 0;
 
+        let tile_offset = self.background_row(y);
         let sprite_scan_y = &self.display_buffers.sprite_scan_lines[y as usize];
-        let vertical_offset = self.vertical_scroll_info[y as usize];
-        let v_y = vertical_offset as u16 + y;
-        let tile_offset = v_y % ((Constants::YTILES * Constants::PATTERNHEIGHT) as u16);
         let horizontal_info_y = &self.horizontal_scroll_info[y as usize];
         let background_scan_y = &self.display_buffers.background_scan_lines[tile_offset as usize];
         let background_scan_y_line = &background_scan_y.scan_line;
@@ -1056,215 +1121,102 @@ impl Vdp {
         }
     }
 
-    fn draw_scan_lines(&mut self) {
-        if self.mode_2_control.enable_display {
+    // Draw the lines the frame has reached (up to 'line'), with the VRAM, CRAM
+    // and registers as they are now, so mid-frame changes show up.
+    fn draw_scan_lines_to(&mut self, line: u16) {
+        let line = line.min(self.interrupt_handler.y_end);
+        while self.next_line < line {
             // Only draw if 'enable_display' has been set.
-            for y in 0..self.interrupt_handler.y_end {
-                self.single_scan(y);
+            if self.mode_2_control.enable_display {
+                self.draw_scan_line(self.next_line);
             }
+            self.next_line += 1;
         }
     }
 
-    fn draw_buffer(&mut self) {
-        self.draw_background();
-        self.draw_sprites();
-
-        // Draw the scan lines here (not in export), so scroll locations are locked in.
-        self.draw_scan_lines();
-
-        self.screen_buffer_pending = true;
-
-        //        self.draw_patterns() // For debuging purposes
+    fn draw_scan_line(&mut self, y: u16) {
+        self.draw_background_line(self.background_row(y));
+        self.draw_sprite_line(y);
+        self.single_scan(y);
     }
 
-    fn update_screen_pattern(&mut self, pattern_number: u16) {
-        let mut index = pattern_number << 6;
-
-        for _py in 0..Constants::PATTERNHEIGHT {
-            for _px in 0..Constants::PATTERNWIDTH {
-                let pixel4 = self.patterns4[index as usize];
-
-                self.patterns16[0][index as usize] = self.screen_palette[pixel4 as usize];
-                self.patterns16[1][index as usize] =
-                    self.screen_palette[(pixel4 | (1 << 4)) as usize];
-                index += 1;
-            }
-        }
+    // Row of the background (name table) shown on line 'y'.
+    fn background_row(&self, y: u16) -> u16 {
+        let v_y = self.vertical_scroll_info[y as usize] as u16 + y;
+        v_y % ((Constants::YTILES * Constants::PATTERNHEIGHT) as u16)
     }
 
-    fn draw_background(&mut self) {
-        let mut tile = 0;
-
-        for y in 0..(Constants::YTILES * Constants::PATTERNHEIGHT) {
-            self.display_buffers.forground_scan_lines[y as usize].has_priority = false;
-        }
-
-        for y in (0..(Constants::YTILES * Constants::PATTERNHEIGHT) as u16)
-            .step_by(Constants::PATTERNHEIGHT as usize)
-        {
-            for x in (0..(Constants::XTILES as u16) * (Constants::PATTERNWIDTH as u16))
-                .step_by(Constants::PATTERNWIDTH as usize)
-            {
-                let mut tile_attribute = self.tile_attributes[tile as usize];
-
-                if !self.display_buffers.forground_scan_lines[y as usize].has_priority
-                    && (tile_attribute.priority != 0)
-                {
-                    for py in y..(y + (Constants::PATTERNHEIGHT as u16)) {
-                        self.display_buffers.forground_scan_lines[py as usize].has_priority = true;
-                    }
-                }
-
-                {
-                    self.update_screen_pattern(tile_attribute.tile_number);
-
-                    let patterns16_palette =
-                        &self.patterns16[tile_attribute.palette_select as usize];
-
-                    let mut patterns16_offset: i16 = (tile_attribute.tile_number as i16) << 6;
-                    let mut pattern_y_delta: i16 = 0;
-                    let mut pattern_x_delta: i16 = 1;
-
-                    if tile_attribute.horizontal_flip {
-                        patterns16_offset += (Constants::PATTERNWIDTH as i16) - 1;
-                        pattern_x_delta = -1;
-                        pattern_y_delta = (Constants::PATTERNWIDTH as i16) * 2;
-                    }
-
-                    if tile_attribute.vertical_flip {
-                        patterns16_offset += ((Constants::PATTERNHEIGHT as i16) - 1) << 3;
-                        pattern_y_delta -= 2 * Constants::PATTERNWIDTH as i16;
-                    }
-
-                    if tile_attribute.priority != 0 {
-                        let mut pattern4_offset: i16 = (tile_attribute.tile_number as i16) << 6;
-
-                        if tile_attribute.horizontal_flip {
-                            pattern4_offset += Constants::PATTERNWIDTH as i16 - 1;
-                        }
-
-                        if tile_attribute.vertical_flip {
-                            pattern4_offset += (Constants::PATTERNHEIGHT as i16 - 1) << 3;
-                        }
-
-                        for py in y..(y + (Constants::PATTERNHEIGHT as u16)) {
-                            let background_y_line =
-                                &mut self.display_buffers.background_scan_lines[py as usize];
-                            let forground_y_line =
-                                &mut self.display_buffers.forground_scan_lines[py as usize];
-                            for px in x..(x + (Constants::PATTERNWIDTH as u16)) {
-                                background_y_line.scan_line[px as usize] =
-                                    patterns16_palette[patterns16_offset as usize];
-
-                                // Indicate a forground pixel if the value is
-                                // non-zero and it is set as a forground pixel...
-                                // well tile
-                                forground_y_line.scan_line[px as usize] = false;
-
-                                // 'priority' is always true in this branch
-                                if self.patterns4[pattern4_offset as usize] != 0x0 {
-                                    forground_y_line.scan_line[px as usize] = true;
-                                }
-
-                                patterns16_offset += pattern_x_delta;
-                                pattern4_offset += pattern_x_delta;
-                            }
-                            patterns16_offset += pattern_y_delta;
-                            pattern4_offset += pattern_y_delta;
-                        }
-                    } else {
-                        if tile_attribute.priority_cleared {
-                            for py in y..(y + (Constants::PATTERNHEIGHT as u16)) {
-                                let forground_y_line =
-                                    &mut self.display_buffers.forground_scan_lines[py as usize];
-                                for px in x..(x + (Constants::PATTERNWIDTH as u16)) {
-                                    forground_y_line.scan_line[px as usize] = false;
-                                }
-                            }
-                            tile_attribute.priority_cleared = false;
-                        }
-
-                        for py in y..(y + (Constants::PATTERNHEIGHT as u16)) {
-                            let background_y_line =
-                                &mut self.display_buffers.background_scan_lines[py as usize];
-                            if pattern_x_delta == 1 {
-                                for i in 0..(Constants::PATTERNWIDTH as u16) {
-                                    background_y_line.scan_line[(x + i) as usize] =
-                                        patterns16_palette[(patterns16_offset as u16 + i) as usize];
-                                }
-                                patterns16_offset += Constants::PATTERNWIDTH as i16;
-                            } else {
-                                for px in 0..(Constants::PATTERNWIDTH as u16) {
-                                    background_y_line.scan_line[(x + px) as usize] =
-                                        patterns16_palette[patterns16_offset as usize];
-                                    patterns16_offset += pattern_x_delta;
-                                }
-                            }
-                            patterns16_offset += pattern_y_delta;
-                        }
-                    }
-                }
-
-                self.tile_attributes[tile as usize] = tile_attribute;
-
-                tile += 1;
+    // Draw a row of the background, and which of its pixels are in front of the sprites.
+    fn draw_background_line(&mut self, row: u16) {
+        let tile_row = row / Constants::PATTERNHEIGHT as u16;
+        let py = row % Constants::PATTERNHEIGHT as u16;
+        let background_y_line = &mut self.display_buffers.background_scan_lines[row as usize];
+        let forground_y_line = &mut self.display_buffers.forground_scan_lines[row as usize];
+        forground_y_line.has_priority = false;
+
+        for tile_x in 0..Constants::XTILES as u16 {
+            let tile_attribute =
+                &self.tile_attributes[(tile_row * Constants::XTILES as u16 + tile_x) as usize];
+            forground_y_line.has_priority |= tile_attribute.priority != 0;
+
+            let pattern_y = if tile_attribute.vertical_flip {
+                Constants::PATTERNHEIGHT as u16 - 1 - py
+            } else {
+                py
+            };
+            let pattern_offset = (tile_attribute.tile_number << 6) | (pattern_y << 3);
+            let palette_select = (tile_attribute.palette_select as u8) << 4;
+
+            for px in 0..Constants::PATTERNWIDTH as u16 {
+                let pattern_x = if tile_attribute.horizontal_flip {
+                    Constants::PATTERNWIDTH as u16 - 1 - px
+                } else {
+                    px
+                };
+                let pixel4 = self.patterns4[(pattern_offset | pattern_x) as usize];
+                let x = (tile_x * Constants::PATTERNWIDTH as u16 + px) as usize;
+                background_y_line.scan_line[x] =
+                    self.screen_palette[(pixel4 | palette_select) as usize];
+
+                // Indicate a forground pixel if the value is non-zero and it's
+                // a forground tile.
+                forground_y_line.scan_line[x] = tile_attribute.priority != 0 && pixel4 != 0;
             }
         }
     }
 
-    fn draw_sprites(&mut self) {
-        // Check for any sprite alterations
-        for i in 0..self.total_sprites {
-            let mut y = self.sprites[i as usize].y;
-            while (y < self.sprites[i as usize].y + (self.mode_2_control.sprite_height as u16))
-                && (y < Constants::SMS_HEIGHT)
-            {
-                y += 1;
-            }
-        }
+    fn draw_sprite_line(&mut self, y: u16) {
+        let sprite_scan_y = &mut self.display_buffers.sprite_scan_lines[y as usize];
+        sprite_scan_y.scan_line.fill(0);
 
-        for y in 0..self.interrupt_handler.y_end {
-            for i in 0..Constants::SMS_WIDTH {
-                self.display_buffers.sprite_scan_lines[y as usize].scan_line[i as usize] = 0;
-            }
-
-            let mut i = 0;
-            while (i < self.display_buffers.sprite_scan_lines[y as usize].num_sprites)
-                && (i < Constants::MAXSPRITESPERSCANLINE as u16)
-            {
-                let sprite_num =
-                    self.display_buffers.sprite_scan_lines[y as usize].sprites[i as usize];
+        let mut i = 0;
+        while (i < sprite_scan_y.num_sprites) && (i < Constants::MAXSPRITESPERSCANLINE as u16) {
+            let sprite = &self.sprites[sprite_scan_y.sprites[i as usize] as usize];
 
-                // Adding check to avoid out of bounds from tiley index
-                if (y > self.sprites[sprite_num as usize].y)
-                    || ((y + Constants::SMS_HEIGHT) > self.sprites[sprite_num as usize].y)
-                {
-                    // FIXME, loosing motivation, this is better but still
-                    // not quite right
-                    let tiley = if self.sprites[sprite_num as usize].y > Constants::SMS_HEIGHT {
-                        y - self.sprites[sprite_num as usize].y + Constants::SMS_HEIGHT
-                    } else {
-                        y - self.sprites[sprite_num as usize].y
-                    };
-
-                    let tile_addr =
-                        (self.sprites[sprite_num as usize].tile_number << 6) | (tiley << 3);
-                    for x in 0..self.mode_2_control.sprite_width {
-                        // If the line is clear
-                        if ((self.sprites[sprite_num as usize].x + x as u16) < Constants::SMS_WIDTH)
-                            && (self.display_buffers.sprite_scan_lines[y as usize].scan_line
-                                [(self.sprites[sprite_num as usize].x + x as u16) as usize]
-                                == 0)
-                        {
-                            self.display_buffers.sprite_scan_lines[y as usize].scan_line
-                                [(self.sprites[sprite_num as usize].x + x as u16) as usize] =
-                                self.patterns4[(tile_addr | x as u16) as usize];
-                        }
+            // Adding check to avoid out of bounds from tiley index
+            if (y > sprite.y) || ((y + Constants::SMS_HEIGHT) > sprite.y) {
+                // FIXME, loosing motivation, this is better but still
+                // not quite right
+                let tiley = if sprite.y > Constants::SMS_HEIGHT {
+                    y - sprite.y + Constants::SMS_HEIGHT
+                } else {
+                    y - sprite.y
+                };
+
+                let tile_addr = (sprite.tile_number << 6) | (tiley << 3);
+                for x in 0..self.mode_2_control.sprite_width {
+                    let screen_x = (sprite.x + x as u16) as usize;
+                    // If the line is clear
+                    if (screen_x < Constants::SMS_WIDTH as usize)
+                        && (sprite_scan_y.scan_line[screen_x] == 0)
+                    {
+                        sprite_scan_y.scan_line[screen_x] =
+                            self.patterns4[(tile_addr | x as u16) as usize];
                     }
                 }
-
-                i += 1;
             }
+
+            i += 1;
         }
     }
 
@@ -1320,6 +1272,14 @@ impl ports::Device for Vdp {
             }
         }
     }
+    fn latch_h_counter(&mut self, clock: &clocks::Clock) {
+        self.h_counter = self.current_h_counter(clock);
+    }
+
+    fn set_timing(&mut self, timing: clocks::TimingProfile) {
+        Vdp::set_timing(self, timing);
+    }
+
     fn port_write(&mut self, clock: &clocks::Clock, port_address: u8, value: u8) {
         match port_address {
             // Even values: 0x80 -> 0xBE
@@ -1337,7 +1297,17 @@ impl ports::Device for Vdp {
     fn poll_interrupts(&mut self, raw_display: &mut Vec<u8>, clock: &clocks::Clock) -> bool {
         self.interrupt_handler.update_in_frame_timing(clock);
 
+        // Draw the lines that have finished, before any line interrupt is
+        // serviced (so its changes apply from the next line).
+        self.draw_scan_lines_to(
+            (self.interrupt_handler.v_sync / Constants::HSYNCCYCLETIME as u32) as u16,
+        );
+
+        let frame_updated = self.interrupt_handler.frame_updated;
         self.interrupt_handler.update_post_frame_timing();
+        if !frame_updated && self.interrupt_handler.frame_updated {
+            self.screen_buffer_pending = true;
+        }
 
         if self.interrupt_handler.v_sync >= Constants::VFRAMETIME {
             if self.mode_2_control.enable_display {
@@ -1349,9 +1319,9 @@ impl ports::Device for Vdp {
 
         self.interrupt_handler.update_vsync_timing(clock);
 
-        // If v-sync has finished, then draw the buffer and update scroll info
+        // If v-sync has finished, then start a new frame and update scroll info
         if self.interrupt_handler.v_sync == 0 {
-            self.draw_buffer();
+            self.next_line = 0;
             self.update_horizontal_scroll_info();
             self.update_vertical_scroll_info();
         }
@@ -1359,6 +1329,10 @@ impl ports::Device for Vdp {
         self.interrupt_handler.poll_interrupts()
     }
 
+    fn next_interrupt(&self) -> Option<clocks::ClockType> {
+        Some(self.interrupt_handler.next_event())
+    }
+
     fn export(&mut self, raw_display: &mut Vec<u8>) -> bool {
         if self.screen_buffer_pending {
             self.driver_update_display(raw_display);
@@ -1371,11 +1345,220 @@ impl ports::Device for Vdp {
             false
         }
     }
+
+    fn redraw(&mut self, raw_display: &mut Vec<u8>) {
+        self.driver_update_display(raw_display);
+    }
+}
+
+// The render buffers (background/forground scan lines and the sprite scan line
+// pixels) aren't saved, they're redrawn for each line before they're used.
+// The mode settings and palette are regenerated from the registers and CRAM.
+impl savestate::SaveState for Vdp {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bytes(&self.c_ram);
+        writer.write_bytes(&self.vdp_register);
+        writer.write_bool(self.screen_buffer_pending);
+        writer.write_u16(self.next_line);
+
+        for scroll_info in [
+            &self.horizontal_scroll_info,
+            &self.last_horizontal_scroll_info,
+        ] {
+            writer.write_u32(scroll_info.len() as u32);
+            for scroll in scroll_info {
+                writer.write_u8(scroll.column_offset);
+                writer.write_u8(scroll.fine_scroll);
+                writer.write_u16(scroll.x_offset);
+            }
+        }
+        writer.write_bytes(&self.vertical_scroll_info);
+        writer.write_bytes(&self.last_vertical_scroll_info);
+
+        writer.write_bytes(&self.patterns4);
+        writer.write_u32(self.tile_attributes.len() as u32);
+        for tile_attribute in &self.tile_attributes {
+            writer.write_u8(tile_attribute.priority);
+            writer.write_bool(tile_attribute.palette_select);
+            writer.write_bool(tile_attribute.vertical_flip);
+            writer.write_bool(tile_attribute.horizontal_flip);
+            writer.write_u16(tile_attribute.tile_number);
+        }
+        writer.write_u32(self.sprites.len() as u32);
+        for sprite in &self.sprites {
+            writer.write_u16(sprite.tile_number);
+            writer.write_u16(sprite.x);
+            writer.write_u16(sprite.y);
+        }
+        writer.write_u8(self.total_sprites);
+
+        // The sprites on each scan line are built up as the attributes change.
+        writer.write_u32(self.display_buffers.sprite_scan_lines.len() as u32);
+        for sprite_scan_line in &self.display_buffers.sprite_scan_lines {
+            writer.write_u16(sprite_scan_line.num_sprites);
+            writer.write_bytes(&sprite_scan_line.sprites);
+        }
+
+        // The last frame drawn (up to 'next_line' of the current one), not all
+        // of it is redrawn each frame.
+        writer.write_u32(self.display_buffers.scan_lines.len() as u32);
+        for scan_line in &self.display_buffers.scan_lines {
+            let mut rgb = vec![0; 3 * scan_line.scan_line.len()];
+            for (colour, dst) in scan_line.scan_line.iter().zip(rgb.chunks_mut(3)) {
+                colour.convert_rgb24(dst);
+            }
+            writer.write_bytes(&rgb);
+        }
+
+        writer.write_u16(self.current_address);
+        writer.write_u16(self.sprite_attributes_address);
+        writer.write_u16(self.tile_attributes_address);
+        writer.write_u8(self.write_bf_low_address);
+        writer.write_u8(self.border_colour);
+        writer.write_u8(self.code_register);
+        writer.write_u8(self.read_be_latch);
+        writer.write_bool(self.address_latch);
+        writer.write_u8(self.h_counter);
+        writer.write_u16(self.sprite_tile_shift);
+        writer.write_u8(self.horizontal_scroll);
+        writer.write_u8(self.vertical_scroll);
+
+        self.interrupt_handler.save_state(writer);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        reader.read_bytes_into(&mut self.ram)?;
+        reader.read_bytes_into(&mut self.c_ram)?;
+        reader.read_bytes_into(&mut self.vdp_register)?;
+        self.screen_buffer_pending = reader.read_bool()?;
+        self.next_line = reader.read_u16()?;
+
+        for scroll_info in [
+            &mut self.horizontal_scroll_info,
+            &mut self.last_horizontal_scroll_info,
+        ] {
+            reader.read_length(scroll_info.len())?;
+            for scroll in scroll_info.iter_mut() {
+                scroll.column_offset = reader.read_u8()?;
+                scroll.fine_scroll = reader.read_u8()?;
+                scroll.x_offset = reader.read_u16()?;
+            }
+        }
+        reader.read_bytes_into(&mut self.vertical_scroll_info)?;
+        reader.read_bytes_into(&mut self.last_vertical_scroll_info)?;
+
+        reader.read_bytes_into(&mut self.patterns4)?;
+        reader.read_length(self.tile_attributes.len())?;
+        for tile_attribute in self.tile_attributes.iter_mut() {
+            tile_attribute.priority = reader.read_u8()?;
+            tile_attribute.palette_select = reader.read_bool()?;
+            tile_attribute.vertical_flip = reader.read_bool()?;
+            tile_attribute.horizontal_flip = reader.read_bool()?;
+            tile_attribute.tile_number = reader.read_u16()?;
+        }
+        reader.read_length(self.sprites.len())?;
+        for sprite in self.sprites.iter_mut() {
+            sprite.tile_number = reader.read_u16()?;
+            sprite.x = reader.read_u16()?;
+            sprite.y = reader.read_u16()?;
+        }
+        self.total_sprites = reader.read_u8()?;
+
+        reader.read_length(self.display_buffers.sprite_scan_lines.len())?;
+        for sprite_scan_line in self.display_buffers.sprite_scan_lines.iter_mut() {
+            sprite_scan_line.num_sprites = reader.read_u16()?;
+            reader.read_bytes_into(&mut sprite_scan_line.sprites)?;
+        }
+
+        reader.read_length(self.display_buffers.scan_lines.len())?;
+        for scan_line in self.display_buffers.scan_lines.iter_mut() {
+            let mut rgb = vec![0; 3 * scan_line.scan_line.len()];
+            reader.read_bytes_into(&mut rgb)?;
+            for (colour, src) in scan_line.scan_line.iter_mut().zip(rgb.chunks(3)) {
+                *colour = display::Colour::new(src[0], src[1], src[2]);
+            }
+        }
+
+        self.current_address = reader.read_u16()?;
+        self.sprite_attributes_address = reader.read_u16()?;
+        self.tile_attributes_address = reader.read_u16()?;
+        self.write_bf_low_address = reader.read_u8()?;
+        self.border_colour = reader.read_u8()?;
+        self.code_register = reader.read_u8()?;
+        self.read_be_latch = reader.read_u8()?;
+        self.address_latch = reader.read_bool()?;
+        self.h_counter = reader.read_u8()?;
+        self.sprite_tile_shift = reader.read_u16()?;
+        self.horizontal_scroll = reader.read_u8()?;
+        self.vertical_scroll = reader.read_u8()?;
+
+        self.interrupt_handler.load_state(reader)?;
+
+        // Regenerate the settings that follow from the registers and CRAM
+        // (without 'update_display_mode', the interrupt state is already restored).
+        self.mode_1_control
+            .update_mode_1_settings(self.vdp_register[Constants::MODE_CONTROL_NO_1 as usize]);
+        self.mode_2_control
+            .update_mode_2_settings(self.vdp_register[Constants::MODE_CONTROL_NO_2 as usize]);
+        self.display_mode = self.mode_1_control.display_mode_1 | self.mode_2_control.display_mode_2;
+        for (colour, data) in self.screen_palette.iter_mut().zip(self.c_ram.iter()) {
+            *colour = Vdp::palette_colour(*data);
+        }
+        self.debug_name_table_offset = self.tile_attributes_address;
+        self.debug_sprite_information_table_offset = self.sprite_attributes_address;
+        Ok(())
+    }
+}
+
+impl savestate::SaveState for VDPInterrupts {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_u8(self.vdp_status_register);
+        writer.write_u32(self.v_sync);
+        writer.write_u16(self.y_end);
+        writer.write_u16(self.active_lines);
+        writer.write_u16(self.current_y_pos);
+        self.last_v_sync_clock.save_state(writer);
+        writer.write_u32(self.line_int_time);
+        writer.write_u16(self.line_interrupt);
+        writer.write_u16(self.line_interrupt_latch);
+        writer.write_bool(self.h_int_pending);
+        writer.write_bool(self.v_int_pending);
+        writer.write_bool(self.v_sync_interrupt_enabled);
+        writer.write_bool(self.h_sync_interrupt_enabled);
+        writer.write_bool(self.frame_updated);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.vdp_status_register = reader.read_u8()?;
+        self.v_sync = reader.read_u32()?;
+        self.y_end = reader.read_u16()?;
+        self.active_lines = reader.read_u16()?;
+        self.current_y_pos = reader.read_u16()?;
+        self.last_v_sync_clock.load_state(reader)?;
+        self.line_int_time = reader.read_u32()?;
+        self.line_interrupt = reader.read_u16()?;
+        self.line_interrupt_latch = reader.read_u16()?;
+        self.h_int_pending = reader.read_bool()?;
+        self.v_int_pending = reader.read_bool()?;
+        self.v_sync_interrupt_enabled = reader.read_bool()?;
+        self.h_sync_interrupt_enabled = reader.read_bool()?;
+        self.frame_updated = reader.read_bool()?;
+        Ok(())
+    }
 }
 
 impl VDPInterrupts {
     pub fn new() -> Self {
         Self {
+            timing: clocks::TimingProfile::NTSC,
+            active_lines: Constants::SMS_HEIGHT,
             vdp_status_register: 0,
             v_sync: 0,
             last_v_sync_clock: clocks::Clock::new(),
@@ -1396,11 +1579,9 @@ impl VDPInterrupts {
 
 impl VDPInterrupts {
     fn update_in_frame_timing(&mut self, clock: &clocks::Clock) {
-        self.v_sync = (clock.cycles - self.last_v_sync_clock.cycles) as u16;
+        self.v_sync = (clock.cycles - self.last_v_sync_clock.cycles) as u32;
 
-        if (self.line_int_time < Constants::VFRAMETIME as u32)
-            && (self.v_sync as u32 >= self.line_int_time)
-        {
+        if (self.line_int_time < Constants::VFRAMETIME) && (self.v_sync >= self.line_int_time) {
             self.current_y_pos = (((clock.cycles - self.last_v_sync_clock.cycles) as u32
                 / Constants::HSYNCCYCLETIME as u32)
                 + 1) as u16;
@@ -1423,7 +1604,7 @@ impl VDPInterrupts {
     }
 
     fn update_vsync_timing(&mut self, clock: &clocks::Clock) {
-        if self.v_sync >= Constants::VSYNCCYCLETIME {
+        if self.v_sync >= self.timing.frame_cycles() {
             self.frame_updated = false;
             self.last_v_sync_clock.cycles = clock.cycles;
             self.v_sync = 0;
@@ -1434,6 +1615,20 @@ impl VDPInterrupts {
         }
     }
 
+    // Clock cycle of the next line interrupt, frame interrupt or end of
+    // v-sync (whichever comes first), as of the last poll.
+    fn next_event(&self) -> clocks::ClockType {
+        let frame_offset =
+            if (self.line_int_time < Constants::VFRAMETIME) && (self.line_int_time > self.v_sync) {
+                self.line_int_time
+            } else if !self.frame_updated {
+                Constants::VFRAMETIME
+            } else {
+                self.timing.frame_cycles()
+            };
+        self.last_v_sync_clock.cycles + frame_offset as clocks::ClockType
+    }
+
     fn poll_interrupts(&mut self) -> bool {
         (self.v_sync_interrupt_enabled && self.v_int_pending)
             || (self.h_sync_interrupt_enabled && self.h_int_pending)
@@ -1519,8 +1714,141 @@ mod tests {
     #[test]
     fn test_check_constants() {
         assert_eq!(vdp::Constants::NUMTILES, 896);
-        assert_eq!(vdp::Constants::BLANKTIME, 17926);
-        assert_eq!(vdp::Constants::VFRAMETIME, 47803);
+        assert_eq!(vdp::Constants::HSYNCCYCLETIME, 228);
+        assert_eq!(vdp::Constants::VFRAMETIME, 43776);
+    }
+
+    #[test]
+    fn test_mid_frame_palette() {
+        use crate::sega::clocks;
+        use crate::sega::ports::Device;
+
+        let mut vdp = vdp::Vdp::new();
+        let mut raw_display = Vec::new();
+        let mut clock = clocks::Clock::new();
+        fn write_bf(vdp: &mut vdp::Vdp, clock: &clocks::Clock, low: u8, high: u8) {
+            vdp.port_write(clock, 0xBF, low);
+            vdp.port_write(clock, 0xBF, high);
+        }
+        let rgb = |vdp: &vdp::Vdp, y: usize| {
+            let mut rgb = [0; 3];
+            vdp.display_buffers.scan_lines[y].scan_line[0].convert_rgb24(&mut rgb);
+            rgb
+        };
+
+        // Mode 4, display enabled, the background is all colour 0 (red).
+        write_bf(&mut vdp, &clock, 0x04, 0x80);
+        write_bf(&mut vdp, &clock, 0x40, 0x81);
+        write_bf(&mut vdp, &clock, 0x00, 0xC0);
+        vdp.port_write(&clock, 0xBE, 0x03);
+        vdp.poll_interrupts(&mut raw_display, &clock);
+
+        // Change to blue at the end of line 99, the lines below are blue.
+        clock.cycles = 100 * vdp::Constants::HSYNCCYCLETIME as u64;
+        vdp.poll_interrupts(&mut raw_display, &clock);
+        write_bf(&mut vdp, &clock, 0x00, 0xC0);
+        vdp.port_write(&clock, 0xBE, 0x30);
+        assert!(!vdp.screen_buffer_pending);
+
+        clock.cycles = vdp::Constants::VFRAMETIME as u64;
+        vdp.poll_interrupts(&mut raw_display, &clock);
+        assert!(vdp.screen_buffer_pending);
+        assert_eq!(rgb(&vdp, 0), [0xFF, 0, 0]);
+        assert_eq!(rgb(&vdp, 99), [0xFF, 0, 0]);
+        assert_eq!(rgb(&vdp, 100), [0, 0, 0xFF]);
+        assert_eq!(rgb(&vdp, 191), [0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_mid_frame_name_table() {
+        use crate::sega::clocks;
+        use crate::sega::ports::Device;
+
+        let mut vdp = vdp::Vdp::new();
+        let mut raw_display = Vec::new();
+        let mut clock = clocks::Clock::new();
+        fn write_bf(vdp: &mut vdp::Vdp, clock: &clocks::Clock, low: u8, high: u8) {
+            vdp.port_write(clock, 0xBF, low);
+            vdp.port_write(clock, 0xBF, high);
+        }
+        let rgb = |vdp: &vdp::Vdp, y: usize| {
+            let mut rgb = [0; 3];
+            vdp.display_buffers.scan_lines[y].scan_line[0].convert_rgb24(&mut rgb);
+            rgb
+        };
+
+        // Mode 4, display enabled, name table at 0x3800 (all tile 0).
+        write_bf(&mut vdp, &clock, 0x04, 0x80);
+        write_bf(&mut vdp, &clock, 0x40, 0x81);
+        write_bf(&mut vdp, &clock, 0xFF, 0x82);
+
+        // Colour 0 is red, colour 1 is blue, and tile 1 is all colour 1.
+        write_bf(&mut vdp, &clock, 0x00, 0xC0);
+        vdp.port_write(&clock, 0xBE, 0x03);
+        vdp.port_write(&clock, 0xBE, 0x30);
+        write_bf(&mut vdp, &clock, 0x20, 0x40);
+        for _ in 0..8 {
+            for data in [0xFF, 0x00, 0x00, 0x00] {
+                vdp.port_write(&clock, 0xBE, data);
+            }
+        }
+
+        // A second name table at 0x3000 (all tile 1), written while unused.
+        write_bf(&mut vdp, &clock, 0x00, 0x70);
+        for _ in 0..vdp::Constants::NUMTILES {
+            vdp.port_write(&clock, 0xBE, 0x01);
+            vdp.port_write(&clock, 0xBE, 0x00);
+        }
+        vdp.poll_interrupts(&mut raw_display, &clock);
+
+        // Switch to the second name table at the end of line 99.
+        clock.cycles = 100 * vdp::Constants::HSYNCCYCLETIME as u64;
+        vdp.poll_interrupts(&mut raw_display, &clock);
+        write_bf(&mut vdp, &clock, 0xFD, 0x82);
+
+        clock.cycles = vdp::Constants::VFRAMETIME as u64;
+        vdp.poll_interrupts(&mut raw_display, &clock);
+        assert!(vdp.screen_buffer_pending);
+        assert_eq!(rgb(&vdp, 0), [0xFF, 0, 0]);
+        assert_eq!(rgb(&vdp, 99), [0xFF, 0, 0]);
+        assert_eq!(rgb(&vdp, 100), [0, 0, 0xFF]);
+        assert_eq!(rgb(&vdp, 191), [0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_sprite_table_switch() {
+        use crate::sega::clocks;
+        use crate::sega::ports::Device;
+
+        let mut vdp = vdp::Vdp::new();
+        let clock = clocks::Clock::new();
+        fn write_bf(vdp: &mut vdp::Vdp, clock: &clocks::Clock, low: u8, high: u8) {
+            vdp.port_write(clock, 0xBF, low);
+            vdp.port_write(clock, 0xBF, high);
+        }
+
+        // Mode 4, sprite table at 0x3F00, then one sprite in a table at 0x3B00.
+        write_bf(&mut vdp, &clock, 0x04, 0x80);
+        write_bf(&mut vdp, &clock, 0x40, 0x81);
+        write_bf(&mut vdp, &clock, 0xFF, 0x85);
+        write_bf(&mut vdp, &clock, 0x00, 0x7B);
+        vdp.port_write(&clock, 0xBE, 0x10);
+        vdp.port_write(&clock, 0xBE, vdp::Constants::LASTSPRITETOKEN);
+        write_bf(&mut vdp, &clock, 0x80, 0x7B);
+        vdp.port_write(&clock, 0xBE, 0x20);
+        vdp.port_write(&clock, 0xBE, 0x05);
+
+        // Moving the table picks up the sprite, and the tile shift moves its tile.
+        write_bf(&mut vdp, &clock, 0x77, 0x85);
+        assert_eq!(vdp.total_sprites, 1);
+        assert_eq!(vdp.sprites[0].y, 0x11);
+        assert_eq!(vdp.sprites[0].x, 0x20);
+        assert_eq!(vdp.sprites[0].tile_number, 0x05);
+        assert_eq!(vdp.display_buffers.sprite_scan_lines[0x10].num_sprites, 0);
+        assert_eq!(vdp.display_buffers.sprite_scan_lines[0x11].num_sprites, 1);
+
+        write_bf(&mut vdp, &clock, 0x04, 0x86);
+        assert_eq!(vdp.sprites[0].tile_number, 0x105);
     }
 }
 
@@ -1,4 +1,6 @@
 use super::clocks;
+use super::memory::romdb;
+use super::savestate;
 use sdl2::event; // Keycode
 use sdl2::keyboard; // Keycode
 
@@ -12,6 +14,13 @@ pub struct Joystick {
     lg2x: u8,
     lg2y: u8,
     x: u8,
+    pause_pressed: bool,
+    nmi_pending: bool,
+
+    // I/O control (port 0x3F), the TR/TH directions and output levels.
+    io_control: u8,
+    nationality: romdb::Nationality,
+    h_counter_latch: bool, // Set when a TH line rises or is pulled low, until it's taken.
 }
 
 impl Joystick {
@@ -32,6 +41,25 @@ impl Joystick {
     const PORT2_LG1_BIT: u8 = (1 << 6);
     const PORT2_LG2_BIT: u8 = (1 << 7);
 
+    // I/O control, a direction bit set is an input, clear is an output.
+    const IO_PORTA_TR_DIRECTION_BIT: u8 = (1 << 0);
+    const IO_PORTA_TH_DIRECTION_BIT: u8 = (1 << 1);
+    const IO_PORTB_TR_DIRECTION_BIT: u8 = (1 << 2);
+    const IO_PORTB_TH_DIRECTION_BIT: u8 = (1 << 3);
+    const IO_PORTA_TR_LEVEL_BIT: u8 = (1 << 4);
+    const IO_PORTA_TH_LEVEL_BIT: u8 = (1 << 5);
+    const IO_PORTB_TR_LEVEL_BIT: u8 = (1 << 6);
+    const IO_PORTB_TH_LEVEL_BIT: u8 = (1 << 7);
+
+    // Port 1 and 2 bits for the TR and TH lines.
+    const PORT1_PORTA_TR_BIT: u8 = Joystick::PORT1_J1FIREB_BIT;
+    const PORT2_PORTB_TR_BIT: u8 = Joystick::PORT2_J2FIREB_BIT;
+    const PORT2_PORTA_TH_BIT: u8 = Joystick::PORT2_LG1_BIT;
+    const PORT2_PORTB_TH_BIT: u8 = Joystick::PORT2_LG2_BIT;
+
+    // Power on, all lines are inputs.
+    const IO_CONTROL_RESET: u8 = 0xFF;
+
     pub fn new() -> Self {
         Self {
             port1_value: 0xFF,
@@ -42,9 +70,79 @@ impl Joystick {
             lg2x: 0,
             lg2y: 0,
             x: 0,
+            pause_pressed: false,
+            nmi_pending: false,
+            io_control: Joystick::IO_CONTROL_RESET,
+            nationality: romdb::Nationality::Export,
+            h_counter_latch: false,
+        }
+    }
+
+    // Japanese consoles read back the inverse of the TH outputs (and ignore
+    // the TR outputs), which is how games tell them apart.
+    pub fn set_nationality(&mut self, nationality: romdb::Nationality) {
+        self.nationality = nationality;
+    }
+
+    pub fn get_io_control(&self) -> u8 {
+        self.io_control
+    }
+
+    // Use the 'level' for 'value_bit' if 'direction' is an output, otherwise
+    // keep the input.
+    fn io_line(&self, value: u8, value_bit: u8, direction: u8, level: u8, invert: bool) -> u8 {
+        if 0 != self.io_control & direction {
+            value
+        } else {
+            Joystick::set_bit(value, value_bit, (0 != self.io_control & level) != invert)
         }
     }
 
+    // The level on the TH lines (port 2 bits), from the outputs or the inputs.
+    fn th_lines(&self) -> u8 {
+        let value = self.io_line(
+            self.port2_value,
+            Joystick::PORT2_PORTA_TH_BIT,
+            Joystick::IO_PORTA_TH_DIRECTION_BIT,
+            Joystick::IO_PORTA_TH_LEVEL_BIT,
+            false,
+        );
+        let value = self.io_line(
+            value,
+            Joystick::PORT2_PORTB_TH_BIT,
+            Joystick::IO_PORTB_TH_DIRECTION_BIT,
+            Joystick::IO_PORTB_TH_LEVEL_BIT,
+            false,
+        );
+        value & (Joystick::PORT2_PORTA_TH_BIT | Joystick::PORT2_PORTB_TH_BIT)
+    }
+
+    pub fn write_io_control(&mut self, value: u8) {
+        let previous = self.th_lines();
+        self.io_control = value;
+        // Latch the H-counter if a TH output has risen.
+        if 0 != !previous & self.th_lines() {
+            self.h_counter_latch = true;
+        }
+    }
+
+    // The light phaser pulls its TH input low when it sees the beam, which
+    // latches the H-counter (not when it's released).
+    fn set_phaser_line(&mut self, bit: u8, value: bool) {
+        let previous = self.th_lines();
+        self.port2_value = Joystick::set_bit(self.port2_value, bit, value);
+        if 0 != previous & !self.th_lines() {
+            self.h_counter_latch = true;
+        }
+    }
+
+    // Return 'true' (once) if the VDP's H-counter should be latched.
+    pub fn take_h_counter_latch(&mut self) -> bool {
+        let latch = self.h_counter_latch;
+        self.h_counter_latch = false;
+        latch
+    }
+
     pub fn set_bit(initial: u8, mask: u8, value: bool) -> u8 {
         if value {
             initial | mask
@@ -70,10 +168,43 @@ impl Joystick {
         self.x
     }
     pub fn read_port1(&self) -> u8 {
-        self.port1_value
+        if self.nationality == romdb::Nationality::Japanese {
+            return self.port1_value;
+        }
+        self.io_line(
+            self.port1_value,
+            Joystick::PORT1_PORTA_TR_BIT,
+            Joystick::IO_PORTA_TR_DIRECTION_BIT,
+            Joystick::IO_PORTA_TR_LEVEL_BIT,
+            false,
+        )
     }
     pub fn read_port2(&self) -> u8 {
-        self.port2_value
+        let japanese = self.nationality == romdb::Nationality::Japanese;
+        let mut value = self.port2_value;
+        if !japanese {
+            value = self.io_line(
+                value,
+                Joystick::PORT2_PORTB_TR_BIT,
+                Joystick::IO_PORTB_TR_DIRECTION_BIT,
+                Joystick::IO_PORTB_TR_LEVEL_BIT,
+                false,
+            );
+        }
+        let value = self.io_line(
+            value,
+            Joystick::PORT2_PORTA_TH_BIT,
+            Joystick::IO_PORTA_TH_DIRECTION_BIT,
+            Joystick::IO_PORTA_TH_LEVEL_BIT,
+            japanese,
+        );
+        self.io_line(
+            value,
+            Joystick::PORT2_PORTB_TH_BIT,
+            Joystick::IO_PORTB_TH_DIRECTION_BIT,
+            Joystick::IO_PORTB_TH_LEVEL_BIT,
+            japanese,
+        )
     }
 
     pub fn j1_up(&mut self, value: bool) {
@@ -116,19 +247,35 @@ impl Joystick {
         self.port2_value = Joystick::set_bit(self.port2_value, Joystick::PORT2_RESET_BIT, value);
     }
 
+    // The pause button isn't read through a port, it's connected to the NMI
+    // line, which is edge triggered (only raise it on a new press).
+    pub fn pause(&mut self, value: bool) {
+        if !value && !self.pause_pressed {
+            self.nmi_pending = true;
+        }
+        self.pause_pressed = !value;
+    }
+
+    // Return 'true' (once) if the pause button has raised an NMI.
+    pub fn poll_nmi(&mut self) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        nmi
+    }
+
     pub fn lg1(&mut self, value: bool) {
         if !value {
             self.x = self.lg1x;
         }
 
-        self.port2_value = Joystick::set_bit(self.port2_value, Joystick::PORT2_LG1_BIT, value);
+        self.set_phaser_line(Joystick::PORT2_LG1_BIT, value);
     }
     pub fn lg2(&mut self, value: bool) {
         if !value {
             self.x = self.lg2x;
         }
 
-        self.port2_value = Joystick::set_bit(self.port2_value, Joystick::PORT2_LG2_BIT, value);
+        self.set_phaser_line(Joystick::PORT2_LG2_BIT, value);
     }
 
     pub fn lg1pos(&mut self, x: u8, y: u8) {
@@ -151,6 +298,66 @@ impl Joystick {
             }
         }
     }
+
+    pub fn port_write(&mut self, _clock: &clocks::Clock, port_address: u8, value: u8) {
+        // 3F plus all of the mirror ports.
+        if port_address & 0xC1 == 0x01 {
+            self.write_io_control(value);
+        }
+    }
+}
+
+impl savestate::SaveState for Joystick {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        for value in [
+            self.port1_value,
+            self.port2_value,
+            self.last_y,
+            self.lg1x,
+            self.lg1y,
+            self.lg2x,
+            self.lg2y,
+            self.x,
+            self.io_control,
+        ] {
+            writer.write_u8(value);
+        }
+        writer.write_bool(self.pause_pressed);
+        writer.write_bool(self.nmi_pending);
+        writer.write_bool(self.h_counter_latch);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        for value in [
+            &mut self.port1_value,
+            &mut self.port2_value,
+            &mut self.last_y,
+            &mut self.lg1x,
+            &mut self.lg1y,
+            &mut self.lg2x,
+            &mut self.lg2y,
+            &mut self.x,
+            &mut self.io_control,
+        ] {
+            *value = reader.read_u8()?;
+        }
+        self.pause_pressed = reader.read_bool()?;
+        self.nmi_pending = reader.read_bool()?;
+        self.h_counter_latch = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+// Save state hotkeys, handled by the machine rather than the joystick.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateHotkey {
+    SelectSlot(u8),
+    Save,
+    Load,
+    Rewind(bool), // Held to rewind, 'false' when released.
 }
 
 pub struct Input {}
@@ -163,7 +370,11 @@ impl Input {
     const KEY_FIRE_A: keyboard::Keycode = keyboard::Keycode::Z;
     const KEY_FIRE_B: keyboard::Keycode = keyboard::Keycode::X;
     const KEY_RESET: keyboard::Keycode = keyboard::Keycode::R;
+    const KEY_PAUSE: keyboard::Keycode = keyboard::Keycode::P;
     const KEY_QUIT: keyboard::Keycode = keyboard::Keycode::Escape;
+    const KEY_SAVE_STATE: keyboard::Keycode = keyboard::Keycode::F5;
+    const KEY_LOAD_STATE: keyboard::Keycode = keyboard::Keycode::F7;
+    const KEY_REWIND: keyboard::Keycode = keyboard::Keycode::Backspace;
 
     pub fn print_keys() {
         println!("Key mappings (Joystick 1):");
@@ -180,10 +391,42 @@ impl Input {
             Input::KEY_FIRE_B
         );
         println!("Reset: {}", Input::KEY_RESET);
+        println!("Pause: {}", Input::KEY_PAUSE);
         println!();
+        println!(
+            "Save state: {}, Load state: {}, Select slot: 0-9",
+            Input::KEY_SAVE_STATE,
+            Input::KEY_LOAD_STATE
+        );
+        println!("Rewind (hold): {}", Input::KEY_REWIND);
         println!("Quit: {}", Input::KEY_QUIT);
     }
 
+    pub fn state_hotkey(event: &event::Event) -> Option<StateHotkey> {
+        match event {
+            event::Event::KeyDown {
+                keycode: Some(keycode),
+                repeat: false,
+                ..
+            } => match *keycode {
+                Input::KEY_SAVE_STATE => Some(StateHotkey::Save),
+                Input::KEY_LOAD_STATE => Some(StateHotkey::Load),
+                Input::KEY_REWIND => Some(StateHotkey::Rewind(true)),
+                keycode => {
+                    let slot = (keycode as i32) - (keyboard::Keycode::Num0 as i32);
+                    (0..=9)
+                        .contains(&slot)
+                        .then_some(StateHotkey::SelectSlot(slot as u8))
+                }
+            },
+            event::Event::KeyUp {
+                keycode: Some(Input::KEY_REWIND),
+                ..
+            } => Some(StateHotkey::Rewind(false)),
+            _ => None,
+        }
+    }
+
     // Return 'true' if handled, otherwise 'false' (ie quit)
     pub fn handle_events(event: event::Event, joystick: &mut Joystick) -> bool {
         match event {
@@ -235,6 +478,12 @@ impl Input {
             } => {
                 joystick.reset(false);
             }
+            event::Event::KeyDown {
+                keycode: Some(Input::KEY_PAUSE),
+                ..
+            } => {
+                joystick.pause(false);
+            }
 
             event::Event::KeyUp {
                 keycode: Some(Input::KEY_UP),
@@ -278,6 +527,12 @@ impl Input {
             } => {
                 joystick.reset(true);
             }
+            event::Event::KeyUp {
+                keycode: Some(Input::KEY_PAUSE),
+                ..
+            } => {
+                joystick.pause(true);
+            }
 
             _ => return true,
         }
@@ -285,3 +540,155 @@ impl Input {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::clocks;
+    use crate::sega::inputs;
+    use crate::sega::memory::romdb;
+    use sdl2::event;
+    use sdl2::keyboard;
+
+    #[test]
+    fn test_pause_nmi() {
+        let mut joystick = inputs::Joystick::new();
+        assert!(!joystick.poll_nmi());
+
+        // Only a new press raises an NMI, holding the button doesn't repeat it.
+        joystick.pause(false);
+        joystick.pause(false);
+        assert!(joystick.poll_nmi());
+        assert!(!joystick.poll_nmi());
+
+        joystick.pause(true);
+        assert!(!joystick.poll_nmi());
+        joystick.pause(false);
+        assert!(joystick.poll_nmi());
+
+        // The pause button isn't visible on the joystick ports.
+        assert_eq!(joystick.read_port1(), 0xFF);
+        assert_eq!(joystick.read_port2(), 0xFF);
+    }
+
+    #[test]
+    fn test_export_io_control() {
+        let mut joystick = inputs::Joystick::new();
+        let clock = clocks::Clock::new();
+
+        // All inputs at power on.
+        assert_eq!(joystick.get_io_control(), 0xFF);
+        assert_eq!(joystick.port_read(&clock, 0xDD), Some(0xFF));
+
+        // TH outputs low then high, read back as written (the region check).
+        joystick.port_write(&clock, 0x3F, 0x55);
+        assert_eq!(joystick.port_read(&clock, 0xDD).unwrap() & 0xC0, 0x00);
+        assert!(!joystick.take_h_counter_latch());
+        joystick.port_write(&clock, 0x3F, 0xF5);
+        assert_eq!(joystick.port_read(&clock, 0xDD).unwrap() & 0xC0, 0xC0);
+        assert!(joystick.take_h_counter_latch());
+        assert!(!joystick.take_h_counter_latch());
+
+        // TR outputs (port A on 0xDC bit 5, port B on 0xDD bit 3).
+        joystick.port_write(&clock, 0x3F, 0x00);
+        assert_eq!(joystick.port_read(&clock, 0xDC), Some(0xDF));
+        assert_eq!(joystick.port_read(&clock, 0xDD), Some(0x37));
+
+        // Inputs again, a mirror port.
+        joystick.port_write(&clock, 0x01, 0x0F);
+        assert_eq!(joystick.port_read(&clock, 0xDC), Some(0xFF));
+        assert_eq!(joystick.port_read(&clock, 0xDD), Some(0xFF));
+    }
+
+    #[test]
+    fn test_japanese_io_control() {
+        let mut joystick = inputs::Joystick::new();
+        joystick.set_nationality(romdb::Nationality::Japanese);
+        let clock = clocks::Clock::new();
+
+        // TH outputs read back inverted.
+        joystick.port_write(&clock, 0x3F, 0x55);
+        assert_eq!(joystick.port_read(&clock, 0xDD).unwrap() & 0xC0, 0xC0);
+        joystick.port_write(&clock, 0x3F, 0xF5);
+        assert_eq!(joystick.port_read(&clock, 0xDD).unwrap() & 0xC0, 0x00);
+        // The lines still rose, so the H-counter is latched.
+        assert!(joystick.take_h_counter_latch());
+
+        // TR outputs are ignored.
+        joystick.port_write(&clock, 0x3F, 0x00);
+        assert_eq!(joystick.port_read(&clock, 0xDC), Some(0xFF));
+        assert_eq!(joystick.port_read(&clock, 0xDD), Some(0xFF));
+    }
+
+    #[test]
+    fn test_light_phaser_latch() {
+        let mut joystick = inputs::Joystick::new();
+
+        // TH is an input, latched as the phaser pulls it low (not on release).
+        joystick.lg1(false);
+        assert!(joystick.take_h_counter_latch());
+        assert_eq!(joystick.read_port2(), 0xBF);
+        joystick.lg1(true);
+        assert!(!joystick.take_h_counter_latch());
+        joystick.lg2(false);
+        assert!(joystick.take_h_counter_latch());
+        joystick.lg2(true);
+
+        // Not while TH is an output.
+        joystick.write_io_control(0xFD);
+        joystick.lg1(false);
+        joystick.lg1(true);
+        assert!(!joystick.take_h_counter_latch());
+    }
+
+    #[test]
+    fn test_state_hotkeys() {
+        let key_down = |keycode, repeat| event::Event::KeyDown {
+            timestamp: 0,
+            window_id: 0,
+            keycode: Some(keycode),
+            scancode: None,
+            keymod: keyboard::Mod::NOMOD,
+            repeat,
+        };
+
+        let test_values = [
+            (keyboard::Keycode::F5, Some(inputs::StateHotkey::Save)),
+            (keyboard::Keycode::F7, Some(inputs::StateHotkey::Load)),
+            (
+                keyboard::Keycode::Num0,
+                Some(inputs::StateHotkey::SelectSlot(0)),
+            ),
+            (
+                keyboard::Keycode::Num9,
+                Some(inputs::StateHotkey::SelectSlot(9)),
+            ),
+            (
+                keyboard::Keycode::Backspace,
+                Some(inputs::StateHotkey::Rewind(true)),
+            ),
+            (keyboard::Keycode::Z, None),
+        ];
+        for (keycode, expected) in test_values {
+            assert_eq!(
+                inputs::Input::state_hotkey(&key_down(keycode, false)),
+                expected
+            );
+        }
+        assert_eq!(
+            inputs::Input::state_hotkey(&key_down(keyboard::Keycode::F5, true)),
+            None
+        );
+        let key_up = event::Event::KeyUp {
+            timestamp: 0,
+            window_id: 0,
+            keycode: Some(keyboard::Keycode::Backspace),
+            scancode: None,
+            keymod: keyboard::Mod::NOMOD,
+            repeat: false,
+        };
+        assert_eq!(
+            inputs::Input::state_hotkey(&key_up),
+            Some(inputs::StateHotkey::Rewind(false))
+        );
+    }
+}
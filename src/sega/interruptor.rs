@@ -1,6 +1,7 @@
 use super::clocks;
 use super::cpu::pc_state;
 use super::memory::memory;
+use super::savestate;
 
 pub struct Interruptor {
     pub next_interrupt: u32,
@@ -27,3 +28,17 @@ impl Interruptor {
         // TODO: Do something
     }
 }
+
+impl savestate::SaveState for Interruptor {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_u32(self.next_interrupt);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.next_interrupt = reader.read_u32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+// Battery-backed cartridge RAM storage.
+//
+// Natively the RAM is kept beside the ROM, as '<rom>.sav'.  On emscripten
+// there's no file system to keep it in, so it's kept (hex encoded) in the
+// browser's local storage, named after a hash of the ROM (as the ROM is
+// dropped on to the page rather than named).
+use std::io;
+
+#[cfg(not(target_os = "emscripten"))]
+pub fn save_name(cartridge_name: &str, _rom: &[u8]) -> String {
+    format!("{}.sav", cartridge_name)
+}
+
+#[cfg(target_os = "emscripten")]
+pub fn save_name(_cartridge_name: &str, rom: &[u8]) -> String {
+    format!("rustsega.{:016x}.sav", hash(rom))
+}
+
+// FNV-1a, just to tell ROMs apart.
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, value| {
+        (hash ^ *value as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(not(target_os = "emscripten"))]
+pub fn load(name: &str) -> io::Result<Vec<u8>> {
+    std::fs::read(name)
+}
+
+#[cfg(not(target_os = "emscripten"))]
+pub fn save(name: &str, data: &[u8]) -> io::Result<()> {
+    std::fs::write(name, data)
+}
+
+#[cfg(target_os = "emscripten")]
+mod local_storage {
+    use std::ffi::{CStr, CString};
+    use std::os::raw::c_char;
+
+    extern "C" {
+        fn emscripten_run_script(script: *const c_char);
+        fn emscripten_run_script_string(script: *const c_char) -> *const c_char;
+    }
+
+    pub fn get_item(name: &str) -> String {
+        let script = CString::new(format!("localStorage.getItem('{}') || ''", name)).unwrap();
+        unsafe {
+            CStr::from_ptr(emscripten_run_script_string(script.as_ptr()))
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+
+    pub fn set_item(name: &str, value: &str) {
+        let script =
+            CString::new(format!("localStorage.setItem('{}', '{}')", name, value)).unwrap();
+        unsafe {
+            emscripten_run_script(script.as_ptr());
+        }
+    }
+}
+
+#[cfg(target_os = "emscripten")]
+pub fn load(name: &str) -> io::Result<Vec<u8>> {
+    let value = local_storage::get_item(name);
+    if value.is_empty() {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    }
+    decode_hex(&value).ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+#[cfg(target_os = "emscripten")]
+pub fn save(name: &str, data: &[u8]) -> io::Result<()> {
+    local_storage::set_item(name, &encode_hex(data));
+    Ok(())
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|value| format!("{:02x}", value)).collect()
+}
+
+pub fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::memory::battery;
+
+    #[test]
+    fn test_hex() {
+        let data = [0x00, 0x7F, 0xA5, 0xFF];
+        assert_eq!(battery::encode_hex(&data), "007fa5ff");
+        assert_eq!(battery::decode_hex("007fa5ff"), Some(data.to_vec()));
+        assert_eq!(battery::decode_hex(""), Some(Vec::new()));
+        assert_eq!(battery::decode_hex("007"), None);
+        assert_eq!(battery::decode_hex("0g"), None);
+        assert_ne!(battery::hash(&[1, 2]), battery::hash(&[2, 1]));
+    }
+}
@@ -1,8 +1,11 @@
+use super::header;
+use super::romdb;
+
 type BankSizeType = u16;
-type NumBanksType = u8;
+pub type NumBanksType = u16;
 
 const BANK_SIZE: BankSizeType = 0x4000;
-const MAX_BANKS: NumBanksType = 64;
+const MAX_BANKS: NumBanksType = 256; // 4MB, as many as an 8-bit bank register can select.
 
 #[derive(Copy, Clone)]
 struct Bank {
@@ -12,12 +15,24 @@ struct Bank {
 pub struct Cartridge {
     filename: String,
     pub num_banks: NumBanksType,
-    rom: Box<[Bank; MAX_BANKS as usize]>,
+    rom: Vec<Bank>,
+    pub info: Option<header::CartridgeInfo>, // From the ROM header, if there is one.
+    pub crc32: u32,                          // Of the whole ROM, for the ROM database.
 }
 
 fn print(cartridge: &Cartridge) {
     println!("read: {}", cartridge.filename);
     println!("Num banks: {}", cartridge.num_banks);
+    println!("CRC32: {:08x}", cartridge.crc32);
+    match &cartridge.info {
+        Some(info) => {
+            println!("{}", info);
+            if !info.checksum_valid() {
+                println!("Warning: ROM checksum mismatch, possibly a bad dump.");
+            }
+        }
+        None => println!("No ROM header found."),
+    }
 }
 
 impl Cartridge {
@@ -25,11 +40,9 @@ impl Cartridge {
         Self {
             filename: filename.to_string(),
             num_banks: 0,
-            rom: Box::new(
-                [Bank {
-                    data: [0; BANK_SIZE as usize],
-                }; MAX_BANKS as usize],
-            ),
+            rom: Vec::new(),
+            info: None,
+            crc32: 0,
         }
     }
 
@@ -52,6 +65,8 @@ impl Cartridge {
             });
         }
 
+        self.info = header::CartridgeInfo::parse(&buffer);
+        self.crc32 = romdb::crc32(&buffer);
         self.load_banks(&mut buffer);
 
         print(self);
@@ -59,42 +74,37 @@ impl Cartridge {
         Ok(())
     }
 
+    // Split the ROM into banks, the last one is padded if it's not a whole bank.
     fn load_banks(&mut self, source: &mut Vec<u8>) {
-        self.rom = Box::new(
-            [Bank {
-                data: [0; BANK_SIZE as usize],
-            }; MAX_BANKS as usize],
-        );
-
-        for i in 0..MAX_BANKS {
-            let (bank, n) = load_bank(source);
-
-            self.rom[i as usize] = bank;
-            source.drain(0..n as usize);
-            self.num_banks += 1
+        if source.len() > MAX_BANKS as usize * BANK_SIZE as usize {
+            println!(
+                "ROM larger than {} banks, ignoring the last {} bytes.",
+                MAX_BANKS,
+                source.len() - MAX_BANKS as usize * BANK_SIZE as usize
+            );
+            source.truncate(MAX_BANKS as usize * BANK_SIZE as usize);
         }
+
+        self.rom = source
+            .chunks(BANK_SIZE as usize)
+            .map(|chunk| {
+                let mut bank = Bank {
+                    data: [0; BANK_SIZE as usize],
+                };
+                bank.data[0..chunk.len()].copy_from_slice(chunk);
+                bank
+            })
+            .collect();
+        self.num_banks = self.rom.len() as NumBanksType;
+        source.clear();
     }
 
     pub fn read(&mut self, bank: NumBanksType, bank_address: BankSizeType) -> u8 {
         self.rom[bank as usize].data[bank_address as usize]
     }
-}
 
-fn load_bank(source: &mut [u8]) -> (Bank, BankSizeType) {
-    let mut bank = Bank {
-        data: [0; BANK_SIZE as usize],
-    };
-
-    // Try to read an entire bank.
-    if source.len() >= BANK_SIZE as usize {
-        bank.data = source[0..BANK_SIZE as usize].try_into().unwrap();
-        (bank, BANK_SIZE as BankSizeType)
-    } else {
-        let length = source.len();
-        if length > 0 {
-            bank.data = source[0..length].try_into().unwrap();
-        }
-        (bank, source.len() as BankSizeType)
+    pub fn bank_data(&self, bank: NumBanksType) -> &[u8] {
+        &self.rom[bank as usize].data
     }
 }
 
@@ -154,4 +164,20 @@ mod tests {
         assert_eq!(cartridge.read(0, 0), 139);
         println!("{}", mem::size_of_val(&cartridge));
     }
+
+    #[test]
+    fn test_partial_bank() {
+        let filename = std::env::temp_dir().join(format!("partial_{}.sms", std::process::id()));
+        let filename = filename.to_str().unwrap();
+        let rom: Vec<u8> = (0..0x8100).map(|i| (i / 0x4000 + 1) as u8).collect();
+        std::fs::write(filename, &rom).unwrap();
+
+        let mut cartridge = Cartridge::new(filename);
+        cartridge.load().unwrap();
+        std::fs::remove_file(filename).unwrap();
+
+        assert_eq!(cartridge.num_banks, 3);
+        assert_eq!((cartridge.read(0, 0), cartridge.read(1, 0x3FFF)), (1, 2));
+        assert_eq!((cartridge.read(2, 0xFF), cartridge.read(2, 0x100)), (3, 0));
+    }
 }
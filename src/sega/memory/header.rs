@@ -0,0 +1,220 @@
+// The SMS/GG ROM header ("TMR SEGA"), 16 bytes at 0x7FF0 (or 0x3FF0/0x1FF0
+// for smaller ROMs):
+//
+// 0x0 - 0x7  "TMR SEGA"
+// 0x8 - 0x9  reserved
+// 0xA - 0xB  checksum (little endian)
+// 0xC - 0xD  product code (BCD, little endian, last 4 digits)
+// 0xE        product code (upper 4 bits, leading digit(s)) | version (lower 4 bits)
+// 0xF        region code (upper 4 bits) | ROM size (lower 4 bits)
+use std::fmt;
+
+pub const SIGNATURE: &[u8; 8] = b"TMR SEGA";
+pub const HEADER_OFFSETS: [usize; 3] = [0x7FF0, 0x3FF0, 0x1FF0];
+const HEADER_SIZE: usize = 0x10;
+const SPLIT: usize = 0x8000; // Checksums larger than this skip the header.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    SmsJapan,
+    SmsExport,
+    GgJapan,
+    GgExport,
+    GgInternational,
+    Unknown(u8),
+}
+
+impl Region {
+    fn from_code(code: u8) -> Self {
+        match code {
+            3 => Region::SmsJapan,
+            4 => Region::SmsExport,
+            5 => Region::GgJapan,
+            6 => Region::GgExport,
+            7 => Region::GgInternational,
+            code => Region::Unknown(code),
+        }
+    }
+
+    pub fn is_game_gear(&self) -> bool {
+        matches!(
+            self,
+            Region::GgJapan | Region::GgExport | Region::GgInternational
+        )
+    }
+
+    pub fn is_japanese(&self) -> bool {
+        matches!(self, Region::SmsJapan | Region::GgJapan)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::SmsJapan => write!(f, "SMS Japan"),
+            Region::SmsExport => write!(f, "SMS Export"),
+            Region::GgJapan => write!(f, "GG Japan"),
+            Region::GgExport => write!(f, "GG Export"),
+            Region::GgInternational => write!(f, "GG International"),
+            Region::Unknown(code) => write!(f, "Unknown ({:X})", code),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartridgeInfo {
+    pub header_offset: usize,
+    pub checksum: u16,
+    pub calculated_checksum: Option<u16>, // 'None' if the ROM is smaller than the declared size.
+    pub product_code: u32,
+    pub version: u8,
+    pub region: Region,
+    pub rom_size: Option<usize>, // Declared size, 'None' for an unknown size code.
+}
+
+// Declared ROM size, from the lower 4 bits of the last header byte.
+fn rom_size(code: u8) -> Option<usize> {
+    match code {
+        0xA => Some(0x2000),
+        0xB => Some(0x4000),
+        0xC => Some(0x8000),
+        0xD => Some(0xC000),
+        0xE => Some(0x10000),
+        0xF => Some(0x20000),
+        0x0 => Some(0x40000),
+        0x1 => Some(0x80000),
+        0x2 => Some(0x100000),
+        _ => None,
+    }
+}
+
+fn from_bcd(value: u8) -> u32 {
+    (value >> 4) as u32 * 10 + (value & 0xF) as u32
+}
+
+// Sum of the bytes up to 'size', skipping the header (which is the last 16
+// bytes of the first 32K, or of the ROM if it's smaller).
+pub fn calculate_checksum(rom: &[u8], size: usize) -> Option<u16> {
+    if rom.len() < size {
+        return None;
+    }
+    let header_end = size.min(SPLIT);
+    let sum = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .fold(0u16, |sum, value| sum.wrapping_add(*value as u16))
+    };
+    Some(sum(&rom[0..header_end - HEADER_SIZE]).wrapping_add(sum(&rom[header_end..size])))
+}
+
+impl CartridgeInfo {
+    // Parse the first header found, 'None' if there isn't one.
+    pub fn parse(rom: &[u8]) -> Option<Self> {
+        let header_offset = HEADER_OFFSETS.into_iter().find(|offset| {
+            rom.get(*offset..*offset + SIGNATURE.len()) == Some(SIGNATURE.as_slice())
+        })?;
+        let header = &rom[header_offset..header_offset + HEADER_SIZE];
+
+        let rom_size = rom_size(header[0xF] & 0xF);
+        Some(Self {
+            header_offset,
+            checksum: u16::from_le_bytes([header[0xA], header[0xB]]),
+            calculated_checksum: rom_size.and_then(|size| calculate_checksum(rom, size)),
+            product_code: from_bcd(header[0xC])
+                + from_bcd(header[0xD]) * 100
+                + (header[0xE] >> 4) as u32 * 10000,
+            version: header[0xE] & 0xF,
+            region: Region::from_code(header[0xF] >> 4),
+            rom_size,
+        })
+    }
+
+    pub fn checksum_valid(&self) -> bool {
+        self.calculated_checksum == Some(self.checksum)
+    }
+}
+
+impl fmt::Display for CartridgeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Product: {:05} Version: {} Region: {} ROM size: ",
+            self.product_code, self.version, self.region
+        )?;
+        match self.rom_size {
+            Some(size) => write!(f, "{}K", size / 1024)?,
+            None => write!(f, "unknown")?,
+        }
+        write!(f, " Checksum: {:04X}", self.checksum)?;
+        match self.calculated_checksum {
+            Some(checksum) if checksum == self.checksum => write!(f, " (ok)"),
+            Some(checksum) => write!(f, " (calculated {:04X})", checksum),
+            None => write!(f, " (ROM smaller than declared)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::memory::header;
+
+    // ROM of 'size' bytes with a header at 'offset'.
+    fn test_rom(size: usize, offset: usize, size_code: u8) -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..size).map(|i| (i * 13) as u8).collect();
+        rom[offset..offset + 8].copy_from_slice(header::SIGNATURE);
+        rom[offset + 0xC..offset + 0x10].copy_from_slice(&[0x07, 0x25, 0x13, 0x40 | size_code]);
+        let checksum = header::calculate_checksum(&rom, size).unwrap();
+        rom[offset + 0xA..offset + 0xC].copy_from_slice(&checksum.to_le_bytes());
+        rom
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let rom = test_rom(0x20000, 0x7FF0, 0xF);
+        let info = header::CartridgeInfo::parse(&rom).unwrap();
+        assert_eq!(info.header_offset, 0x7FF0);
+        assert_eq!(info.product_code, 12507);
+        assert_eq!(info.version, 3);
+        assert_eq!(info.region, header::Region::SmsExport);
+        assert_eq!(info.rom_size, Some(0x20000));
+        assert!(info.checksum_valid());
+        assert_eq!(
+            format!("{}", info),
+            format!(
+                "Product: 12507 Version: 3 Region: SMS Export ROM size: 128K Checksum: {:04X} (ok)",
+                info.checksum
+            )
+        );
+
+        // The header isn't part of the checksum, the rest of the ROM is.
+        let mut changed = rom.clone();
+        changed[0x7FF8] ^= 0xFF;
+        assert!(header::CartridgeInfo::parse(&changed)
+            .unwrap()
+            .checksum_valid());
+        changed[0x1FFFF] ^= 0xFF;
+        assert!(!header::CartridgeInfo::parse(&changed)
+            .unwrap()
+            .checksum_valid());
+
+        // Smaller ROMs.
+        let info = header::CartridgeInfo::parse(&test_rom(0x4000, 0x3FF0, 0xB)).unwrap();
+        assert_eq!(info.header_offset, 0x3FF0);
+        assert!(info.checksum_valid());
+        let info = header::CartridgeInfo::parse(&test_rom(0x2000, 0x1FF0, 0xA)).unwrap();
+        assert_eq!(info.header_offset, 0x1FF0);
+        assert!(info.checksum_valid());
+
+        // Declared larger than the ROM.
+        let info = header::CartridgeInfo::parse(&test_rom(0x8000, 0x7FF0, 0xC)[0..0x8000]);
+        assert!(info.unwrap().checksum_valid());
+        let mut rom = test_rom(0x8000, 0x7FF0, 0xC);
+        rom[0x7FFF] = 0x41; // 256K
+        let info = header::CartridgeInfo::parse(&rom).unwrap();
+        assert_eq!(info.calculated_checksum, None);
+        assert!(!info.checksum_valid());
+
+        assert_eq!(header::CartridgeInfo::parse(&[0; 0x8000]), None);
+        assert_eq!(header::CartridgeInfo::parse(&[]), None);
+    }
+}
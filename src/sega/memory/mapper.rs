@@ -0,0 +1,647 @@
+// Cartridge mappers, the bank switching hardware on the cartridge.
+//
+// A mapper holds its (bank select) registers, and maps the 8K segments of
+// 0x0000 - 0xBFFF to 'pages' of ROM or cartridge RAM.  'MemoryAbsolute' turns
+// the pages into its memory map offsets.
+use super::super::savestate;
+use super::memory::AddressType;
+use super::romdb;
+
+pub const SEGMENTS: usize = 6; // 8K segments, 0x0000 - 0xBFFF.
+const SEGMENT_SIZE: u32 = 0x2000;
+const BANK_SIZE: u32 = 0x4000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Page {
+    Rom(u32),         // 8K of ROM, at this ROM offset.
+    RomFixed(u32),    // As 'Rom', but with the first 1K fixed to the start of the ROM.
+    RomReversed(u32), // As 'Rom', but with the bits of each byte reversed.
+    CartRam(u32),     // 8K of cartridge RAM, at this offset.
+}
+
+pub trait Mapper: savestate::SaveState {
+    fn mapper_type(&self) -> romdb::MapperType;
+
+    // Set the registers to their power on values.
+    fn reset(&mut self);
+
+    // Handle a memory write, 'true' if it changed the registers (so the pages
+    // need mapping again).
+    fn write(&mut self, address: AddressType, data: u8) -> bool;
+
+    // Page mapped to the 8K 'segment' (0 to SEGMENTS - 1).
+    fn page(&self, segment: usize) -> Page;
+
+    // Whether the mapper uses 'Page::RomReversed' (so needs a reversed copy of the ROM).
+    fn reverses_rom(&self) -> bool {
+        false
+    }
+}
+
+pub fn new_mapper(mapper_type: romdb::MapperType) -> Box<dyn Mapper> {
+    match mapper_type {
+        romdb::MapperType::Sega => Box::new(SegaMapper::new()),
+        romdb::MapperType::Codemasters => Box::new(CodemastersMapper::new(false)),
+        romdb::MapperType::CodemastersRam => Box::new(CodemastersMapper::new(true)),
+        romdb::MapperType::Korean => Box::new(KoreanMapper::new()),
+        romdb::MapperType::Msx => Box::new(MsxMapper::new(false)),
+        romdb::MapperType::Nemesis => Box::new(MsxMapper::new(true)),
+        romdb::MapperType::FourPak => Box::new(FourPakMapper::new()),
+        romdb::MapperType::Janggun => Box::new(JanggunMapper::new()),
+    }
+}
+
+// Choose the mapper from the ROM itself, for when the ROM database doesn't say.
+pub fn detect_mapper(rom: &[u8]) -> romdb::MapperType {
+    // Codemasters ROMs have their own header at 0x7FE0, with a checksum (at
+    // 0x7FE6) followed by its complement (0x10000 - checksum).
+    if let Some(header) = rom.get(0x7FE6..0x7FEA) {
+        let checksum = u16::from_le_bytes([header[0], header[1]]);
+        let complement = u16::from_le_bytes([header[2], header[3]]);
+        if checksum != 0 && checksum.wrapping_add(complement) == 0 {
+            return romdb::MapperType::Codemasters;
+        }
+    }
+    romdb::MapperType::Sega
+}
+
+// Offset of 'segment' within 16K 'bank'.
+fn bank_offset(bank: u8, segment: usize) -> u32 {
+    bank as u32 * BANK_SIZE + (segment as u32 & 1) * SEGMENT_SIZE
+}
+
+// Offset of 8K 'page'.
+fn page_offset(page: u8) -> u32 {
+    page as u32 * SEGMENT_SIZE
+}
+
+// The standard Sega mapper, registers at 0xFFFC - 0xFFFF (written through to
+// the RAM underneath).
+pub struct SegaMapper {
+    ram_select: u8,
+    banks: [u8; 3],
+}
+
+impl SegaMapper {
+    const RAM_SELECT_REGISTER: AddressType = 0xFFFC;
+    const PAGE0_BANK_SELECT_REGISTER: AddressType = 0xFFFD;
+
+    const MAPCARTRAM: u8 = 0x08;
+    const PAGEOFRAM: u8 = 0x04;
+
+    pub fn new() -> Self {
+        Self {
+            ram_select: 0,
+            banks: [0, 1, 2],
+        }
+    }
+}
+
+impl Mapper for SegaMapper {
+    fn mapper_type(&self) -> romdb::MapperType {
+        romdb::MapperType::Sega
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn write(&mut self, address: AddressType, data: u8) -> bool {
+        if address == Self::RAM_SELECT_REGISTER {
+            self.ram_select = data;
+            true
+        } else if address >= Self::PAGE0_BANK_SELECT_REGISTER {
+            self.banks[(address - Self::PAGE0_BANK_SELECT_REGISTER) as usize] = data;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn page(&self, segment: usize) -> Page {
+        match segment {
+            0 => Page::RomFixed(bank_offset(self.banks[0], segment)),
+            4 | 5 if 0 != self.ram_select & Self::MAPCARTRAM => {
+                let ram_page = if 0 != self.ram_select & Self::PAGEOFRAM {
+                    BANK_SIZE
+                } else {
+                    0
+                };
+                Page::CartRam(ram_page + (segment as u32 & 1) * SEGMENT_SIZE)
+            }
+            _ => Page::Rom(bank_offset(self.banks[segment / 2], segment)),
+        }
+    }
+}
+
+impl savestate::SaveState for SegaMapper {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_u8(self.ram_select);
+        writer.write_bytes(&self.banks);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.ram_select = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.banks)
+    }
+}
+
+// Codemasters mapper, a register at the start of each 16K slot (0x0000,
+// 0x4000, 0x8000), and no fixed first 1K.  Some carts have 8K of RAM, mapped
+// to 0xA000 - 0xBFFF by bit 7 of the 0x4000 register.
+pub struct CodemastersMapper {
+    has_ram: bool,
+    banks: [u8; 3],
+}
+
+impl CodemastersMapper {
+    const RAM_ENABLE: u8 = 0x80;
+
+    pub fn new(has_ram: bool) -> Self {
+        Self {
+            has_ram,
+            banks: [0, 1, 0],
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.has_ram && 0 != self.banks[1] & Self::RAM_ENABLE
+    }
+}
+
+impl Mapper for CodemastersMapper {
+    fn mapper_type(&self) -> romdb::MapperType {
+        if self.has_ram {
+            romdb::MapperType::CodemastersRam
+        } else {
+            romdb::MapperType::Codemasters
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.has_ram);
+    }
+
+    fn write(&mut self, address: AddressType, data: u8) -> bool {
+        match address {
+            0x0000 | 0x4000 | 0x8000 => {
+                self.banks[(address / 0x4000) as usize] = data;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn page(&self, segment: usize) -> Page {
+        if segment == 5 && self.ram_enabled() {
+            Page::CartRam(0)
+        } else {
+            let mut bank = self.banks[segment / 2];
+            if self.has_ram {
+                bank &= !Self::RAM_ENABLE;
+            }
+            Page::Rom(bank_offset(bank, segment))
+        }
+    }
+}
+
+impl savestate::SaveState for CodemastersMapper {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_bytes(&self.banks);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        reader.read_bytes_into(&mut self.banks)
+    }
+}
+
+// Korean mapper, the first 32K is fixed and a single register at 0xA000
+// selects the 16K bank at 0x8000 - 0xBFFF.
+pub struct KoreanMapper {
+    bank: u8,
+}
+
+impl KoreanMapper {
+    const BANK_SELECT_REGISTER: AddressType = 0xA000;
+
+    pub fn new() -> Self {
+        Self { bank: 0 }
+    }
+}
+
+impl Mapper for KoreanMapper {
+    fn mapper_type(&self) -> romdb::MapperType {
+        romdb::MapperType::Korean
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn write(&mut self, address: AddressType, data: u8) -> bool {
+        if address == Self::BANK_SELECT_REGISTER {
+            self.bank = data;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn page(&self, segment: usize) -> Page {
+        match segment {
+            4 | 5 => Page::Rom(bank_offset(self.bank, segment)),
+            _ => Page::Rom(page_offset(segment as u8)),
+        }
+    }
+}
+
+impl savestate::SaveState for KoreanMapper {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_u8(self.bank);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.bank = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+// Korean MSX style mapper (for the MSX ports), 8K pages selected by the
+// registers at 0x0000 - 0x0003, for 0x8000, 0xA000, 0x4000 and 0x6000 (in that
+// order).  The first 16K is fixed, except for the Nemesis variant, which has
+// the last 8K of its (128K) ROM at 0x0000 - 0x1FFF.
+pub struct MsxMapper {
+    nemesis: bool,
+    pages: [u8; 4],
+}
+
+impl MsxMapper {
+    const SEGMENT_REGISTERS: [usize; 4] = [2, 3, 0, 1]; // Register for segments 2 to 5.
+    const NEMESIS_FIRST_PAGE: u8 = 0x0F;
+
+    pub fn new(nemesis: bool) -> Self {
+        Self {
+            nemesis,
+            pages: [0; 4],
+        }
+    }
+}
+
+impl Mapper for MsxMapper {
+    fn mapper_type(&self) -> romdb::MapperType {
+        if self.nemesis {
+            romdb::MapperType::Nemesis
+        } else {
+            romdb::MapperType::Msx
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.nemesis);
+    }
+
+    fn write(&mut self, address: AddressType, data: u8) -> bool {
+        if address < self.pages.len() as AddressType {
+            self.pages[address as usize] = data;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn page(&self, segment: usize) -> Page {
+        match segment {
+            0 if self.nemesis => Page::Rom(page_offset(Self::NEMESIS_FIRST_PAGE)),
+            0 | 1 => Page::Rom(page_offset(segment as u8)),
+            _ => Page::Rom(page_offset(
+                self.pages[Self::SEGMENT_REGISTERS[segment - 2]],
+            )),
+        }
+    }
+}
+
+impl savestate::SaveState for MsxMapper {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_bytes(&self.pages);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        reader.read_bytes_into(&mut self.pages)
+    }
+}
+
+// "4 PAK All Action" multi-cart mapper, registers at 0x3FFE, 0x7FFF and 0xBFFF
+// select the 16K banks for each slot.  The bank at 0x8000 - 0xBFFF is offset by
+// the game selected by the first register (bits 4 and 5).
+pub struct FourPakMapper {
+    banks: [u8; 3],
+}
+
+impl FourPakMapper {
+    const REGISTERS: [AddressType; 3] = [0x3FFE, 0x7FFF, 0xBFFF];
+    const GAME_MASK: u8 = 0x30;
+
+    pub fn new() -> Self {
+        Self { banks: [0, 1, 2] }
+    }
+}
+
+impl Mapper for FourPakMapper {
+    fn mapper_type(&self) -> romdb::MapperType {
+        romdb::MapperType::FourPak
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn write(&mut self, address: AddressType, data: u8) -> bool {
+        match Self::REGISTERS
+            .iter()
+            .position(|register| *register == address)
+        {
+            Some(slot) => {
+                self.banks[slot] = data;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn page(&self, segment: usize) -> Page {
+        let bank = match segment / 2 {
+            2 => (self.banks[0] & Self::GAME_MASK).wrapping_add(self.banks[2]),
+            slot => self.banks[slot],
+        };
+        Page::Rom(bank_offset(bank, segment))
+    }
+}
+
+impl savestate::SaveState for FourPakMapper {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_bytes(&self.banks);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        reader.read_bytes_into(&mut self.banks)
+    }
+}
+
+// Janggun-ui Adeul mapper, the first 16K is fixed and 0x4000 - 0xBFFF is 8K
+// pages selected by registers at 0x4000, 0x6000, 0x8000 and 0xA000.  The Sega
+// registers at 0xFFFE and 0xFFFF also select 16K banks (as pairs of pages),
+// with bit 6 reversing the bits of every byte read from that slot.
+pub struct JanggunMapper {
+    pages: [u8; 4],
+    reversed: [bool; 2], // For 0x4000 - 0x7FFF and 0x8000 - 0xBFFF.
+}
+
+impl JanggunMapper {
+    const PAGE1_BANK_SELECT_REGISTER: AddressType = 0xFFFE;
+    const REVERSE: u8 = 0x40;
+    const BANK_MASK: u8 = 0x3F;
+
+    pub fn new() -> Self {
+        Self {
+            pages: [2, 3, 4, 5],
+            reversed: [false; 2],
+        }
+    }
+}
+
+impl Mapper for JanggunMapper {
+    fn mapper_type(&self) -> romdb::MapperType {
+        romdb::MapperType::Janggun
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn write(&mut self, address: AddressType, data: u8) -> bool {
+        match address {
+            0x4000 | 0x6000 | 0x8000 | 0xA000 => {
+                self.pages[(address / 0x2000) as usize - 2] = data;
+                true
+            }
+            Self::PAGE1_BANK_SELECT_REGISTER | 0xFFFF => {
+                let slot = (address - Self::PAGE1_BANK_SELECT_REGISTER) as usize;
+                let bank = data & Self::BANK_MASK;
+                self.pages[slot * 2] = bank << 1;
+                self.pages[slot * 2 + 1] = (bank << 1) + 1;
+                self.reversed[slot] = 0 != data & Self::REVERSE;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn page(&self, segment: usize) -> Page {
+        match segment {
+            0 | 1 => Page::Rom(page_offset(segment as u8)),
+            _ => {
+                let offset = page_offset(self.pages[segment - 2]);
+                if self.reversed[segment / 2 - 1] {
+                    Page::RomReversed(offset)
+                } else {
+                    Page::Rom(offset)
+                }
+            }
+        }
+    }
+
+    fn reverses_rom(&self) -> bool {
+        true
+    }
+}
+
+impl savestate::SaveState for JanggunMapper {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_bytes(&self.pages);
+        writer.write_bool(self.reversed[0]);
+        writer.write_bool(self.reversed[1]);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        reader.read_bytes_into(&mut self.pages)?;
+        self.reversed = [reader.read_bool()?, reader.read_bool()?];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::memory::mapper::{self, Mapper, Page};
+    use crate::sega::memory::romdb;
+
+    #[test]
+    fn test_sega_mapper() {
+        let mut mapper = mapper::SegaMapper::new();
+        assert!(!mapper.write(0xFFFB, 1));
+        assert!(mapper.write(0xFFFD, 3));
+        assert!(mapper.write(0xFFFE, 4));
+        assert!(mapper.write(0xFFFF, 5));
+        assert_eq!(
+            (0..mapper::SEGMENTS)
+                .map(|segment| mapper.page(segment))
+                .collect::<Vec<_>>(),
+            vec![
+                Page::RomFixed(0xC000),
+                Page::Rom(0xE000),
+                Page::Rom(0x10000),
+                Page::Rom(0x12000),
+                Page::Rom(0x14000),
+                Page::Rom(0x16000),
+            ]
+        );
+
+        mapper.write(0xFFFC, 0x08);
+        assert_eq!(
+            (mapper.page(4), mapper.page(5)),
+            (Page::CartRam(0), Page::CartRam(0x2000))
+        );
+        mapper.write(0xFFFC, 0x0C);
+        assert_eq!(
+            (mapper.page(4), mapper.page(5)),
+            (Page::CartRam(0x4000), Page::CartRam(0x6000))
+        );
+    }
+
+    #[test]
+    fn test_detect_mapper() {
+        let mut rom = vec![0; 0x8000];
+        assert_eq!(mapper::detect_mapper(&rom), romdb::MapperType::Sega);
+        rom[0x7FE6..0x7FEA].copy_from_slice(&[0x34, 0x12, 0xCC, 0xED]);
+        assert_eq!(mapper::detect_mapper(&rom), romdb::MapperType::Codemasters);
+        assert_eq!(
+            mapper::detect_mapper(&rom[0..0x4000]),
+            romdb::MapperType::Sega
+        );
+    }
+
+    fn pages(mapper: &dyn Mapper) -> Vec<Page> {
+        (0..mapper::SEGMENTS)
+            .map(|segment| mapper.page(segment))
+            .collect()
+    }
+
+    #[test]
+    fn test_korean_mapper() {
+        let mut mapper = mapper::KoreanMapper::new();
+        assert!(!mapper.write(0xFFFF, 3));
+        assert!(mapper.write(0xA000, 5));
+        assert_eq!(
+            pages(&mapper),
+            vec![
+                Page::Rom(0x0000),
+                Page::Rom(0x2000),
+                Page::Rom(0x4000),
+                Page::Rom(0x6000),
+                Page::Rom(0x14000),
+                Page::Rom(0x16000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_msx_mapper() {
+        let mut mapper = mapper::MsxMapper::new(false);
+        assert!(mapper.write(0x0000, 0x10));
+        assert!(mapper.write(0x0001, 0x11));
+        assert!(mapper.write(0x0002, 0x12));
+        assert!(mapper.write(0x0003, 0x13));
+        assert!(!mapper.write(0x0004, 0x14));
+        assert_eq!(
+            pages(&mapper),
+            vec![
+                Page::Rom(0x0000),
+                Page::Rom(0x2000),
+                Page::Rom(0x24000),
+                Page::Rom(0x26000),
+                Page::Rom(0x20000),
+                Page::Rom(0x22000),
+            ]
+        );
+
+        let mapper = mapper::MsxMapper::new(true);
+        assert_eq!(mapper.mapper_type(), romdb::MapperType::Nemesis);
+        assert_eq!(
+            (mapper.page(0), mapper.page(1)),
+            (Page::Rom(0x1E000), Page::Rom(0x2000))
+        );
+    }
+
+    #[test]
+    fn test_four_pak_mapper() {
+        let mut mapper = mapper::FourPakMapper::new();
+        assert!(mapper.write(0x3FFE, 0x21));
+        assert!(mapper.write(0x7FFF, 0x22));
+        assert!(mapper.write(0xBFFF, 0x03));
+        assert!(!mapper.write(0xFFFF, 0x04));
+        assert_eq!(
+            pages(&mapper),
+            vec![
+                Page::Rom(0x84000),
+                Page::Rom(0x86000),
+                Page::Rom(0x88000),
+                Page::Rom(0x8A000),
+                Page::Rom(0x8C000), // Bank 0x23, game 0x20 + bank 3.
+                Page::Rom(0x8E000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_janggun_mapper() {
+        let mut mapper = mapper::JanggunMapper::new();
+        assert!(mapper.write(0x4000, 0x10));
+        assert!(mapper.write(0x6000, 0x11));
+        assert!(mapper.write(0x8000, 0x12));
+        assert!(mapper.write(0xA000, 0x13));
+        assert_eq!(
+            pages(&mapper),
+            vec![
+                Page::Rom(0x0000),
+                Page::Rom(0x2000),
+                Page::Rom(0x20000),
+                Page::Rom(0x22000),
+                Page::Rom(0x24000),
+                Page::Rom(0x26000),
+            ]
+        );
+
+        // 16K banks, bit 6 reverses the slot.
+        assert!(mapper.write(0xFFFE, 0x45));
+        assert!(mapper.write(0xFFFF, 0x06));
+        assert_eq!(
+            pages(&mapper)[2..],
+            [
+                Page::RomReversed(0x14000),
+                Page::RomReversed(0x16000),
+                Page::Rom(0x18000),
+                Page::Rom(0x1A000),
+            ]
+        );
+        assert!(mapper.write(0x8000, 0x01));
+        assert_eq!(mapper.page(4), Page::Rom(0x2000));
+        assert_eq!(mapper.page(3), Page::RomReversed(0x16000));
+    }
+}
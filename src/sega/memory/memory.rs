@@ -1,4 +1,10 @@
+use super::super::savestate;
+use super::battery;
 use super::cartridge;
+use super::header;
+use super::mapper;
+use super::romdb;
+use std::io;
 
 ///  Map the current 'pc' address to an 'absolute' address.  The
 /// structure of the 'absolute' address is somewhat arbitrary, but  the
@@ -7,17 +13,18 @@ use super::cartridge;
 ///
 /// segments:
 ///
-/// cartridge - ROM - 0x4000 * 64 (0x3F)
 /// cartridge - RAM - 0x4000 * 2
 /// system    - RAM - 0x2000
+/// cartridge - ROM - 0x4000 * number of banks (up to 256)
 ///
 ///
-/// ROM - 0x000000 - 0x0FFFFF
-/// ROM - 0x100000 - 0x1FFFFF
-/// RAM   0x200000 - 0x207FFF
-/// RAM   0x208000 - 0x20A000
-///
-/// -> Total = 3F 0x42
+/// RAM   0x000000 - 0x007FFF
+/// RAM   0x008000 - 0x009FFF
+/// 0xFF  0x00A000 - 0x00BFFF (open bus, for disabled slots)
+/// ROM   0x010000 - + ROM size
+/// ROM   + ROM size (with the first 1K of every bank from bank 0)
+/// ROM   + ROM size (bit reversed, only for mappers that need it)
+/// BIOS  + BIOS size, + BIOS size (with the first 1K fixed), if there's a BIOS
 ///
 /// mapped memory:
 ///     0x0000 - 0x03FF                     -> ROM (bank 0) (0x0000 - 0x03FF)
@@ -51,27 +58,52 @@ pub struct MemoryAbsoluteConstants {}
 
 pub struct MemoryBase {}
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    Bios,
+    Cartridge,
+    Empty, // Card or expansion slot (neither are emulated), or nothing enabled.
+}
+
 pub struct MemoryAbsolute {
-    page_2: u8,
-    ram_select: u8,
+    mapper: Box<dyn mapper::Mapper>,
+
+    // Memory control (port 0x3E), enables the slots, BIOS and RAM (active low).
+    memory_control: u8,
+    bios: Option<Vec<u8>>,
+    bios_mapper: Box<dyn mapper::Mapper>,
+    bios_offset: AbsoluteAddressType,
+    bios_size: AbsoluteAddressType, // 0 if there's no BIOS.
 
     upper_mappings: Vec<AbsoluteAddressType>,
 
     // Complete memory map
     memory_map: Vec<u8>,
+    rom_size: AbsoluteAddressType, // Whole banks, ROM offsets wrap at this size.
+
+    // Where the (battery-backed) cartridge RAM is saved, empty if there's no cartridge.
+    battery_name: String,
+    cart_ram_dirty: bool, // Enabled or written since it was last saved.
+
+    cartridge_info: Option<header::CartridgeInfo>,
+    romdb: romdb::RomDb,
+    rom_entry: Option<romdb::RomEntry>, // The ROM database entry for the cartridge, if there is one.
 }
 
 impl MemoryBase {
-    const MEMREGISTERS: AddressType = 0xFFFC;
     const ADDRESS_MASK: AddressType = 0xFFFF;
 
-    const RAM_SELECT_REGISTER: AddressType = 0xFFFC;
-    const PAGE0_BANK_SELECT_REGISTER: AddressType = 0xFFFD;
-    const PAGE1_BANK_SELECT_REGISTER: AddressType = 0xFFFE;
-    const PAGE2_BANK_SELECT_REGISTER: AddressType = 0xFFFF;
+    // Memory control bits (port 0x3E), a slot is enabled when its bit is clear.
+    const EXPANSION_DISABLE: u8 = 0x80;
+    const CARTRIDGE_DISABLE: u8 = 0x40;
+    const CARD_DISABLE: u8 = 0x20;
+    const RAM_DISABLE: u8 = 0x10;
+    const BIOS_DISABLE: u8 = 0x08;
+    const IO_DISABLE: u8 = 0x04;
 
-    const MAPCARTRAM: u8 = 0x08;
-    const PAGEOFRAM: u8 = 0x04;
+    // Power on values, with the cartridge or the BIOS enabled.
+    const CARTRIDGE_MEMORY_CONTROL: u8 = 0xAB;
+    const BIOS_MEMORY_CONTROL: u8 = 0xE3;
 
     // Memory map offsets
     const PAGE0: u16 = 0x400; // 0 to Page0 offset always holds bank 0
@@ -84,59 +116,154 @@ impl MemoryBase {
 }
 
 type BankSizeType = u16;
-type NumBanksType = u8;
 pub type AddressType = u16;
 type AbsoluteAddressType = u32;
 
-//const fn max<T: ~const PartialOrd + Copy>(a: T, b: T) -> T {
-//    [a, b][(a < b) as usize]
-//}
-const fn max(a: AbsoluteAddressType, b: AbsoluteAddressType) -> AbsoluteAddressType {
-    [a, b][(a < b) as usize]
-}
-
 impl MemoryAbsoluteConstants {
-    const ABSOLUTE_PAGE_0_ROM_OFFSET: AbsoluteAddressType = 0x000000;
-    const ABSOLUTE_PAGE_X_ROM_OFFSET: AbsoluteAddressType = 0x100000;
-    const ABSOLUTE_CART_RAM_OFFSET: AbsoluteAddressType = 0x200000;
-    const ABSOLUTE_SYS_RAM_OFFSET: AbsoluteAddressType = 0x208000;
+    const ABSOLUTE_CART_RAM_OFFSET: AbsoluteAddressType = 0x000000;
+    const ABSOLUTE_SYS_RAM_OFFSET: AbsoluteAddressType = 0x008000;
+    const ABSOLUTE_ROM_OFFSET: AbsoluteAddressType = 0x010000;
     const ABSOLUTE_SEGMENT_SIZE: AbsoluteAddressType = 0x2000;
+    const ABSOLUTE_CART_RAM_SIZE: AbsoluteAddressType =
+        MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE * 4;
+    const ABSOLUTE_RAM_SIZE: AbsoluteAddressType = MemoryAbsoluteConstants::ABSOLUTE_SYS_RAM_OFFSET
+        + MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE;
+    const ABSOLUTE_OPEN_BUS_OFFSET: AbsoluteAddressType = 0x00A000;
+
+    const DEFAULT_ROM_SIZE: AbsoluteAddressType = 0x100000; // Before a cartridge is loaded.
 }
 
 impl MemoryAbsolute {
     pub fn new() -> Self {
-        // TODO: Improve auto increment the offsets
-        let mut index = 0;
-        fn new_segment(index: &mut u32) -> u32 {
-            *index += 1;
-            MemoryAbsoluteConstants::ABSOLUTE_PAGE_X_ROM_OFFSET
-                + (MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE * *index)
-        }
-
-        Self {
-            upper_mappings: vec![
-                MemoryAbsoluteConstants::ABSOLUTE_PAGE_0_ROM_OFFSET,
-                new_segment(&mut index),
-                new_segment(&mut index),
-                new_segment(&mut index),
-                new_segment(&mut index),
-                new_segment(&mut index),
-                MemoryAbsoluteConstants::ABSOLUTE_SYS_RAM_OFFSET,
-                MemoryAbsoluteConstants::ABSOLUTE_SYS_RAM_OFFSET,
-            ],
+        let mut memory = Self {
+            mapper: Box::new(mapper::SegaMapper::new()),
+            memory_control: MemoryBase::CARTRIDGE_MEMORY_CONTROL,
+            bios: None,
+            bios_mapper: Box::new(mapper::SegaMapper::new()),
+            bios_offset: 0,
+            bios_size: 0,
+            // System RAM (and its mirror) at the top, the rest is up to the mapper.
+            upper_mappings: vec![MemoryAbsoluteConstants::ABSOLUTE_SYS_RAM_OFFSET; 8],
 
             // Complete memory map
             memory_map: vec![
                 0;
-                max(
-                    MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_OFFSET
-                        + MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE,
-                    MemoryAbsoluteConstants::ABSOLUTE_SYS_RAM_OFFSET
-                        + MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE
-                ) as usize
+                (MemoryAbsoluteConstants::ABSOLUTE_ROM_OFFSET
+                    + MemoryAbsoluteConstants::DEFAULT_ROM_SIZE * 2)
+                    as usize
             ],
-            page_2: 0,
-            ram_select: 0,
+            rom_size: MemoryAbsoluteConstants::DEFAULT_ROM_SIZE,
+            battery_name: String::new(),
+            cart_ram_dirty: false,
+            cartridge_info: None,
+            romdb: romdb::RomDb::embedded(),
+            rom_entry: None,
+        };
+        memory.memory_map[MemoryAbsoluteConstants::ABSOLUTE_OPEN_BUS_OFFSET as usize
+            ..(MemoryAbsoluteConstants::ABSOLUTE_OPEN_BUS_OFFSET
+                + MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE) as usize]
+            .fill(0xFF);
+        memory.bios_offset = memory.memory_map.len() as AbsoluteAddressType;
+        memory.map_pages();
+        memory.cart_ram_dirty = false;
+        memory
+    }
+
+    // BIOS image, mapped in at power on (instead of the cartridge) from the next 'reset'.
+    pub fn set_bios(&mut self, bios: Vec<u8>) {
+        self.bios = Some(bios);
+    }
+
+    // The slot mapped to 0x0000 - 0xBFFF, the BIOS takes priority.
+    pub fn active_slot(&self) -> Slot {
+        if self.bios_size > 0 && 0 == self.memory_control & MemoryBase::BIOS_DISABLE {
+            Slot::Bios
+        } else if 0 == self.memory_control & MemoryBase::CARTRIDGE_DISABLE {
+            Slot::Cartridge
+        } else {
+            Slot::Empty
+        }
+    }
+
+    pub fn get_memory_control(&self) -> u8 {
+        self.memory_control
+    }
+
+    // Write to the memory control port (0x3E).
+    pub fn memory_control(&mut self, value: u8) {
+        self.memory_control = value;
+        self.map_pages();
+    }
+
+    // Where the copy of the ROM with the fixed first 1K starts.
+    fn fixed_rom_offset(&self) -> AbsoluteAddressType {
+        MemoryAbsoluteConstants::ABSOLUTE_ROM_OFFSET + self.rom_size
+    }
+
+    // Where the bit reversed copy of the ROM starts (if there is one).
+    fn reversed_rom_offset(&self) -> AbsoluteAddressType {
+        MemoryAbsoluteConstants::ABSOLUTE_ROM_OFFSET + self.rom_size * 2
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.rom_size as usize
+    }
+
+    pub fn mapper_type(&self) -> romdb::MapperType {
+        self.mapper.mapper_type()
+    }
+
+    // Memory map offset of a ROM 'page', for a ROM at 'rom_offset' (followed by
+    // its fixed first 1K and bit reversed copies).  Bank numbers past the end of
+    // the ROM wrap around (mirror).
+    fn rom_mapping(
+        page: mapper::Page,
+        rom_offset: AbsoluteAddressType,
+        rom_size: AbsoluteAddressType,
+    ) -> AbsoluteAddressType {
+        match page {
+            mapper::Page::Rom(offset) => rom_offset + offset % rom_size,
+            mapper::Page::RomFixed(offset) => rom_offset + rom_size + offset % rom_size,
+            mapper::Page::RomReversed(offset) => rom_offset + rom_size * 2 + offset % rom_size,
+            mapper::Page::CartRam(offset) => {
+                MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_OFFSET + offset
+            }
+        }
+    }
+
+    // Map the segments from the enabled slot's mapper (and the system RAM, if enabled).
+    fn map_pages(&mut self) {
+        let slot = self.active_slot();
+        for segment in 0..mapper::SEGMENTS {
+            self.upper_mappings[segment] = match slot {
+                Slot::Bios => Self::rom_mapping(
+                    self.bios_mapper.page(segment),
+                    self.bios_offset,
+                    self.bios_size,
+                ),
+                Slot::Cartridge => {
+                    let page = self.mapper.page(segment);
+                    if let mapper::Page::CartRam(_) = page {
+                        // Count as changed once it's been mapped in.
+                        self.cart_ram_dirty = true;
+                    }
+                    Self::rom_mapping(
+                        page,
+                        MemoryAbsoluteConstants::ABSOLUTE_ROM_OFFSET,
+                        self.rom_size,
+                    )
+                }
+                Slot::Empty => MemoryAbsoluteConstants::ABSOLUTE_OPEN_BUS_OFFSET,
+            };
+        }
+
+        let ram = if 0 == self.memory_control & MemoryBase::RAM_DISABLE {
+            MemoryAbsoluteConstants::ABSOLUTE_SYS_RAM_OFFSET
+        } else {
+            MemoryAbsoluteConstants::ABSOLUTE_OPEN_BUS_OFFSET
+        };
+        for mapping in self.upper_mappings[mapper::SEGMENTS..].iter_mut() {
+            *mapping = ram;
         }
     }
 
@@ -144,6 +271,25 @@ impl MemoryAbsolute {
         self.upper_mappings[(address >> 13) as usize] | (address & 0x1FFF) as AbsoluteAddressType
     }
 
+    pub fn rom_bank(&self, address: AddressType) -> Option<u8> {
+        let mapping = self.upper_mappings[(address >> 13) as usize];
+        if mapping < MemoryAbsoluteConstants::ABSOLUTE_ROM_OFFSET {
+            return None;
+        }
+        let (rom_offset, rom_size) = if mapping >= self.bios_offset {
+            (self.bios_offset, self.bios_size)
+        } else {
+            (MemoryAbsoluteConstants::ABSOLUTE_ROM_OFFSET, self.rom_size)
+        };
+        if (mapping - rom_offset) / rom_size == 1 && address < MemoryBase::PAGE0 {
+            // Always bank 0, regardless of the page 0 bank select.
+            Some(0)
+        } else {
+            let rom_offset = (mapping - rom_offset) % rom_size;
+            Some((rom_offset / MemoryBase::BANK_SIZE as AbsoluteAddressType) as u8)
+        }
+    }
+
     pub fn read(&self, address: AddressType) -> u8 {
         self.memory_map[(self.upper_mappings[(address >> 13) as usize]
             | (address & 0x1FFF) as AbsoluteAddressType) as usize]
@@ -151,16 +297,109 @@ impl MemoryAbsolute {
 
     pub fn reset(&mut self, cartridge_name: &str) {
         let mut cartridge = cartridge::Cartridge::new(cartridge_name);
-        match cartridge.load() {
+        let loaded = match cartridge.load() {
             Ok(()) => {
                 println!("Ok");
+                true
             }
             _ => {
                 println!("Error loading cartridge.");
+                false
             }
+        };
+
+        self.cartridge_info = cartridge.info.clone();
+        self.rom_entry = if loaded {
+            self.romdb.lookup(cartridge.crc32).cloned()
+        } else {
+            None
+        };
+        if let Some(rom_entry) = &self.rom_entry {
+            println!("ROM database: {}", rom_entry);
         }
+        self.populate_absolute_memory_map(cartridge);
 
-        self.initialise_read(cartridge);
+        let mapper_type = match self.rom_entry.as_ref().and_then(|entry| entry.mapper) {
+            Some(mapper_type) => mapper_type,
+            None => mapper::detect_mapper(self.rom()),
+        };
+        println!("Mapper: {:?}", mapper_type);
+        self.mapper = mapper::new_mapper(mapper_type);
+        self.populate_reversed_rom();
+        self.populate_bios();
+        self.initialise_read();
+
+        self.battery_name = String::new();
+        self.cart_ram_dirty = false;
+        if loaded {
+            self.battery_name = battery::save_name(cartridge_name, self.rom());
+            self.load_cart_ram();
+        }
+    }
+
+    fn cart_ram_mut(&mut self) -> &mut [u8] {
+        let start = MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_OFFSET as usize;
+        &mut self.memory_map
+            [start..start + MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_SIZE as usize]
+    }
+
+    fn load_cart_ram(&mut self) {
+        match battery::load(&self.battery_name) {
+            Ok(data) => {
+                if data.len() == MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_SIZE as usize {
+                    self.cart_ram_mut().copy_from_slice(&data);
+                    println!("Loaded cartridge RAM from {}", self.battery_name);
+                } else {
+                    println!(
+                        "Ignoring cartridge RAM in {}, expected {} bytes (found {})",
+                        self.battery_name,
+                        MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_SIZE,
+                        data.len()
+                    );
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                println!(
+                    "Unable to load cartridge RAM from {}: {}",
+                    self.battery_name, e
+                );
+            }
+        }
+    }
+
+    // Replace the ROM database consulted on 'reset'.
+    pub fn set_romdb(&mut self, romdb: romdb::RomDb) {
+        self.romdb = romdb;
+    }
+
+    pub fn rom_entry(&self) -> Option<&romdb::RomEntry> {
+        self.rom_entry.as_ref()
+    }
+
+    // The parsed ROM header, 'None' if the cartridge doesn't have one.
+    pub fn cartridge_info(&self) -> Option<&header::CartridgeInfo> {
+        self.cartridge_info.as_ref()
+    }
+
+    pub fn battery_name(&self) -> &str {
+        &self.battery_name
+    }
+
+    // Save the cartridge RAM, if it's been enabled or written since it was
+    // last saved.  Returns 'true' if it was saved.
+    pub fn save_cart_ram(&mut self) -> io::Result<bool> {
+        if !self.cart_ram_dirty || self.battery_name.is_empty() {
+            return Ok(false);
+        }
+        let start = MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_OFFSET as usize;
+        battery::save(
+            &self.battery_name,
+            &self.memory_map
+                [start..start + MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_SIZE as usize],
+        )?;
+        self.cart_ram_dirty = false;
+        Ok(true)
     }
 
     pub fn write(&mut self, address: AddressType, data: u8) {
@@ -168,39 +407,91 @@ impl MemoryAbsolute {
         self.private_write(address, data)
     }
 
-    fn initialise_read(&mut self, cartridge: cartridge::Cartridge) {
+    fn initialise_read(&mut self) {
         // Un-optimised address translation, uses paging registers.
+        self.mapper.reset();
+        self.bios_mapper.reset();
+        self.memory_control = if self.bios_size > 0 {
+            MemoryBase::BIOS_MEMORY_CONTROL
+        } else {
+            MemoryBase::CARTRIDGE_MEMORY_CONTROL
+        };
+        self.map_pages();
+    }
 
-        self.populate_absolute_memory_map(cartridge);
-        self.write(0xFFFC, 0);
-        self.write(0xFFFD, 0);
-        self.write(0xFFFE, 1);
-        self.write(0xFFFF, 2);
-    }
-
-    fn populate_absolute_memory_map(&mut self, mut cartridge: cartridge::Cartridge) {
-        for bank in 0..cartridge.num_banks as NumBanksType {
-            // Page '0'/'1' lookup
-            for address in 0..MemoryBase::PAGE0 as BankSizeType {
-                let bank_address = address & MemoryBase::LOWERMASK; // BANK_MASK
-                let bank_offset = (bank_address as AbsoluteAddressType)
-                    + (bank as AbsoluteAddressType)
-                        * (MemoryBase::BANK_SIZE as AbsoluteAddressType);
-                self.memory_map[(MemoryAbsoluteConstants::ABSOLUTE_PAGE_0_ROM_OFFSET + bank_offset)
-                    as usize] = cartridge.read(0, bank_address);
-                self.memory_map[(MemoryAbsoluteConstants::ABSOLUTE_PAGE_X_ROM_OFFSET + bank_offset)
-                    as usize] = cartridge.read(bank, bank_address);
+    fn rom(&self) -> &[u8] {
+        let start = MemoryAbsoluteConstants::ABSOLUTE_ROM_OFFSET as usize;
+        &self.memory_map[start..start + self.rom_size as usize]
+    }
+
+    fn populate_absolute_memory_map(&mut self, cartridge: cartridge::Cartridge) {
+        // At least one bank, so there's something to map.
+        let num_banks = cartridge.num_banks.max(1);
+        self.rom_size =
+            num_banks as AbsoluteAddressType * MemoryBase::BANK_SIZE as AbsoluteAddressType;
+
+        let mut rom = vec![0; self.rom_size as usize];
+        for (bank, data) in rom.chunks_mut(MemoryBase::BANK_SIZE as usize).enumerate() {
+            if bank < cartridge.num_banks as usize {
+                data.copy_from_slice(cartridge.bank_data(bank as cartridge::NumBanksType));
             }
+        }
+        self.memory_map
+            .truncate(MemoryAbsoluteConstants::ABSOLUTE_ROM_OFFSET as usize);
+        self.append_rom(&rom);
+    }
+
+    // Add 'rom' (whole banks) to the end of the memory map, followed by a copy
+    // for page '0' lookup (with the first 1K of every bank from bank 0).
+    fn append_rom(&mut self, rom: &[u8]) {
+        self.memory_map.extend_from_slice(rom);
+        let fixed_start = self.memory_map.len();
+        self.memory_map.extend_from_slice(rom);
+        for bank_start in
+            (fixed_start..self.memory_map.len()).step_by(MemoryBase::BANK_SIZE as usize)
+        {
+            self.memory_map.copy_within(
+                fixed_start..fixed_start + MemoryBase::PAGE0 as usize,
+                bank_start,
+            );
+        }
+    }
+
+    // Add the BIOS (if there is one) after the cartridge's ROM.
+    fn populate_bios(&mut self) {
+        self.bios_offset = self.memory_map.len() as AbsoluteAddressType;
+        self.bios_size = 0;
+        let Some(mut bios) = self.bios.clone() else {
+            return;
+        };
+        if bios.is_empty() {
+            return;
+        }
+
+        // Mirror BIOSes smaller than a bank, pad the others to whole banks.
+        while bios.len() < MemoryBase::BANK_SIZE as usize {
+            bios.extend_from_within(..);
+        }
+        bios.resize(
+            bios.len().next_multiple_of(MemoryBase::BANK_SIZE as usize),
+            0,
+        );
+        self.bios_size = bios.len() as AbsoluteAddressType;
+        self.append_rom(&bios);
+    }
 
-            for address in MemoryBase::PAGE0..MemoryBase::PAGE1 as BankSizeType {
-                let bank_address = address & MemoryBase::LOWERMASK; // BANK_MASK
-                let bank_offset = (bank_address as AbsoluteAddressType)
-                    + (bank as AbsoluteAddressType)
-                        * (MemoryBase::BANK_SIZE as AbsoluteAddressType);
-                self.memory_map[(MemoryAbsoluteConstants::ABSOLUTE_PAGE_0_ROM_OFFSET + bank_offset)
-                    as usize] = cartridge.read(bank, bank_address);
-                self.memory_map[(MemoryAbsoluteConstants::ABSOLUTE_PAGE_X_ROM_OFFSET + bank_offset)
-                    as usize] = cartridge.read(bank, bank_address);
+    // Add (or remove) the bit reversed copy of the ROM, depending on the mapper.
+    fn populate_reversed_rom(&mut self) {
+        let start = self.reversed_rom_offset() as usize;
+        self.memory_map.truncate(start);
+        self.bios_offset = start as AbsoluteAddressType;
+        self.bios_size = 0;
+        if self.mapper.reverses_rom() {
+            let rom = MemoryAbsoluteConstants::ABSOLUTE_ROM_OFFSET as usize
+                ..self.fixed_rom_offset() as usize;
+            self.memory_map.extend_from_within(rom);
+            for value in &mut self.memory_map[start..] {
+                *value = value.reverse_bits();
             }
         }
     }
@@ -208,60 +499,22 @@ impl MemoryAbsolute {
     fn private_write(&mut self, address: AddressType, data: u8) {
         let address = address & MemoryBase::ADDRESS_MASK; // ADDRESS_MASK;
 
-        if address >= MemoryBase::RAM_OFFSET && address >= MemoryBase::MEMREGISTERS {
-            // Should make these conditiona,
-            if address == MemoryBase::PAGE0_BANK_SELECT_REGISTER {
-                self.upper_mappings[0] = MemoryAbsoluteConstants::ABSOLUTE_PAGE_0_ROM_OFFSET
-                    + (MemoryBase::BANK_SIZE as AbsoluteAddressType * data as AbsoluteAddressType);
-                self.upper_mappings[1] = MemoryAbsoluteConstants::ABSOLUTE_PAGE_X_ROM_OFFSET
-                    + (MemoryBase::BANK_SIZE as AbsoluteAddressType * data as AbsoluteAddressType)
-                    + MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE;
-            } else if address == MemoryBase::PAGE1_BANK_SELECT_REGISTER {
-                self.upper_mappings[2] = MemoryAbsoluteConstants::ABSOLUTE_PAGE_X_ROM_OFFSET
-                    + (MemoryBase::BANK_SIZE as AbsoluteAddressType * data as AbsoluteAddressType);
-                self.upper_mappings[3] = MemoryAbsoluteConstants::ABSOLUTE_PAGE_X_ROM_OFFSET
-                    + (MemoryBase::BANK_SIZE as AbsoluteAddressType * data as AbsoluteAddressType)
-                    + MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE;
-            } else if (address == MemoryBase::RAM_SELECT_REGISTER)
-                || (address == MemoryBase::PAGE2_BANK_SELECT_REGISTER)
-            {
-                if address == MemoryBase::RAM_SELECT_REGISTER {
-                    self.ram_select = data;
-                } else if address == MemoryBase::PAGE2_BANK_SELECT_REGISTER {
-                    self.page_2 = data;
-                }
-
-                if 0 != self.ram_select & MemoryBase::MAPCARTRAM {
-                    // page2_is_cartridge_ram
-                    // Cart RAM select.
-                    if 0 != self.ram_select & MemoryBase::PAGEOFRAM {
-                        self.upper_mappings[4] = MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_OFFSET
-                            + (MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE * 2);
-                        self.upper_mappings[5] = MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_OFFSET
-                            + (MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE * 3);
-                    } else {
-                        self.upper_mappings[4] = MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_OFFSET;
-                        self.upper_mappings[5] = MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_OFFSET
-                            + MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE;
-                    }
-                } else {
-                    self.upper_mappings[4] = MemoryAbsoluteConstants::ABSOLUTE_PAGE_X_ROM_OFFSET
-                        + (MemoryBase::BANK_SIZE as AbsoluteAddressType
-                            * self.page_2 as AbsoluteAddressType);
-                    self.upper_mappings[5] = MemoryAbsoluteConstants::ABSOLUTE_PAGE_X_ROM_OFFSET
-                        + (MemoryBase::BANK_SIZE as AbsoluteAddressType
-                            * self.page_2 as AbsoluteAddressType)
-                        + MemoryAbsoluteConstants::ABSOLUTE_SEGMENT_SIZE;
-                }
-            }
+        let remap = match self.active_slot() {
+            Slot::Bios => self.bios_mapper.write(address, data),
+            Slot::Cartridge => self.mapper.write(address, data),
+            Slot::Empty => false,
+        };
+        if remap {
+            self.map_pages();
         }
         let absolute_address = self.get_absolute_address(address);
-        if absolute_address
-            >= std::cmp::min(
-                MemoryAbsoluteConstants::ABSOLUTE_CART_RAM_OFFSET,
-                MemoryAbsoluteConstants::ABSOLUTE_SYS_RAM_OFFSET,
-            )
-        {
+        // Only the RAM is writable.
+        if absolute_address < MemoryAbsoluteConstants::ABSOLUTE_RAM_SIZE {
+            if absolute_address < MemoryAbsoluteConstants::ABSOLUTE_SYS_RAM_OFFSET
+                && self.memory_map[absolute_address as usize] != data
+            {
+                self.cart_ram_dirty = true;
+            }
             self.memory_map[absolute_address as usize] = data;
         }
     }
@@ -288,23 +541,105 @@ macro_rules! impl_common_memoryrw {
             ) -> () {
                 self.write(address, data);
             }
+
+            fn rom_bank(&self, address: $crate::sega::memory::memory::AddressType) -> Option<u8> {
+                self.rom_bank(address)
+            }
         }
     };
 }
 
 pub(crate) use impl_common_memoryrw;
 
-impl_common_memoryrw!(MemoryAbsolute);
+// Not the common rules, as it's the only memory with a memory control port.
+impl MemoryRW for MemoryAbsolute {
+    fn read(&self, address: AddressType) -> u8 {
+        self.read(address)
+    }
+
+    // Also create a 'little endian' 16-bit read.
+    fn read16(&self, address: AddressType) -> u16 {
+        self.read(address) as u16 + ((self.read(address + 1) as u16) << 8)
+    }
+
+    fn write(&mut self, address: AddressType, data: u8) {
+        self.write(address, data);
+    }
+
+    fn rom_bank(&self, address: AddressType) -> Option<u8> {
+        self.rom_bank(address)
+    }
+
+    fn memory_control(&mut self, value: u8) {
+        self.memory_control(value);
+    }
+}
+
+// Only the RAM is saved, the ROM comes from the cartridge and BIOS (which have
+// to be the same ones).
+impl savestate::SaveState for MemoryAbsolute {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        writer.write_u8(self.mapper.mapper_type() as u8);
+        writer.write_u32(self.rom_size);
+        writer.write_u32(self.bios_size);
+        writer.write_u8(self.memory_control);
+        self.mapper.save_state(writer);
+        self.bios_mapper.save_state(writer);
+        writer
+            .write_bytes(&self.memory_map[0..MemoryAbsoluteConstants::ABSOLUTE_RAM_SIZE as usize]);
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        let mapper_type = reader.read_u8()?;
+        let rom_size = reader.read_u32()?;
+        if mapper_type != self.mapper.mapper_type() as u8 || rom_size != self.rom_size {
+            return Err(savestate::StateError::Invalid(
+                "saved with a different cartridge".to_string(),
+            ));
+        }
+        if reader.read_u32()? != self.bios_size {
+            return Err(savestate::StateError::Invalid(
+                "saved with a different BIOS".to_string(),
+            ));
+        }
+        self.memory_control = reader.read_u8()?;
+        self.mapper.load_state(reader)?;
+        self.bios_mapper.load_state(reader)?;
+
+        // Save the restored cartridge RAM, if it's different.
+        let cart_ram = self.cart_ram_mut().to_vec();
+        let cart_ram_dirty = self.cart_ram_dirty;
+        reader.read_bytes_into(
+            &mut self.memory_map[0..MemoryAbsoluteConstants::ABSOLUTE_RAM_SIZE as usize],
+        )?;
+        self.map_pages();
+        self.cart_ram_dirty = cart_ram_dirty || self.cart_ram_mut() != cart_ram.as_slice();
+        Ok(())
+    }
+}
 
 pub trait MemoryRW {
     fn read(&self, address: AddressType) -> u8;
     fn read16(&self, address: AddressType) -> u16;
     fn write(&mut self, address: AddressType, data: u8);
+    // Read for the tracer and disassembler, rather than the CPU (so it isn't
+    // watched).
+    fn peek(&self, address: AddressType) -> u8 {
+        self.read(address)
+    }
+    // ROM bank mapped at 'address', 'None' for RAM (or unbanked memory).
+    fn rom_bank(&self, address: AddressType) -> Option<u8>;
+    // Write to the memory control port (0x3E), only the console's memory has one.
+    fn memory_control(&mut self, _value: u8) {}
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::sega::memory::memory::MemoryAbsolute;
+    use crate::sega::memory::memory::{MemoryAbsolute, Slot};
+    use crate::sega::memory::romdb;
     use std::mem;
     #[test]
     fn test_simple_memory_check() {
@@ -318,4 +653,267 @@ mod tests {
             mem::size_of_val(&memory.upper_mappings)
         );
     }
+
+    #[test]
+    fn test_rom_bank() {
+        let mut memory = MemoryAbsolute::new();
+        memory.write(0xFFFD, 1);
+        memory.write(0xFFFE, 3);
+        memory.write(0xFFFF, 5);
+
+        assert_eq!(memory.rom_bank(0x0100), Some(0)); // First 1K is fixed.
+        assert_eq!(memory.rom_bank(0x0400), Some(1));
+        assert_eq!(memory.rom_bank(0x7FFF), Some(3));
+        assert_eq!(memory.rom_bank(0x8000), Some(5));
+        assert_eq!(memory.rom_bank(0xC000), None);
+
+        // Cartridge RAM in page 2.
+        memory.write(0xFFFC, 0x08);
+        assert_eq!(memory.rom_bank(0x8000), None);
+    }
+
+    #[test]
+    fn test_cart_ram_battery() {
+        let rom = std::env::temp_dir().join(format!("battery_{}.sms", std::process::id()));
+        let rom = rom.to_str().unwrap();
+        let save = format!("{}.sav", rom);
+        std::fs::write(rom, vec![0; 0x4000]).unwrap();
+
+        // Nothing to save until the cartridge RAM has been enabled.
+        let mut memory = MemoryAbsolute::new();
+        memory.reset(rom);
+        memory.write(0xC000, 0x42);
+        assert!(!memory.save_cart_ram().unwrap());
+
+        memory.write(0xFFFC, 0x08);
+        memory.write(0x8000, 0x42);
+        memory.write(0xBFFF, 0x43);
+        assert!(memory.save_cart_ram().unwrap());
+        assert!(!memory.save_cart_ram().unwrap());
+        let data = std::fs::read(&save).unwrap();
+        assert_eq!(data.len(), 0x8000);
+        assert_eq!((data[0], data[0x3FFF]), (0x42, 0x43));
+
+        // Loaded on reset.
+        let mut memory = MemoryAbsolute::new();
+        memory.reset(rom);
+        memory.write(0xFFFC, 0x08);
+        assert_eq!((memory.read(0x8000), memory.read(0xBFFF)), (0x42, 0x43));
+
+        std::fs::remove_file(rom).unwrap();
+        std::fs::remove_file(&save).unwrap();
+    }
+
+    // Load a ROM with each 16K bank filled with its bank number, and optionally
+    // a Codemasters header (so the mapper is detected as Codemasters).
+    fn load_banked_rom(
+        name: &str,
+        banks: usize,
+        codemasters_header: bool,
+        romdb_entry: Option<&str>,
+    ) -> MemoryAbsolute {
+        let mut rom: Vec<u8> = (0..banks)
+            .flat_map(|bank| vec![bank as u8; 0x4000])
+            .collect();
+        if codemasters_header {
+            // Codemasters header checksum (and complement).
+            rom[0x7FE6..0x7FEA].copy_from_slice(&[0x34, 0x12, 0xCC, 0xED]);
+        }
+
+        let filename = std::env::temp_dir().join(format!("{}_{}.sms", name, std::process::id()));
+        let filename = filename.to_str().unwrap();
+        std::fs::write(filename, &rom).unwrap();
+
+        let mut memory = MemoryAbsolute::new();
+        if let Some(entry) = romdb_entry {
+            let mut romdb = romdb::RomDb::embedded();
+            romdb
+                .add_entries(&format!("{:08x} {}", romdb::crc32(&rom), entry))
+                .unwrap();
+            memory.set_romdb(romdb);
+        }
+        memory.reset(filename);
+        std::fs::remove_file(filename).unwrap();
+        memory
+    }
+
+    #[test]
+    fn test_codemasters_mapper() {
+        let mut memory = load_banked_rom("codemasters", 8, true, None);
+        assert_eq!(memory.mapper_type(), romdb::MapperType::Codemasters);
+        assert_eq!(
+            (
+                memory.read(0x0000),
+                memory.read(0x4000),
+                memory.read(0x8000)
+            ),
+            (0, 1, 0)
+        );
+
+        // No fixed first 1K, and the Sega registers are just RAM.
+        memory.write(0x0000, 3);
+        memory.write(0x4000, 4);
+        memory.write(0x8000, 5);
+        memory.write(0xFFFF, 6);
+        assert_eq!(
+            (
+                memory.read(0x0000),
+                memory.read(0x3FFF),
+                memory.read(0x4000)
+            ),
+            (3, 3, 4)
+        );
+        assert_eq!((memory.read(0x8000), memory.read(0xBFFF)), (5, 5));
+        assert_eq!(memory.rom_bank(0x0000), Some(3));
+
+        // Writes to ROM don't stick (other than the registers).
+        memory.write(0xA000, 0x42);
+        assert_eq!(memory.read(0xA000), 5);
+
+        // The RAM variant, 8K mapped at 0xA000 by bit 7 of the 0x4000 register.
+        let mut memory =
+            load_banked_rom("codemasters_ram", 8, false, Some("mapper=codemasters-ram"));
+        assert_eq!(memory.mapper_type(), romdb::MapperType::CodemastersRam);
+        memory.write(0x8000, 5);
+        memory.write(0x4000, 0x82);
+        assert_eq!((memory.read(0x4000), memory.read(0x8000)), (2, 5));
+        memory.write(0xA000, 0x42);
+        memory.write(0xBFFF, 0x43);
+        assert_eq!((memory.read(0xA000), memory.read(0xBFFF)), (0x42, 0x43));
+        assert_eq!(memory.rom_bank(0xA000), None);
+
+        memory.write(0x4000, 0x02);
+        assert_eq!(memory.read(0xA000), 5);
+        memory.write(0x4000, 0x80);
+        assert_eq!(memory.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn test_sega_mapper_selected() {
+        // The database entry takes precedence over the Codemasters header.
+        let mut memory = load_banked_rom("sega_mapper", 8, true, Some("mapper=sega"));
+        assert_eq!(memory.mapper_type(), romdb::MapperType::Sega);
+        assert_eq!(
+            (
+                memory.read(0x0000),
+                memory.read(0x4000),
+                memory.read(0x8000)
+            ),
+            (0, 1, 2)
+        );
+        memory.write(0xFFFD, 3);
+        assert_eq!((memory.read(0x03FF), memory.read(0x0400)), (0, 3));
+    }
+
+    #[test]
+    fn test_janggun_reversed() {
+        let mut memory = load_banked_rom("janggun", 8, false, Some("mapper=janggun"));
+        assert_eq!(memory.mapper_type(), romdb::MapperType::Janggun);
+        memory.write(0xFFFF, 0x43);
+        assert_eq!(memory.read(0x8000), 0xC0); // 0x03 reversed.
+        assert_eq!(memory.rom_bank(0x8000), Some(3));
+        memory.write(0xFFFF, 0x03);
+        assert_eq!(memory.read(0x8000), 0x03);
+
+        // 8K pages.
+        memory.write(0xA000, 0x09);
+        assert_eq!((memory.read(0x8000), memory.read(0xA000)), (3, 4));
+    }
+
+    #[test]
+    fn test_bios_memory_control() {
+        let filename = std::env::temp_dir().join(format!("bios_{}.sms", std::process::id()));
+        let filename = filename.to_str().unwrap();
+        let rom: Vec<u8> = (0..4).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        std::fs::write(filename, &rom).unwrap();
+
+        // An 8K BIOS, mirrored to fill a bank.
+        let mut memory = MemoryAbsolute::new();
+        memory.set_bios(vec![0xB0; 0x2000]);
+        memory.reset(filename);
+        std::fs::remove_file(filename).unwrap();
+        assert_eq!(memory.active_slot(), Slot::Bios);
+        assert_eq!(memory.get_memory_control(), 0xE3);
+        assert_eq!(
+            (
+                memory.read(0x0000),
+                memory.read(0x3FFF),
+                memory.read(0x8000)
+            ),
+            (0xB0, 0xB0, 0xB0)
+        );
+        assert_eq!(memory.rom_bank(0x0000), Some(0));
+        memory.write(0xC000, 0x42);
+
+        // The BIOS enables the cartridge, the RAM is kept.
+        memory.memory_control(0xAB);
+        assert_eq!(memory.active_slot(), Slot::Cartridge);
+        assert_eq!(
+            (
+                memory.read(0x0000),
+                memory.read(0x4000),
+                memory.read(0xC000)
+            ),
+            (0, 1, 0x42)
+        );
+        memory.write(0xFFFF, 3);
+        assert_eq!(memory.read(0x8000), 3);
+        assert_eq!(memory.rom_bank(0x8000), Some(3));
+
+        // Nothing in the card slot.
+        memory.memory_control(0xCB);
+        assert_eq!(memory.active_slot(), Slot::Empty);
+        assert_eq!((memory.read(0x0000), memory.read(0xC000)), (0xFF, 0x42));
+        assert_eq!(memory.rom_bank(0x0000), None);
+
+        // RAM disabled, writes are lost.
+        memory.memory_control(0xBB);
+        memory.write(0xC000, 0x24);
+        assert_eq!(memory.read(0xC000), 0xFF);
+        memory.memory_control(0xAB);
+        assert_eq!((memory.read(0x8000), memory.read(0xC000)), (3, 0x42));
+
+        // Without a BIOS the cartridge is enabled from power on.
+        let memory = load_banked_rom("no_bios", 4, false, None);
+        assert_eq!(memory.mapper_type(), romdb::MapperType::Sega);
+        assert_eq!(memory.active_slot(), Slot::Cartridge);
+        assert_eq!(memory.get_memory_control(), 0xAB);
+    }
+
+    #[test]
+    fn test_rom_sizes() {
+        // 48K, bank numbers wrap at the end of the ROM.
+        let mut memory = load_banked_rom("rom_48k", 3, false, Some("mapper=sega"));
+        assert_eq!(memory.rom_size(), 0xC000);
+        for (bank, expected) in [(2, 2), (3, 0), (4, 1), (0xFF, 0)] {
+            memory.write(0xFFFF, bank);
+            assert_eq!(memory.read(0x8000), expected, "bank {}", bank);
+            assert_eq!(memory.rom_bank(0x8000), Some(expected));
+        }
+
+        // 1.5M, larger than the old 1M limit.
+        let mut memory = load_banked_rom("rom_1536k", 96, false, Some("mapper=sega"));
+        assert_eq!(memory.rom_size(), 96 * 0x4000);
+        for (bank, expected) in [(0x40, 0x40), (0x5F, 0x5F), (0x60, 0), (0x80, 0x20)] {
+            memory.write(0xFFFE, bank);
+            assert_eq!(memory.read(0x4000), expected, "bank {}", bank);
+        }
+        // The first 1K is still fixed.
+        memory.write(0xFFFD, 0x5F);
+        assert_eq!((memory.read(0x03FF), memory.read(0x0400)), (0, 0x5F));
+        assert_eq!(
+            (memory.rom_bank(0x03FF), memory.rom_bank(0x0400)),
+            (Some(0), Some(0x5F))
+        );
+
+        // 4M, every bank the 8-bit registers can select.
+        let mut memory = load_banked_rom("rom_4m", 256, false, Some("mapper=sega"));
+        memory.write(0xFFFF, 0xFF);
+        assert_eq!(memory.read(0xBFFF), 0xFF);
+
+        // 8K pages wrap too.
+        let mut memory = load_banked_rom("rom_msx", 3, false, Some("mapper=msx"));
+        memory.write(0x0000, 7); // 8K page 7, 16K bank 3 -> bank 0.
+        assert_eq!(memory.read(0x8000), 0);
+    }
 }
@@ -1,2 +1,6 @@
+pub mod battery;
 pub mod cartridge;
+pub mod header;
+pub mod mapper;
 pub mod memory;
+pub mod romdb;
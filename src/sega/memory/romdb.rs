@@ -0,0 +1,328 @@
+// ROM database, keyed by the CRC32 of the ROM.  For the games that need a
+// particular mapper, region, input device (etc) that can't be told from the
+// header.  The format is described in 'romdb.txt' (the embedded database).
+use std::collections::HashMap;
+use std::fmt;
+
+const EMBEDDED: &str = include_str!("romdb.txt");
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapperType {
+    Sega,
+    Codemasters,
+    CodemastersRam, // With 8K of on-cartridge RAM.
+    Korean,
+    Msx,
+    Nemesis,
+    FourPak,
+    Janggun,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum System {
+    Sms,
+    GameGear,
+    Sg1000,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Nationality {
+    Japanese,
+    Export,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VideoStandard {
+    Ntsc,
+    Pal,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Device {
+    Joypad,
+    Paddle,
+    LightPhaser,
+    SportsPad,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quirk {
+    Sms1Vdp,   // Relies on the original (315-5124) VDP.
+    Glasses3d, // Uses the 3D glasses.
+    GgSmsMode, // Game Gear game that runs in SMS mode.
+}
+
+// Settings for a ROM, 'None' where the database doesn't say.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RomEntry {
+    pub mapper: Option<MapperType>,
+    pub system: Option<System>,
+    pub nationality: Option<Nationality>,
+    pub video: Option<VideoStandard>,
+    pub device: Option<Device>,
+    pub quirks: Vec<Quirk>,
+}
+
+impl fmt::Display for RomEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut settings = Vec::new();
+        if let Some(mapper) = self.mapper {
+            settings.push(format!("mapper: {:?}", mapper));
+        }
+        if let Some(system) = self.system {
+            settings.push(format!("system: {:?}", system));
+        }
+        if let Some(nationality) = self.nationality {
+            settings.push(format!("region: {:?}", nationality));
+        }
+        if let Some(video) = self.video {
+            settings.push(format!("tv: {:?}", video));
+        }
+        if let Some(device) = self.device {
+            settings.push(format!("device: {:?}", device));
+        }
+        if !self.quirks.is_empty() {
+            settings.push(format!("quirks: {:?}", self.quirks));
+        }
+        write!(f, "{}", settings.join(" "))
+    }
+}
+
+fn parse_value<T: Copy>(key: &str, value: &str, values: &[(&str, T)]) -> Result<T, String> {
+    values
+        .iter()
+        .find(|(name, _)| *name == value)
+        .map(|(_, setting)| *setting)
+        .ok_or_else(|| format!("unknown {} '{}'", key, value))
+}
+
+impl Nationality {
+    // From a 'region' setting (or option), 'japan' or 'export'.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        parse_value(
+            "region",
+            value,
+            &[
+                ("japan", Nationality::Japanese),
+                ("export", Nationality::Export),
+            ],
+        )
+    }
+}
+
+impl VideoStandard {
+    // From a 'tv' setting (or option), 'ntsc' or 'pal'.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        parse_value(
+            "tv",
+            value,
+            &[("ntsc", VideoStandard::Ntsc), ("pal", VideoStandard::Pal)],
+        )
+    }
+}
+
+impl RomEntry {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "mapper" => {
+                self.mapper = Some(parse_value(
+                    key,
+                    value,
+                    &[
+                        ("sega", MapperType::Sega),
+                        ("codemasters", MapperType::Codemasters),
+                        ("codemasters-ram", MapperType::CodemastersRam),
+                        ("korean", MapperType::Korean),
+                        ("msx", MapperType::Msx),
+                        ("nemesis", MapperType::Nemesis),
+                        ("4pak", MapperType::FourPak),
+                        ("janggun", MapperType::Janggun),
+                    ],
+                )?)
+            }
+            "system" => {
+                self.system = Some(parse_value(
+                    key,
+                    value,
+                    &[
+                        ("sms", System::Sms),
+                        ("gg", System::GameGear),
+                        ("sg1000", System::Sg1000),
+                    ],
+                )?)
+            }
+            "region" => self.nationality = Some(Nationality::parse(value)?),
+            "tv" => self.video = Some(VideoStandard::parse(value)?),
+            "device" => {
+                self.device = Some(parse_value(
+                    key,
+                    value,
+                    &[
+                        ("joypad", Device::Joypad),
+                        ("paddle", Device::Paddle),
+                        ("lightphaser", Device::LightPhaser),
+                        ("sportspad", Device::SportsPad),
+                    ],
+                )?)
+            }
+            "quirks" => {
+                for quirk in value.split(',') {
+                    self.quirks.push(parse_value(
+                        "quirk",
+                        quirk,
+                        &[
+                            ("sms1-vdp", Quirk::Sms1Vdp),
+                            ("3d-glasses", Quirk::Glasses3d),
+                            ("gg-sms-mode", Quirk::GgSmsMode),
+                        ],
+                    )?);
+                }
+            }
+            _ => return Err(format!("unknown setting '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+pub struct RomDb {
+    entries: HashMap<u32, RomEntry>,
+}
+
+impl RomDb {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    // The database built into the emulator.
+    pub fn embedded() -> Self {
+        let mut romdb = Self::new();
+        romdb
+            .add_entries(EMBEDDED)
+            .expect("Embedded ROM database should be valid");
+        romdb
+    }
+
+    // The embedded database, with the entries in 'filename' taking precedence.
+    pub fn with_overrides(filename: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(filename).map_err(|e| e.to_string())?;
+        let mut romdb = Self::embedded();
+        romdb.add_entries(&text)?;
+        Ok(romdb)
+    }
+
+    // Add (or replace) the entries described by 'text'.
+    pub fn add_entries(&mut self, text: &str) -> Result<(), String> {
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let Some(crc) = fields.next() else {
+                continue;
+            };
+
+            let error = |e: String| format!("line {}: {}", line_number + 1, e);
+            let crc = u32::from_str_radix(crc.trim_start_matches("0x"), 16)
+                .map_err(|_| error(format!("invalid CRC32 '{}'", crc)))?;
+            let mut entry = RomEntry::default();
+            for field in fields {
+                let (key, value) = field
+                    .split_once('=')
+                    .ok_or_else(|| error(format!("expected 'key=value', found '{}'", field)))?;
+                entry.set(key, value).map_err(error)?;
+            }
+            self.entries.insert(crc, entry);
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, crc: u32) -> Option<&RomEntry> {
+        self.entries.get(&crc)
+    }
+}
+
+impl Default for RomDb {
+    fn default() -> Self {
+        Self::embedded()
+    }
+}
+
+// CRC32 (IEEE, as used by zip and the ROM databases).
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, value| {
+        (0..8).fold(crc ^ *value as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::memory::romdb;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(romdb::crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(romdb::crc32(&[]), 0);
+    }
+
+    #[test]
+    fn test_romdb() {
+        let mut romdb = romdb::RomDb::embedded();
+        let entry = romdb.lookup(0xA577CE46).unwrap();
+        assert_eq!(entry.mapper, Some(romdb::MapperType::Codemasters));
+        assert_eq!(entry.video, Some(romdb::VideoStandard::Pal));
+        assert_eq!(
+            romdb.lookup(0x5E53C7F7).unwrap().mapper,
+            Some(romdb::MapperType::CodemastersRam)
+        );
+        assert_eq!(romdb.lookup(0x12345678), None);
+
+        // Later entries replace earlier ones.
+        romdb
+            .add_entries(
+                "# Comment\n\
+                 \n\
+                 0xa577ce46 system=gg region=japan device=paddle quirks=sms1-vdp,3d-glasses # Replaced\n\
+                 12345678 tv=ntsc\n",
+            )
+            .unwrap();
+        let entry = romdb.lookup(0xA577CE46).unwrap();
+        assert_eq!(
+            *entry,
+            romdb::RomEntry {
+                mapper: None,
+                system: Some(romdb::System::GameGear),
+                nationality: Some(romdb::Nationality::Japanese),
+                video: None,
+                device: Some(romdb::Device::Paddle),
+                quirks: vec![romdb::Quirk::Sms1Vdp, romdb::Quirk::Glasses3d],
+            }
+        );
+        assert_eq!(
+            format!("{}", entry),
+            "system: GameGear region: Japanese device: Paddle quirks: [Sms1Vdp, Glasses3d]"
+        );
+        assert_eq!(
+            romdb.lookup(0x12345678).unwrap().video,
+            Some(romdb::VideoStandard::Ntsc)
+        );
+
+        assert_eq!(
+            romdb.add_entries("\n1234 mapper=sage"),
+            Err("line 2: unknown mapper 'sage'".to_string())
+        );
+        assert_eq!(
+            romdb.add_entries("1234 colour=red"),
+            Err("line 1: unknown setting 'colour'".to_string())
+        );
+        assert_eq!(
+            romdb.add_entries("1234 pal"),
+            Err("line 1: expected 'key=value', found 'pal'".to_string())
+        );
+        assert_eq!(
+            romdb.add_entries("game mapper=sega"),
+            Err("line 1: invalid CRC32 'game'".to_string())
+        );
+    }
+}
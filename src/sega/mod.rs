@@ -1,9 +1,12 @@
 pub mod audio;
 pub mod clocks;
 pub mod cpu;
+pub mod debugger;
 pub mod graphics;
 pub mod inputs;
 pub mod interruptor;
 pub mod memory;
 pub mod ports;
+pub mod rewind;
+pub mod savestate;
 pub mod sega;
@@ -1,6 +1,8 @@
 use super::audio::sound;
 use super::clocks;
+use super::debugger::watch;
 use super::inputs;
+use super::savestate;
 
 struct NullPort {}
 
@@ -15,11 +17,19 @@ pub trait Port {
     fn read(&mut self, clock: &clocks::Clock) -> u8;
 }
 
-pub trait Device {
+pub trait Device: savestate::SaveState {
     fn poll_interrupts(&mut self, raw_display: &mut Vec<u8>, clock: &clocks::Clock) -> bool;
     fn port_write(&mut self, clock: &clocks::Clock, port_address: u8, value: u8);
     fn port_read(&mut self, clock: &clocks::Clock, port_address: u8) -> Option<u8>;
     fn export(&mut self, raw_display: &mut Vec<u8>) -> bool;
+    // Export the last frame, even if it's already been exported (eg after restoring a state).
+    fn redraw(&mut self, raw_display: &mut Vec<u8>);
+    // Clock cycle of the next interrupt (or timing event that leads to one).
+    fn next_interrupt(&self) -> Option<clocks::ClockType>;
+    // A TH line has changed (eg the light phaser), latch the H-counter.
+    fn latch_h_counter(&mut self, _clock: &clocks::Clock) {}
+    // The console's video standard has been chosen (eg a PAL cartridge).
+    fn set_timing(&mut self, _timing: clocks::TimingProfile) {}
 }
 
 impl Port for NullPort {
@@ -37,6 +47,10 @@ pub struct Ports {
     devices: Vec<Box<dyn Device>>,
     pub joysticks: inputs::Joystick,
     pub audio: sound::Sound,
+    pub watchpoints: Vec<watch::Watchpoint>,
+    watch_hit: Option<watch::WatchHit>,
+    // Last write to the memory control port (0x3E), until the memory takes it.
+    memory_control: Option<u8>,
 }
 
 impl Ports {
@@ -52,6 +66,9 @@ impl Ports {
             devices: Vec::new(),
             joysticks: inputs::Joystick::new(),
             audio: sound::Sound::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            memory_control: None,
         }
     }
 
@@ -59,11 +76,23 @@ impl Ports {
         self.devices.push(device);
     }
 
+    pub fn set_timing(&mut self, timing: clocks::TimingProfile) {
+        for device in self.devices.iter_mut() {
+            device.set_timing(timing);
+        }
+    }
+
     pub fn add_port(&mut self, port_address: u8, port: Box<dyn Port>) {
         self.ports[port_address as usize] = port;
     }
 
     pub fn port_read(&mut self, clock: &clocks::Clock, port_address: u8) -> u8 {
+        let value = self.device_port_read(clock, port_address);
+        self.check_watchpoints(port_address, watch::AccessKind::Read, value);
+        value
+    }
+
+    fn device_port_read(&mut self, clock: &clocks::Clock, port_address: u8) -> u8 {
         for i in 0..self.devices.len() {
             if let Some(value) = self.devices[i].port_read(clock, port_address) {
                 return value;
@@ -77,18 +106,52 @@ impl Ports {
         0
     }
 
+    // Keep the first watchpoint hit (until it's taken).
+    fn check_watchpoints(&mut self, port_address: u8, kind: watch::AccessKind, value: u8) {
+        if !self.watchpoints.is_empty() && self.watch_hit.is_none() {
+            self.watch_hit = watch::find_hit(&self.watchpoints, port_address as u16, kind, value);
+        }
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<watch::WatchHit> {
+        self.watch_hit.take()
+    }
+
     pub fn port_write(&mut self, clock: &clocks::Clock, port_address: u8, value: u8) {
+        self.check_watchpoints(port_address, watch::AccessKind::Write, value);
+
         for i in 0..self.devices.len() {
             // TODO: Replace with something useful.
             self.devices[i].port_write(clock, port_address, value);
         }
 
+        self.joysticks.port_write(clock, port_address, value);
+        self.check_h_counter_latch(clock);
+
+        if port_address & 0xC1 == 0x00 {
+            // 3E plus all of the mirror ports.
+            self.memory_control = Some(value);
+        }
+
         if port_address & 0xC0 == 0x40 {
             // 7E + 7F plus all of the pirror ports.
             self.audio.write_port(value);
         }
     }
 
+    // Latch the H-counter, if a TH line has asked for it.
+    fn check_h_counter_latch(&mut self, clock: &clocks::Clock) {
+        if self.joysticks.take_h_counter_latch() {
+            for device in self.devices.iter_mut() {
+                device.latch_h_counter(clock);
+            }
+        }
+    }
+
+    pub fn take_memory_control(&mut self) -> Option<u8> {
+        self.memory_control.take()
+    }
+
     pub fn export(&mut self, raw_display: &mut Vec<u8>) -> bool {
         let mut result = false;
         for i in 0..self.devices.len() {
@@ -97,7 +160,24 @@ impl Ports {
         result
     }
 
+    pub fn redraw(&mut self, raw_display: &mut Vec<u8>) {
+        for device in self.devices.iter_mut() {
+            device.redraw(raw_display);
+        }
+    }
+
+    // Earliest 'next_interrupt' of all of the devices.
+    pub fn next_interrupt(&self) -> Option<clocks::ClockType> {
+        self.devices
+            .iter()
+            .filter_map(|device| device.next_interrupt())
+            .min()
+    }
+
     pub fn poll_interrupts(&mut self, raw_display: &mut Vec<u8>, clock: &clocks::Clock) -> bool {
+        // The light phaser changes TH outside of the port writes.
+        self.check_h_counter_latch(clock);
+
         let mut interrupt = false;
         for i in 0..self.devices.len() {
             interrupt |= self.devices[i].poll_interrupts(raw_display, clock);
@@ -106,3 +186,29 @@ impl Ports {
         interrupt
     }
 }
+
+// The joysticks, audio and devices (in the order they were added), the
+// watchpoints belong to the debugger, so aren't included.
+impl savestate::SaveState for Ports {
+    fn save_state(&self, writer: &mut savestate::StateWriter) {
+        self.joysticks.save_state(writer);
+        self.audio.save_state(writer);
+        writer.write_u32(self.devices.len() as u32);
+        for device in &self.devices {
+            device.save_state(writer);
+        }
+    }
+
+    fn load_state(
+        &mut self,
+        reader: &mut savestate::StateReader,
+    ) -> Result<(), savestate::StateError> {
+        self.joysticks.load_state(reader)?;
+        self.audio.load_state(reader)?;
+        reader.read_length(self.devices.len())?;
+        for device in self.devices.iter_mut() {
+            device.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+// Rewind, a ring buffer of machine snapshots (save states) taken every
+// 'interval' frames.
+//
+// Only the newest snapshot is kept in full, each older one is kept as a delta
+// from the snapshot after it.  Most of a snapshot doesn't change from one to
+// the next (the cartridge RAM and VRAM especially, the ROM isn't saved), so the
+// deltas are stored as runs of unchanged bytes and the (XOR of the) changed
+// bytes.  As XOR is its
+// own inverse, applying a delta to the newer snapshot gives the older one.
+use super::savestate;
+use std::collections::VecDeque;
+
+pub struct Rewind {
+    interval: u32,
+    budget: usize, // Bytes, the oldest snapshots are dropped to stay within it.
+    frames: u32,   // Frames since the last snapshot.
+
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>, // Oldest first, 'deltas.back()' gives the snapshot before 'latest'.
+    deltas_size: usize,
+}
+
+impl Rewind {
+    // Every frame, so rewinding steps back a frame at a time.  A longer
+    // interval keeps more history in the same budget (and takes fewer
+    // snapshots), but then steps back that many frames at a time.
+    pub const DEFAULT_INTERVAL: u32 = 1;
+    pub const DEFAULT_BUDGET: usize = 32 * 1024 * 1024;
+
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    // Number of snapshots that can be stepped back through.
+    pub fn len(&self) -> usize {
+        match self.latest {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.latest.as_ref().map_or(0, |latest| latest.len()) + self.deltas_size
+    }
+
+    pub fn clear(&mut self) {
+        self.frames = 0;
+        self.latest = None;
+        self.deltas.clear();
+        self.deltas_size = 0;
+    }
+
+    // Call once per frame, takes a snapshot every 'interval' frames.
+    pub fn frame<S: savestate::SaveState>(&mut self, state: &S) {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.capture(state);
+        }
+    }
+
+    pub fn capture<S: savestate::SaveState>(&mut self, state: &S) {
+        self.frames = 0;
+        let snapshot = savestate::to_bytes(state);
+
+        if let Some(latest) = self.latest.take() {
+            if latest.len() == snapshot.len() {
+                let delta = encode_delta(&latest, &snapshot);
+                self.deltas_size += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // Can't take a delta, so start again.
+                self.clear();
+            }
+        }
+        self.latest = Some(snapshot);
+
+        while self.memory_used() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => {
+                    // Not even a single snapshot fits.
+                    self.latest = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    // Restore the newest snapshot (dropping it), 'false' if there's none left.
+    pub fn step_back<S: savestate::SaveState>(&mut self, state: &mut S) -> bool {
+        let Some(mut snapshot) = self.latest.take() else {
+            return false;
+        };
+        self.frames = 0;
+
+        if let Err(e) = savestate::from_bytes(state, &snapshot) {
+            println!("Unable to rewind: {}", e);
+            self.clear();
+            return false;
+        }
+
+        if let Some(delta) = self.deltas.pop_back() {
+            self.deltas_size -= delta.len();
+            apply_delta(&delta, &mut snapshot);
+            self.latest = Some(snapshot);
+        }
+        true
+    }
+}
+
+fn write_length(delta: &mut Vec<u8>, mut length: usize) {
+    // LEB128, 7 bits at a time (lowest first).
+    while length >= 0x80 {
+        delta.push((length as u8) | 0x80);
+        length >>= 7;
+    }
+    delta.push(length as u8);
+}
+
+fn read_length(delta: &[u8], index: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let value = delta[*index];
+        *index += 1;
+        length |= ((value & 0x7F) as usize) << shift;
+        if value & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
+
+// Pairs of (unchanged length, changed length, changed bytes XOR'd), the states
+// must be the same length.
+pub fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    assert_eq!(from.len(), to.len());
+    let mut delta = Vec::new();
+    let mut index = 0;
+    while index < to.len() {
+        let start = index;
+        while index < to.len() && from[index] == to[index] {
+            index += 1;
+        }
+        write_length(&mut delta, index - start);
+
+        let start = index;
+        while index < to.len() && from[index] != to[index] {
+            index += 1;
+        }
+        write_length(&mut delta, index - start);
+        delta.extend(
+            from[start..index]
+                .iter()
+                .zip(&to[start..index])
+                .map(|(from, to)| from ^ to),
+        );
+    }
+    delta
+}
+
+// Apply the delta (in place), in either direction.
+pub fn apply_delta(delta: &[u8], state: &mut [u8]) {
+    let mut index = 0;
+    let mut position = 0;
+    while index < delta.len() {
+        position += read_length(delta, &mut index);
+        let length = read_length(delta, &mut index);
+        for (value, change) in state[position..position + length]
+            .iter_mut()
+            .zip(&delta[index..index + length])
+        {
+            *value ^= change;
+        }
+        index += length;
+        position += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::clocks;
+    use crate::sega::rewind;
+    use crate::sega::savestate;
+
+    #[test]
+    fn test_delta() {
+        // A large, mostly unchanged state (like the RAM and VRAM in a snapshot).
+        let from: Vec<u8> = (0..0x200000).map(|i| (i * 7) as u8).collect();
+        let mut to = from.clone();
+        to[0] ^= 1;
+        to[0x1000..0x1010].fill(0xAA);
+        to[0x1FFFFF] = 0x42;
+
+        let delta = rewind::encode_delta(&from, &to);
+        assert!(delta.len() < 40, "{}", delta.len());
+
+        let mut state = from.clone();
+        rewind::apply_delta(&delta, &mut state);
+        assert!(state == to);
+        rewind::apply_delta(&delta, &mut state);
+        assert!(state == from);
+
+        assert!(rewind::encode_delta(&from, &from).len() < 8);
+        assert!(rewind::encode_delta(&[], &[]).is_empty());
+    }
+
+    fn clock(cycles: u64) -> clocks::Clock {
+        let mut clock = clocks::Clock::new();
+        clock.cycles = cycles;
+        clock
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut rewind = rewind::Rewind::new(2, 1024);
+        for cycles in 1..=10 {
+            rewind.frame(&clock(cycles));
+        }
+        assert_eq!(rewind.len(), 5);
+
+        // Steps back through every other frame, newest first.
+        let mut restored = clock(0);
+        for expected in [10, 8, 6, 4, 2] {
+            assert!(rewind.step_back(&mut restored));
+            assert_eq!(restored.cycles, expected);
+        }
+        assert!(!rewind.step_back(&mut restored));
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.memory_used(), 0);
+
+        // The oldest snapshots are dropped to stay within the budget.
+        let snapshot_size = savestate::to_bytes(&clock(0)).len();
+        let mut rewind = rewind::Rewind::new(1, snapshot_size + 20);
+        for cycles in 1..=100 {
+            rewind.capture(&clock(cycles << 40));
+            assert!(rewind.memory_used() <= snapshot_size + 20);
+        }
+        let snapshots = rewind.len();
+        assert!((2..100).contains(&snapshots), "{}", snapshots);
+        for cycles in ((101 - snapshots as u64)..=100).rev() {
+            assert!(rewind.step_back(&mut restored));
+            assert_eq!(restored.cycles, cycles << 40);
+        }
+        assert!(!rewind.step_back(&mut restored));
+    }
+}
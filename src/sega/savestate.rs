@@ -0,0 +1,397 @@
+// Save states, a snapshot of the whole machine.
+//
+// The format is a small header (magic + version), followed by each component
+// writing its own state in a fixed order (see the 'SaveState' implementations).
+// Values are little endian, variable length data is prefixed with its length.
+// Bump 'VERSION' whenever the layout of any component changes.
+use std::fmt;
+use std::fs;
+use std::io;
+
+pub const MAGIC: &[u8; 4] = b"RSMS";
+pub const VERSION: u16 = 8;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Invalid(String), // Doesn't match the machine being restored (eg a buffer size).
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Io(e) => write!(f, "{}", e),
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported save state version {} (expected {})",
+                version, VERSION
+            ),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Invalid(message) => write!(f, "Invalid save state: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> Self {
+        StateError::Io(e)
+    }
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, values: &[u8]) {
+        self.write_u32(values.len() as u32);
+        self.data.extend_from_slice(values);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.position < length {
+            return Err(StateError::Truncated);
+        }
+        let values = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(values)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(StateError::Invalid(format!("bool value {}", value))),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+
+    // Read into an existing (fixed size) buffer, the sizes must match.
+    pub fn read_bytes_into(&mut self, values: &mut [u8]) -> Result<(), StateError> {
+        let data = self.read_bytes()?;
+        if data.len() != values.len() {
+            return Err(StateError::Invalid(format!(
+                "expected {} bytes, found {}",
+                values.len(),
+                data.len()
+            )));
+        }
+        values.copy_from_slice(data);
+        Ok(())
+    }
+
+    // Length of a saved list, which must match the existing one.
+    pub fn read_length(&mut self, expected: usize) -> Result<(), StateError> {
+        let length = self.read_u32()? as usize;
+        if length != expected {
+            return Err(StateError::Invalid(format!(
+                "expected {} entries, found {}",
+                expected, length
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub fn to_bytes<S: SaveState>(state: &S) -> Vec<u8> {
+    let mut writer = StateWriter::new();
+    for value in MAGIC {
+        writer.write_u8(*value);
+    }
+    writer.write_u16(VERSION);
+    state.save_state(&mut writer);
+    writer.into_bytes()
+}
+
+fn load_bytes<S: SaveState>(state: &mut S, data: &[u8]) -> Result<(), StateError> {
+    let mut reader = StateReader::new(data);
+    for value in MAGIC {
+        if reader.read_u8().map_err(|_| StateError::BadMagic)? != *value {
+            return Err(StateError::BadMagic);
+        }
+    }
+    let version = reader.read_u16()?;
+    if version != VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    state.load_state(&mut reader)?;
+    if !reader.is_empty() {
+        return Err(StateError::Invalid(
+            "unexpected data at the end".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Restore a state, on an error the original state is kept (rather than
+// leaving the machine partially restored).
+pub fn from_bytes<S: SaveState>(state: &mut S, data: &[u8]) -> Result<(), StateError> {
+    let original = to_bytes(state);
+    load_bytes(state, data).inspect_err(|_| {
+        load_bytes(state, &original).expect("Unable to restore the original state");
+    })
+}
+
+pub fn save_file<S: SaveState>(state: &S, filename: &str) -> Result<(), StateError> {
+    fs::write(filename, to_bytes(state))?;
+    Ok(())
+}
+
+pub fn load_file<S: SaveState>(state: &mut S, filename: &str) -> Result<(), StateError> {
+    from_bytes(state, &fs::read(filename)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::clocks;
+    use crate::sega::cpu::core;
+    use crate::sega::debugger::watch;
+    use crate::sega::graphics::display;
+    use crate::sega::graphics::vdp;
+    use crate::sega::memory::memory;
+    use crate::sega::memory::romdb;
+    use crate::sega::savestate;
+    use crate::sega::sega;
+
+    #[test]
+    fn test_reader_writer() {
+        let mut writer = savestate::StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789ABCDE);
+        writer.write_u64(0x0123456789ABCDEF);
+        writer.write_bytes(&[1, 2, 3]);
+        let data = writer.into_bytes();
+        assert_eq!(&data[0..4], &[0x12, 0x01, 0x56, 0x34]);
+
+        let mut reader = savestate::StateReader::new(&data);
+        assert_eq!(reader.read_u8().unwrap(), 0x12);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u16().unwrap(), 0x3456);
+        assert_eq!(reader.read_u32().unwrap(), 0x789ABCDE);
+        assert_eq!(reader.read_u64().unwrap(), 0x0123456789ABCDEF);
+        let mut values = [0; 2];
+        assert!(matches!(
+            reader.read_bytes_into(&mut values),
+            Err(savestate::StateError::Invalid(_))
+        ));
+        assert!(reader.is_empty());
+        assert!(matches!(
+            reader.read_u8(),
+            Err(savestate::StateError::Truncated)
+        ));
+    }
+
+    type TestCore = core::Core<watch::WatchedMemory<memory::MemoryAbsolute>>;
+
+    // A program that keeps the VDP (VRAM, name table and sprites), sound and
+    // RAM busy, with a line/frame interrupt handler that reads the VDP.
+    fn test_machine() -> TestCore {
+        let mut rom = vec![0; 0x4000];
+        let mut load = |address: usize, program: &[u8]| {
+            rom[address..address + program.len()].copy_from_slice(program);
+        };
+        load(0x0000, &[0xF3, 0x31, 0xF0, 0xDF, 0xED, 0x56, 0x18, 0x78]); // DI; LD SP,DFF0; IM 1; JR 80
+        load(
+            0x0038,
+            &[
+                0xF5, 0xDB, 0xBF, 0x32, 0x02, 0xC0, 0xDB, 0x7E, 0x32, 0x03, 0xC0, 0xF1, 0xFB, 0xED,
+                0x4D,
+            ], // PUSH AF; IN A,(BF); LD (C002),A; IN A,(7E); LD (C003),A; POP AF; EI; RETI
+        );
+        load(0x0066, &[0xED, 0x45]); // RETN
+        let mut setup = Vec::new();
+        for (low, high) in [
+            (0x14, 0x80), // Mode 4, line interrupts
+            (0x60, 0x81), // Display and frame interrupts enabled
+            (0xFF, 0x82), // Name table at 0x3800
+            (0xFF, 0x85), // Sprite attributes at 0x3F00
+            (0x10, 0x8A), // Line counter
+            (0x00, 0xC0), // CRAM address 0
+        ] {
+            setup.extend_from_slice(&[0x3E, low, 0xD3, 0xBF, 0x3E, high, 0xD3, 0xBF]);
+        }
+        setup.extend_from_slice(&[0x3E, 0x3F, 0xD3, 0xBE, 0x3E, 0x0C, 0xD3, 0xBE]); // Palette
+        setup.extend_from_slice(&[0x3E, 0x00, 0xD3, 0xBF, 0x3E, 0x40, 0xD3, 0xBF]); // VRAM address 0
+        setup.extend_from_slice(&[
+            0xFB, // EI
+            0x7D, 0xD3, 0xBE, // LD A,L; OUT (BE),A
+            0xD3, 0x7F, // OUT (7F),A
+            0x23, // INC HL
+            0x22, 0x00, 0xC1, // LD (C100),HL
+            0x18, 0xF6, // JR (to LD A,L)
+        ]);
+        load(0x0080, &setup);
+
+        let filename = std::env::temp_dir().join(format!("savestate_{}.sms", std::process::id()));
+        std::fs::write(&filename, rom).unwrap();
+        let core = sega::Sega::build_sega(
+            filename.to_str().unwrap(),
+            sega::MachineOptions {
+                romdb: romdb::RomDb::embedded(),
+                bios: None,
+                nationality: None,
+                video: None,
+            },
+        );
+        std::fs::remove_file(&filename).unwrap();
+        core
+    }
+
+    // Clock, PC and whether a frame was exported, after each step.
+    fn run(core: &mut TestCore, steps: u32) -> Vec<(u64, u16, bool)> {
+        (0..steps)
+            .map(|_| {
+                core.step(false).unwrap();
+                let exported = core.export();
+                (core.clock.cycles, core.get_pc_state().get_pc(), exported)
+            })
+            .collect()
+    }
+
+    fn display(core: &mut TestCore) -> Vec<u8> {
+        let mut buffer = vec![
+            0;
+            (vdp::Constants::SMS_WIDTH as usize)
+                * (vdp::Constants::SMS_HEIGHT as usize)
+                * (display::SDLUtility::bytes_per_pixel() as usize)
+        ];
+        core.generate_display(&mut buffer);
+        buffer
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut core = test_machine();
+
+        // Snapshot part way through a frame.
+        while core.clock.cycles < 30000 {
+            core.step(false).unwrap();
+        }
+        let snapshot = savestate::to_bytes(&core);
+
+        let trace = run(&mut core, 20000);
+        let state = savestate::to_bytes(&core);
+        let frame = display(&mut core);
+        assert!(trace.iter().filter(|(_, _, exported)| *exported).count() >= 2);
+        assert!(trace.iter().any(|(_, pc, _)| *pc == 0x38));
+
+        // Restoring in to the same machine, and in to a new one, runs exactly the same.
+        savestate::from_bytes(&mut core, &snapshot).unwrap();
+        assert_eq!(savestate::to_bytes(&core), snapshot);
+        assert!(run(&mut core, 20000) == trace);
+        assert!(savestate::to_bytes(&core) == state);
+
+        let mut restored = test_machine();
+        savestate::from_bytes(&mut restored, &snapshot).unwrap();
+        assert!(run(&mut restored, 20000) == trace);
+        assert!(savestate::to_bytes(&restored) == state);
+        assert!(display(&mut restored) == frame);
+    }
+
+    #[test]
+    fn test_header() {
+        let mut clock = clocks::Clock::new();
+        clock.cycles = 1234;
+        let data = savestate::to_bytes(&clock);
+        assert_eq!(&data[0..4], savestate::MAGIC);
+
+        let mut restored = clocks::Clock::new();
+        savestate::from_bytes(&mut restored, &data).unwrap();
+        assert_eq!(restored.cycles, 1234);
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] ^= 0xFF;
+        let mut bad_version = data.clone();
+        bad_version[4] ^= 0xFF;
+        let mut extra = data.clone();
+        extra.push(0);
+        for (data, expected) in [
+            (&bad_magic, "Not a save state"),
+            (&bad_version, "Unsupported save state version"),
+            (&data[0..data.len() - 1].to_vec(), "Save state is truncated"),
+            (&extra, "Invalid save state"),
+        ] {
+            let mut restored = clocks::Clock::new();
+            restored.cycles = 42;
+            let error = savestate::from_bytes(&mut restored, data).unwrap_err();
+            assert!(format!("{}", error).starts_with(expected), "{}", error);
+            assert_eq!(restored.cycles, 42); // Left unchanged.
+        }
+    }
+}
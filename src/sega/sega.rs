@@ -6,18 +6,44 @@ use sdl2::video;
 use super::audio::sound;
 use super::clocks;
 use super::cpu;
+use super::debugger;
 use super::graphics;
 use super::inputs;
 use super::interruptor;
 use super::memory;
 use super::ports;
+use super::rewind;
+use super::savestate;
+use std::thread;
+use std::time;
+
+type SegaMemory = debugger::watch::WatchedMemory<memory::memory::MemoryAbsolute>;
+type SegaDebugger = Box<dyn debugger::frontend::Frontend<memory::memory::MemoryAbsolute>>;
+
+// Settings for the emulated console (rather than for this run of the emulator).
+#[derive(Default)]
+pub struct MachineOptions {
+    pub romdb: memory::romdb::RomDb,
+    pub bios: Option<Vec<u8>>, // Boots the BIOS, which then starts the cartridge.
+    // 'None' to use the ROM database (or header), otherwise export.
+    pub nationality: Option<memory::romdb::Nationality>,
+    // 'None' to use the ROM database, otherwise NTSC.
+    pub video: Option<memory::romdb::VideoStandard>,
+}
 
 pub struct Sega {
-    core: cpu::core::Core<memory::memory::MemoryAbsolute>,
-    debug: bool,
+    core: cpu::core::Core<SegaMemory>,
+    debugger: Option<SegaDebugger>,
+    rewind: Option<rewind::Rewind>,
+    rewinding: bool,
+    frames_since_cart_ram_save: u32,
     realtime: bool,
     stop_clock: clocks::ClockType,
     fullscreen: bool,
+    cartridge_name: String,
+    state_slot: u8,
+    nationality: Option<memory::romdb::Nationality>, // Chosen region, rather than the cartridge's.
+    video: Option<memory::romdb::VideoStandard>,     // Chosen video standard, likewise.
 
     pub powered: bool,
 
@@ -29,9 +55,14 @@ pub struct Sega {
 
 impl Sega {
     const DISPLAY_UPDATES_PER_KEY_EVENT: u32 = 1; // Number of display updates per key press event. (reduces texture creation overhead).
-    const CPU_STEPS_PER_AUDIO_UPDATE: u32 = 50; // Number of times to step the CPU before updating the audio.
+    const CYCLES_PER_AUDIO_UPDATE: clocks::ClockType = 500; // Number of CPU cycles (about 50 instructions) between audio updates.
+    const CART_RAM_SAVE_FRAMES: u32 = 300; // Number of frames between saves of the cartridge RAM (if it's changed).
+    const REWIND_FRAME_TIME: time::Duration = time::Duration::from_millis(1000 / 60); // Time to show each rewound frame.
 
-    pub fn build_sega(cartridge_name: &str) -> cpu::core::Core<memory::memory::MemoryAbsolute> {
+    pub fn build_sega(
+        cartridge_name: &str,
+        options: MachineOptions,
+    ) -> cpu::core::Core<SegaMemory> {
         let clock = clocks::Clock::new();
         let mut memory = memory::memory::MemoryAbsolute::new();
         let pc_state = cpu::pc_state::PcState::new();
@@ -39,13 +70,88 @@ impl Sega {
         let mut ports = ports::Ports::new();
         let interruptor = interruptor::Interruptor::new();
 
+        memory.set_romdb(options.romdb);
+        if let Some(bios) = options.bios {
+            memory.set_bios(bios);
+        }
+        memory.reset(cartridge_name);
+
         // Add the graphics device to the list of ports.
         // Joysticks are held directly, not as a 'device' (don't need to pass to ports).
         ports.add_device(Box::new(vdp));
 
-        memory.reset(cartridge_name);
+        let mut core = cpu::core::Core::new(
+            clock,
+            debugger::watch::WatchedMemory::new(memory),
+            pc_state,
+            ports,
+            interruptor,
+        );
+        Self::apply_rom_settings(&mut core, options.nationality, options.video);
+        core
+    }
+
+    // Settings that depend on the cartridge, from the ROM database (or header)
+    // unless they've been chosen.  Reapplied whenever a cartridge is loaded.
+    fn apply_rom_settings(
+        core: &mut cpu::core::Core<SegaMemory>,
+        nationality: Option<memory::romdb::Nationality>,
+        video: Option<memory::romdb::VideoStandard>,
+    ) {
+        Self::check_rom_entry(core.memory.rom_entry());
+        let nationality = Self::nationality(nationality, &core.memory);
+        core.ports.joysticks.set_nationality(nationality);
+
+        let timing = clocks::TimingProfile::for_video(Self::video(video, &core.memory));
+        core.ports.set_timing(timing);
+        core.set_timing(timing);
+    }
+
+    // The console's video standard, unless it's been chosen, from the ROM database.
+    fn video(
+        video: Option<memory::romdb::VideoStandard>,
+        memory: &memory::memory::MemoryAbsolute,
+    ) -> memory::romdb::VideoStandard {
+        video
+            .or_else(|| memory.rom_entry().and_then(|entry| entry.video))
+            .unwrap_or(memory::romdb::VideoStandard::Ntsc)
+    }
 
-        cpu::core::Core::new(clock, memory, pc_state, ports, interruptor)
+    // The console's region, unless it's been chosen, from the ROM database and
+    // then the header (the game may still run on either).
+    fn nationality(
+        nationality: Option<memory::romdb::Nationality>,
+        memory: &memory::memory::MemoryAbsolute,
+    ) -> memory::romdb::Nationality {
+        nationality
+            .or_else(|| memory.rom_entry().and_then(|entry| entry.nationality))
+            .or_else(|| {
+                memory
+                    .cartridge_info()
+                    .filter(|info| info.region.is_japanese())
+                    .map(|_| memory::romdb::Nationality::Japanese)
+            })
+            .unwrap_or(memory::romdb::Nationality::Export)
+    }
+
+    // Warn about what the ROM database says the game needs, that isn't emulated.
+    fn check_rom_entry(rom_entry: Option<&memory::romdb::RomEntry>) {
+        let Some(rom_entry) = rom_entry else {
+            return;
+        };
+        if let Some(system) = rom_entry.system {
+            if system != memory::romdb::System::Sms {
+                println!("Warning: {:?} game, running as a Master System.", system);
+            }
+        }
+        if let Some(device) = rom_entry.device {
+            if device != memory::romdb::Device::Joypad {
+                println!(
+                    "Warning: game uses a {:?}, only joypads are supported.",
+                    device
+                );
+            }
+        }
     }
 
     pub fn get_console_size() -> graphics::display::ConsoleSize {
@@ -57,6 +163,7 @@ impl Sega {
 
     pub fn reset(&mut self, cartridge_name: &str) {
         self.core.memory.reset(cartridge_name);
+        Self::apply_rom_settings(&mut self.core, self.nationality, self.video);
         self.core.reset();
     }
 
@@ -74,6 +181,10 @@ impl Sega {
         for event in event_pump.poll_iter() {
             graphics::display::SDLUtility::handle_events(&event);
 
+            if let Some(hotkey) = inputs::Input::state_hotkey(&event) {
+                me.handle_state_hotkey(hotkey);
+            }
+
             if !inputs::Input::handle_events(event, &mut me.core.ports.joysticks) {
                 return false;
             };
@@ -115,19 +226,35 @@ impl Sega {
     }
 
     pub fn new(
-        debug: bool,
+        tracer: Option<cpu::trace::Tracer>,
         realtime: bool,
         stop_clock: clocks::ClockType,
         cartridge_name: &str,
         fullscreen: bool,
+        ignore_faults: bool,
+        options: MachineOptions,
     ) -> Self {
-        let core = Self::build_sega(cartridge_name);
+        let (nationality, video) = (options.nationality, options.video);
+        let mut core = Self::build_sega(cartridge_name, options);
+        if ignore_faults {
+            core.set_fault_policy(cpu::fault::FaultPolicy::TreatAsNop);
+        }
+        if let Some(tracer) = tracer {
+            core.set_tracer(tracer);
+        }
         Self {
             core,
-            debug,
+            debugger: None,
+            rewind: None,
+            rewinding: false,
+            frames_since_cart_ram_save: 0,
             realtime,
             stop_clock,
             fullscreen,
+            cartridge_name: cartridge_name.to_string(),
+            state_slot: 0,
+            nationality,
+            video,
             powered: false,
             sdl_context: None,
             canvas: None,
@@ -135,6 +262,95 @@ impl Sega {
         }
     }
 
+    // Save states are kept next to the cartridge, one file per slot.
+    pub fn state_filename(&self, slot: u8) -> String {
+        format!("{}.s{}", self.cartridge_name, slot)
+    }
+
+    pub fn save_state(&self, filename: &str) -> Result<(), savestate::StateError> {
+        savestate::save_file(&self.core, filename)
+    }
+
+    pub fn load_state(&mut self, filename: &str) -> Result<(), savestate::StateError> {
+        savestate::load_file(&mut self.core, filename)?;
+        // Carry on in real time from the restored clock.
+        self.core.resync_realtime();
+        self.core.redraw();
+        Ok(())
+    }
+
+    fn handle_state_hotkey(&mut self, hotkey: inputs::StateHotkey) {
+        match hotkey {
+            inputs::StateHotkey::SelectSlot(slot) => {
+                self.state_slot = slot;
+                println!("Save state slot {}", slot);
+            }
+            inputs::StateHotkey::Save => {
+                let filename = self.state_filename(self.state_slot);
+                match self.save_state(&filename) {
+                    Ok(()) => println!("Saved state to {}", filename),
+                    Err(e) => println!("Unable to save state to {}: {}", filename, e),
+                }
+            }
+            inputs::StateHotkey::Load => {
+                let filename = self.state_filename(self.state_slot);
+                match self.load_state(&filename) {
+                    Ok(()) => println!("Loaded state from {}", filename),
+                    Err(e) => println!("Unable to load state from {}: {}", filename, e),
+                }
+            }
+            inputs::StateHotkey::Rewind(held) => {
+                if self.rewinding && !held {
+                    self.core.resync_realtime();
+                }
+                self.rewinding = held && self.rewind.is_some();
+            }
+        }
+    }
+
+    // Save the (battery-backed) cartridge RAM, if it's changed.
+    fn save_cart_ram(core: &mut cpu::core::Core<SegaMemory>) {
+        let memory = &mut core.memory.memory;
+        if let Err(e) = memory.save_cart_ram() {
+            println!(
+                "Unable to save cartridge RAM to {}: {}",
+                memory.battery_name(),
+                e
+            );
+        }
+    }
+
+    pub fn set_rewind(&mut self, rewind: rewind::Rewind) {
+        self.rewind = Some(rewind);
+    }
+
+    pub fn set_debugger(&mut self, debugger: SegaDebugger) {
+        self.debugger = Some(debugger);
+    }
+
+    // Step the CPU (stopping in the debugger if needed), false to stop the emulator.
+    fn step_core(
+        core: &mut cpu::core::Core<SegaMemory>,
+        debugger: &mut Option<SegaDebugger>,
+        realtime: bool,
+    ) -> bool {
+        if let Some(debugger) = debugger.as_mut() {
+            if !debugger.before_step(core) {
+                // Stopping, so write out the instructions leading up to it.
+                core.flush_trace();
+                return false;
+            }
+        }
+        if let Err(cpu_fault) = core.step(realtime) {
+            println!("{}", cpu_fault);
+            return false;
+        }
+        if let Some(debugger) = debugger.as_mut() {
+            debugger.after_step(core);
+        }
+        true
+    }
+
     pub fn draw_loop(
         &mut self,
         pixel_format: pixels::PixelFormatEnum,
@@ -157,25 +373,58 @@ impl Sega {
                 console_size.console_height,
             );
 
-            let mut audio_steps = 0;
+            let mut next_audio_update = self.core.clock.cycles;
             let mut display_refreshes = 0;
             while display_refreshes < iterations {
-                if self.stop_clock > 0 && self.core.clock.cycles > self.stop_clock {
-                    return false;
-                }
-                self.core.step(self.debug, self.realtime);
-
-                if 0 == audio_steps % Sega::CPU_STEPS_PER_AUDIO_UPDATE {
-                    // Top-up the audio queue
-                    let audio_queue = self.audio_queue.as_mut().expect("Optional audio not set");
-                    sound::SDLUtility::top_up_audio_queue(audio_queue, |fill_size| {
-                        self.core.ports.audio.get_next_audio_chunk(fill_size)
-                    });
-                }
-                audio_steps += 1;
+                let frame_ready = if self.rewinding {
+                    // Step back a snapshot per display refresh, rather than running the CPU.
+                    let rewind = self.rewind.as_mut().expect("Optional rewind not set");
+                    let restored = rewind.step_back(&mut self.core);
+                    if restored {
+                        self.core.redraw();
+                    } else {
+                        display_refreshes += 1; // Nothing left, wait for the key to be released.
+                    }
+                    if self.realtime {
+                        thread::sleep(Sega::REWIND_FRAME_TIME);
+                    }
+                    restored
+                } else {
+                    if self.stop_clock > 0 && self.core.clock.cycles > self.stop_clock {
+                        return false;
+                    }
+                    if !Self::step_core(&mut self.core, &mut self.debugger, self.realtime) {
+                        return false;
+                    }
+
+                    // By cycles rather than steps, as a halted step can cover a whole frame.
+                    if self.core.clock.cycles >= next_audio_update {
+                        // Top-up the audio queue
+                        let audio_queue =
+                            self.audio_queue.as_mut().expect("Optional audio not set");
+                        sound::SDLUtility::top_up_audio_queue(audio_queue, |fill_size| {
+                            self.core.ports.audio.get_next_audio_chunk(fill_size)
+                        });
+                        next_audio_update = self.core.clock.cycles + Sega::CYCLES_PER_AUDIO_UPDATE;
+                    }
+
+                    let exported = self.core.export();
+                    if exported {
+                        if let Some(rewind) = self.rewind.as_mut() {
+                            rewind.frame(&self.core);
+                        }
+
+                        self.frames_since_cart_ram_save += 1;
+                        if self.frames_since_cart_ram_save >= Sega::CART_RAM_SAVE_FRAMES {
+                            self.frames_since_cart_ram_save = 0;
+                            Self::save_cart_ram(&mut self.core);
+                        }
+                    }
+                    exported
+                };
 
                 // If an 'export' occurred (buffer was draw), then update the texture.
-                if self.core.export() {
+                if frame_ready {
                     texture
                         .with_lock(None, |buffer: &mut [u8], _pitch: usize| {
                             self.core.generate_display(buffer)
@@ -202,22 +451,24 @@ impl Sega {
             }
             true
         } else {
-            let mut audio_steps = 0;
+            let mut next_audio_update = self.core.clock.cycles;
             let mut display_refreshes = 0;
             while display_refreshes < iterations {
                 if self.stop_clock > 0 && self.core.clock.cycles > self.stop_clock {
                     return false;
                 }
-                self.core.step(self.debug, self.realtime);
+                if !Self::step_core(&mut self.core, &mut self.debugger, self.realtime) {
+                    return false;
+                }
 
-                if 0 == audio_steps % Sega::CPU_STEPS_PER_AUDIO_UPDATE {
+                if self.core.clock.cycles >= next_audio_update {
                     // Top-up the audio queue
                     let audio_queue = self.audio_queue.as_mut().expect("Optional audio not set");
                     sound::SDLUtility::top_up_audio_queue(audio_queue, |fill_size| {
                         self.core.ports.audio.get_next_audio_chunk(fill_size)
                     });
+                    next_audio_update = self.core.clock.cycles + Sega::CYCLES_PER_AUDIO_UPDATE;
                 }
-                audio_steps += 1;
 
                 display_refreshes += 1;
             }
@@ -252,3 +503,61 @@ impl Sega {
         self.sdl_context = Some(sdl_context);
     }
 }
+
+impl Drop for Sega {
+    fn drop(&mut self) {
+        Self::save_cart_ram(&mut self.core);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sega::clocks;
+    use crate::sega::memory::romdb;
+    use crate::sega::sega;
+
+    fn write_rom(name: &str, fill: u8) -> (String, u32) {
+        let rom = vec![fill; 0x8000];
+        let filename = std::env::temp_dir().join(format!("{}_{}.sms", name, std::process::id()));
+        let filename = filename.to_str().unwrap().to_string();
+        std::fs::write(&filename, &rom).unwrap();
+        (filename, romdb::crc32(&rom))
+    }
+
+    #[test]
+    fn test_reset_applies_rom_timing() {
+        let (ntsc_name, _) = write_rom("reset_ntsc", 0x00);
+        let (pal_name, pal_crc) = write_rom("reset_pal", 0xFF);
+        let mut romdb = romdb::RomDb::embedded();
+        romdb
+            .add_entries(&format!("{:08x} tv=pal", pal_crc))
+            .unwrap();
+
+        let mut sega = sega::Sega::new(
+            None,
+            false,
+            0,
+            &ntsc_name,
+            false,
+            false,
+            sega::MachineOptions {
+                romdb,
+                ..Default::default()
+            },
+        );
+        assert_eq!(sega.core.get_timing().frame_cycles(), 59736);
+
+        // Loading the cartridge later (as the web build does) picks up its video standard.
+        sega.reset(&pal_name);
+        assert_eq!(sega.core.get_timing(), clocks::TimingProfile::PAL);
+        assert_eq!(sega.core.get_timing().frame_cycles(), 71364);
+
+        // The VDP's V-counter follows it too (NTSC would have jumped back to 0xD5).
+        let mut clock = clocks::Clock::new();
+        clock.cycles = 0xDB * clocks::TimingProfile::CYCLES_PER_LINE as u64;
+        assert_eq!(sega.core.ports.port_read(&clock, 0x7E), 0xDB);
+
+        std::fs::remove_file(&ntsc_name).unwrap();
+        std::fs::remove_file(&pal_name).unwrap();
+    }
+}